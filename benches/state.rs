@@ -0,0 +1,308 @@
+//! Benchmarks of the shared state under concurrent load.
+//!
+//! They start ellidri and connect thousands of clients to it over TCP.  Run them with:
+//!
+//!     cargo bench --bench state
+//!
+//! Set `ELLIDRI_BENCH_BIN` to the path of another ellidri executable to benchmark it instead.
+//! Set `ELLIDRI_BENCH_BASELINE` to the path of an older ellidri executable to run the benchmarks
+//! against both, and print how throughput and latency changed.
+
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fs};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+
+const CLIENTS: usize = 1000;
+const CHANNELS: usize = 20;
+const MESSAGES_PER_CLIENT: usize = 20;
+const JOINS_PER_CLIENT: usize = 5;
+
+/// Number of clients that may register at the same time, to stay below the listen backlog.
+const CONCURRENT_CONNECTS: usize = 100;
+
+/// A running ellidri, killed on drop.
+struct Server {
+    process: Child,
+    address: SocketAddr,
+}
+
+impl Server {
+    fn start(program: &str) -> Server {
+        let address = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap();
+        let config = env::temp_dir().join(format!("ellidri-bench-{}.conf", address.port()));
+        let contents = format!(
+            "domain bench.test\n\
+             listen {}\n\
+             connection_class default {{\n    exempt true\n}}\n",
+            address,
+        );
+        fs::write(&config, contents).unwrap();
+
+        let process = Command::new(program)
+            .arg(&config)
+            .env("ELLIDRI_LOG", "error")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { process, address };
+
+        let start = Instant::now();
+        while std::net::TcpStream::connect(address).is_err() {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "ellidri did not start"
+            );
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = fs::remove_file(&config);
+        server
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+/// Where clients connect to.
+#[derive(Clone)]
+struct Target {
+    address: SocketAddr,
+    connects: Arc<Semaphore>,
+}
+
+/// A registered client with echo-message enabled.
+struct Client {
+    nick: String,
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    line: String,
+}
+
+impl Client {
+    async fn connect(target: &Target, n: usize) -> Client {
+        let _permit = target.connects.acquire().await.unwrap();
+        let (reader, writer) = TcpStream::connect(target.address)
+            .await
+            .unwrap()
+            .into_split();
+        let mut client = Client {
+            nick: format!("n{}", n),
+            reader: BufReader::new(reader),
+            writer,
+            line: String::new(),
+        };
+        let registration = format!(
+            "CAP REQ echo-message\r\nNICK n{}\r\nUSER u{} 0 * :Real\r\nCAP END\r\n",
+            n, n,
+        );
+        client.send(&registration).await;
+        assert!(
+            client.wait_for(|line| command(line) == "001").await,
+            "registration failed"
+        );
+        client
+    }
+
+    async fn send(&mut self, lines: &str) {
+        self.writer.write_all(lines.as_bytes()).await.unwrap();
+    }
+
+    /// Reads lines until one matches `f`.  Returns false when the connection is closed.
+    async fn wait_for(&mut self, f: impl Fn(&str) -> bool) -> bool {
+        loop {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line).await {
+                Ok(0) | Err(_) => return false,
+                Ok(_) if f(&self.line) => return true,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Sends `lines` and returns the time it took to receive `count` lines that match `f`.
+    async fn time(&mut self, lines: &str, count: usize, f: impl Fn(&str) -> bool) -> Duration {
+        let start = Instant::now();
+        self.send(lines).await;
+        for _ in 0..count {
+            assert!(self.wait_for(&f).await, "connection closed by ellidri");
+        }
+        start.elapsed()
+    }
+
+    /// Joins `channels` (comma-separated) and returns the time it took.
+    async fn join(&mut self, channels: &str, count: usize) -> Duration {
+        let line = format!("JOIN {}\r\n", channels);
+        self.time(&line, count, |line| command(line) == "366").await
+    }
+
+    /// Sends a message to `channel` and returns the time it took to be echoed.
+    async fn message(&mut self, channel: &str) -> Duration {
+        let line = format!("PRIVMSG {} :Hello, world!\r\n", channel);
+        let prefix = format!(":{}!", self.nick);
+        self.time(&line, 1, |line| {
+            line.starts_with(&prefix) && command(line) == "PRIVMSG"
+        })
+        .await
+    }
+}
+
+/// Returns the command of `line`, which must have a prefix.
+fn command(line: &str) -> &str {
+    line.split(' ').nth(1).unwrap_or("")
+}
+
+/// The results of a benchmark.
+struct Stats {
+    name: &'static str,
+    throughput: f64,
+    p50: Duration,
+    p99: Duration,
+}
+
+fn report(name: &'static str, total: Duration, mut latencies: Vec<Duration>) -> Stats {
+    latencies.sort_unstable();
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    println!(
+        "{}: {} messages in {:?} ({:.0} msg/s), latency p50 {:?}, p99 {:?}, max {:?}",
+        name,
+        latencies.len(),
+        total,
+        latencies.len() as f64 / total.as_secs_f64(),
+        percentile(50),
+        percentile(99),
+        latencies[latencies.len() - 1],
+    );
+    Stats {
+        name,
+        throughput: latencies.len() as f64 / total.as_secs_f64(),
+        p50: percentile(50),
+        p99: percentile(99),
+    }
+}
+
+/// Prints how `after` changed compared to `before`.
+fn compare(before: &Stats, after: &Stats) {
+    let change = |before: f64, after: f64| (after / before - 1.0) * 100.0;
+    println!(
+        "{}: throughput {:+.0}%, latency p50 {:+.0}%, p99 {:+.0}%",
+        before.name,
+        change(before.throughput, after.throughput),
+        change(before.p50.as_secs_f64(), after.p50.as_secs_f64()),
+        change(before.p99.as_secs_f64(), after.p99.as_secs_f64()),
+    );
+}
+
+/// Runs `tasks` at the same time, and reports the latencies they return.
+async fn run<T>(name: &'static str, tasks: impl Iterator<Item = T>) -> Stats
+where
+    T: Future<Output = Vec<Duration>> + Send + 'static,
+{
+    let start = Instant::now();
+    let tasks: Vec<_> = tasks.map(tokio::spawn).collect();
+    let mut latencies = Vec::new();
+    for task in tasks {
+        latencies.extend(task.await.unwrap());
+    }
+    report(name, start.elapsed(), latencies)
+}
+
+/// Every client joins one channel, then all clients talk at the same time.
+async fn channel_fanout(target: Target) -> Stats {
+    let mut clients = Vec::with_capacity(CLIENTS);
+    for n in 0..CLIENTS {
+        let mut client = Client::connect(&target, n).await;
+        client.join(&format!("#c{}", n % CHANNELS), 1).await;
+        clients.push(client);
+    }
+
+    let tasks = clients
+        .into_iter()
+        .enumerate()
+        .map(|(n, mut client)| async move {
+            let channel = format!("#c{}", n % CHANNELS);
+            let mut latencies = Vec::with_capacity(MESSAGES_PER_CLIENT);
+            for _ in 0..MESSAGES_PER_CLIENT {
+                latencies.push(client.message(&channel).await);
+            }
+            // Keep reading so that other clients are not slowed down by a full socket.
+            tokio::spawn(async move { client.wait_for(|_| false).await });
+            latencies
+        });
+    run("channel fan-out", tasks).await
+}
+
+/// Clients quit and reconnect to several channels at once, like after a netsplit.
+async fn reconnect_storm(target: Target) -> Stats {
+    let tasks = (CLIENTS..2 * CLIENTS).map(|n| {
+        let target = target.clone();
+        async move {
+            let channels = (0..JOINS_PER_CLIENT)
+                .map(|c| format!("#r{}", (n + c * CHANNELS) % (JOINS_PER_CLIENT * CHANNELS)))
+                .collect::<Vec<_>>()
+                .join(",");
+            let mut client = Client::connect(&target, n).await;
+            let join = client.join(&channels, JOINS_PER_CLIENT).await;
+            let quit = client
+                .time("QUIT :Bye\r\n", 1, |line| line.starts_with("ERROR"))
+                .await;
+            let mut client = Client::connect(&target, n).await;
+            let rejoin = client.join(&channels, JOINS_PER_CLIENT).await;
+            vec![join, quit, rejoin]
+        }
+    });
+    run("reconnect storm", tasks).await
+}
+
+/// Runs every benchmark against a new instance of `program`.
+fn bench(program: &str) -> Vec<Stats> {
+    println!("{}", program);
+    let server = Server::start(program);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let target = Target {
+        address: server.address,
+        connects: Arc::new(Semaphore::new(CONCURRENT_CONNECTS)),
+    };
+    vec![
+        runtime.block_on(channel_fanout(target.clone())),
+        runtime.block_on(reconnect_storm(target)),
+    ]
+}
+
+fn main() {
+    // `cargo test --benches` runs benchmarks with `--bench` missing, only check that they build.
+    if !env::args().any(|arg| arg == "--bench") {
+        return;
+    }
+
+    let program = env::var("ELLIDRI_BENCH_BIN")
+        .unwrap_or_else(|_| String::from(env!("CARGO_BIN_EXE_ellidri")));
+    match env::var("ELLIDRI_BENCH_BASELINE") {
+        Ok(baseline) => {
+            let before = bench(&baseline);
+            let after = bench(&program);
+            println!("compared to the baseline");
+            for (before, after) in before.iter().zip(&after) {
+                compare(before, after);
+            }
+        }
+        Err(_) => {
+            bench(&program);
+        }
+    }
+}
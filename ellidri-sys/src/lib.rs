@@ -0,0 +1,462 @@
+//! Operating system interfaces for ellidri.
+//!
+//! ellidri forbids unsafe code.  The few interfaces that need it are gathered here, behind safe
+//! functions.
+
+#![warn(clippy::all, rust_2018_idioms)]
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(unix)]
+mod unix {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::path::Path;
+    use std::{env, io, process, ptr};
+
+    /// The first file descriptor passed by the service manager (`SD_LISTEN_FDS_START`).
+    const LISTEN_FDS_START: RawFd = 3;
+
+    /// The largest buffer given to `getpwnam_r(3)` and similar functions.
+    const MAX_ENTRY_BUFFER: usize = 1 << 20;
+
+    /// Takes ownership of the file descriptors passed by the service manager, following the socket
+    /// activation protocol of systemd.
+    ///
+    /// Returns the file descriptors along with their name, from `LISTEN_FDNAMES`.  The environment
+    /// variables of the protocol are removed, so that only the first call returns file
+    /// descriptors.  It must therefore be called before other threads are spawned.
+    pub fn listen_fds() -> Vec<(String, OwnedFd)> {
+        let pid = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<RawFd>().ok());
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        let count = match count {
+            Some(count) if pid == Some(process::id()) => count.max(0),
+            _ => return Vec::new(),
+        };
+        let mut names = names.split(':');
+
+        (LISTEN_FDS_START..LISTEN_FDS_START + count)
+            .map(|fd| {
+                let name = names.next().unwrap_or("unknown").to_owned();
+                // SAFETY: the service manager has opened these file descriptors for this process
+                // (checked with LISTEN_PID), and nothing else takes ownership of them since the
+                // environment variables have been removed.
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                (name, fd)
+            })
+            .collect()
+    }
+
+    /// Clears the close-on-exec flag of `fd`, so that it stays open in the program that replaces
+    /// ellidri with `execve(2)`.
+    pub fn set_inheritable(fd: &impl AsRawFd) -> io::Result<()> {
+        let fd = fd.as_raw_fd();
+        // SAFETY: fcntl does not touch memory, and `fd` is kept open by the borrow.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes ownership of a file descriptor left open by the previous ellidri process, after it
+    /// has replaced itself with this one to upgrade.
+    ///
+    /// Returns `None` when `fd` is not open, or when it is a standard stream or has the
+    /// close-on-exec flag.  The standard library opens everything with this flag, so the file
+    /// descriptor cannot be owned by anything else in the process.  The flag is set back on the
+    /// returned file descriptor.
+    pub fn inherited_fd(fd: RawFd) -> Option<OwnedFd> {
+        if fd <= libc::STDERR_FILENO {
+            return None;
+        }
+        // SAFETY: fcntl does not touch memory.  The file descriptor is open, has been passed
+        // across execve(2) and is not owned by anything in this process, as explained above.
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags == -1 || flags & libc::FD_CLOEXEC != 0 {
+                return None;
+            }
+            if libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) == -1 {
+                return None;
+            }
+            Some(OwnedFd::from_raw_fd(fd))
+        }
+    }
+
+    /// Changes the root directory of the process to `dir`, see `chroot(2)`, and its working
+    /// directory to the new root.
+    pub fn chroot(dir: &Path) -> io::Result<()> {
+        let dir = CString::new(dir.as_os_str().as_bytes())?;
+        // SAFETY: both paths are NUL-terminated strings that live during the whole call.
+        unsafe {
+            if libc::chroot(dir.as_ptr()) == -1 || libc::chdir(b"/\0".as_ptr().cast()) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the effective user ID and group ID of the process.
+    pub fn ids() -> (u32, u32) {
+        // SAFETY: geteuid and getegid always succeed and do not touch memory.
+        unsafe { (libc::geteuid(), libc::getegid()) }
+    }
+
+    /// Sets the real, effective and saved group IDs of every thread of the process to `gid`, and
+    /// removes its supplementary groups.
+    pub fn set_group(gid: u32) -> io::Result<()> {
+        // SAFETY: setgroups reads one group ID from `gid`, which lives during the whole call.
+        // setgid does not touch memory.
+        unsafe {
+            if libc::setgroups(1, &gid) == -1 || libc::setgid(gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the real, effective and saved user IDs of every thread of the process to `uid`.  When
+    /// the process runs as root, it cannot get its privileges back afterwards.
+    pub fn set_user(uid: u32) -> io::Result<()> {
+        // SAFETY: setuid does not touch memory.
+        if unsafe { libc::setuid(uid) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Returns the user ID and the primary group ID of the user `name`, see `getpwnam(3)`.
+    ///
+    /// Users are looked up through the name service switch, so that users from LDAP and other
+    /// sources are found too.  Returns `None` when there is no such user.
+    pub fn user_by_name(name: &str) -> io::Result<Option<(u32, u32)>> {
+        let name = CString::new(name)?;
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        with_buffer(|buf| {
+            let mut res = ptr::null_mut();
+            // SAFETY: getpwnam_r reads `name`, a NUL-terminated string, and writes the entry in
+            // `passwd` and the strings it points to in `buf`, whose length is given.  All of them
+            // live during the whole call.  `res` then points to the entry, if it is found.
+            unsafe {
+                let err = libc::getpwnam_r(
+                    name.as_ptr(),
+                    passwd.as_mut_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut res,
+                );
+                Ok(found(err, res)?.then(|| ((*res).pw_uid, (*res).pw_gid)))
+            }
+        })
+    }
+
+    /// Returns the user ID and the primary group ID of the user of ID `uid`, see `getpwuid(3)`.
+    ///
+    /// Returns `None` when there is no such user.
+    pub fn user_by_id(uid: u32) -> io::Result<Option<(u32, u32)>> {
+        let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+        with_buffer(|buf| {
+            let mut res = ptr::null_mut();
+            // SAFETY: getpwuid_r writes the entry in `passwd` and the strings it points to in
+            // `buf`, whose length is given.  Both live during the whole call.  `res` then points to
+            // the entry, if it is found.
+            unsafe {
+                let err = libc::getpwuid_r(
+                    uid,
+                    passwd.as_mut_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut res,
+                );
+                Ok(found(err, res)?.then(|| ((*res).pw_uid, (*res).pw_gid)))
+            }
+        })
+    }
+
+    /// Returns the group ID of the group `name`, see `getgrnam(3)`.
+    ///
+    /// Groups are looked up through the name service switch, like users in `user_by_name`.
+    /// Returns `None` when there is no such group.
+    pub fn group_by_name(name: &str) -> io::Result<Option<u32>> {
+        let name = CString::new(name)?;
+        let mut group = MaybeUninit::<libc::group>::uninit();
+        with_buffer(|buf| {
+            let mut res = ptr::null_mut();
+            // SAFETY: getgrnam_r reads `name`, a NUL-terminated string, and writes the entry in
+            // `group` and the strings it points to in `buf`, whose length is given.  All of them
+            // live during the whole call.  `res` then points to the entry, if it is found.
+            unsafe {
+                let err = libc::getgrnam_r(
+                    name.as_ptr(),
+                    group.as_mut_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut res,
+                );
+                Ok(found(err, res)?.then(|| (*res).gr_gid))
+            }
+        })
+    }
+
+    /// Calls `lookup` with a buffer for the strings of a user or group entry, and again with a
+    /// larger buffer each time the entry does not fit (`ERANGE`).
+    ///
+    /// `lookup` returns `Ok(None)` when there is no such entry, or the error number of the call.
+    /// Entries that need more than `MAX_ENTRY_BUFFER` bytes fail with `ERANGE`.
+    fn with_buffer<T>(
+        mut lookup: impl FnMut(&mut [libc::c_char]) -> Result<Option<T>, libc::c_int>,
+    ) -> io::Result<Option<T>> {
+        let mut buf = vec![0; 1024];
+        loop {
+            match lookup(&mut buf) {
+                Ok(res) => return Ok(res),
+                Err(libc::ERANGE) if buf.len() < MAX_ENTRY_BUFFER => buf.resize(buf.len() * 2, 0),
+                Err(err) => return Err(io::Error::from_raw_os_error(err)),
+            }
+        }
+    }
+
+    /// Returns whether `getpwnam_r(3)` or a similar function has found the entry, given its
+    /// return value and its result pointer.  POSIX lets these functions fail with various errors
+    /// when the entry does not exist, besides leaving the result null.
+    fn found<T>(err: libc::c_int, res: *mut T) -> Result<bool, libc::c_int> {
+        match err {
+            0 => Ok(!res.is_null()),
+            libc::ENOENT | libc::ESRCH | libc::EBADF | libc::EPERM => Ok(false),
+            err => Err(err),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::convert::TryInto;
+    use std::ffi::{CString, OsString};
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read};
+    use std::mem;
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::path::Path;
+
+    /// The size of `struct inotify_event`, without the name that follows it.
+    const EVENT_HEADER_LEN: usize = 16;
+
+    /// The filesystem access rights of Landlock rules, see `landlock(7)`.
+    ///
+    /// Only the rights of the first version of Landlock are used, so that rulesets work with every
+    /// kernel that supports it.
+    pub mod landlock {
+        pub const EXECUTE: u64 = 1 << 0;
+        pub const WRITE_FILE: u64 = 1 << 1;
+        pub const READ_FILE: u64 = 1 << 2;
+        pub const READ_DIR: u64 = 1 << 3;
+        pub const REMOVE_DIR: u64 = 1 << 4;
+        pub const REMOVE_FILE: u64 = 1 << 5;
+        pub const MAKE_CHAR: u64 = 1 << 6;
+        pub const MAKE_DIR: u64 = 1 << 7;
+        pub const MAKE_REG: u64 = 1 << 8;
+        pub const MAKE_SOCK: u64 = 1 << 9;
+        pub const MAKE_FIFO: u64 = 1 << 10;
+        pub const MAKE_BLOCK: u64 = 1 << 11;
+        pub const MAKE_SYM: u64 = 1 << 12;
+
+        /// Every right above, which is what rulesets restrict.
+        pub const ALL: u64 = (1 << 13) - 1;
+
+        /// The rights that apply to files.  The others only apply to directories.
+        pub const FILE: u64 = EXECUTE | WRITE_FILE | READ_FILE;
+    }
+
+    /// `struct landlock_ruleset_attr`.
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    /// `struct landlock_path_beneath_attr`.
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// `LANDLOCK_RULE_PATH_BENEATH`.
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    /// A Landlock ruleset, which restricts the access to the filesystem to what its rules allow
+    /// once it is enforced.
+    pub struct Landlock(OwnedFd);
+
+    impl Landlock {
+        /// Creates a ruleset that allows nothing.  Fails with `ENOSYS` or `EOPNOTSUPP` when the
+        /// kernel does not support Landlock.
+        pub fn new() -> io::Result<Self> {
+            let attr = RulesetAttr {
+                handled_access_fs: landlock::ALL,
+            };
+            // SAFETY: the kernel reads `attr`, whose size is given, during the call.  The file
+            // descriptor it returns is new, and owned by the returned ruleset.
+            unsafe {
+                let fd = libc::syscall(
+                    libc::SYS_landlock_create_ruleset,
+                    &attr as *const RulesetAttr,
+                    mem::size_of::<RulesetAttr>(),
+                    0,
+                );
+                if fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Self(OwnedFd::from_raw_fd(fd as RawFd)))
+            }
+        }
+
+        /// Allows `access` to `path`, and to everything below it if it is a directory.  When it
+        /// is a file, the rights that only apply to directories are ignored.
+        pub fn allow(&self, path: &Path, access: u64) -> io::Result<()> {
+            let file = OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_PATH)
+                .open(path)?;
+            let access = if file.metadata()?.is_dir() {
+                access
+            } else {
+                access & landlock::FILE
+            };
+            if access == 0 {
+                return Ok(());
+            }
+            let attr = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: file.as_raw_fd(),
+            };
+            // SAFETY: the kernel reads `attr` during the call, and `file` is kept open by it.
+            let res = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    self.0.as_raw_fd(),
+                    RULE_PATH_BENEATH,
+                    &attr as *const PathBeneathAttr,
+                    0,
+                )
+            };
+            if res == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+
+        /// Enforces the ruleset on the calling thread, and on the threads and processes it creates
+        /// afterwards, including through `execve(2)`.  The other threads are not restricted.
+        ///
+        /// The thread is also prevented from gaining privileges, see `PR_SET_NO_NEW_PRIVS`.
+        pub fn restrict_self(self) -> io::Result<()> {
+            // SAFETY: prctl and landlock_restrict_self do not touch memory, and the ruleset is
+            // kept open during the call.
+            unsafe {
+                let no_new_privs = libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+                if no_new_privs == -1
+                    || libc::syscall(libc::SYS_landlock_restrict_self, self.0.as_raw_fd(), 0) == -1
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// An inotify instance, see `inotify(7)`.
+    ///
+    /// Its file descriptor is non-blocking, so that it can be polled by an event loop.
+    pub struct Inotify(File);
+
+    /// A change to a file of a watched directory.
+    pub struct Event {
+        /// The watch descriptor of the directory, as returned by `Inotify::watch_dir`.
+        pub watch: i32,
+
+        /// The name of the file in the directory.
+        pub name: OsString,
+    }
+
+    impl Inotify {
+        pub fn new() -> io::Result<Self> {
+            // SAFETY: inotify_init1 does not touch memory.  The file descriptor it returns is
+            // new, and owned by the returned file.
+            unsafe {
+                let fd = libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC);
+                if fd == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Self(File::from_raw_fd(fd)))
+            }
+        }
+
+        /// Watches the files of the directory `dir` that are written, moved or removed.
+        ///
+        /// Returns the watch descriptor of the directory, which is the same for every call with
+        /// the same directory.
+        pub fn watch_dir(&self, dir: &Path) -> io::Result<i32> {
+            let dir = CString::new(dir.as_os_str().as_bytes())?;
+            let mask = libc::IN_CLOSE_WRITE
+                | libc::IN_MOVED_FROM
+                | libc::IN_MOVED_TO
+                | libc::IN_DELETE
+                | libc::IN_ONLYDIR;
+            // SAFETY: `dir` is a NUL-terminated string that lives during the whole call.
+            let watch = unsafe { libc::inotify_add_watch(self.0.as_raw_fd(), dir.as_ptr(), mask) };
+            if watch == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(watch)
+        }
+
+        /// Returns the pending events.  Fails with `io::ErrorKind::WouldBlock` when there are
+        /// none.
+        pub fn read_events(&self) -> io::Result<Vec<Event>> {
+            let mut buf = [0; 4096];
+            let n = (&self.0).read(&mut buf)?;
+            let mut buf = &buf[..n];
+
+            let mut events = Vec::new();
+            while EVENT_HEADER_LEN <= buf.len() {
+                let watch = i32::from_ne_bytes(buf[0..4].try_into().unwrap());
+                let len = u32::from_ne_bytes(buf[12..16].try_into().unwrap()) as usize;
+                let name = buf
+                    .get(EVENT_HEADER_LEN..EVENT_HEADER_LEN + len)
+                    .unwrap_or(&[]);
+                let name = name.split(|b| *b == 0).next().unwrap_or(&[]);
+                events.push(Event {
+                    watch,
+                    name: OsString::from_vec(name.to_vec()),
+                });
+                buf = buf.get(EVENT_HEADER_LEN + len..).unwrap_or(&[]);
+            }
+            Ok(events)
+        }
+    }
+
+    impl AsRawFd for Inotify {
+        fn as_raw_fd(&self) -> RawFd {
+            self.0.as_raw_fd()
+        }
+    }
+}
@@ -13,6 +13,9 @@ macro_rules! commands {
         }
 
         impl Command {
+            /// Every known command.
+            pub const ALL: &'static [Command] = &[ $( Command::$cmd, )* ];
+
             /// From a given command string, returns the corresponding command, or `None`
             /// otherwise.
             ///
@@ -105,11 +108,14 @@ commands! {
     Authenticate "AUTHENTICATE" 1
     Away     "AWAY"     0
     Cap      "CAP"      1
+    Dline    "DLINE"    1
+    GlobOps  "GLOBOPS"  1
     Info     "INFO"     0
     Invite   "INVITE"   2
     Join     "JOIN"     1
     Kick     "KICK"     2
     Kill     "KILL"     2
+    Kline    "KLINE"    1
     List     "LIST"     0
     LUsers   "LUSERS"   0
     Mode     "MODE"     1
@@ -125,12 +131,22 @@ commands! {
     PrivMsg  "PRIVMSG"  2
     Quit     "QUIT"     0
     Rehash   "REHASH"   0
+    SaJoin   "SAJOIN"   2
+    SaMode   "SAMODE"   2
+    SaNick   "SANICK"   2
+    SaPart   "SAPART"   2
+    SaTopic  "SATOPIC"  2
     SetName  "SETNAME"  1
+    Stats    "STATS"    1
     TagMsg   "TAGMSG"   1
     Time     "TIME"     0
     Topic    "TOPIC"    1
+    UnDline  "UNDLINE"  1
+    UnKline  "UNKLINE"  1
+    Upgrade  "UPGRADE"  0
     User     "USER"     4
     Version  "VERSION"  0
+    WallOps  "WALLOPS"  1
     Who      "WHO"      0
     WhoIs    "WHOIS"    1
 }
@@ -0,0 +1,172 @@
+//! mIRC formatting and CTCP helpers.
+//!
+//! <https://modern.ircdocs.horse/formatting.html>
+
+use std::borrow::Cow;
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const RESET: char = '\x0f';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const STRIKETHROUGH: char = '\x1e';
+const UNDERLINE: char = '\x1f';
+
+/// The delimiter of CTCP messages.
+pub const CTCP_DELIM: char = '\x01';
+
+fn is_format_char(c: char) -> bool {
+    matches!(
+        c,
+        BOLD | COLOR | HEX_COLOR | RESET | MONOSPACE | REVERSE | ITALIC | STRIKETHROUGH | UNDERLINE
+    )
+}
+
+/// Returns the number of leading bytes of `s` that are made of at most `max` characters matching
+/// `pred`.
+fn prefix_len(s: &str, max: usize, pred: impl Fn(char) -> bool) -> usize {
+    s.chars()
+        .take(max)
+        .take_while(|c| pred(*c))
+        .map(char::len_utf8)
+        .sum()
+}
+
+/// Returns the length of the color parameters at the start of `s`, given the number of digits of
+/// each color and the digit predicate.
+///
+/// Colors are of the form `<fg>[,<bg>]`.  The comma is part of the color code only if it is
+/// followed by a background color.
+fn color_len(s: &str, max: usize, is_digit: impl Fn(char) -> bool + Copy) -> usize {
+    let fg = prefix_len(s, max, is_digit);
+    if fg == 0 {
+        return 0;
+    }
+    let rest = &s[fg..];
+    if let Some(bg) = rest.strip_prefix(',') {
+        let bg = prefix_len(bg, max, is_digit);
+        if bg != 0 {
+            return fg + 1 + bg;
+        }
+    }
+    fg
+}
+
+/// Whether the given string contains any formatting code (bold, colors, italics...).
+///
+/// # Example
+///
+/// ```rust
+/// # use ellidri_tokens::format;
+/// assert!(format::has_formatting("\x0304red\x03 text"));
+/// assert!(format::has_formatting("\x02bold\x02"));
+/// assert!(!format::has_formatting("plain text"));
+/// ```
+pub fn has_formatting(s: &str) -> bool {
+    s.contains(is_format_char)
+}
+
+/// Removes all formatting codes from the given string, along with color parameters.
+///
+/// Does not allocate when `s` has no formatting code.
+///
+/// # Example
+///
+/// ```rust
+/// # use ellidri_tokens::format;
+/// assert_eq!(format::strip_formatting("\x0304,12red\x0f, \x02bold\x02"), "red, bold");
+/// assert_eq!(format::strip_formatting("hey\x03, \x0312friend"), "hey, friend");
+/// assert_eq!(format::strip_formatting("\x04ff00ffpink"), "pink");
+/// ```
+pub fn strip_formatting(s: &str) -> Cow<'_, str> {
+    if !has_formatting(s) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find(is_format_char) {
+        res.push_str(&rest[..i]);
+        let code = rest[i..].chars().next().unwrap();
+        rest = &rest[i + code.len_utf8()..];
+        match code {
+            COLOR => rest = &rest[color_len(rest, 2, |c| c.is_ascii_digit())..],
+            HEX_COLOR => rest = &rest[color_len(rest, 6, |c| c.is_ascii_hexdigit())..],
+            _ => {}
+        }
+    }
+    res.push_str(rest);
+
+    Cow::Owned(res)
+}
+
+/// Returns the command of the CTCP message in `s` (e.g. "ACTION" or "VERSION"), or `None` if
+/// `s` is not a CTCP message.
+///
+/// # Example
+///
+/// ```rust
+/// # use ellidri_tokens::format;
+/// assert_eq!(format::ctcp_command("\x01ACTION waves\x01"), Some("ACTION"));
+/// assert_eq!(format::ctcp_command("\x01VERSION"), Some("VERSION"));
+/// assert_eq!(format::ctcp_command("hello"), None);
+/// assert_eq!(format::ctcp_command("\x01"), None);
+/// ```
+pub fn ctcp_command(s: &str) -> Option<&str> {
+    let ctcp = s.strip_prefix(CTCP_DELIM)?;
+    let ctcp = ctcp.strip_suffix(CTCP_DELIM).unwrap_or(ctcp);
+    ctcp.split(' ').next().filter(|command| !command.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_formatting() {
+        let cases = [
+            ("", ""),
+            ("plain", "plain"),
+            ("\x02\x1d\x1f\x1e\x11\x16\x0f", ""),
+            ("\x03", ""),
+            ("\x03,05x", ",05x"),
+            ("\x031x", "x"),
+            ("\x03123", "3"),
+            ("\x0312,345", "5"),
+            ("\x0312,", ","),
+            ("\x04FF00", ""),
+            ("\x04ff00ff,00ff00g", "g"),
+            ("\x04fF00fF0", "0"),
+            ("é\x02à", "éà"),
+        ];
+
+        for (input, expected) in &cases {
+            assert_eq!(
+                strip_formatting(input),
+                *expected,
+                "strip_formatting({:?})",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_ctcp_command() {
+        let cases = [
+            ("", None),
+            ("hello", None),
+            ("\x01", None),
+            ("\x01\x01", None),
+            ("\x01 waves\x01", None),
+            ("\x01ACTION\x01", Some("ACTION")),
+            ("\x01ACTION waves\x01", Some("ACTION")),
+            ("\x01VERSION", Some("VERSION")),
+        ];
+
+        for (input, expected) in &cases {
+            assert_eq!(ctcp_command(input), *expected, "ctcp_command({:?})", input);
+        }
+    }
+} // mod tests
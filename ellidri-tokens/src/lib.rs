@@ -18,6 +18,7 @@ pub use message::{tag_escape, tags, Message, Tag, MESSAGE_LENGTH, PARAMS_LENGTH}
 
 mod buffers;
 mod command;
+pub mod format;
 mod message;
 pub mod mode;
 pub mod rpl;
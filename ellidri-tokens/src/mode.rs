@@ -3,18 +3,46 @@
 use std::str;
 
 /// User modes supported by ellidri.  Advertised in welcome messages.
-pub const USER_MODES: &str = "aio";
+pub const USER_MODES: &str = "aiosw";
+
+/// Server notice masks supported by ellidri, given as the parameter of user mode +s.
+pub const SNOMASKS: &str = "acfgknoqrt";
+
+/// Server notice masks, see [SNOMASKS].
+pub mod snomask {
+    /// Uses of IRC operator override commands (SAJOIN, SAMODE...).
+    pub const OVERRIDE: char = 'a';
+    /// Clients connecting.
+    pub const CONNECT: char = 'c';
+    /// Clients sending messages faster than their class allows, or disconnected for not reading
+    /// theirs.
+    pub const FLOOD: char = 'f';
+    /// GLOBOPS messages.
+    pub const GLOBOPS: char = 'g';
+    /// KILLs.
+    pub const KILL: char = 'k';
+    /// Nickname changes.
+    pub const NICK: char = 'n';
+    /// Clients becoming IRC operators.
+    pub const OPER: char = 'o';
+    /// Clients quitting.
+    pub const QUIT: char = 'q';
+    /// Configuration reloads.
+    pub const REHASH: char = 'r';
+    /// TLS handshake failures.
+    pub const TLS: char = 't';
+}
 
 /// Channel modes that have no parameters and are supported by ellidri.  Advertised in welcome
 /// messages.
-pub const SIMPLE_CHAN_MODES: &str = "imnst";
+pub const SIMPLE_CHAN_MODES: &str = "CMOPRSTcimnstz";
 
 /// Channel modes that require a parameter and are supported by ellidri.  Advertised in welcome
 /// messages.
 pub const EXTENDED_CHAN_MODES: &str = "beIkl";
 
 /// CHANMODES feature advertised in RPL_ISUPPORT.
-pub const CHANMODES: &str = "CHANMODES=beI,k,l,imnst";
+pub const CHANMODES: &str = "CHANMODES=beI,k,l,CMOPRSTcimnstz";
 
 /// Iterator over the modes of a string.
 struct SimpleQuery<'a> {
@@ -74,16 +102,20 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 /// Item of a user mode query.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
-pub enum UserChange {
+pub enum UserChange<'a> {
     Invisible(bool),
     DeOperator,
+
+    /// Server notices, with the snomask to apply.  The snomask is empty when none was given.
+    ServerNotices(bool, &'a str),
+    WallOps(bool),
 }
 
-impl UserChange {
+impl UserChange<'_> {
     /// Whether this change is enabling or disabling a mode.
     pub fn value(self) -> bool {
         match self {
-            Self::Invisible(v) => v,
+            Self::Invisible(v) | Self::ServerNotices(v, _) | Self::WallOps(v) => v,
             Self::DeOperator => false,
         }
     }
@@ -93,6 +125,8 @@ impl UserChange {
         match self {
             Self::Invisible(_) => 'i',
             Self::DeOperator => 'o',
+            Self::ServerNotices(_, _) => 's',
+            Self::WallOps(_) => 'w',
         }
     }
 }
@@ -103,27 +137,75 @@ impl UserChange {
 ///
 /// ```rust
 /// # use ellidri_tokens::mode::{self, Error, UserChange};
-/// let mut query = mode::user_query("+io-oXa");
+/// let mut query = mode::user_query("+ios-oXa", &["+ck-q"]);
 ///
 /// assert_eq!(query.next(), Some(Ok(UserChange::Invisible(true))));
 /// assert_eq!(query.next(), Some(Err(Error::Unchangeable('o', true))));
+/// assert_eq!(query.next(), Some(Ok(UserChange::ServerNotices(true, "+ck-q"))));
 /// assert_eq!(query.next(), Some(Ok(UserChange::DeOperator)));
 /// assert_eq!(query.next(), Some(Err(Error::Unknown('X', false))));
 /// assert_eq!(query.next(), Some(Err(Error::Unchangeable('a', false))));
 /// assert_eq!(query.next(), None);
 /// ```
-pub fn user_query(modes: &str) -> impl Iterator<Item = Result<UserChange>> + '_ {
-    SimpleQuery::new(modes).map(|(value, mode)| match mode {
+pub fn user_query<'a, I, S>(
+    modes: &'a str,
+    params: I,
+) -> impl Iterator<Item = Result<UserChange<'a>>>
+where
+    I: IntoIterator<Item = &'a S> + 'a,
+    S: AsRef<str> + 'a,
+{
+    let mut params = params
+        .into_iter()
+        .map(|p| p.as_ref())
+        .filter(|p| !p.is_empty());
+    SimpleQuery::new(modes).map(move |(value, mode)| match mode {
         'i' => Ok(UserChange::Invisible(value)),
         'o' if !value => Ok(UserChange::DeOperator),
+        's' if value => Ok(UserChange::ServerNotices(true, params.next().unwrap_or(""))),
+        's' => Ok(UserChange::ServerNotices(false, "")),
+        'w' => Ok(UserChange::WallOps(value)),
         other if USER_MODES.contains(other) => Err(Error::Unchangeable(other, value)),
         other => Err(Error::Unknown(other, value)),
     })
 }
 
+/// An iterator over the changes of a server notice mask.
+///
+/// # Example
+///
+/// ```rust
+/// # use ellidri_tokens::mode::{self, Error};
+/// let mut query = mode::snomask_query("ck-qX");
+///
+/// assert_eq!(query.next(), Some(Ok((true, 'c'))));
+/// assert_eq!(query.next(), Some(Ok((true, 'k'))));
+/// assert_eq!(query.next(), Some(Ok((false, 'q'))));
+/// assert_eq!(query.next(), Some(Err(Error::Unknown('X', false))));
+/// assert_eq!(query.next(), None);
+/// ```
+pub fn snomask_query(snomask: &str) -> impl Iterator<Item = Result<(bool, char)>> + '_ {
+    SimpleQuery::new(snomask).map(|(value, mask)| {
+        if SNOMASKS.contains(mask) {
+            Ok((value, mask))
+        } else {
+            Err(Error::Unknown(mask, value))
+        }
+    })
+}
+
 /// Item of a channel mode query.
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ChannelChange<'a> {
+    NoColors(bool),
+    NoCtcp(bool),
+    NoNotice(bool),
+    StripColors(bool),
+    OperOnly(bool),
+    Permanent(bool),
+    RegisteredOnly(bool),
+    RegisteredSpeak(bool),
+    TlsOnly(bool),
     InviteOnly(bool),
     Moderated(bool),
     NoPrivMsgFromOutside(bool),
@@ -137,6 +219,8 @@ pub enum ChannelChange<'a> {
     ChangeBan(bool, &'a str),
     ChangeException(bool, &'a str),
     ChangeInvitation(bool, &'a str),
+    ChangeFounder(bool, &'a str),
+    ChangeProtected(bool, &'a str),
     ChangeOperator(bool, &'a str),
     ChangeHalfop(bool, &'a str),
     ChangeVoice(bool, &'a str),
@@ -147,7 +231,16 @@ impl ChannelChange<'_> {
     pub fn value(&self) -> bool {
         use ChannelChange::*;
         match self {
-            InviteOnly(v)
+            NoColors(v)
+            | NoCtcp(v)
+            | NoNotice(v)
+            | StripColors(v)
+            | OperOnly(v)
+            | Permanent(v)
+            | RegisteredOnly(v)
+            | RegisteredSpeak(v)
+            | TlsOnly(v)
+            | InviteOnly(v)
             | Moderated(v)
             | NoPrivMsgFromOutside(v)
             | Secret(v)
@@ -156,6 +249,8 @@ impl ChannelChange<'_> {
             | ChangeBan(v, _)
             | ChangeException(v, _)
             | ChangeInvitation(v, _)
+            | ChangeFounder(v, _)
+            | ChangeProtected(v, _)
             | ChangeOperator(v, _)
             | ChangeHalfop(v, _)
             | ChangeVoice(v, _) => *v,
@@ -168,6 +263,15 @@ impl ChannelChange<'_> {
     pub fn symbol(&self) -> char {
         use ChannelChange::*;
         match self {
+            NoColors(_) => 'c',
+            NoCtcp(_) => 'C',
+            NoNotice(_) => 'T',
+            StripColors(_) => 'S',
+            OperOnly(_) => 'O',
+            Permanent(_) => 'P',
+            RegisteredOnly(_) => 'R',
+            RegisteredSpeak(_) => 'M',
+            TlsOnly(_) => 'z',
             InviteOnly(_) => 'i',
             Moderated(_) => 'm',
             NoPrivMsgFromOutside(_) => 'n',
@@ -178,12 +282,28 @@ impl ChannelChange<'_> {
             ChangeBan(_, _) | GetBans => 'b',
             ChangeException(_, _) | GetExceptions => 'e',
             ChangeInvitation(_, _) | GetInvitations => 'I',
+            ChangeFounder(_, _) => 'q',
+            ChangeProtected(_, _) => 'a',
             ChangeOperator(_, _) => 'o',
             ChangeHalfop(_, _) => 'h',
             ChangeVoice(_, _) => 'v',
         }
     }
 
+    /// The nickname of the member targeted by this mode change, if this change is about member
+    /// modes (e.g. +o, -v).
+    pub fn member(&self) -> Option<&str> {
+        use ChannelChange::*;
+        match self {
+            ChangeFounder(_, p)
+            | ChangeProtected(_, p)
+            | ChangeOperator(_, p)
+            | ChangeHalfop(_, p)
+            | ChangeVoice(_, p) => Some(p),
+            _ => None,
+        }
+    }
+
     /// The parameter of this mode change.
     pub fn param(&self) -> Option<&str> {
         use ChannelChange::*;
@@ -192,6 +312,8 @@ impl ChannelChange<'_> {
             | ChangeBan(_, p)
             | ChangeException(_, p)
             | ChangeInvitation(_, p)
+            | ChangeFounder(_, p)
+            | ChangeProtected(_, p)
             | ChangeOperator(_, p)
             | ChangeHalfop(_, p)
             | ChangeVoice(_, p) => Some(p),
@@ -232,6 +354,15 @@ where
     SimpleQuery::new(modes).map(move |(value, mode)| {
         use ChannelChange::*;
         match mode {
+            'c' => Ok(NoColors(value)),
+            'C' => Ok(NoCtcp(value)),
+            'T' => Ok(NoNotice(value)),
+            'S' => Ok(StripColors(value)),
+            'O' => Ok(OperOnly(value)),
+            'P' => Ok(Permanent(value)),
+            'R' => Ok(RegisteredOnly(value)),
+            'M' => Ok(RegisteredSpeak(value)),
+            'z' => Ok(TlsOnly(value)),
             'i' => Ok(InviteOnly(value)),
             'm' => Ok(Moderated(value)),
             'n' => Ok(NoPrivMsgFromOutside(value)),
@@ -279,6 +410,20 @@ where
                     Ok(GetInvitations)
                 }
             }
+            'q' => {
+                if let Some(param) = params.next() {
+                    Ok(ChangeFounder(value, param))
+                } else {
+                    Err(Error::MissingParam('q', value))
+                }
+            }
+            'a' => {
+                if let Some(param) = params.next() {
+                    Ok(ChangeProtected(value, param))
+                } else {
+                    Err(Error::MissingParam('a', value))
+                }
+            }
             'o' => {
                 if let Some(param) = params.next() {
                     Ok(ChangeOperator(value, param))
@@ -12,8 +12,17 @@ pub const YOURHOST: &str = "002"; // :Your host is...
 pub const CREATED: &str = "003"; // :This server was created...
 pub const MYINFO: &str = "004"; // <servername> <version> <umodes> <chan modes> <chan modes with a parameter>
 pub const ISUPPORT: &str = "005"; // 1*13<TOKEN[=value]> :are supported by this server
+pub const SNOMASKIS: &str = "008"; // <snomask> :Server notice mask
 
+pub const STATSLINKINFO: &str = "211"; // <linkname> <sendq> <sent messages> <sent Kbytes> <received messages> <received Kbytes> <time open>
+pub const STATSCOMMANDS: &str = "212"; // <command> <count> <byte count> <remote count>
+pub const STATSKLINE: &str = "216"; // K <host> * <username> :<reason>
+pub const ENDOFSTATS: &str = "219"; // <stats letter> :End of STATS report
+pub const STATSPLINE: &str = "220"; // P <port> <address> <flags>
 pub const UMODEIS: &str = "221"; // <modes>
+pub const STATSDLINE: &str = "225"; // d <mask> :<reason>
+pub const STATSUPTIME: &str = "242"; // :Server Up <days> days <hours>:<minutes>:<seconds>
+pub const STATSOLINE: &str = "243"; // O <hostmask> * <name> <port> <class>
 pub const LUSERCLIENT: &str = "251"; // :<int> users and <int> services on <int> servers
 pub const LUSEROP: &str = "252"; // <int> :operator(s) online
 pub const LUSERUNKNOWN: &str = "253"; // <int> :unknown connection(s)
@@ -34,6 +43,7 @@ pub const ENDOFWHO: &str = "315"; // <name> :End of WHO list
 pub const WHOISIDLE: &str = "317"; // <nick> <integer> [<integer>] :seconds idle [, signon time]
 pub const ENDOFWHOIS: &str = "318"; // <nick> :End of WHOIS list
 pub const WHOISCHANNELS: &str = "319"; // <nick> :*( (@/+) <channel> " " )
+pub const WHOISSPECIAL: &str = "320"; // <nick> :<info>
 pub const LIST: &str = "322"; // <channel> <# of visible members> <topic>
 pub const LISTEND: &str = "323"; // :End of list
 pub const CHANNELMODEIS: &str = "324"; // <channel> <modes> <mode params>
@@ -56,6 +66,7 @@ pub const MOTD: &str = "372"; // :- <text>
 pub const ENDOFINFO: &str = "374"; // :End of INFO
 pub const MOTDSTART: &str = "375"; // :- <servername> Message of the day -
 pub const ENDOFMOTD: &str = "376"; // :End of MOTD command
+pub const WHOISHOST: &str = "378"; // <nick> :is connecting from *@<host> <ip>
 pub const YOUREOPER: &str = "381"; // :You are now an operator
 pub const REHASHING: &str = "382"; // <config file> :Rehashing
 pub const TIME: &str = "391"; // <servername> :<time in whatever format>
@@ -66,6 +77,7 @@ pub const ERR_CANNOTSENDTOCHAN: &str = "404"; // <channel> :Cannot send to chann
 pub const ERR_INVALIDCAPCMD: &str = "410"; // <command> :Unknown cap command
 pub const ERR_NORECIPIENT: &str = "411"; // :No recipient given
 pub const ERR_NOTEXTTOSEND: &str = "412"; // :No text to send
+pub const ERR_BADMASK: &str = "415"; // <mask> :Bad Server/host mask
 pub const ERR_INPUTTOOLONG: &str = "417"; // :Input line was too long
 pub const ERR_UNKNOWNCOMMAND: &str = "421"; // <command> :Unknown command
 pub const ERR_NOMOTD: &str = "422"; // :MOTD file missing
@@ -86,11 +98,15 @@ pub const ERR_UNKNOWNMODE: &str = "472"; // <char> :Don't know this mode for <ch
 pub const ERR_INVITEONLYCHAN: &str = "473"; // <channel> :Cannot join channel (+I)
 pub const ERR_BANNEDFROMCHAN: &str = "474"; // <channel> :Cannot join channel (+b)
 pub const ERR_BADCHANKEY: &str = "475"; // <channel> :Cannot join channel (+k)
+pub const ERR_NEEDREGGEDNICK: &str = "477"; // <channel> :Cannot join channel (+R)
 pub const ERR_NOPRIVILEDGES: &str = "481"; // :Permission Denied- You're not an IRC operator
 pub const ERR_CHANOPRIVSNEEDED: &str = "482"; // <channel> :You're not an operator
+pub const ERR_SECUREONLYCHAN: &str = "489"; // <channel> :Cannot join channel (+z)
+pub const ERR_NOOPERHOST: &str = "491"; // :No O-lines for your host
 
 pub const ERR_UMODEUNKNOWNFLAG: &str = "501"; // :Unknown mode flag
 pub const ERR_USERSDONTMATCH: &str = "502"; // :Can't change mode for other users
+pub const ERR_CANTJOINOPERSONLY: &str = "520"; // <channel> :Cannot join channel (+O)
 
 pub const LOGGEDIN: &str = "900"; // <nick> <nick>!<ident>@<host> <account> :You are now logged in as <user>
 pub const LOGGEDOUT: &str = "901"; // <nick> <nick>!<ident>@<host> :You are now logged out
@@ -0,0 +1,163 @@
+//! The audit log.
+//!
+//! Actions of IRC operators are appended to the audit log, one JSON object per line:
+//!
+//! ```json
+//! {"time":"2020-01-01T00:00:00.000Z","actor":"alice!~alice@127.0.0.1","action":"KILL",
+//!  "target":"bob","success":true,"details":"flooding"}
+//! ```
+//!
+//! (without the line break).  Records are written by a dedicated thread, so that the state does
+//! not block on file I/O.  Once the file would grow past its maximum size, it is renamed with a
+//! `.1` suffix, replacing the previous one, and a new file is started.
+
+use crate::util;
+use std::fs;
+use std::io::{self, Write};
+use std::sync::mpsc;
+use std::thread;
+
+/// Messages from the state to the writing thread.
+enum Entry {
+    /// A record to append.
+    Record(String),
+
+    /// The path and the maximum size of the file, after a configuration reload.
+    Configure(String, u64),
+}
+
+/// A handle to the audit log.
+pub struct Log {
+    /// Where records are sent, or `None` while no audit log has ever been configured.
+    entries: Option<mpsc::Sender<Entry>>,
+}
+
+impl Log {
+    /// Starts the audit log at `path`.  No record is written when `path` is empty.
+    pub fn new(path: String, max_size: u64) -> Log {
+        let mut log = Log { entries: None };
+        log.configure(path, max_size);
+        log
+    }
+
+    /// Changes where records are written and the size at which the file is rotated.
+    pub fn configure(&mut self, path: String, max_size: u64) {
+        if self.entries.is_none() {
+            if path.is_empty() {
+                return;
+            }
+            let (entries, receiver) = mpsc::channel();
+            let spawned = thread::Builder::new()
+                .name(String::from("audit"))
+                .spawn(move || write_entries(receiver));
+            if let Err(err) = spawned {
+                log::error!("Failed to start the audit log: {}", err);
+                return;
+            }
+            self.entries = Some(entries);
+        }
+        self.send(Entry::Configure(path, max_size));
+    }
+
+    /// Records that `actor` (the full name of an operator, or of a client trying to become one)
+    /// has done `action` on `target`.
+    pub fn record(&self, actor: &str, action: &str, target: &str, success: bool, details: &str) {
+        if self.entries.is_none() {
+            return;
+        }
+        let mut record = String::with_capacity(128);
+        record.push_str("{\"time\":");
+        util::push_json_string(&mut record, &util::time_precise());
+        record.push_str(",\"actor\":");
+        util::push_json_string(&mut record, actor);
+        record.push_str(",\"action\":");
+        util::push_json_string(&mut record, action);
+        record.push_str(",\"target\":");
+        util::push_json_string(&mut record, target);
+        record.push_str(if success {
+            ",\"success\":true"
+        } else {
+            ",\"success\":false"
+        });
+        record.push_str(",\"details\":");
+        util::push_json_string(&mut record, details);
+        record.push_str("}\n");
+        self.send(Entry::Record(record));
+    }
+
+    fn send(&self, entry: Entry) {
+        if let Some(entries) = &self.entries {
+            let _ = entries.send(entry);
+        }
+    }
+}
+
+/// The state of the writing thread.
+struct Writer {
+    path: String,
+    max_size: u64,
+    file: Option<fs::File>,
+    size: u64,
+}
+
+/// Writes the records received from `entries`, until the `Log` is dropped.
+fn write_entries(entries: mpsc::Receiver<Entry>) {
+    let mut writer = Writer {
+        path: String::new(),
+        max_size: 0,
+        file: None,
+        size: 0,
+    };
+    for entry in entries {
+        match entry {
+            Entry::Record(record) => {
+                if let Err(err) = writer.write(&record) {
+                    log::error!(
+                        "Failed to write to the audit log {:?}: {}",
+                        writer.path,
+                        err
+                    );
+                    writer.file = None;
+                }
+            }
+            Entry::Configure(path, max_size) => {
+                if path != writer.path {
+                    writer.file = None;
+                    writer.path = path;
+                }
+                writer.max_size = max_size;
+            }
+        }
+    }
+}
+
+impl Writer {
+    fn write(&mut self, record: &str) -> io::Result<()> {
+        if self.path.is_empty() {
+            return Ok(());
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let len = record.len() as u64;
+        if 0 < self.max_size && 0 < self.size && self.max_size < self.size + len {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+            self.open()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(record.as_bytes())?;
+            self.size += len;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+}
@@ -1,7 +1,9 @@
 use crate::data::modes;
 use crate::util;
-use ellidri_tokens::{mode, rpl, MessageBuffer};
+use ellidri_tokens::{mode, rpl, Message, MessageBuffer, TagBuffer};
 use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
 /// Modes applied to clients on a per-channel basis.
 ///
@@ -35,6 +37,18 @@ impl MemberModes {
         }
     }
 
+    /// Returns the modes whose symbols are in `symbols`.  This is the reverse of
+    /// `MemberModes::all_symbols`.
+    pub fn from_symbols(symbols: &str) -> Self {
+        MemberModes {
+            founder: symbols.contains('~'),
+            protected: symbols.contains('&'),
+            operator: symbols.contains('@'),
+            halfop: symbols.contains('%'),
+            voice: symbols.contains('+'),
+        }
+    }
+
     /// Returns the highest enabled mode.
     pub fn symbol(self) -> Option<char> {
         if self.founder {
@@ -53,18 +67,39 @@ impl MemberModes {
     }
 
     pub fn is_at_least_op(self) -> bool {
-        self.operator || self.founder
+        self.operator || self.protected || self.founder
     }
 
     pub fn is_at_least_halfop(self) -> bool {
-        self.halfop || self.operator || self.founder
+        self.halfop || self.operator || self.protected || self.founder
+    }
+
+    /// Whether a member with these modes can kick, or remove the modes of, a member with the
+    /// `target` modes.
+    ///
+    /// Founders and protected members can only be acted upon by founders, operators can act on
+    /// other operators and halfops, and halfops can only act on voiced and regular members.
+    ///
+    /// Since founders can set and remove +q, a channel may have several founders, and a founder
+    /// can step down by removing their own +q.
+    pub fn can_act_on(self, target: MemberModes) -> bool {
+        if target.founder || target.protected {
+            self.founder
+        } else if target.operator || target.halfop {
+            self.is_at_least_op()
+        } else {
+            self.is_at_least_halfop()
+        }
     }
 
     pub fn has_voice(self) -> bool {
         self.voice || self.halfop || self.operator || self.protected || self.founder
     }
 
-    pub fn can_change(self, modes: modes::Channel<'_>) -> bool {
+    /// Whether a member with these modes can apply all of `modes`.  `operator` is whether the
+    /// member is an IRC operator, which is needed along with the operator rank to set the
+    /// oper-only and permanent modes.
+    pub fn can_change(self, modes: modes::Channel<'_>, operator: bool) -> bool {
         use mode::ChannelChange::*;
 
         modes.iter().all(|mode| match mode {
@@ -77,12 +112,21 @@ impl MemberModes {
             | Ok(ChangeException(_, _))
             | Ok(ChangeInvitation(_, _))
             | Ok(ChangeVoice(_, _)) => self.is_at_least_halfop(),
-            Ok(InviteOnly(_))
+            Ok(NoColors(_))
+            | Ok(NoCtcp(_))
+            | Ok(NoNotice(_))
+            | Ok(StripColors(_))
+            | Ok(InviteOnly(_))
             | Ok(NoPrivMsgFromOutside(_))
             | Ok(Secret(_))
             | Ok(Key(_, _))
             | Ok(ChangeOperator(_, _))
+            | Ok(RegisteredOnly(_))
+            | Ok(RegisteredSpeak(_))
+            | Ok(TlsOnly(_))
             | Ok(ChangeHalfop(_, _)) => self.is_at_least_op(),
+            Ok(ChangeFounder(_, _)) | Ok(ChangeProtected(_, _)) => self.founder,
+            Ok(OperOnly(_)) | Ok(Permanent(_)) => operator && self.is_at_least_op(),
         })
     }
 }
@@ -116,6 +160,25 @@ pub struct Channel {
     pub no_msg_from_outside: bool,
     pub secret: bool,
     pub topic_restricted: bool,
+
+    // Content filtering modes.
+    pub no_colors: bool,
+    pub no_ctcp: bool,
+    pub no_notice: bool,
+    pub strip_colors: bool,
+
+    // Identity-gated modes.
+    pub oper_only: bool,
+    pub registered_only: bool,
+    pub registered_speak: bool,
+    pub tls_only: bool,
+
+    /// Whether the channel is kept when its last member leaves, and written to the state file.
+    pub permanent: bool,
+
+    /// Held while a message is sent to the members, so that they all receive the messages of the
+    /// channel in the same order.  See `State::handle_message`.
+    pub message_lock: Arc<Mutex<()>>,
 }
 
 impl Channel {
@@ -138,6 +201,16 @@ impl Channel {
             no_msg_from_outside: false,
             secret: false,
             topic_restricted: false,
+            no_colors: false,
+            no_ctcp: false,
+            no_notice: false,
+            strip_colors: false,
+            oper_only: false,
+            registered_only: false,
+            registered_speak: false,
+            tls_only: false,
+            permanent: false,
+            message_lock: Arc::default(),
         };
         for change in mode::simple_channel_query(modes).filter_map(Result::ok) {
             channel
@@ -148,10 +221,13 @@ impl Channel {
     }
 
     /// Adds a member with the default mode.
+    ///
+    /// The first member of the channel becomes its founder, unless the channel is permanent.
+    /// Other members can later become founders as well, see `MemberModes::can_act_on`.
     pub fn add_member(&mut self, id: usize) {
-        let modes = if self.members.is_empty() {
+        let modes = if self.members.is_empty() && !self.permanent {
             MemberModes {
-                founder: false,
+                founder: true,
                 protected: false,
                 operator: true,
                 halfop: false,
@@ -189,6 +265,38 @@ impl Channel {
         }
     }
 
+    /// Whether the given client can talk without being logged in to an account.
+    pub fn can_talk_anonymously(&self, id: usize) -> bool {
+        if !self.registered_speak {
+            return true;
+        }
+        matches!(self.members.get(&id), Some(member) if member.has_voice())
+    }
+
+    /// Whether `issuer` has the rank to apply `change` to the member it targets.
+    ///
+    /// Only removals are checked, so that members cannot strip the modes of higher-ranked members.
+    /// Changes that do not target another member are always allowed.  `id_of` must return the
+    /// identifier of the client with the given nickname.
+    pub fn outranks_target(
+        &self,
+        issuer: usize,
+        change: &mode::ChannelChange<'_>,
+        id_of: impl Fn(&str) -> Option<usize>,
+    ) -> bool {
+        if change.value() {
+            return true;
+        }
+        let target = match change.member().and_then(id_of) {
+            Some(target) if target != issuer => target,
+            _ => return true,
+        };
+        match (self.members.get(&issuer), self.members.get(&target)) {
+            (Some(issuer), Some(target)) => issuer.can_act_on(*target),
+            _ => true,
+        }
+    }
+
     pub fn can_invite(&self, id: usize) -> bool {
         let member = match self.members.get(&id) {
             Some(member) => member,
@@ -204,6 +312,30 @@ impl Channel {
     pub fn modes(&self, mut out: MessageBuffer<'_>, full_info: bool) {
         let modes = out.raw_param();
         modes.push('+');
+        if self.no_ctcp {
+            modes.push('C');
+        }
+        if self.registered_speak {
+            modes.push('M');
+        }
+        if self.oper_only {
+            modes.push('O');
+        }
+        if self.permanent {
+            modes.push('P');
+        }
+        if self.registered_only {
+            modes.push('R');
+        }
+        if self.strip_colors {
+            modes.push('S');
+        }
+        if self.no_notice {
+            modes.push('T');
+        }
+        if self.no_colors {
+            modes.push('c');
+        }
         if self.invite_only {
             modes.push('i');
         }
@@ -219,6 +351,9 @@ impl Channel {
         if self.topic_restricted {
             modes.push('t');
         }
+        if self.tls_only {
+            modes.push('z');
+        }
         if self.user_limit.is_some() {
             modes.push('l');
         }
@@ -246,6 +381,42 @@ impl Channel {
 
         let mut applied = false;
         match change {
+            NoColors(value) => {
+                applied = self.no_colors != value;
+                self.no_colors = value;
+            }
+            NoCtcp(value) => {
+                applied = self.no_ctcp != value;
+                self.no_ctcp = value;
+            }
+            NoNotice(value) => {
+                applied = self.no_notice != value;
+                self.no_notice = value;
+            }
+            StripColors(value) => {
+                applied = self.strip_colors != value;
+                self.strip_colors = value;
+            }
+            OperOnly(value) => {
+                applied = self.oper_only != value;
+                self.oper_only = value;
+            }
+            Permanent(value) => {
+                applied = self.permanent != value;
+                self.permanent = value;
+            }
+            RegisteredOnly(value) => {
+                applied = self.registered_only != value;
+                self.registered_only = value;
+            }
+            RegisteredSpeak(value) => {
+                applied = self.registered_speak != value;
+                self.registered_speak = value;
+            }
+            TlsOnly(value) => {
+                applied = self.tls_only != value;
+                self.tls_only = value;
+            }
             InviteOnly(value) => {
                 applied = self.invite_only != value;
                 self.invite_only = value;
@@ -312,53 +483,61 @@ impl Channel {
                     self.invex_mask.remove(param)
                 };
             }
+            ChangeFounder(value, param) => {
+                applied = self.change_member(param, nick_of, |modes| {
+                    let applied = modes.founder != value;
+                    modes.founder = value;
+                    applied
+                })?;
+            }
+            ChangeProtected(value, param) => {
+                applied = self.change_member(param, nick_of, |modes| {
+                    let applied = modes.protected != value;
+                    modes.protected = value;
+                    applied
+                })?;
+            }
             ChangeOperator(value, param) => {
-                let mut has_it = false;
-                for (member, modes) in &mut self.members {
-                    if nick_of(*member) == param {
-                        has_it = true;
-                        applied = modes.operator != value;
-                        modes.operator = value;
-                        break;
-                    }
-                }
-                if !has_it {
-                    return Err(rpl::ERR_USERNOTINCHANNEL);
-                }
+                applied = self.change_member(param, nick_of, |modes| {
+                    let applied = modes.operator != value;
+                    modes.operator = value;
+                    applied
+                })?;
             }
             ChangeHalfop(value, param) => {
-                let mut has_it = false;
-                for (member, modes) in &mut self.members {
-                    if nick_of(*member) == param {
-                        has_it = true;
-                        applied = modes.halfop != value;
-                        modes.halfop = value;
-                        break;
-                    }
-                }
-                if !has_it {
-                    return Err(rpl::ERR_USERNOTINCHANNEL);
-                }
+                applied = self.change_member(param, nick_of, |modes| {
+                    let applied = modes.halfop != value;
+                    modes.halfop = value;
+                    applied
+                })?;
             }
             ChangeVoice(value, param) => {
-                let mut has_it = false;
-                for (member, modes) in &mut self.members {
-                    if nick_of(*member) == param {
-                        has_it = true;
-                        applied = modes.voice != value;
-                        modes.voice = value;
-                        break;
-                    }
-                }
-                if !has_it {
-                    return Err(rpl::ERR_USERNOTINCHANNEL);
-                }
+                applied = self.change_member(param, nick_of, |modes| {
+                    let applied = modes.voice != value;
+                    modes.voice = value;
+                    applied
+                })?;
             }
             _ => {}
         }
         Ok(applied)
     }
 
+    /// Applies `change` to the modes of the member named `nick`, and returns whether the modes
+    /// have changed.
+    fn change_member<'a>(
+        &mut self,
+        nick: &str,
+        nick_of: impl Fn(usize) -> &'a str,
+        change: impl FnOnce(&mut MemberModes) -> bool,
+    ) -> Result<bool, &'static str> {
+        self.members
+            .iter_mut()
+            .find(|(member, _)| nick_of(**member) == nick)
+            .map(|(_, modes)| change(modes))
+            .ok_or(rpl::ERR_USERNOTINCHANNEL)
+    }
+
     pub fn symbol(&self) -> &'static str {
         if self.secret {
             "@"
@@ -366,12 +545,94 @@ impl Channel {
             "="
         }
     }
+
+    /// Writes the channel as a `CHANNEL <name> <modes> [<limit>] [<key>]` message, its topic and
+    /// lists as tags of `msg`, so that it can be restored with `Channel::restore`.
+    ///
+    /// Members are not saved.
+    pub fn save(&self, mut msg: TagBuffer<'_>, name: &str) {
+        if let Some(topic) = &self.topic {
+            msg = msg
+                .tag("topic", Some(&topic.content))
+                .tag("topic-who", Some(&topic.who))
+                .tag("topic-time", Some(topic.time));
+        }
+        let masks = |set: &util::MaskSet| set.masks().collect::<Vec<_>>().join(",");
+        let msg = msg
+            .tag("bans", Some(masks(&self.ban_mask)))
+            .tag("exceptions", Some(masks(&self.exception_mask)))
+            .tag("invitations", Some(masks(&self.invex_mask)))
+            .prefixed_command("", "CHANNEL")
+            .param(name);
+        self.modes(msg, true);
+    }
+
+    /// Restores the modes, topic and lists written by `Channel::save` in `msg`.
+    ///
+    /// Unknown tags are ignored.  Fails when `msg` lacks the name or the modes of the channel.
+    pub fn restore(&mut self, msg: &Message<'_>) -> Result<(), ()> {
+        if msg.num_params < 2 {
+            return Err(());
+        }
+        let params = &msg.params[2..msg.num_params];
+        for change in mode::channel_query(msg.params[1], params).flatten() {
+            let _ = self.apply_mode_change(change, usize::MAX, |_| "");
+        }
+
+        let mut topic = Topic {
+            content: String::new(),
+            who: String::new(),
+            time: 0,
+        };
+        for tag in msg.tags() {
+            let value = tag.unescape_value();
+            let list = match tag.key {
+                "topic" => {
+                    topic.content = value;
+                    continue;
+                }
+                "topic-who" => {
+                    topic.who = value;
+                    continue;
+                }
+                "topic-time" => {
+                    topic.time = value.parse().unwrap_or(0);
+                    continue;
+                }
+                "bans" => &mut self.ban_mask,
+                "exceptions" => &mut self.exception_mask,
+                "invitations" => &mut self.invex_mask,
+                _ => continue,
+            };
+            for mask in value.split(',').filter(|mask| !mask.is_empty()) {
+                list.insert(mask);
+            }
+        }
+        if !topic.content.is_empty() {
+            self.topic = Some(topic);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const FOUNDER: MemberModes = MemberModes {
+        founder: true,
+        protected: false,
+        operator: false,
+        halfop: false,
+        voice: false,
+    };
+    const PROTECTED: MemberModes = MemberModes {
+        founder: false,
+        protected: true,
+        operator: false,
+        halfop: false,
+        voice: false,
+    };
     const OPERATOR: MemberModes = MemberModes {
         founder: false,
         protected: false,
@@ -405,5 +666,87 @@ mod tests {
         assert!(VOICE.has_voice());
         assert!(!VOICE.is_at_least_halfop());
         assert!(!VOICE.is_at_least_op());
+        assert!(PROTECTED.is_at_least_op());
+        assert!(FOUNDER.is_at_least_op());
+    }
+
+    #[test]
+    fn test_member_modes_can_act_on() {
+        let regular = MemberModes::default();
+        let cases = [
+            (FOUNDER, FOUNDER, true),
+            (FOUNDER, PROTECTED, true),
+            (PROTECTED, FOUNDER, false),
+            (PROTECTED, PROTECTED, false),
+            (PROTECTED, OPERATOR, true),
+            (OPERATOR, PROTECTED, false),
+            (OPERATOR, OPERATOR, true),
+            (OPERATOR, HALFOP, true),
+            (HALFOP, OPERATOR, false),
+            (HALFOP, HALFOP, false),
+            (HALFOP, VOICE, true),
+            (HALFOP, regular, true),
+            (VOICE, regular, false),
+        ];
+
+        for (i, (issuer, target, expected)) in cases.iter().enumerate() {
+            assert_eq!(issuer.can_act_on(*target), *expected, "case #{}", i);
+        }
+    }
+
+    #[test]
+    fn test_channel_save_restore() {
+        let mut channel = Channel::new("+Pnt");
+        channel.key = Some(String::from("key"));
+        channel.user_limit = Some(4);
+        channel.topic = Some(Topic {
+            content: String::from("a topic; with spaces"),
+            who: String::from("alice"),
+            time: 42,
+        });
+        channel.ban_mask.insert("bad!*@*");
+        channel.exception_mask.insert("good!*@*");
+        channel.invex_mask.insert("a!*@*");
+        channel.invex_mask.insert("b!*@*");
+
+        let mut buf = ellidri_tokens::Buffer::new();
+        channel.save(buf.tagged_message(""), "#chan");
+        let saved = buf.build();
+        let msg = Message::parse(&saved).unwrap();
+        assert_eq!(msg.params[0], "#chan");
+
+        let mut restored = Channel::new("");
+        restored.restore(&msg).unwrap();
+        assert!(restored.permanent && restored.no_msg_from_outside && restored.topic_restricted);
+        assert!(!restored.secret);
+        assert_eq!(restored.key.as_deref(), Some("key"));
+        assert_eq!(restored.user_limit, Some(4));
+        let topic = restored.topic.unwrap();
+        assert_eq!(topic.content, "a topic; with spaces");
+        assert_eq!(topic.who, "alice");
+        assert_eq!(topic.time, 42);
+        assert_eq!(restored.ban_mask.masks().collect::<Vec<_>>(), ["bad!*@*"]);
+        assert_eq!(
+            restored.exception_mask.masks().collect::<Vec<_>>(),
+            ["good!*@*"]
+        );
+        assert_eq!(
+            restored.invex_mask.masks().collect::<Vec<_>>(),
+            ["a!*@*", "b!*@*"]
+        );
+    }
+
+    #[test]
+    fn test_channel_restore_invalid() {
+        let mut channel = Channel::new("");
+        assert!(channel
+            .restore(&Message::parse("CHANNEL").unwrap())
+            .is_err());
+        assert!(channel
+            .restore(&Message::parse("CHANNEL #chan").unwrap())
+            .is_err());
+        assert!(channel
+            .restore(&Message::parse("CHANNEL #chan +n").unwrap())
+            .is_ok());
     }
 } // mod tests
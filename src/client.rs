@@ -1,23 +1,71 @@
 //! Client data, connection state and capability logic.
 
-use crate::{data, util};
-use ellidri_tokens::{mode, Buffer, MessageBuffer, ReplyBuffer};
+use crate::{config, data, util};
+use ellidri_tokens::{mode, Buffer, Message, MessageBuffer, ReplyBuffer, TagBuffer};
 use ellidri_unicase::UniCase;
 use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
 use std::fmt::Write as _;
+use std::future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::task::Poll;
+use tokio::sync::{mpsc, Notify};
+
+/// The remote end of a connection.
+#[derive(Clone, Debug)]
+pub struct Peer {
+    /// Identifies the connection in logs.
+    pub name: String,
+
+    /// The IP address of the client, or `None` for Unix domain sockets.
+    pub ip: Option<IpAddr>,
+
+    /// The host of the client, as shown to other users.
+    pub host: String,
+}
+
+impl Peer {
+    /// A client connected through the Unix domain socket at `address`, given `host` as its host.
+    ///
+    /// These clients have no IP address, and are named in logs after the socket and a number.
+    pub fn local(address: impl fmt::Display, host: &str) -> Peer {
+        static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+        let n = CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        Peer {
+            name: format!("{}#{}", address, n),
+            ip: None,
+            host: host.to_owned(),
+        }
+    }
+}
+
+impl From<SocketAddr> for Peer {
+    fn from(addr: SocketAddr) -> Peer {
+        Peer {
+            name: addr.to_string(),
+            ip: Some(addr.ip()),
+            host: addr.ip().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name.fmt(f)
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct MessageQueueItem {
-    pub start: usize,
     buf: Arc<String>,
 }
 
 impl From<Buffer> for MessageQueueItem {
     fn from(val: Buffer) -> Self {
         Self {
-            start: 0,
             buf: Arc::new(val.build()),
         }
     }
@@ -26,22 +74,171 @@ impl From<Buffer> for MessageQueueItem {
 impl From<ReplyBuffer> for MessageQueueItem {
     fn from(val: ReplyBuffer) -> Self {
         Self {
-            start: 0,
             buf: Arc::new(val.build()),
         }
     }
 }
 
 impl AsRef<str> for MessageQueueItem {
-    /// # Panics
-    ///
-    /// This function panics when `self.start` is greater than the content's length.
     fn as_ref(&self) -> &str {
-        &self.buf.as_ref()[self.start..]
+        self.buf.as_ref()
+    }
+}
+
+/// Traffic statistics of a connection, shared between the state and the connection task.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    /// Number of bytes waiting in the message queue (the sendq).
+    pub sendq: AtomicUsize,
+
+    /// The time the last line has been received from the client, in seconds since the epoch.
+    pub last_received: AtomicU64,
+
+    /// Messages and bytes written to the connection, which excludes the sendq.
+    pub sent_messages: AtomicUsize,
+    pub sent_bytes: AtomicUsize,
+
+    pub received_messages: AtomicUsize,
+    pub received_bytes: AtomicUsize,
+}
+
+impl Traffic {
+    /// Records a line received from the client.
+    pub fn record_received(&self, len: usize) {
+        self.received_messages.fetch_add(1, Ordering::Relaxed);
+        self.received_bytes.fetch_add(len, Ordering::Relaxed);
+        self.last_received.store(util::time(), Ordering::Relaxed);
+    }
+
+    /// Records messages that have been written to the connection.
+    pub fn record_sent(&self, messages: usize, bytes: usize) {
+        self.sent_messages.fetch_add(messages, Ordering::Relaxed);
+        self.sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Flood-control limits of a connection, set by the state from the connection class of the client
+/// and enforced by the connection task.
+#[derive(Debug, Default)]
+pub struct Limits {
+    /// Number of milliseconds needed to recover one point.
+    pub rate: AtomicU32,
+
+    /// Number of points the client can spend at once.
+    pub burst: AtomicU32,
+
+    /// Maximum number of bytes in the sendq, 0 for no limit.
+    pub sendq: AtomicUsize,
+
+    /// Number of seconds of inactivity after which the client is sent a PING.
+    pub ping_freq: AtomicU64,
+
+    /// Notified when a message has been dropped because the sendq is full.
+    pub sendq_exceeded: Notify,
+}
+
+/// The sending end of the queue of messages to be written to a client.
+#[derive(Clone, Debug)]
+pub struct MessageQueue {
+    sender: mpsc::UnboundedSender<MessageQueueItem>,
+    traffic: Arc<Traffic>,
+    limits: Arc<Limits>,
+}
+
+impl MessageQueue {
+    pub fn send(&self, msg: MessageQueueItem) {
+        let len = msg.as_ref().len();
+        let max_sendq = self.limits.sendq.load(Ordering::Relaxed);
+        if max_sendq != 0 && max_sendq < self.traffic.sendq.load(Ordering::Relaxed) + len {
+            self.limits.sendq_exceeded.notify_one();
+            return;
+        }
+        if self.sender.send(msg).is_ok() {
+            self.traffic.sendq.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    /// Pushes `msg` even when it exceeds the sendq limit.  Used for the last message of a client
+    /// that is being disconnected anyway.
+    pub fn send_last(&self, msg: MessageQueueItem) {
+        let len = msg.as_ref().len();
+        if self.sender.send(msg).is_ok() {
+            self.traffic.sendq.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
     }
 }
 
-pub type MessageQueue = mpsc::UnboundedSender<MessageQueueItem>;
+/// The receiving end of the queue of messages to be written to a client.
+#[derive(Debug)]
+pub struct MessageQueueReceiver {
+    receiver: mpsc::UnboundedReceiver<MessageQueueItem>,
+    traffic: Arc<Traffic>,
+    limits: Arc<Limits>,
+}
+
+impl MessageQueueReceiver {
+    /// Receives the next message to be written.  Returns `None` when the client has been removed
+    /// from the state.
+    pub async fn recv(&mut self) -> Option<MessageQueueItem> {
+        let msg = self.receiver.recv().await?;
+        self.traffic
+            .sendq
+            .fetch_sub(msg.as_ref().len(), Ordering::Relaxed);
+        Some(msg)
+    }
+
+    /// Returns the next message if it is already in the queue, without waiting for one.
+    pub async fn recv_ready(&mut self) -> Option<MessageQueueItem> {
+        let receiver = &mut self.receiver;
+        let msg = future::poll_fn(|cx| match receiver.poll_recv(cx) {
+            Poll::Ready(msg) => Poll::Ready(msg),
+            Poll::Pending => Poll::Ready(None),
+        })
+        .await?;
+        self.traffic
+            .sendq
+            .fetch_sub(msg.as_ref().len(), Ordering::Relaxed);
+        Some(msg)
+    }
+
+    pub fn traffic(&self) -> Arc<Traffic> {
+        self.traffic.clone()
+    }
+
+    pub fn limits(&self) -> Arc<Limits> {
+        self.limits.clone()
+    }
+}
+
+/// Creates a new message queue for a client.
+pub fn message_queue() -> (MessageQueue, MessageQueueReceiver) {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let traffic = Traffic::default();
+    traffic.last_received.store(util::time(), Ordering::Relaxed);
+    let traffic = Arc::new(traffic);
+    let limits = Arc::new(Limits::default());
+    let queue = MessageQueue {
+        sender,
+        traffic: traffic.clone(),
+        limits: limits.clone(),
+    };
+    (
+        queue,
+        MessageQueueReceiver {
+            receiver,
+            traffic,
+            limits,
+        },
+    )
+}
 
 /// A state machine that represent the connection with a client. It keeps track of what message the
 /// client can send.
@@ -56,6 +253,9 @@ pub enum ConnectionState {
     CapNickGiven,
     CapUserGiven,
     CapNegotiation,
+
+    /// The client has sent everything needed to register, but must answer the PING cookie first.
+    PongAwaited,
     Registered,
     Quit,
 }
@@ -67,6 +267,34 @@ impl Default for ConnectionState {
 }
 
 impl ConnectionState {
+    pub const ALL: [ConnectionState; 10] = [
+        ConnectionState::ConnectionEstablished,
+        ConnectionState::NickGiven,
+        ConnectionState::UserGiven,
+        ConnectionState::CapGiven,
+        ConnectionState::CapNickGiven,
+        ConnectionState::CapUserGiven,
+        ConnectionState::CapNegotiation,
+        ConnectionState::PongAwaited,
+        ConnectionState::Registered,
+        ConnectionState::Quit,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ConnectionState::ConnectionEstablished => "established",
+            ConnectionState::NickGiven => "nick-given",
+            ConnectionState::UserGiven => "user-given",
+            ConnectionState::CapGiven => "cap-given",
+            ConnectionState::CapNickGiven => "cap-nick-given",
+            ConnectionState::CapUserGiven => "cap-user-given",
+            ConnectionState::CapNegotiation => "cap-negotiation",
+            ConnectionState::PongAwaited => "pong-awaited",
+            ConnectionState::Registered => "registered",
+            ConnectionState::Quit => "quit",
+        }
+    }
+
     pub fn apply(self, request: &data::Request<'_>) -> Result<ConnectionState, ()> {
         use data::Request::*;
         match self {
@@ -134,6 +362,12 @@ impl ConnectionState {
                 Quit { .. } => Ok(ConnectionState::Quit),
                 _ => Err(()),
             },
+            ConnectionState::PongAwaited => match request {
+                Pong { .. } => Ok(ConnectionState::Registered),
+                CapEnd | CapList { .. } | CapLs { .. } | CapReq { .. } | Ping { .. } => Ok(self),
+                Quit { .. } => Ok(ConnectionState::Quit),
+                _ => Err(()),
+            },
             ConnectionState::Registered => match request {
                 Pass { .. } | User { .. } => Err(()),
                 Quit { .. } => Ok(ConnectionState::Quit),
@@ -148,6 +382,50 @@ impl ConnectionState {
     }
 }
 
+/// Set of server notice masks, see `mode::SNOMASKS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Snomask(u32);
+
+impl Snomask {
+    fn bit(mask: char) -> u32 {
+        mode::SNOMASKS.find(mask).map_or(0, |i| 1 << i)
+    }
+
+    /// The snomask with all known server notice masks.
+    pub fn all() -> Self {
+        Self((1 << mode::SNOMASKS.len()) - 1)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, mask: char) -> bool {
+        self.0 & Self::bit(mask) != 0
+    }
+
+    /// Applies a snomask change such as "+ck-q".  Unknown masks are ignored.
+    pub fn apply(&mut self, changes: &str) {
+        for (value, mask) in mode::snomask_query(changes).filter_map(mode::Result::ok) {
+            if value {
+                self.0 |= Self::bit(mask);
+            } else {
+                self.0 &= !Self::bit(mask);
+            }
+        }
+    }
+}
+
+impl fmt::Display for Snomask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_char('+')?;
+        for mask in mode::SNOMASKS.chars().filter(|m| self.contains(*m)) {
+            f.write_char(mask)?;
+        }
+        Ok(())
+    }
+}
+
 const FULL_NAME_LENGTH: usize = 64;
 
 /// Client data.
@@ -170,24 +448,51 @@ pub struct Client {
     host: String,
     account: Option<String>,
 
+    /// The IP address the client connects from, or `None` for Unix domain sockets.
+    ip: Option<IpAddr>,
+
+    /// The connection class the client belongs to.
+    class: Arc<config::ConnectionClass>,
+
+    /// Whether the client is connected through TLS.
+    tls: bool,
+
+    /// SHA-256 fingerprint of the TLS client certificate, in lowercase hexadecimal.
+    certfp: Option<String>,
+
     /// The nick!user@host
     full_name: String,
 
     /// The time when the user has signed in
     signon_time: u64,
 
-    /// The time of the last action
-    last_action_time: u64,
+    /// The time of the last action.  Messages update it with shared access to the state.
+    last_action_time: AtomicU64,
 
     /// Whether the client has issued a PASS command with the right password.
     pub has_given_password: bool,
 
+    /// The token of the registration PING, until the client answers it.
+    ping_cookie: Option<String>,
+
     // Modes: https://tools.ietf.org/html/rfc2812.html#section-3.1.5
     pub away_message: Option<String>,
     pub invisible: bool,
     pub operator: bool,
 
+    /// The class given by the OPER block the client has used, `Some` when `operator` is set.
+    pub oper_class: Option<config::OperClass>,
+
+    /// The server notices the client is subscribed to, with user mode +s.
+    pub snomask: Snomask,
+
+    /// Whether the client receives WALLOPS messages.
+    pub wallops: bool,
+
     pub invites: HashSet<UniCase<String>>,
+
+    /// The channels the client is a member of.  Must be kept consistent with `Channel::members`.
+    pub channels: HashSet<UniCase<String>>,
 }
 
 impl Client {
@@ -195,9 +500,16 @@ impl Client {
     ///
     /// The nickname is set to "*", as it seems it's what freenode server does.  The username and
     /// the realname are set to empty strings.
-    pub fn new(domain: Arc<str>, queue: MessageQueue, host: String) -> Self {
+    pub fn new(
+        domain: Arc<str>,
+        queue: MessageQueue,
+        peer: &Peer,
+        tls: bool,
+        certfp: Option<String>,
+        class: Arc<config::ConnectionClass>,
+    ) -> Self {
         let now = util::time();
-        Self {
+        let client = Self {
             queue,
             domain,
             full_name: String::with_capacity(FULL_NAME_LENGTH),
@@ -207,27 +519,43 @@ impl Client {
             nick: String::from("*"),
             user: String::new(),
             real: String::new(),
-            host,
+            host: peer.host.clone(),
             account: None,
+            ip: peer.ip,
+            class,
+            tls,
+            certfp,
             signon_time: now,
-            last_action_time: now,
+            last_action_time: AtomicU64::new(now),
             has_given_password: false,
+            ping_cookie: None,
             away_message: None,
             invisible: false,
             operator: false,
+            oper_class: None,
+            snomask: Snomask::default(),
+            wallops: false,
             invites: HashSet::new(),
-        }
+            channels: HashSet::new(),
+        };
+        client.update_limits();
+        client
     }
 
     /// Add a message to the client message queue.
     ///
     /// Use this function to send messages to the client.
     pub fn send(&self, msg: impl Into<MessageQueueItem>) {
-        let mut msg = msg.into();
-        if self.cap_enabled.has_message_tags() {
-            msg.start = 0;
-        }
-        let _ = self.queue.send(msg);
+        self.queue.send(msg.into());
+    }
+
+    /// Sends `msg` regardless of the sendq limit, see `MessageQueue::send_last`.
+    pub fn send_last(&self, msg: impl Into<MessageQueueItem>) {
+        self.queue.send_last(msg.into());
+    }
+
+    pub fn traffic(&self) -> &Traffic {
+        self.queue.traffic()
     }
 
     pub fn reply(&self, label: &str) -> ReplyBuffer {
@@ -249,6 +577,25 @@ impl Client {
         self.state
     }
 
+    /// Holds the registration of the client until it answers a PING with the given token.
+    pub fn await_pong(&mut self, cookie: String) -> ConnectionState {
+        self.ping_cookie = Some(cookie);
+        self.state = ConnectionState::PongAwaited;
+        self.state
+    }
+
+    /// Checks the payload of a PONG against the token of the registration PING.  Returns false
+    /// when the client still has to answer the PING with another token.
+    pub fn check_pong(&mut self, payload: &str) -> bool {
+        match &self.ping_cookie {
+            Some(cookie) if cookie != payload => false,
+            _ => {
+                self.ping_cookie = None;
+                true
+            }
+        }
+    }
+
     /// Whether or not the client can issue the given command.
     ///
     /// This function does not change the connection state.
@@ -315,16 +662,65 @@ impl Client {
         self.account.as_ref().map(|s| s.as_ref())
     }
 
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn certfp(&self) -> Option<&str> {
+        self.certfp.as_deref()
+    }
+
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    pub fn class(&self) -> &Arc<config::ConnectionClass> {
+        &self.class
+    }
+
+    pub fn set_class(&mut self, class: Arc<config::ConnectionClass>) {
+        self.class = class;
+        self.update_limits();
+    }
+
+    /// Whether the client bypasses flood control and sendq limits, either because it is an IRC
+    /// operator or because its class is exempt.
+    pub fn is_exempt(&self) -> bool {
+        self.operator || self.class.exempt
+    }
+
+    /// Updates the limits enforced by the connection task.  Must be called when the class or the
+    /// operator status of the client changes.
+    pub fn update_limits(&self) {
+        let limits = self.queue.limits();
+        limits.rate.store(self.class.rate, Ordering::Relaxed);
+        limits.burst.store(self.class.burst, Ordering::Relaxed);
+        let sendq = if self.is_exempt() {
+            0
+        } else {
+            self.class.sendq
+        };
+        limits.sendq.store(sendq, Ordering::Relaxed);
+        limits
+            .ping_freq
+            .store(self.class.ping_freq, Ordering::Relaxed);
+    }
+
+    /// Whether the client is an IRC operator whose class grants the given privilege.
+    pub fn has_privilege(&self, privilege: config::Privilege) -> bool {
+        matches!(&self.oper_class, Some(class) if self.operator && class.has(privilege))
+    }
+
     pub fn signon_time(&self) -> u64 {
         self.signon_time
     }
 
     pub fn idle_time(&self) -> u64 {
-        util::time() - self.last_action_time
+        util::time() - self.last_action_time.load(Ordering::Relaxed)
     }
 
-    pub fn update_idle_time(&mut self) {
-        self.last_action_time = util::time();
+    pub fn update_idle_time(&self) {
+        self.last_action_time.store(util::time(), Ordering::Relaxed);
     }
 
     pub fn away_message(&self) -> Option<&str> {
@@ -343,21 +739,163 @@ impl Client {
         if self.operator {
             modes.push('o');
         }
+        if !self.snomask.is_empty() {
+            modes.push('s');
+        }
+        if self.wallops {
+            modes.push('w');
+        }
     }
 
-    pub fn apply_mode_change(&mut self, change: mode::UserChange) -> bool {
+    pub fn apply_mode_change(&mut self, change: mode::UserChange<'_>) -> bool {
         use mode::UserChange::*;
         let applied;
         match change {
+            ServerNotices(true, changes) => {
+                if !self.operator {
+                    return false;
+                }
+                let old = self.snomask;
+                if changes.is_empty() {
+                    self.snomask = Snomask::all();
+                } else {
+                    self.snomask.apply(changes);
+                }
+                applied = self.snomask != old;
+            }
+            ServerNotices(false, _) => {
+                applied = !self.snomask.is_empty();
+                self.snomask = Snomask::default();
+            }
             Invisible(value) => {
                 applied = self.invisible != value;
                 self.invisible = value;
             }
+            WallOps(value) => {
+                applied = self.wallops != value;
+                self.wallops = value;
+            }
             DeOperator => {
                 applied = self.operator;
                 self.operator = false;
+                self.oper_class = None;
+                self.snomask = Snomask::default();
+                self.update_limits();
             }
         }
         applied
     }
+
+    /// Writes the data of the client as tags of `msg`, so that it can be restored with
+    /// `Client::restore` after an upgrade.
+    ///
+    /// The connection class, TLS status and channels are not saved.
+    pub fn save<'a>(&self, msg: TagBuffer<'a>) -> TagBuffer<'a> {
+        let mut caps = String::new();
+        self.cap_enabled.write(&mut caps);
+        let cap_version = match self.cap_version {
+            data::cap::Version::V300 => "300",
+            data::cap::Version::V302 => "302",
+        };
+        let invites: Vec<&str> = self
+            .invites
+            .iter()
+            .map(|name| name.get().as_str())
+            .collect();
+
+        let mut msg = msg
+            .tag("nick", Some(&self.nick))
+            .tag("user", Some(&self.user))
+            .tag("real", Some(&self.real))
+            .tag("host", Some(&self.host))
+            .tag("ip", self.ip)
+            .tag("state", Some(self.state.as_str()))
+            .tag("cap-version", Some(cap_version))
+            .tag("caps", Some(caps))
+            .tag("signon", Some(self.signon_time))
+            .tag(
+                "last-action",
+                Some(self.last_action_time.load(Ordering::Relaxed)),
+            )
+            .tag("invites", Some(invites.join(",")));
+        if self.has_given_password {
+            msg = msg.tag("password", None::<&str>);
+        }
+        if let Some(cookie) = &self.ping_cookie {
+            msg = msg.tag("ping-cookie", Some(cookie));
+        }
+        if let Some(away_message) = &self.away_message {
+            msg = msg.tag("away", Some(away_message));
+        }
+        if self.invisible {
+            msg = msg.tag("invisible", None::<&str>);
+        }
+        if self.wallops {
+            msg = msg.tag("wallops", None::<&str>);
+        }
+        if let (true, Some(class)) = (self.operator, &self.oper_class) {
+            let privileges: Vec<&str> = class.privileges.iter().map(|p| p.as_str()).collect();
+            msg = msg
+                .tag("oper", Some(&class.name))
+                .tag("privileges", Some(privileges.join(" ")))
+                .tag("snomask", Some(self.snomask));
+        }
+        msg
+    }
+
+    /// Restores the data written by `Client::save` in the tags of `msg`.
+    ///
+    /// Unknown tags are ignored.  The host and the IP address are not restored, they must be given
+    /// to `Client::new` instead.
+    pub fn restore(&mut self, msg: &Message<'_>) {
+        let mut oper_class = None;
+        for tag in msg.tags() {
+            let value = tag.unescape_value();
+            match tag.key {
+                "nick" => self.nick = value,
+                "user" => self.user = value,
+                "real" => self.real = value,
+                "state" => {
+                    let state = ConnectionState::ALL.iter().find(|s| s.as_str() == value);
+                    self.state = state.cloned().unwrap_or_default();
+                }
+                "cap-version" => self.cap_version = data::cap::Version::from(value.as_str()),
+                "caps" => {
+                    if let Ok(diff) = data::cap::Diff::try_from(value.as_str()) {
+                        self.cap_enabled.update(diff);
+                    }
+                }
+                "signon" => self.signon_time = value.parse().unwrap_or(self.signon_time),
+                "last-action" => {
+                    if let Ok(time) = value.parse() {
+                        self.last_action_time.store(time, Ordering::Relaxed);
+                    }
+                }
+                "invites" => {
+                    let invites = value.split(',').filter(|name| !name.is_empty());
+                    self.invites = invites.map(|name| UniCase::new(name.to_owned())).collect();
+                }
+                "password" => self.has_given_password = true,
+                "ping-cookie" => self.ping_cookie = Some(value),
+                "away" => self.away_message = Some(value),
+                "invisible" => self.invisible = true,
+                "wallops" => self.wallops = true,
+                "oper" => {
+                    let class = oper_class.get_or_insert_with(config::OperClass::default);
+                    class.name = value;
+                }
+                "privileges" => {
+                    let class = oper_class.get_or_insert_with(config::OperClass::default);
+                    let privileges = value.split(' ').map(config::Privilege::try_from);
+                    class.privileges = privileges.filter_map(Result::ok).collect();
+                }
+                "snomask" => self.snomask.apply(&value),
+                _ => {}
+            }
+        }
+        self.operator = oper_class.is_some();
+        self.oper_class = oper_class;
+        self.update_full_name();
+        self.update_limits();
+    }
 }
@@ -4,21 +4,32 @@
 //!
 //! [1]: https://git.sr.ht/~taiite/ellidri/tree/master/doc/ellidri.conf
 
-use ellidri_tokens::mode;
+pub use parser::Location;
+
+use parser::Spec;
+
+use crate::{data, lines, util};
+use ellidri_tokens::{mode, Command};
 use gethostname::gethostname;
 use scfg::Scfg;
+use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::{fmt, fs, io, net, path};
+use std::{fmt, io, net, path, str};
+
+mod dump;
+mod parser;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     Io(io::Error),
-    Format(scfg::ParseError),
     Content(String),
     InvalidDomain,
     InvalidModes,
+
+    /// An error in the directive at the given location.
+    At(Location, Box<Error>),
 }
 
 impl Error {
@@ -31,7 +42,7 @@ impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Self::Io(err) => Some(err),
-            Self::Format(err) => Some(err),
+            Self::At(_, err) => err.source(),
             _ => None,
         }
     }
@@ -43,20 +54,16 @@ impl From<io::Error> for Error {
     }
 }
 
-impl From<scfg::ParseError> for Error {
-    fn from(val: scfg::ParseError) -> Self {
-        Self::Format(val)
-    }
-}
-
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Io(err) => err.fmt(f),
-            Self::Format(err) => err.fmt(f),
             Self::Content(message) => message.fmt(f),
             Self::InvalidDomain => write!(f, "'domain' must be a domain name (e.g. irc.com)"),
             Self::InvalidModes => write!(f, "'default_chan_mode' must be a mode string (e.g. +nt)"),
+            Self::At(location, err) => {
+                write!(f, "{}:{}: {}", location.file.display(), location.line, err)
+            }
         }
     }
 }
@@ -68,11 +75,83 @@ pub struct Tls {
     pub key: path::PathBuf,
 }
 
-/// Listening address + port + optional TLS settings.
+/// The address a binding listens on.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Address {
+    /// An IP address and a TCP port.
+    Tcp(net::SocketAddr),
+
+    /// The path of a Unix domain socket, written `unix:/path/to/socket` in the configuration.
+    Unix(path::PathBuf),
+}
+
+impl str::FromStr for Address {
+    type Err = net::AddrParseError;
+
+    fn from_str(s: &str) -> std::result::Result<Address, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Address::Unix(path.into())),
+            None => s.parse().map(Address::Tcp),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(address) => address.fmt(f),
+            Address::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Options of the sockets of a binding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketOptions {
+    /// Whether to disable Nagle's algorithm (TCP_NODELAY) on client connections.
+    pub nodelay: bool,
+
+    /// The size of the kernel send buffer (SO_SNDBUF), or `None` for the system default.
+    pub send_buffer: Option<u32>,
+
+    /// The size of the kernel receive buffer (SO_RCVBUF), or `None` for the system default.
+    pub recv_buffer: Option<u32>,
+
+    /// The permissions of the socket file of Unix domain socket bindings.
+    pub mode: Option<u32>,
+
+    /// The user ID and group ID that own the socket file of Unix domain socket bindings.
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+
+    /// The host of clients connected through Unix domain socket bindings.
+    pub host: String,
+}
+
+impl Default for SocketOptions {
+    fn default() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            send_buffer: None,
+            recv_buffer: None,
+            mode: None,
+            owner: None,
+            group: None,
+            host: String::from("localhost"),
+        }
+    }
+}
+
+/// Listening address + optional TLS settings + socket options.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Binding {
-    pub address: net::SocketAddr,
+    pub address: Address,
     pub tls: Option<Tls>,
+    pub options: SocketOptions,
+
+    /// The name of the socket passed by the service manager that this binding uses, instead of
+    /// binding its own.
+    pub name: Option<String>,
 }
 
 impl TryFrom<&scfg::Directive> for Binding {
@@ -90,12 +169,171 @@ impl TryFrom<&scfg::Directive> for Binding {
                     err
                 ))
             })?;
-        let tls = directive.child().and_then(|child| {
-            let certificate = child.get("certificate")?.params().get(0)?.into();
-            let key = child.get("key")?.params().get(0)?.into();
-            Some(Tls { certificate, key })
-        });
-        Ok(Binding { address, tls })
+        let mut tls = None;
+        if let Some(child) = directive.child() {
+            let certificate = get_setting_str(child, "certificate").transpose()?;
+            let key = get_setting_str(child, "key").transpose()?;
+            tls = match (certificate, key) {
+                (Some(certificate), Some(key)) => Some(Tls {
+                    certificate: certificate.into(),
+                    key: key.into(),
+                }),
+                (None, None) => None,
+                _ => {
+                    return Err(Error::s(
+                        "'listen' needs both a 'certificate' and a 'key' for TLS",
+                    ))
+                }
+            };
+        }
+        let mut options = SocketOptions::default();
+        let mut name = None;
+        if let Some(child) = directive.child() {
+            if let Some(value) = get_setting_str(child, "name") {
+                name = Some(value?);
+            }
+            if let Some(nodelay) = child.get("nodelay") {
+                options.nodelay = parse_bool(nodelay, "nodelay")?;
+            }
+            options.send_buffer = parse_buffer_size(child, "send_buffer")?;
+            options.recv_buffer = parse_buffer_size(child, "recv_buffer")?;
+            if let Some(mode) = get_setting_str(child, "mode") {
+                let mode = u32::from_str_radix(&mode?, 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o7777)
+                    .ok_or_else(|| Error::s("'mode' must be an octal number (e.g. 660)"))?;
+                options.mode = Some(mode);
+            }
+            if let Some(owner) = get_setting_str(child, "owner") {
+                let owner = owner?;
+                let uid = match owner.parse() {
+                    Ok(uid) => uid,
+                    Err(_) => parse_user(&owner, "owner")?.0,
+                };
+                options.owner = Some(uid);
+            }
+            if let Some(group) = get_setting_str(child, "group") {
+                options.group = Some(parse_group(&group?, "group")?);
+            }
+            if let Some(host) = get_setting_str(child, "host") {
+                options.host = host?;
+            }
+        }
+        Ok(Binding {
+            address,
+            tls,
+            options,
+            name,
+        })
+    }
+}
+
+fn parse_buffer_size(doc: &Scfg, name: &str) -> Result<Option<u32>> {
+    get_setting_usize(doc, name)
+        .map(|size| {
+            u32::try_from(size?)
+                .ok()
+                .filter(|size| *size != 0)
+                .ok_or_else(|| Error::Content(format!("'{}' must be a positive integer", name)))
+        })
+        .transpose()
+}
+
+/// Parses a user, given either by name or by ID, into its ID and the ID of its primary group.
+fn parse_user(name: &str, directive: &str) -> Result<(u32, u32)> {
+    util::lookup_user(name)?
+        .ok_or_else(|| Error::Content(format!("'{}' has an unknown name {:?}", directive, name)))
+}
+
+/// Parses a group, given either by name or by ID.
+fn parse_group(name: &str, directive: &str) -> Result<u32> {
+    util::lookup_group(name)?
+        .ok_or_else(|| Error::Content(format!("'{}' has an unknown name {:?}", directive, name)))
+}
+
+/// A privilege IRC operators can be granted through their class.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Privilege {
+    /// Use the KILL command.
+    Kill,
+    /// Manage server bans with KLINE, UNKLINE, DLINE and UNDLINE.
+    Kline,
+    /// Use the REHASH command.
+    Rehash,
+    /// See secret channels and invisible users.
+    SeeHidden,
+    /// See the real host of users in WHOIS replies.
+    SeeRealHosts,
+    /// Bypass channel member ranks.
+    Override,
+    /// Send messages to all users, with `$*` targets and WALLOPS.
+    Broadcast,
+    /// Use the UPGRADE command.
+    Upgrade,
+    /// Query the server statistics and configuration with STATS.
+    Stats,
+}
+
+impl Privilege {
+    pub const ALL: [Privilege; 9] = [
+        Privilege::Kill,
+        Privilege::Kline,
+        Privilege::Rehash,
+        Privilege::SeeHidden,
+        Privilege::SeeRealHosts,
+        Privilege::Override,
+        Privilege::Broadcast,
+        Privilege::Upgrade,
+        Privilege::Stats,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Privilege::Kill => "kill",
+            Privilege::Kline => "kline",
+            Privilege::Rehash => "rehash",
+            Privilege::SeeHidden => "see-hidden",
+            Privilege::SeeRealHosts => "see-real-hosts",
+            Privilege::Override => "override",
+            Privilege::Broadcast => "broadcast",
+            Privilege::Upgrade => "upgrade",
+            Privilege::Stats => "stats",
+        }
+    }
+}
+
+impl TryFrom<&str> for Privilege {
+    type Error = Error;
+
+    fn try_from(val: &str) -> Result<Privilege> {
+        Privilege::ALL
+            .iter()
+            .find(|p| p.as_str() == val)
+            .cloned()
+            .ok_or_else(|| Error::Content(format!("unknown oper privilege {:?}", val)))
+    }
+}
+
+/// A named set of privileges given to IRC operators.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OperClass {
+    pub name: String,
+    pub privileges: Vec<Privilege>,
+}
+
+impl OperClass {
+    pub fn has(&self, privilege: Privilege) -> bool {
+        self.privileges.contains(&privilege)
+    }
+}
+
+impl Default for OperClass {
+    /// The class of operators that have no `class` setting, with all privileges.
+    fn default() -> OperClass {
+        OperClass {
+            name: String::from("default"),
+            privileges: Privilege::ALL.to_vec(),
+        }
     }
 }
 
@@ -103,7 +341,324 @@ impl TryFrom<&scfg::Directive> for Binding {
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct Oper {
     pub name: String,
+
+    /// Either a bcrypt hash, an argon2 hash in the PHC string format, or the password in
+    /// plain-text.
     pub password: String,
+
+    pub class: OperClass,
+
+    /// `user@host` masks the client must match.  Empty when any host is accepted.
+    pub hosts: Vec<String>,
+
+    /// SHA-256 fingerprint of the TLS client certificate the client must present, in lowercase
+    /// hexadecimal without separators.
+    pub fingerprint: Option<String>,
+}
+
+fn parse_oper_class(directive: &scfg::Directive) -> Result<OperClass> {
+    let name = directive
+        .params()
+        .first()
+        .ok_or_else(|| Error::s("'oper_class' is missing its name"))?
+        .clone();
+    let mut privileges = Vec::new();
+    let child = directive.child();
+    for p in child
+        .and_then(|c| c.get("privileges"))
+        .map_or(&[][..], |d| d.params())
+    {
+        privileges.push(Privilege::try_from(p.as_str())?);
+    }
+    Ok(OperClass { name, privileges })
+}
+
+fn parse_oper(directive: &scfg::Directive, classes: &[OperClass]) -> Result<Oper> {
+    let name = directive
+        .params()
+        .first()
+        .ok_or_else(|| Error::s("'oper' is missing its name"))?
+        .clone();
+    let mut res = Oper {
+        name,
+        password: directive.params().get(1).cloned().unwrap_or_default(),
+        ..Oper::default()
+    };
+
+    if let Some(child) = directive.child() {
+        if let Some(password) = get_setting_str(child, "password") {
+            res.password = password?;
+        }
+        if let Some(class) = get_setting_str(child, "class") {
+            let class = class?;
+            res.class = classes
+                .iter()
+                .find(|c| c.name == class)
+                .cloned()
+                .ok_or_else(|| Error::Content(format!("oper class {:?} is not defined", class)))?;
+        }
+        if let Some(hosts) = child.get("hosts") {
+            res.hosts = hosts.params().to_vec();
+        }
+        if let Some(fingerprint) = get_setting_str(child, "fingerprint") {
+            let fingerprint: String = fingerprint?
+                .chars()
+                .filter(|c| *c != ':')
+                .map(|c| c.to_ascii_lowercase())
+                .collect();
+            if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::s(
+                    "'fingerprint' must be a SHA-256 hash in hexadecimal",
+                ));
+            }
+            res.fingerprint = Some(fingerprint);
+        }
+    }
+
+    if res.password.is_empty() {
+        return Err(Error::Content(format!(
+            "oper {:?} has no password",
+            res.name
+        )));
+    }
+
+    Ok(res)
+}
+
+/// An IP address range in CIDR notation (`192.0.2.0/24`, `2001:db8::/32`).  A bare address
+/// matches only itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cidr {
+    pub address: net::IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: net::IpAddr) -> bool {
+        // Compare IPv4-mapped IPv6 addresses as IPv4 ones.
+        let ip = match ip {
+            net::IpAddr::V6(ip6) if self.address.is_ipv4() => {
+                ip6.to_ipv4().map_or(ip, net::IpAddr::V4)
+            }
+            ip => ip,
+        };
+        match (self.address, ip) {
+            (net::IpAddr::V4(a), net::IpAddr::V4(b)) => {
+                prefix_matches(&a.octets(), &b.octets(), self.prefix_len)
+            }
+            (net::IpAddr::V6(a), net::IpAddr::V6(b)) => {
+                prefix_matches(&a.octets(), &b.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(a: &[u8], b: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let rest_bits = prefix_len % 8;
+    if a[..full_bytes] != b[..full_bytes] {
+        return false;
+    }
+    if rest_bits == 0 {
+        return true;
+    }
+    let mask = 0xff_u8 << (8 - rest_bits);
+    a[full_bytes] & mask == b[full_bytes] & mask
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Cidr> {
+        let bad_cidr = || Error::Content(format!("{:?} is not a valid IP address range", s));
+        let (address, prefix_len) = match s.find('/') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let address: net::IpAddr = address.parse().map_err(|_| bad_cidr())?;
+        let max_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len.parse().map_err(|_| bad_cidr())?,
+            None => max_len,
+        };
+        if max_len < prefix_len {
+            return Err(bad_cidr());
+        }
+        Ok(Cidr {
+            address,
+            prefix_len,
+        })
+    }
+}
+
+/// Flood control and resource limits applied to the clients that match the class.
+///
+/// Clients are given the first class that matches them, or the one named "default".
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectionClass {
+    pub name: String,
+
+    /// IP address ranges the client must connect from.  Empty when any address is accepted.
+    pub ips: Vec<Cidr>,
+
+    /// When set, whether the client must or must not use TLS.
+    pub tls: Option<bool>,
+
+    /// Accounts the client must be logged in to.  Empty when any client is accepted.
+    pub accounts: Vec<String>,
+
+    /// Number of milliseconds needed to recover one flood-control point.
+    pub rate: u32,
+
+    /// Number of points a client can spend at once.
+    pub burst: u32,
+
+    /// Overrides of the point cost of commands, by command name.
+    pub costs: HashMap<&'static str, u32>,
+
+    /// Maximum number of bytes waiting to be sent to the client, 0 for no limit.
+    pub sendq: usize,
+
+    /// Number of seconds of inactivity after which the server sends a PING to the client.
+    pub ping_freq: u64,
+
+    /// Maximum number of clients in this class, 0 for no limit.
+    pub max_clients: usize,
+
+    /// Whether clients of this class bypass flood control and sendq limits.
+    pub exempt: bool,
+}
+
+impl ConnectionClass {
+    /// Whether a client with the given properties belongs to this class.
+    ///
+    /// Clients of Unix domain sockets have no IP address, and only belong to classes without IP
+    /// ranges.
+    pub fn matches(&self, ip: Option<net::IpAddr>, tls: bool, account: Option<&str>) -> bool {
+        let ip_matches = self.ips.is_empty()
+            || matches!(ip, Some(ip) if self.ips.iter().any(|cidr| cidr.contains(ip)));
+        let tls_matches = self.tls.is_none() || self.tls == Some(tls);
+        let account_matches = self.accounts.is_empty()
+            || matches!(account, Some(account) if self.accounts.iter().any(|a| a == account));
+        ip_matches && tls_matches && account_matches
+    }
+}
+
+impl Default for ConnectionClass {
+    /// The class of clients that match no configured class.
+    fn default() -> ConnectionClass {
+        ConnectionClass {
+            name: String::from("default"),
+            ips: Vec::new(),
+            tls: None,
+            accounts: Vec::new(),
+            rate: 125,
+            burst: 32,
+            costs: HashMap::new(),
+            sendq: 1 << 20,
+            ping_freq: 120,
+            max_clients: 0,
+            exempt: false,
+        }
+    }
+}
+
+fn parse_bool(directive: &scfg::Directive, name: &str) -> Result<bool> {
+    match directive.params().first().map(String::as_str) {
+        Some("true") | Some("yes") => Ok(true),
+        Some("false") | Some("no") => Ok(false),
+        _ => Err(Error::Content(format!(
+            "'{}' only accepts true or false",
+            name
+        ))),
+    }
+}
+
+fn parse_connection_class(directive: &scfg::Directive) -> Result<ConnectionClass> {
+    let name = directive
+        .params()
+        .first()
+        .ok_or_else(|| Error::s("'connection_class' is missing its name"))?
+        .clone();
+    let mut res = ConnectionClass {
+        name,
+        ..ConnectionClass::default()
+    };
+
+    let child = match directive.child() {
+        Some(child) => child,
+        None => return Ok(res),
+    };
+    if let Some(ips) = child.get("ips") {
+        res.ips = ips
+            .params()
+            .iter()
+            .map(|ip| ip.parse())
+            .collect::<Result<_>>()?;
+    }
+    if let Some(tls) = child.get("tls") {
+        res.tls = Some(parse_bool(tls, "tls")?);
+    }
+    if let Some(accounts) = child.get("accounts") {
+        res.accounts = accounts.params().to_vec();
+    }
+    if let Some(rate) = get_setting_usize(child, "rate") {
+        res.rate = u32::try_from(rate?)
+            .ok()
+            .filter(|rate| *rate != 0)
+            .ok_or_else(|| Error::s("'rate' must be a positive integer"))?;
+    }
+    if let Some(burst) = get_setting_usize(child, "burst") {
+        res.burst = u32::try_from(burst?).map_err(|_| Error::s("'burst' is too large"))?;
+    }
+    for cost in child.get_all("cost").unwrap_or(&[]) {
+        let command = cost.params().first().map(String::as_str).unwrap_or("");
+        let command = Command::parse(command).ok_or_else(|| {
+            Error::Content(format!("'cost' has an unknown command {:?}", command))
+        })?;
+        let points = cost
+            .params()
+            .get(1)
+            .and_then(|points| points.parse().ok())
+            .ok_or_else(|| Error::s("'cost' needs a command and an integer"))?;
+        res.costs.insert(command.as_str(), points);
+    }
+    if let Some(sendq) = get_setting_usize(child, "sendq") {
+        res.sendq = sendq?;
+    }
+    if let Some(ping_freq) = get_setting_usize(child, "ping_freq") {
+        res.ping_freq = ping_freq? as u64;
+    }
+    if let Some(max_clients) = get_setting_usize(child, "max_clients") {
+        res.max_clients = max_clients?;
+    }
+    if let Some(exempt) = child.get("exempt") {
+        res.exempt = parse_bool(exempt, "exempt")?;
+    }
+
+    Ok(res)
+}
+
+/// A server ban, either on `user@host` masks (K-line) or on IP address masks (D-line).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ban {
+    pub mask: String,
+    pub reason: String,
+}
+
+fn parse_ban(directive: &scfg::Directive, name: &str) -> Result<Ban> {
+    let mask = directive
+        .params()
+        .first()
+        .ok_or_else(|| Error::Content(format!("'{}' is missing its mask", name)))?
+        .clone();
+    let reason = directive
+        .params()
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| String::from("Banned"));
+    Ok(Ban { mask, reason })
 }
 
 /// Settings for `State`.
@@ -114,7 +669,12 @@ pub struct State {
     pub org_mail: String,
     pub default_chan_mode: String,
     pub motd_file: String,
+    pub state_file: String,
     pub opers: Vec<Oper>,
+    pub classes: Vec<ConnectionClass>,
+    pub default_class: ConnectionClass,
+    pub klines: Vec<Ban>,
+    pub dlines: Vec<Ban>,
     pub password: String,
     pub awaylen: usize,
     pub channellen: usize,
@@ -125,6 +685,11 @@ pub struct State {
     pub topiclen: usize,
     pub userlen: usize,
     pub login_timeout: u64,
+    pub ping_cookie: bool,
+    pub disabled_capabilities: Vec<String>,
+    pub shutdown_message: String,
+    pub audit_log: String,
+    pub audit_log_size: u64,
 }
 
 impl Default for State {
@@ -136,7 +701,12 @@ impl Default for State {
             org_mail: String::from("unspecified"),
             default_chan_mode: String::from("+nst"),
             motd_file: String::from("/etc/motd"),
+            state_file: String::new(),
             opers: Vec::new(),
+            classes: Vec::new(),
+            default_class: ConnectionClass::default(),
+            klines: Vec::new(),
+            dlines: Vec::new(),
             password: String::new(),
             awaylen: 300,
             channellen: 50,
@@ -147,6 +717,11 @@ impl Default for State {
             topiclen: 300,
             userlen: 64,
             login_timeout: 60_000,
+            ping_cookie: false,
+            disabled_capabilities: Vec::new(),
+            shutdown_message: String::from(lines::SHUTTING_DOWN),
+            audit_log: String::new(),
+            audit_log_size: 10_000_000,
         }
     }
 }
@@ -156,17 +731,42 @@ pub struct Config {
     pub bindings: Vec<Binding>,
     pub workers: usize,
     pub state: State,
+
+    /// Whether to reload the configuration when one of its files changes.
+    pub watch_config: bool,
+
+    /// The path of the configuration file, followed by the include patterns it has been read
+    /// with, in the order they appear.
+    pub sources: Vec<path::PathBuf>,
+
+    /// The user ID and group ID to switch to once the sockets are bound.
+    pub user: Option<u32>,
+    pub group: Option<u32>,
+
+    /// The directory to change the root directory to once the sockets are bound, or empty.
+    pub chroot: String,
+
+    /// Whether to restrict the access to the filesystem with Landlock once the sockets are bound.
+    pub landlock: bool,
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             bindings: vec![Binding {
-                address: net::SocketAddr::from(([127, 0, 0, 1], 6667)),
+                address: Address::Tcp(net::SocketAddr::from(([127, 0, 0, 1], 6667))),
                 tls: None,
+                options: SocketOptions::default(),
+                name: None,
             }],
             workers: 0,
             state: State::default(),
+            watch_config: false,
+            sources: Vec::new(),
+            user: None,
+            group: None,
+            chroot: String::new(),
+            landlock: false,
         }
     }
 }
@@ -193,90 +793,425 @@ fn get_setting_usize(doc: &Scfg, name: &str) -> Option<Result<usize>> {
     })
 }
 
+const fn setting(name: &'static str) -> Spec {
+    Spec {
+        name,
+        repeated: false,
+        block: None,
+    }
+}
+
+const fn list(name: &'static str) -> Spec {
+    Spec {
+        name,
+        repeated: true,
+        block: None,
+    }
+}
+
+const fn block(name: &'static str, repeated: bool, directives: &'static [Spec]) -> Spec {
+    Spec {
+        name,
+        repeated,
+        block: Some(directives),
+    }
+}
+
+/// The directives accepted in configuration files.
+static ROOT: Spec = block(
+    "",
+    false,
+    &[
+        block(
+            "listen",
+            true,
+            &[
+                setting("certificate"),
+                setting("key"),
+                setting("name"),
+                setting("nodelay"),
+                setting("send_buffer"),
+                setting("recv_buffer"),
+                setting("mode"),
+                setting("owner"),
+                setting("group"),
+                setting("host"),
+            ],
+        ),
+        setting("workers"),
+        setting("watch_config"),
+        setting("user"),
+        setting("group"),
+        setting("chroot"),
+        setting("landlock"),
+        setting("domain"),
+        block(
+            "admin_info",
+            false,
+            &[setting("name"), setting("location"), setting("mail")],
+        ),
+        setting("default_chan_mode"),
+        setting("motd_file"),
+        setting("state_file"),
+        block("oper_class", true, &[setting("privileges")]),
+        block(
+            "oper",
+            true,
+            &[
+                setting("password"),
+                setting("class"),
+                setting("hosts"),
+                setting("fingerprint"),
+            ],
+        ),
+        block(
+            "connection_class",
+            true,
+            &[
+                setting("ips"),
+                setting("tls"),
+                setting("accounts"),
+                setting("rate"),
+                setting("burst"),
+                list("cost"),
+                setting("sendq"),
+                setting("ping_freq"),
+                setting("max_clients"),
+                setting("exempt"),
+            ],
+        ),
+        list("kline"),
+        list("dline"),
+        setting("password"),
+        setting("awaylen"),
+        setting("channellen"),
+        setting("keylen"),
+        setting("kicklen"),
+        setting("namelen"),
+        setting("nicklen"),
+        setting("topiclen"),
+        setting("userlen"),
+        setting("login_timeout"),
+        setting("ping_cookie"),
+        setting("disabled_capabilities"),
+        setting("shutdown_message"),
+        setting("audit_log"),
+        setting("audit_log_size"),
+    ],
+);
+
 impl Config {
     /// Reads the configuration file at the given path.
     pub fn from_file(path: impl AsRef<path::Path>) -> Result<Self> {
-        let doc: Scfg = fs::read_to_string(path)?.parse()?;
-        let mut res = Config::default();
+        let (doc, locations, sources) = parser::read(path.as_ref(), &ROOT)?;
+        let at = |name, index| locations.at(name, index);
+        let mut res = Config {
+            sources,
+            ..Config::default()
+        };
 
         if let Some(listen_directives) = doc.get_all("listen") {
             res.bindings.clear();
-            for listen_directive in listen_directives {
-                res.bindings.push(Binding::try_from(listen_directive)?)
+            for (i, listen_directive) in listen_directives.iter().enumerate() {
+                res.bindings
+                    .push(Binding::try_from(listen_directive).map_err(at("listen", i))?)
             }
         }
         if let Some(workers) = get_setting_usize(&doc, "workers") {
-            res.workers = workers?;
+            res.workers = workers.map_err(at("workers", 0))?;
+        }
+        if let Some(watch_config) = doc.get("watch_config") {
+            res.watch_config =
+                parse_bool(watch_config, "watch_config").map_err(at("watch_config", 0))?;
+        }
+        if let Some(user) = get_setting_str(&doc, "user") {
+            let (uid, gid) = user
+                .and_then(|user| parse_user(&user, "user"))
+                .map_err(at("user", 0))?;
+            res.user = Some(uid);
+            res.group = Some(gid);
+        }
+        if let Some(group) = get_setting_str(&doc, "group") {
+            let gid = group
+                .and_then(|group| parse_group(&group, "group"))
+                .map_err(at("group", 0))?;
+            res.group = Some(gid);
+        }
+        if let Some(chroot) = get_setting_str(&doc, "chroot") {
+            res.chroot = chroot.map_err(at("chroot", 0))?;
+        }
+        if let Some(landlock) = doc.get("landlock") {
+            res.landlock = parse_bool(landlock, "landlock").map_err(at("landlock", 0))?;
         }
         if let Some(domain) = get_setting_str(&doc, "domain") {
-            res.state.domain = domain?;
+            res.state.domain = domain.map_err(at("domain", 0))?;
             if res.state.domain.contains(' ') {
-                return Err(Error::InvalidDomain);
+                return Err(at("domain", 0)(Error::InvalidDomain));
             }
         }
         if let Some(org) = doc.get("admin_info") {
             let org = org
                 .child()
-                .ok_or_else(|| Error::s("'admin_info' has an empty body"))?;
+                .ok_or_else(|| Error::s("'admin_info' has an empty body"))
+                .map_err(at("admin_info", 0))?;
             if let Some(org_name) = get_setting_str(&org, "name") {
-                res.state.org_name = org_name?;
+                res.state.org_name = org_name.map_err(at("admin_info", 0))?;
             }
             if let Some(org_location) = get_setting_str(&org, "location") {
-                res.state.org_location = org_location?;
+                res.state.org_location = org_location.map_err(at("admin_info", 0))?;
             }
             if let Some(org_mail) = get_setting_str(&org, "mail") {
-                res.state.org_mail = org_mail?;
+                res.state.org_mail = org_mail.map_err(at("admin_info", 0))?;
             }
         }
         if let Some(default_chan_mode) = get_setting_str(&doc, "default_chan_mode") {
-            res.state.default_chan_mode = default_chan_mode?;
+            res.state.default_chan_mode = default_chan_mode.map_err(at("default_chan_mode", 0))?;
             if !mode::is_channel_mode_string(&res.state.default_chan_mode) {
-                return Err(Error::InvalidModes);
+                return Err(at("default_chan_mode", 0)(Error::InvalidModes));
             }
         }
         if let Some(motd_file) = get_setting_str(&doc, "motd_file") {
-            res.state.motd_file = motd_file?.into();
+            res.state.motd_file = motd_file.map_err(at("motd_file", 0))?.into();
         }
-        for oper in doc.get_all("oper").unwrap_or(&[]) {
-            let password = oper
-                .params()
-                .get(1)
-                .ok_or_else(|| Error::s("'oper' must have two parameters"))?
-                .clone();
-            let name = oper.params().get(0).unwrap().clone();
-            res.state.opers.push(Oper { name, password });
+        if let Some(state_file) = get_setting_str(&doc, "state_file") {
+            res.state.state_file = state_file.map_err(at("state_file", 0))?;
+        }
+        let mut oper_classes = Vec::new();
+        for (i, oper_class) in doc.get_all("oper_class").unwrap_or(&[]).iter().enumerate() {
+            oper_classes.push(parse_oper_class(oper_class).map_err(at("oper_class", i))?);
+        }
+        for (i, oper) in doc.get_all("oper").unwrap_or(&[]).iter().enumerate() {
+            res.state
+                .opers
+                .push(parse_oper(oper, &oper_classes).map_err(at("oper", i))?);
+        }
+        for (i, class) in doc
+            .get_all("connection_class")
+            .unwrap_or(&[])
+            .iter()
+            .enumerate()
+        {
+            let class = parse_connection_class(class).map_err(at("connection_class", i))?;
+            if class.name == "default" {
+                res.state.default_class = class;
+            } else {
+                res.state.classes.push(class);
+            }
+        }
+        for (i, kline) in doc.get_all("kline").unwrap_or(&[]).iter().enumerate() {
+            res.state
+                .klines
+                .push(parse_ban(kline, "kline").map_err(at("kline", i))?);
+        }
+        for (i, dline) in doc.get_all("dline").unwrap_or(&[]).iter().enumerate() {
+            res.state
+                .dlines
+                .push(parse_ban(dline, "dline").map_err(at("dline", i))?);
         }
         if let Some(password) = get_setting_str(&doc, "password") {
-            res.state.password = password?;
+            res.state.password = password.map_err(at("password", 0))?;
         }
         if let Some(awaylen) = get_setting_usize(&doc, "awaylen") {
-            res.state.awaylen = awaylen?;
+            res.state.awaylen = awaylen.map_err(at("awaylen", 0))?;
         }
         if let Some(channellen) = get_setting_usize(&doc, "channellen") {
-            res.state.channellen = channellen?;
+            res.state.channellen = channellen.map_err(at("channellen", 0))?;
         }
         if let Some(keylen) = get_setting_usize(&doc, "keylen") {
-            res.state.keylen = keylen?;
+            res.state.keylen = keylen.map_err(at("keylen", 0))?;
         }
         if let Some(kicklen) = get_setting_usize(&doc, "kicklen") {
-            res.state.kicklen = kicklen?;
+            res.state.kicklen = kicklen.map_err(at("kicklen", 0))?;
         }
         if let Some(namelen) = get_setting_usize(&doc, "namelen") {
-            res.state.namelen = namelen?;
+            res.state.namelen = namelen.map_err(at("namelen", 0))?;
         }
         if let Some(nicklen) = get_setting_usize(&doc, "nicklen") {
-            res.state.nicklen = nicklen?;
+            res.state.nicklen = nicklen.map_err(at("nicklen", 0))?;
         }
         if let Some(topiclen) = get_setting_usize(&doc, "topiclen") {
-            res.state.topiclen = topiclen?;
+            res.state.topiclen = topiclen.map_err(at("topiclen", 0))?;
         }
         if let Some(userlen) = get_setting_usize(&doc, "userlen") {
-            res.state.userlen = userlen?;
+            res.state.userlen = userlen.map_err(at("userlen", 0))?;
         }
         if let Some(login_timeout) = get_setting_usize(&doc, "login_timeout") {
-            res.state.login_timeout = login_timeout? as u64;
+            res.state.login_timeout = login_timeout.map_err(at("login_timeout", 0))? as u64;
+        }
+        if let Some(ping_cookie) = doc.get("ping_cookie") {
+            res.state.ping_cookie =
+                parse_bool(ping_cookie, "ping_cookie").map_err(at("ping_cookie", 0))?;
+        }
+        if let Some(disabled) = doc.get("disabled_capabilities") {
+            for cap in disabled.params() {
+                if !data::cap::ls_common().split(' ').any(|c| c == cap) {
+                    return Err(at("disabled_capabilities", 0)(Error::Content(format!(
+                        "'disabled_capabilities' got an unknown capability {:?}",
+                        cap
+                    ))));
+                }
+            }
+            res.state.disabled_capabilities = disabled.params().to_vec();
+        }
+        if let Some(shutdown_message) = get_setting_str(&doc, "shutdown_message") {
+            res.state.shutdown_message = shutdown_message.map_err(at("shutdown_message", 0))?;
+        }
+        if let Some(audit_log) = get_setting_str(&doc, "audit_log") {
+            res.state.audit_log = audit_log.map_err(at("audit_log", 0))?;
+        }
+        if let Some(audit_log_size) = get_setting_usize(&doc, "audit_log_size") {
+            res.state.audit_log_size = audit_log_size.map_err(at("audit_log_size", 0))? as u64;
         }
 
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_cidr_contains() {
+        let cases = [
+            ("10.0.0.0/8", "10.1.2.3", true),
+            ("10.0.0.0/8", "11.1.2.3", false),
+            ("192.0.2.128/25", "192.0.2.200", true),
+            ("192.0.2.128/25", "192.0.2.100", false),
+            ("127.0.0.1", "127.0.0.1", true),
+            ("127.0.0.1", "127.0.0.2", false),
+            ("0.0.0.0/0", "203.0.113.7", true),
+            ("10.0.0.0/8", "::ffff:10.0.0.1", true),
+            ("2001:db8::/32", "2001:db8:1::1", true),
+            ("2001:db8::/32", "2001:db9::1", false),
+            ("2001:db8::/32", "10.0.0.1", false),
+        ];
+
+        for (cidr, ip, contains) in &cases {
+            let parsed: Cidr = cidr.parse().unwrap();
+            assert_eq!(
+                parsed.contains(ip.parse().unwrap()),
+                *contains,
+                "{:?}.contains({:?})",
+                cidr,
+                ip
+            );
+        }
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("not an ip".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_address_from_str() {
+        let tcp: Address = "127.0.0.1:6667".parse().unwrap();
+        assert_eq!(
+            tcp,
+            Address::Tcp(net::SocketAddr::from(([127, 0, 0, 1], 6667)))
+        );
+        assert_eq!(tcp.to_string(), "127.0.0.1:6667");
+
+        let unix: Address = "unix:/run/ellidri.sock".parse().unwrap();
+        assert_eq!(unix, Address::Unix("/run/ellidri.sock".into()));
+        assert_eq!(unix.to_string(), "unix:/run/ellidri.sock");
+
+        assert!("/run/ellidri.sock".parse::<Address>().is_err());
+    }
+
+    #[test]
+    fn test_from_file_includes() {
+        let dir = std::env::temp_dir().join(format!("ellidri-config-test.{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("conf.d")).unwrap();
+        let write = |name: &str, contents: &str| fs::write(dir.join(name), contents).unwrap();
+        write(
+            "ellidri.conf",
+            "domain ${ELLIDRI_TEST_DOMAIN}\ninclude conf.d/*.conf\n",
+        );
+        write("conf.d/b.conf", "password file:../secret\n");
+        write(
+            "conf.d/a.conf",
+            "oper_class all {\n    privileges kill\n}\n",
+        );
+        write("conf.d/c.txt", "password ignored\n");
+        write("secret", "hunter2\n");
+        std::env::set_var("ELLIDRI_TEST_DOMAIN", "irc.example.com");
+
+        let cfg = Config::from_file(dir.join("ellidri.conf")).unwrap();
+        assert_eq!(cfg.state.domain, "irc.example.com");
+        assert_eq!(cfg.state.password, "hunter2");
+
+        write("conf.d/b.conf", "\nawaylen lots\n");
+        match Config::from_file(dir.join("ellidri.conf")) {
+            Err(Error::At(location, _)) => {
+                assert_eq!(location.file, dir.join("conf.d/b.conf"));
+                assert_eq!(location.line, 2);
+            }
+            _ => panic!("the error should name the included file"),
+        }
+
+        write("conf.d/b.conf", "oper_class broken {\n");
+        assert!(Config::from_file(dir.join("ellidri.conf")).is_err());
+        write("ellidri.conf", "include ellidri.conf\n");
+        assert!(Config::from_file(dir.join("ellidri.conf")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_file_validation() {
+        let path = std::env::temp_dir().join(format!("ellidri-check-test.{}", std::process::id()));
+        let error_line = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            match Config::from_file(&path) {
+                Err(Error::At(location, _)) => Some(location.line),
+                _ => None,
+            }
+        };
+
+        assert_eq!(error_line("domain irc.example.com\nnicklen 20\n"), None);
+        assert_eq!(error_line("domain irc.example.com\nnicklne 20\n"), Some(2));
+        assert_eq!(
+            error_line("admin_info {\n    name a\n    key b\n}\n"),
+            Some(3)
+        );
+        assert_eq!(error_line("\ndomain a.com\ndomain b.com\n"), Some(3));
+        assert_eq!(error_line("oper a a\noper b b\n"), None);
+        assert_eq!(error_line("kline *@a.com\nkline *@b.com\n"), None);
+        assert_eq!(error_line("domain a.com\n\ndline\n"), Some(3));
+        assert_eq!(error_line("domain a.com {\n}\n"), Some(1));
+        assert_eq!(
+            error_line("listen 0.0.0.0:6697 {\n    certificate cert.pem\n}\n"),
+            Some(1)
+        );
+        assert_eq!(error_line("disabled_capabilities setname\n"), None);
+        assert_eq!(error_line("\ndisabled_capabilities sasl\n"), Some(2));
+
+        fs::remove_file(&path).unwrap();
+
+        let doc = concat!(env!("CARGO_MANIFEST_DIR"), "/doc/");
+        Config::from_file(format!("{}config_full.scfg", doc)).unwrap();
+        Config::from_file(format!("{}config_example.scfg", doc)).unwrap();
+    }
+
+    #[test]
+    fn test_from_file_user() {
+        let path = std::env::temp_dir().join(format!("ellidri-user-test.{}", std::process::id()));
+        let ids = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            Config::from_file(&path)
+                .map(|cfg| (cfg.user, cfg.group))
+                .ok()
+        };
+
+        assert_eq!(ids(""), Some((None, None)));
+        assert_eq!(ids("user root\n"), Some((Some(0), Some(0))));
+        assert_eq!(ids("user 0\ngroup 5\n"), Some((Some(0), Some(5))));
+        assert_eq!(ids("group 5\n"), Some((None, Some(5))));
+        assert_eq!(ids("user no-such-user-ellidri\n"), None);
+
+        fs::remove_file(&path).unwrap();
+    }
+} // mod tests
@@ -0,0 +1,183 @@
+//! Writing of the effective configuration, for `--dump-config`.
+//!
+//! The output can be read back, except for passwords, which are replaced by `<hidden>` so that
+//! the output can be shared.  Oper classes that no oper uses are not written.
+
+use super::{Binding, Config, ConnectionClass, Oper, OperClass};
+use shell_words::quote;
+use std::fmt;
+
+/// What passwords are replaced with.
+const HIDDEN: &str = "<hidden>";
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = &self.state;
+
+        for binding in &self.bindings {
+            write_binding(f, binding)?;
+        }
+        writeln!(f, "workers {}", self.workers)?;
+        writeln!(f, "watch_config {}", self.watch_config)?;
+        if let Some(user) = self.user {
+            writeln!(f, "user {}", user)?;
+        }
+        if let Some(group) = self.group {
+            writeln!(f, "group {}", group)?;
+        }
+        writeln!(f, "chroot {}", quote(&self.chroot))?;
+        writeln!(f, "landlock {}", self.landlock)?;
+        writeln!(f, "domain {}", quote(&state.domain))?;
+        writeln!(f, "admin_info {{")?;
+        writeln!(f, "    name {}", quote(&state.org_name))?;
+        writeln!(f, "    location {}", quote(&state.org_location))?;
+        writeln!(f, "    mail {}", quote(&state.org_mail))?;
+        writeln!(f, "}}")?;
+        writeln!(f, "default_chan_mode {}", quote(&state.default_chan_mode))?;
+        writeln!(f, "motd_file {}", quote(&state.motd_file))?;
+        writeln!(f, "state_file {}", quote(&state.state_file))?;
+
+        let mut oper_classes: Vec<&OperClass> = Vec::new();
+        for oper in &state.opers {
+            if oper.class != OperClass::default() && !oper_classes.contains(&&oper.class) {
+                oper_classes.push(&oper.class);
+            }
+        }
+        for class in oper_classes {
+            writeln!(f, "oper_class {} {{", quote(&class.name))?;
+            write!(f, "    privileges")?;
+            for privilege in &class.privileges {
+                write!(f, " {}", privilege.as_str())?;
+            }
+            writeln!(f)?;
+            writeln!(f, "}}")?;
+        }
+        for oper in &state.opers {
+            write_oper(f, oper)?;
+        }
+
+        write_connection_class(f, &state.default_class)?;
+        for class in &state.classes {
+            write_connection_class(f, class)?;
+        }
+        for kline in &state.klines {
+            writeln!(f, "kline {} {}", quote(&kline.mask), quote(&kline.reason))?;
+        }
+        for dline in &state.dlines {
+            writeln!(f, "dline {} {}", quote(&dline.mask), quote(&dline.reason))?;
+        }
+
+        if state.password.is_empty() {
+            writeln!(f, "password ''")?;
+        } else {
+            writeln!(f, "password {}", quote(HIDDEN))?;
+        }
+        writeln!(f, "awaylen {}", state.awaylen)?;
+        writeln!(f, "channellen {}", state.channellen)?;
+        writeln!(f, "keylen {}", state.keylen)?;
+        writeln!(f, "kicklen {}", state.kicklen)?;
+        writeln!(f, "namelen {}", state.namelen)?;
+        writeln!(f, "nicklen {}", state.nicklen)?;
+        writeln!(f, "topiclen {}", state.topiclen)?;
+        writeln!(f, "userlen {}", state.userlen)?;
+        writeln!(f, "login_timeout {}", state.login_timeout)?;
+        writeln!(f, "ping_cookie {}", state.ping_cookie)?;
+        if !state.disabled_capabilities.is_empty() {
+            write!(f, "disabled_capabilities")?;
+            for cap in &state.disabled_capabilities {
+                write!(f, " {}", cap)?;
+            }
+            writeln!(f)?;
+        }
+        writeln!(f, "shutdown_message {}", quote(&state.shutdown_message))?;
+        writeln!(f, "audit_log {}", quote(&state.audit_log))?;
+        writeln!(f, "audit_log_size {}", state.audit_log_size)
+    }
+}
+
+fn write_binding(f: &mut fmt::Formatter<'_>, binding: &Binding) -> fmt::Result {
+    let options = &binding.options;
+
+    writeln!(f, "listen {} {{", quote(&binding.address.to_string()))?;
+    if let Some(tls) = &binding.tls {
+        writeln!(
+            f,
+            "    certificate {}",
+            quote(&tls.certificate.to_string_lossy())
+        )?;
+        writeln!(f, "    key {}", quote(&tls.key.to_string_lossy()))?;
+    }
+    if let Some(name) = &binding.name {
+        writeln!(f, "    name {}", quote(name))?;
+    }
+    writeln!(f, "    nodelay {}", options.nodelay)?;
+    if let Some(send_buffer) = options.send_buffer {
+        writeln!(f, "    send_buffer {}", send_buffer)?;
+    }
+    if let Some(recv_buffer) = options.recv_buffer {
+        writeln!(f, "    recv_buffer {}", recv_buffer)?;
+    }
+    if let Some(mode) = options.mode {
+        writeln!(f, "    mode {:o}", mode)?;
+    }
+    if let Some(owner) = options.owner {
+        writeln!(f, "    owner {}", owner)?;
+    }
+    if let Some(group) = options.group {
+        writeln!(f, "    group {}", group)?;
+    }
+    writeln!(f, "    host {}", quote(&options.host))?;
+    writeln!(f, "}}")
+}
+
+fn write_oper(f: &mut fmt::Formatter<'_>, oper: &Oper) -> fmt::Result {
+    writeln!(f, "oper {} {{", quote(&oper.name))?;
+    writeln!(f, "    password {}", quote(HIDDEN))?;
+    if oper.class != OperClass::default() {
+        writeln!(f, "    class {}", quote(&oper.class.name))?;
+    }
+    if !oper.hosts.is_empty() {
+        write!(f, "    hosts")?;
+        for host in &oper.hosts {
+            write!(f, " {}", quote(host))?;
+        }
+        writeln!(f)?;
+    }
+    if let Some(fingerprint) = &oper.fingerprint {
+        writeln!(f, "    fingerprint {}", fingerprint)?;
+    }
+    writeln!(f, "}}")
+}
+
+fn write_connection_class(f: &mut fmt::Formatter<'_>, class: &ConnectionClass) -> fmt::Result {
+    writeln!(f, "connection_class {} {{", quote(&class.name))?;
+    if !class.ips.is_empty() {
+        write!(f, "    ips")?;
+        for cidr in &class.ips {
+            write!(f, " {}/{}", cidr.address, cidr.prefix_len)?;
+        }
+        writeln!(f)?;
+    }
+    if let Some(tls) = class.tls {
+        writeln!(f, "    tls {}", tls)?;
+    }
+    if !class.accounts.is_empty() {
+        write!(f, "    accounts")?;
+        for account in &class.accounts {
+            write!(f, " {}", quote(account))?;
+        }
+        writeln!(f)?;
+    }
+    writeln!(f, "    rate {}", class.rate)?;
+    writeln!(f, "    burst {}", class.burst)?;
+    let mut costs: Vec<_> = class.costs.iter().collect();
+    costs.sort();
+    for (command, points) in costs {
+        writeln!(f, "    cost {} {}", command, points)?;
+    }
+    writeln!(f, "    sendq {}", class.sendq)?;
+    writeln!(f, "    ping_freq {}", class.ping_freq)?;
+    writeln!(f, "    max_clients {}", class.max_clients)?;
+    writeln!(f, "    exempt {}", class.exempt)?;
+    writeln!(f, "}}")
+}
@@ -0,0 +1,331 @@
+//! Reading of configuration files.
+//!
+//! The syntax is the one of the `scfg` crate, plus:
+//!
+//! - `include <pattern>...` directives, which are replaced by the directives of the files they
+//!   name.  Relative paths are relative to the directory of the including file, and the last
+//!   component of a pattern may contain `*` and `?` wildcards.  Matching files are read in
+//!   alphabetical order.
+//! - `${NAME}` in parameters, which is replaced by the value of the environment variable `NAME`.
+//! - Parameters starting with `file:`, which are replaced by the contents of the file whose path
+//!   follows, without the trailing line break.
+//!
+//! Directives are checked against a `Spec`, so that misspelled, misplaced and repeated
+//! directives are reported along with the file and line they come from.  The location of
+//! top-level directives is also remembered for the errors found while reading their values.
+
+use super::{Error, Result};
+use crate::util;
+use scfg::Scfg;
+use std::collections::HashMap;
+use std::{env, fs, path, str};
+
+/// Maximum number of nested includes, to stop on include loops.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Where a directive has been read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub file: path::PathBuf,
+    pub line: usize,
+}
+
+/// The locations of the top-level directives of a document.
+#[derive(Debug, Default)]
+pub struct Locations(HashMap<String, Vec<Location>>);
+
+impl Locations {
+    /// Returns a function that adds the location of the `index`-th top-level directive named
+    /// `name` to an error.
+    pub fn at<'a>(&'a self, name: &'a str, index: usize) -> impl FnOnce(Error) -> Error + 'a {
+        move |err| match self.0.get(name).and_then(|locations| locations.get(index)) {
+            Some(location) => Error::At(location.clone(), Box::new(err)),
+            None => err,
+        }
+    }
+}
+
+/// A directive the configuration accepts.
+pub struct Spec {
+    pub name: &'static str,
+
+    /// Whether the directive can appear several times in the same block.
+    pub repeated: bool,
+
+    /// The directives its block accepts, or `None` if it cannot have a block.
+    pub block: Option<&'static [Spec]>,
+}
+
+impl Spec {
+    /// Whether a directive named `name` is accepted in this block or one of its children.
+    fn knows(&self, name: &str) -> bool {
+        self.block
+            .unwrap_or(&[])
+            .iter()
+            .any(|spec| spec.name == name || spec.knows(name))
+    }
+}
+
+/// Reads the configuration file at `path`, along with the files it includes.
+///
+/// `root` gives the directives accepted at the top level.  Also returns the path of the file and
+/// the include patterns, see `Config::sources`.
+pub fn read(
+    path: &path::Path,
+    root: &'static Spec,
+) -> Result<(Scfg, Locations, Vec<path::PathBuf>)> {
+    let mut doc = Scfg::new();
+    let mut locations = Locations::default();
+    let mut sources = vec![path.to_owned()];
+    read_file(
+        path,
+        &mut doc,
+        root,
+        root,
+        &mut sources,
+        Some(&mut locations),
+        0,
+    )?;
+    Ok((doc, locations, sources))
+}
+
+fn read_file(
+    path: &path::Path,
+    block: &mut Scfg,
+    spec: &'static Spec,
+    root: &'static Spec,
+    sources: &mut Vec<path::PathBuf>,
+    locations: Option<&mut Locations>,
+    depth: usize,
+) -> Result<()> {
+    let contents = fs::read_to_string(path)?;
+    let mut reader = Reader {
+        path,
+        root,
+        sources,
+        lines: contents.lines().enumerate(),
+        depth,
+        line: 0,
+    };
+    if reader.read_block(block, spec, locations)? {
+        return Err(reader.error("unexpected '}'"));
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    path: &'a path::Path,
+    root: &'static Spec,
+    sources: &'a mut Vec<path::PathBuf>,
+    lines: std::iter::Enumerate<str::Lines<'a>>,
+    depth: usize,
+    line: usize,
+}
+
+impl Reader<'_> {
+    fn location(&self) -> Location {
+        Location {
+            file: self.path.to_owned(),
+            line: self.line,
+        }
+    }
+
+    fn error(&self, message: impl Into<String>) -> Error {
+        Error::At(self.location(), Box::new(Error::Content(message.into())))
+    }
+
+    /// Reads directives into `block`, whose accepted directives are given by `spec`.  Returns
+    /// true if the block has been ended by a closing brace, false if the end of the file has
+    /// been reached.
+    fn read_block(
+        &mut self,
+        block: &mut Scfg,
+        spec: &'static Spec,
+        mut locations: Option<&mut Locations>,
+    ) -> Result<bool> {
+        while let Some((i, line)) = self.lines.next() {
+            self.line = i + 1;
+            let line = line.trim();
+            let mut words = shell_words::split(line).map_err(|err| self.error(err.to_string()))?;
+            if words.is_empty() {
+                continue;
+            }
+            if words.len() == 1 && line.ends_with('}') {
+                return Ok(true);
+            }
+
+            let has_child = words.last().map(String::as_str) == Some("{") && line.ends_with('{');
+            if has_child {
+                words.pop();
+            }
+            let name = if words.is_empty() {
+                String::new()
+            } else {
+                words.remove(0)
+            };
+
+            if name == "include" {
+                if has_child {
+                    return Err(self.error("'include' cannot have a block"));
+                }
+                self.include(&words, block, spec, locations.as_deref_mut())?;
+                continue;
+            }
+
+            let child_spec = self.check(block, spec, &name, has_child)?;
+
+            if let Some(locations) = &mut locations {
+                let location = self.location();
+                locations.0.entry(name.clone()).or_default().push(location);
+            }
+            let directive = block.add(name);
+            for word in &words {
+                directive.append_param(self.substitute(word)?);
+            }
+            if has_child {
+                let start = self.line;
+                if !self.read_block(directive.get_or_create_child(), child_spec, None)? {
+                    self.line = start;
+                    return Err(self.error("this block is missing its closing '}'"));
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Checks that the directive `name` can be added to `block`, and returns its spec.
+    fn check(
+        &self,
+        block: &Scfg,
+        spec: &'static Spec,
+        name: &str,
+        has_child: bool,
+    ) -> Result<&'static Spec> {
+        let where_ = if spec.name.is_empty() {
+            String::from("at the top level")
+        } else {
+            format!("in '{}' blocks", spec.name)
+        };
+        let child_spec = match spec.block.unwrap_or(&[]).iter().find(|s| s.name == name) {
+            Some(child_spec) => child_spec,
+            None if self.root.knows(name) => {
+                return Err(self.error(format!("'{}' is not allowed {}", name, where_)));
+            }
+            None => return Err(self.error(format!("unknown directive '{}' {}", name, where_))),
+        };
+        if !child_spec.repeated && block.contains(name) {
+            return Err(self.error(format!("'{}' is set more than once {}", name, where_)));
+        }
+        if has_child && child_spec.block.is_none() {
+            return Err(self.error(format!("'{}' cannot have a block", name)));
+        }
+        Ok(child_spec)
+    }
+
+    /// Reads the files matching `patterns` into `block`.
+    fn include(
+        &mut self,
+        patterns: &[String],
+        block: &mut Scfg,
+        spec: &'static Spec,
+        mut locations: Option<&mut Locations>,
+    ) -> Result<()> {
+        if patterns.is_empty() {
+            return Err(self.error("'include' is missing a path"));
+        }
+        if MAX_INCLUDE_DEPTH <= self.depth {
+            return Err(self.error("too many nested includes"));
+        }
+        for pattern in patterns {
+            let pattern = self.relative(&self.substitute(pattern)?);
+            self.sources.push(pattern.clone());
+            for path in self.expand(&pattern)? {
+                read_file(
+                    &path,
+                    block,
+                    spec,
+                    self.root,
+                    self.sources,
+                    locations.as_deref_mut(),
+                    self.depth + 1,
+                )
+                .map_err(|err| match err {
+                    Error::Io(err) => self.error(format!("{}: {}", path.display(), err)),
+                    err => err,
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the files matching `pattern`, in alphabetical order.
+    fn expand(&self, pattern: &path::Path) -> Result<Vec<path::PathBuf>> {
+        let mask = match pattern.file_name().and_then(|name| name.to_str()) {
+            Some(mask) if mask.contains(['*', '?']) => mask,
+            _ => return Ok(vec![pattern.to_owned()]),
+        };
+        let dir = pattern
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| path::Path::new("."));
+        let entries =
+            fs::read_dir(dir).map_err(|err| self.error(format!("{}: {}", dir.display(), err)))?;
+
+        let mut paths = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|err| self.error(format!("{}: {}", dir.display(), err)))?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.starts_with('.') && !mask.starts_with('.') {
+                continue;
+            }
+            if util::match_mask(mask, name) && !entry.path().is_dir() {
+                paths.push(entry.path());
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    /// Returns `path`, relative to the directory of the file being read.
+    fn relative(&self, path: &str) -> path::PathBuf {
+        match self.path.parent() {
+            Some(dir) => dir.join(path),
+            None => path.into(),
+        }
+    }
+
+    /// Replaces environment variables in `param`, and reads the file it names if it starts with
+    /// `file:`.
+    fn substitute(&self, param: &str) -> Result<String> {
+        let mut res = String::with_capacity(param.len());
+        let mut rest = param;
+        while let Some(start) = rest.find("${") {
+            res.push_str(&rest[..start]);
+            rest = &rest[start + 2..];
+            let end = rest
+                .find('}')
+                .ok_or_else(|| self.error(format!("missing '}}' after '${{' in {:?}", param)))?;
+            let value = env::var(&rest[..end]).map_err(|_| {
+                self.error(format!(
+                    "environment variable {:?} is not set",
+                    &rest[..end]
+                ))
+            })?;
+            res.push_str(&value);
+            rest = &rest[end + 1..];
+        }
+        res.push_str(rest);
+
+        if let Some(path) = res.strip_prefix("file:") {
+            let path = self.relative(path);
+            let contents = fs::read_to_string(&path)
+                .map_err(|err| self.error(format!("{}: {}", path.display(), err)))?;
+            res = contents.trim_end_matches(['\n', '\r']).to_owned();
+        }
+        Ok(res)
+    }
+}
@@ -16,6 +16,9 @@
 //! - A command channel:  bindings accept commands that change their configuration.  All commands
 //!   are described in the `Command` enum.
 //!
+//! On startup, bindings can also use the sockets passed by systemd instead of binding their own.
+//! See the `systemd` module.
+//!
 //! # The configuration file
 //!
 //! ellidri reads a configuration file at startup.  This configuration file is meant to specify its
@@ -25,7 +28,8 @@
 //! This is because the number of workers is yet unknown, and cannot be changed afterwards.
 //!
 //! Configuration can then be reloaded upon receiving a SIGUSR1 signal (on UNIX systems only,
-//! windows is not yet supported), or a REHASH command.  When it happens, `Control` reread the
+//! windows is not yet supported), a REHASH command, or when one of its files changes if
+//! `watch_config` is set (see the `watch` module).  When it happens, `Control` reread the
 //! configuration file and performs a diff algorithm to know which task needs to be stopped.  This
 //! is really simple:
 //!
@@ -36,19 +40,46 @@
 //!   command to it, either to make it listen for raw TCP connections, or to listen for TLS
 //!   connections with a given `TlsAcceptor` (see `tokio-tls` doc for that).
 //!
-//! Bindings are identified by their socket address (IP address + TCP port).  TLS identities are
-//! not kept track of, thus ellidri might reload the same TLS identity for a binding (it is fine to
-//! let it do we are not reading thousands for TLS identities here).
+//! Bindings are identified by their address (IP address + TCP port, or the path of a Unix domain
+//! socket).  TLS identities are not kept track of, thus ellidri might reload the same TLS identity
+//! for a binding (it is fine to let it do we are not reading thousands for TLS identities here).
+//!
+//! # Dropping privileges
+//!
+//! When `user`, `group`, `chroot` or `landlock` is set, the sockets of the bindings are bound and
+//! the TLS identities are loaded before the runtime is created, then privileges are dropped (see
+//! the `sandbox` module), and the bindings take the sockets as if they had been passed by systemd.
+//!
+//! # Upgrades
+//!
+//! Upon receiving SIGUSR2 (on UNIX systems only) or an UPGRADE command, `Control` checks that the
+//! configuration file and the TLS identities can still be read, then gathers the listening sockets
+//! of the bindings, the state and the plain-text connections, and executes ellidri again, from the
+//! path it has been started from.  The new process takes them
+//! over before reading its configuration.  See the `upgrade` module.
+//!
+//! # Shutdown
+//!
+//! Upon receiving SIGTERM or SIGINT (Ctrl-C on windows), `Control` drops all bindings, waits for
+//! them to stop accepting connections, and disconnects every client with the configured shutdown
+//! message.  It then waits at most `SHUTDOWN_TIMEOUT_SECS` seconds for the connections to send
+//! their last messages before returning, which stops the runtime.  A second signal makes ellidri
+//! exit immediately.
 
-use crate::{Config, net, State, tls};
-use crate::config::{Binding, Tls};
+use crate::{Config, net, sandbox, State, systemd, tls, upgrade, util, watch};
+use crate::config::{self, Address, Binding, SocketOptions, Tls};
+use ellidri_tokens::mode::snomask;
+use std::collections::HashMap;
 use std::future::Future;
-use std::net::SocketAddr;
 use std::sync::Arc;
-use std::{fs, process};
+use std::path::PathBuf;
+use std::{env, fs, io, process};
 use tokio::runtime as rt;
-use tokio::sync::{mpsc, Notify};
-use tokio::task;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::{task, time};
+
+/// Maximum number of seconds to wait for connections to close on shutdown.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
 
 /// A command from `Control` to binding tasks.
 pub enum Command {
@@ -57,17 +88,28 @@ pub enum Command {
 
     /// Ask the binding task to listen for TLS connections with the given acceptor.
     UseTls(tls::Acceptor),
+
+    /// Ask the binding task to apply the given options to new connections.
+    SetOptions(SocketOptions),
+
+    /// Ask the binding task to stop and send its listening socket back, so that it can be passed
+    /// to the next process.  See the `upgrade` module.
+    #[cfg(unix)]
+    Upgrade(oneshot::Sender<net::InheritedFd>),
 }
 
 /// A binding task that is ready to be spawned on the runtime.
 struct LoadedBinding<F> {
     /// The address to be bound.
-    address: SocketAddr,
+    address: Address,
 
     /// Either `None` when the binding listens for raw TCP connections, or `Some(acceptor)` when the
     /// bindings listens for TLS connections with `acceptor`.
     acceptor: Option<tls::Acceptor>,
 
+    /// The options of the sockets of the binding.
+    options: SocketOptions,
+
     /// The sending end of the channel that brings commands to the task.
     handle: mpsc::Sender<Command>,
 
@@ -75,6 +117,22 @@ struct LoadedBinding<F> {
     future: F,
 }
 
+/// The configuration read by `reload_config`, the bindings to spawn, and the addresses of the
+/// bindings that could not be loaded along with the error.
+type ReloadedConfig<F> = (Config, Vec<LoadedBinding<F>>, Vec<(Address, String)>);
+
+/// A binding task that has been spawned on the runtime.
+struct RunningBinding {
+    /// The address the binding listens on.
+    address: Address,
+
+    /// Whether the binding listens for TLS connections.
+    tls: bool,
+
+    /// The sending end of the channel that brings commands to the task.
+    handle: mpsc::Sender<Command>,
+}
+
 /// Creates a tokio runtime with the given number of worker threads.
 fn create_runtime(workers: usize) -> rt::Runtime {
     let mut builder = rt::Builder::new_multi_thread();
@@ -97,69 +155,149 @@ fn create_runtime(workers: usize) -> rt::Runtime {
 /// the program on failure, it is not to be called for reloading.
 ///
 /// It spawns all the generated bindings on the runtime, and returns their listening address and
-/// command channel.
+/// command channel.  Bindings use the socket in `fds` that has their name, or their address when
+/// it has been passed by the previous process on upgrade or bound by `bind_early`.  TLS
+/// identities are taken from `store` when they have already been loaded by `bind_early`.
 fn load_bindings(
     bindings: Vec<Binding>,
+    fds: Vec<(String, net::InheritedFd)>,
+    store: Option<tls::IdentityStore>,
     shared: &State,
-    stop: &mpsc::Sender<SocketAddr>,
-) -> Vec<(SocketAddr, mpsc::Sender<Command>)> {
+    stop: &mpsc::Sender<Address>,
+) -> Vec<RunningBinding> {
     let mut res = Vec::with_capacity(bindings.len());
-    let mut store = tls::IdentityStore::default();
+    let mut store = store.unwrap_or_default();
+    let mut fds: HashMap<_, _> = fds.into_iter().collect();
 
-    for Binding { address, tls } in bindings {
+    for Binding { address, tls, options, name } in bindings {
         let (handle, commands) = mpsc::channel(8);
+        let fd = name
+            .and_then(|name| fds.remove(&name))
+            .or_else(|| fds.remove(&address.to_string()));
         if let Some(Tls { certificate, key, ..  }) = tls {
             let acceptor = match store.acceptor(certificate, key) {
                 Ok(acceptor) => acceptor,
                 Err(_) => process::exit(1),
             };
             let server = net::listen(
-                address,
+                address.clone(),
+                fd,
+                options,
                 shared.clone(),
                 Some(acceptor),
                 stop.clone(),
                 commands,
             );
-            res.push((address, handle));
+            res.push(RunningBinding { address, tls: true, handle });
             tokio::spawn(server);
         } else {
-            let server = net::listen(address, shared.clone(), None, stop.clone(), commands);
-            res.push((address, handle));
+            let server = net::listen(
+                address.clone(),
+                fd,
+                options,
+                shared.clone(),
+                None,
+                stop.clone(),
+                commands,
+            );
+            res.push(RunningBinding { address, tls: false, handle });
             tokio::spawn(server);
         }
     }
 
+    for name in fds.keys() {
+        log::warn!("No binding is named {:?}, closing the socket passed with this name", name);
+    }
+
     res
 }
 
+/// Binds the sockets of `bindings` that have not been passed by the service manager or by the
+/// previous process, and loads their TLS identities into `store`, so that privileges can be
+/// dropped before the runtime starts.  See the `sandbox` module.
+///
+/// The sockets are added to `fds` under their address.  They are registered to a single-threaded
+/// runtime while being bound, since the runtime that will serve them does not exist yet.
+/// Bindings that fail to bind are removed.  Like `load_bindings`, this function exits the program
+/// when a TLS identity cannot be loaded.
+#[cfg(unix)]
+fn bind_early(
+    bindings: &mut Vec<Binding>,
+    fds: &mut Vec<(String, net::InheritedFd)>,
+    store: &mut tls::IdentityStore,
+) {
+    let runtime = rt::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap_or_else(|err| {
+            log::error!("Failed to start the tokio runtime: {}", err);
+            process::exit(1);
+        });
+    let _guard = runtime.enter();
+
+    bindings.retain(|Binding { address, tls, options, name }| {
+        if let Some(Tls { certificate, key, .. }) = tls {
+            if store.acceptor(certificate, key).is_err() {
+                process::exit(1);
+            }
+        }
+        let address_str = address.to_string();
+        if fds.iter().any(|(fd_name, _)| *fd_name == address_str || Some(fd_name) == name.as_ref()) {
+            return true;
+        }
+        match net::bind(address, options) {
+            Ok(fd) => {
+                fds.push((address_str, fd));
+                true
+            }
+            Err(err) => {
+                log::error!("Binding {} failed to come online: {}", address, err);
+                false
+            }
+        }
+    });
+}
+
 /// Reloads the configuration at `config_path`.
 ///
 /// In four steps:
 ///
 /// - Read the configuration and load the authentication provider,
 /// - Remove old bindings that are not used anymore,
-/// - Add new bindings, or send them a command to listen for raw TCP or TLS connections,
-/// - Update the shared state.
+/// - Add new bindings, or send them a command to listen for raw TCP or TLS connections.  Bindings
+///   whose TLS identity cannot be loaded keep running with their previous settings,
+/// - Update the shared state and the files being watched.
 async fn do_rehash(
     config_path: String,
     shared: &State,
-    stop: mpsc::Sender<SocketAddr>,
-    bindings: &mut Vec<(SocketAddr, mpsc::Sender<Command>)>,
+    stop: mpsc::Sender<Address>,
+    bindings: &mut Vec<RunningBinding>,
+    watcher: &mut watch::Watcher,
 ) {
     log::info!("Reloading configuration from {:?}", config_path);
     let shared_clone = shared.clone();
     let reloaded = task::spawn_blocking(|| reload_config(config_path, shared_clone, stop)).await;
-    let (cfg, new_bindings) = match reloaded {
-        Ok(Some(reloaded)) => reloaded,
-        _ => return,
+    let (cfg, new_bindings, failed) = match reloaded {
+        Ok(Ok(reloaded)) => reloaded,
+        Ok(Err(err)) => {
+            shared.rehash_failed(&err).await;
+            return;
+        }
+        Err(err) => {
+            log::error!("Failed to reload the configuration: {}", err);
+            shared.rehash_failed(&err.to_string()).await;
+            return;
+        }
     };
 
     let mut i = 0;
     while i < bindings.len() {
-        let old_address = bindings[i].0;
-        if new_bindings
-            .iter()
-            .all(|new_b| old_address != new_b.address)
+        let old_address = &bindings[i].address;
+        let kept = failed.iter().any(|(address, _)| address == old_address);
+        if !kept
+            && new_bindings
+                .iter()
+                .all(|new_b| *old_address != new_b.address)
         {
             bindings.swap_remove(i);
         } else {
@@ -168,14 +306,20 @@ async fn do_rehash(
     }
 
     for new_b in new_bindings {
-        if let Some(i) = bindings.iter().position(|old_b| old_b.0 == new_b.address) {
-            let res = bindings[i]
-                .1
+        let tls = new_b.acceptor.is_some();
+        if let Some(i) = bindings.iter().position(|old_b| old_b.address == new_b.address) {
+            bindings[i].tls = tls;
+            let handle = &bindings[i].handle;
+            let res = handle
                 .send(match new_b.acceptor {
                     Some(acceptor) => Command::UseTls(acceptor),
                     None => Command::UsePlain,
                 })
                 .await;
+            let res = match res {
+                Ok(()) => handle.send(Command::SetOptions(new_b.options)).await,
+                Err(err) => Err(err),
+            };
             if res.is_err() {
                 // Failure to send the command means either the binding task have dropped the
                 // command channel, or the binding task doesn't exist anymore.  Both possibilities
@@ -184,19 +328,56 @@ async fn do_rehash(
                 // new one on the runtime.
                 bindings.swap_remove(i);
                 tokio::spawn(new_b.future);
-                bindings.push((new_b.address, new_b.handle));
+                bindings.push(RunningBinding {
+                    address: new_b.address,
+                    tls,
+                    handle: new_b.handle,
+                });
             }
         } else {
             tokio::spawn(new_b.future);
-            bindings.push((new_b.address, new_b.handle));
+            bindings.push(RunningBinding {
+                address: new_b.address,
+                tls,
+                handle: new_b.handle,
+            });
         }
     }
 
+    for (address, err) in failed {
+        let notice = format!("Binding {} kept its previous settings: {}", address, err);
+        shared.server_notice(snomask::REHASH, notice).await;
+    }
+
+    watcher.configure(cfg.watch_config, cfg.sources);
     shared.rehash(cfg.state).await;
+    report_bindings(shared, bindings).await;
 
     log::info!("Configuration reloaded");
 }
 
+/// Writes the permanent channels to the state file, if one is configured.
+async fn save_channels(shared: &State) {
+    let (path, saved) = match shared.save_channels().await {
+        Some(saved) => saved,
+        None => return,
+    };
+    let res = task::spawn_blocking(move || {
+        let res = util::write_atomically(&path, &saved);
+        (path, res)
+    });
+    if let Ok((path, Err(err))) = res.await {
+        log::error!("Failed to write {:?}: {}", path, err);
+    }
+}
+
+/// Gives the list of listening addresses to the shared state, so that operators can see them.
+async fn report_bindings(shared: &State, bindings: &[RunningBinding]) {
+    shared
+        .set_bindings(bindings.iter().map(|b| (b.address.clone(), b.tls)).collect())
+        .await;
+}
+
 /// Re-read the configuration file and re-generate the bindings.
 ///
 /// See documentation of `reload_bindings` for how bindings are re-generated.
@@ -204,88 +385,138 @@ async fn do_rehash(
 /// This function will put the contents of the MOTD file into `Config.motd_file`, so that the
 /// shared state can use the field as-is, since it must not use blocking operations such as reading
 /// a file.
+///
+/// Returns why the configuration could not be read on failure.
 fn reload_config(
     config_path: String,
     shared: State,
-    stop: mpsc::Sender<SocketAddr>,
-) -> Option<(Config, Vec<LoadedBinding<impl Future<Output = ()>>>)> {
+    stop: mpsc::Sender<Address>,
+) -> Result<ReloadedConfig<impl Future<Output = ()>>, String> {
     let mut cfg = match Config::from_file(&config_path) {
         Ok(cfg) => cfg,
         Err(err) => {
             log::error!("Failed to read {:?}: {}", config_path, err);
-            return None;
+            return Err(err.to_string());
         }
     };
-    cfg.state.motd_file = match fs::read_to_string(&cfg.state.motd_file) {
+    read_motd(&mut cfg.state);
+    let (new_bindings, failed) = reload_bindings(&cfg.bindings, &shared, &stop);
+    Ok((cfg, new_bindings, failed))
+}
+
+/// Replaces the path of the MOTD file in `cfg.motd_file` by its contents.
+fn read_motd(cfg: &mut config::State) {
+    log::info!("Loading MOTD from {:?}", cfg.motd_file);
+    cfg.motd_file = match fs::read_to_string(&cfg.motd_file) {
         Ok(motd) => motd,
         Err(err) => {
-            log::warn!("Failed to read {:?}: {}", cfg.state.motd_file, err);
+            log::warn!("Failed to read {:?}: {}", cfg.motd_file, err);
             String::new()
         }
     };
-    let new_bindings = reload_bindings(&cfg.bindings, &shared, &stop);
-    Some((cfg, new_bindings))
 }
 
 /// Equivalent of `load_bindings` for when exiting the program is not acceptable.
 ///
 /// Instead of spawning the binding tasks on the runtime, this function returns them in an array.
 /// Also instead of exiting on failure, it continues its process.  Binding tasks that could not
-/// be generated are not returned.
+/// be generated are not returned, their addresses are returned along with the error instead.
 ///
 /// Otherwise both functions have the same behavior.
 fn reload_bindings(
     bindings: &[Binding],
     shared: &State,
-    stop: &mpsc::Sender<SocketAddr>,
-) -> Vec<LoadedBinding<impl Future<Output = ()>>> {
+    stop: &mpsc::Sender<Address>,
+) -> (Vec<LoadedBinding<impl Future<Output = ()>>>, Vec<(Address, String)>) {
     let mut res = Vec::with_capacity(bindings.len());
+    let mut failed = Vec::new();
     let mut store = tls::IdentityStore::default();
 
-    for Binding { address, tls } in bindings {
+    for Binding { address, tls, options, .. } in bindings {
         let (handle, commands) = mpsc::channel(8);
         if let Some(Tls { certificate, key, ..  }) = tls {
             let acceptor = match store.acceptor(certificate, key) {
                 Ok(acceptor) => acceptor,
-                Err(_) => continue,
+                Err(err) => {
+                    log::error!("Failed to load the TLS identity of {}: {}", address, err);
+                    failed.push((address.clone(), err.to_string()));
+                    continue;
+                }
             };
             let future = net::listen(
-                *address,
+                address.clone(),
+                None,
+                options.clone(),
                 shared.clone(),
                 Some(acceptor.clone()),
                 stop.clone(),
                 commands,
             );
             res.push(LoadedBinding {
-                address: *address,
+                address: address.clone(),
                 acceptor: Some(acceptor),
+                options: options.clone(),
                 handle,
                 future,
             });
         } else {
-            let future = net::listen(*address, shared.clone(), None, stop.clone(), commands);
+            let future = net::listen(
+                address.clone(),
+                None,
+                options.clone(),
+                shared.clone(),
+                None,
+                stop.clone(),
+                commands,
+            );
             res.push(LoadedBinding {
-                address: *address,
+                address: address.clone(),
                 acceptor: None,
+                options: options.clone(),
                 handle,
                 future,
             });
         }
     }
 
-    res
+    (res, failed)
 }
 
 pub fn load_config_and_run(config_path: String) {
-    let cfg = Config::from_file(&config_path).unwrap_or_else(|err| {
+    // Sockets must be taken before the runtime spawns its threads.
+    let mut handoff = upgrade::take();
+    // Upgrades execute ellidri from this path, resolved before a new version replaces the file
+    // and before the sandbox hides it.
+    let exe = env::current_exe();
+    let mut cfg = Config::from_file(&config_path).unwrap_or_else(|err| {
         log::error!("Failed to read {:?}: {}", config_path, err);
         process::exit(1);
     });
+    let sandbox = sandbox::Sandbox::new(&cfg);
+    read_motd(&mut cfg.state);
+    let mut fds = systemd::listen_fds();
+    if let Some(handoff) = &mut handoff {
+        fds.append(&mut handoff.listeners);
+    }
+    // Privileges are dropped before the runtime spawns its threads, see the `sandbox` module.
+    let mut store = None;
+    if sandbox.is_enabled() {
+        #[cfg(unix)]
+        bind_early(&mut cfg.bindings, &mut fds, store.get_or_insert_with(Default::default));
+        sandbox.apply(handoff.is_some());
+    }
     let runtime = create_runtime(cfg.workers);
-    runtime.block_on(run(config_path, cfg));
+    runtime.block_on(run(config_path, exe, cfg, fds, store, handoff));
 }
 
-pub async fn run(config_path: String, cfg: Config) {
+pub async fn run(
+    config_path: String,
+    exe: io::Result<PathBuf>,
+    cfg: Config,
+    fds: Vec<(String, net::InheritedFd)>,
+    store: Option<tls::IdentityStore>,
+    handoff: Option<upgrade::Handoff>,
+) {
     let signal_fail = |err| {
         log::error!("Cannot listen for signals to reload the configuration: {}", err);
         process::exit(1);
@@ -305,34 +536,243 @@ pub async fn run(config_path: String, cfg: Config) {
         windows::ctrl_break().unwrap_or_else(signal_fail)
     };
 
+    let mut upgrade_signals = UpgradeSignal::new().unwrap_or_else(|err| {
+        log::error!("Cannot listen for signals to upgrade: {}", err);
+        process::exit(1);
+    });
+
+    let mut shutdown_signals = ShutdownSignals::new().unwrap_or_else(|err| {
+        log::error!("Cannot listen for signals to shut down: {}", err);
+        process::exit(1);
+    });
+
     let (stop, mut failures) = mpsc::channel(8);
     let rehash = Arc::new(Notify::new());
 
-    let shared = State::new(cfg.state, rehash.clone()).await;
-    let mut bindings = load_bindings(cfg.bindings, &shared, &stop);
+    let upgrade = Arc::new(Notify::new());
+    let persist = Arc::new(Notify::new());
+    let mut watcher = watch::Watcher::new(rehash.clone());
+    watcher.configure(cfg.watch_config, cfg.sources);
+    let shared = State::new(cfg.state, rehash.clone(), upgrade.clone(), persist.clone()).await;
+    let mut bindings = load_bindings(cfg.bindings, fds, store, &shared, &stop);
+    report_bindings(&shared, &bindings).await;
+    if let Some(handoff) = handoff {
+        upgrade::resume(handoff, &shared).await;
+    }
+
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::watchdog(shared.clone(), interval));
+    }
+    systemd::notify(systemd::READY);
 
     loop {
         tokio::select! {
             addr = failures.recv() => match addr {
-                Some(addr) => for i in 0..bindings.len() {
-                    if bindings[i].0 == addr {
-                        bindings.swap_remove(i);
-                        break;
-                    }
+                Some(addr) => if let Some(i) = bindings.iter().position(|b| b.address == addr) {
+                    bindings.swap_remove(i);
+                    report_bindings(&shared, &bindings).await;
                 }
                 None => {
                     // `failures.recv()` returns `None` when all senders have been dropped, so
                     // when all bindings tasks have stopped.
                     log::error!("No binding left, exiting.");
+                    systemd::notify(systemd::STOPPING);
                     return;
                 }
             },
             _ = rehash.notified() => {
-                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings).await;
+                systemd::notify(systemd::RELOADING);
+                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings, &mut watcher).await;
+                systemd::notify(systemd::READY);
             },
             _ = signals.recv() => {
-                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings).await;
+                systemd::notify(systemd::RELOADING);
+                do_rehash(config_path.clone(), &shared, stop.clone(), &mut bindings, &mut watcher).await;
+                systemd::notify(systemd::READY);
+            },
+            _ = upgrade.notified() => {
+                do_upgrade(&config_path, &exe, &shared, &stop, &mut bindings).await;
+            },
+            _ = upgrade_signals.recv() => {
+                do_upgrade(&config_path, &exe, &shared, &stop, &mut bindings).await;
             },
+            _ = persist.notified() => save_channels(&shared).await,
+            _ = shutdown_signals.recv() => break,
+        }
+    }
+
+    log::info!("Shutting down");
+    systemd::notify(systemd::STOPPING);
+    drop(bindings);
+    drop(stop);
+
+    let shutdown = async {
+        // `failures.recv()` returns `None` once every binding task has stopped and dropped its
+        // `stop` sender.
+        while failures.recv().await.is_some() {}
+        shared.shutdown().await;
+        save_channels(&shared).await;
+        while net::open_connections() != 0 {
+            time::sleep(time::Duration::from_millis(50)).await;
+        }
+    };
+    let timeout = time::Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+
+    tokio::select! {
+        res = time::timeout(timeout, shutdown) => if res.is_err() {
+            log::warn!("{} connections did not close in time", net::open_connections());
+        },
+        _ = shutdown_signals.recv() => {
+            log::warn!("Received a second signal, exiting now");
+            process::exit(1);
+        },
+    }
+}
+
+/// Upgrades ellidri without disconnecting plain-text clients, by executing `exe`.
+///
+/// The configuration file and the TLS identities are read first, since the next process will need
+/// them and runs with the same privileges.  If ellidri fails to execute itself, it takes back the
+/// sockets and the state, and loads the bindings from this configuration.
+#[cfg(unix)]
+async fn do_upgrade(
+    config_path: &str,
+    exe: &io::Result<PathBuf>,
+    shared: &State,
+    stop: &mpsc::Sender<Address>,
+    bindings: &mut Vec<RunningBinding>,
+) {
+    let exe = match exe {
+        Ok(exe) => exe,
+        Err(err) => {
+            log::error!("Not upgrading, failed to find the executable of ellidri: {}", err);
+            return;
+        }
+    };
+    let cfg = match Config::from_file(config_path) {
+        Ok(cfg) => cfg,
+        Err(err) => {
+            log::error!("Not upgrading, failed to read {:?}: {}", config_path, err);
+            return;
+        }
+    };
+    let mut store: tls::IdentityStore = Default::default();
+    for Tls { certificate, key, .. } in cfg.bindings.iter().filter_map(|b| b.tls.as_ref()) {
+        if let Err(err) = store.acceptor(certificate.clone(), key.clone()) {
+            log::error!("Not upgrading, failed to load {:?} and {:?}: {}", certificate, key, err);
+            return;
+        }
+    }
+    log::info!("Upgrading");
+    systemd::notify(systemd::RELOADING);
+
+    let mut listeners = Vec::with_capacity(bindings.len());
+    for binding in bindings.drain(..) {
+        let (fd, listener) = oneshot::channel();
+        if binding.handle.send(Command::Upgrade(fd)).await.is_err() {
+            continue;
+        }
+        if let Ok(listener) = listener.await {
+            listeners.push((binding.address.to_string(), listener));
+        }
+    }
+
+    let mut handoff = upgrade::handoff(shared, listeners).await;
+    let err = task::block_in_place(|| upgrade::exec(&handoff, exe));
+    log::error!("Failed to upgrade: {}", err);
+
+    let fds = std::mem::take(&mut handoff.listeners);
+    *bindings = load_bindings(cfg.bindings, fds, Some(store), shared, stop);
+    report_bindings(shared, bindings).await;
+    upgrade::resume(handoff, shared).await;
+    systemd::notify(systemd::READY);
+}
+
+#[cfg(not(unix))]
+async fn do_upgrade(
+    _: &str,
+    _: &io::Result<PathBuf>,
+    _: &State,
+    _: &mpsc::Sender<Address>,
+    _: &mut Vec<RunningBinding>,
+) {
+    log::warn!("Upgrades are not supported on this platform");
+}
+
+/// The signal that asks ellidri to upgrade.
+struct UpgradeSignal {
+    #[cfg(unix)]
+    user_defined2: tokio::signal::unix::Signal,
+}
+
+impl UpgradeSignal {
+    #[cfg(unix)]
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix;
+
+        Ok(Self {
+            user_defined2: unix::signal(unix::SignalKind::user_defined2())?,
+        })
+    }
+
+    #[cfg(windows)]
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {})
+    }
+
+    /// Returns when SIGUSR2 is received.
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        self.user_defined2.recv().await;
+    }
+
+    /// Never returns, windows has no such signal.
+    #[cfg(windows)]
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// The signals that ask ellidri to shut down.
+struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+    #[cfg(windows)]
+    ctrl_c: tokio::signal::windows::CtrlC,
+}
+
+impl ShutdownSignals {
+    #[cfg(unix)]
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix;
+
+        Ok(Self {
+            terminate: unix::signal(unix::SignalKind::terminate())?,
+            interrupt: unix::signal(unix::SignalKind::interrupt())?,
+        })
+    }
+
+    #[cfg(windows)]
+    fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            ctrl_c: tokio::signal::windows::ctrl_c()?,
+        })
+    }
+
+    /// Returns when SIGTERM or SIGINT is received.
+    #[cfg(unix)]
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.terminate.recv() => {},
+            _ = self.interrupt.recv() => {},
         }
     }
+
+    /// Returns when Ctrl-C is received.
+    #[cfg(windows)]
+    async fn recv(&mut self) {
+        self.ctrl_c.recv().await;
+    }
 }
@@ -150,10 +150,6 @@ caps! {
 }
 
 impl Capabilities {
-    pub fn has_message_tags(&self) -> bool {
-        self.message_tags || self.server_time
-    }
-
     pub fn is_capable_of(&self, command: Command) -> bool {
         match command {
             Command::Authenticate => self.sasl,
@@ -14,18 +14,18 @@ impl<'a> Channel<'a> {
 }
 
 #[derive(Clone, Copy, Debug)]
-pub struct User<'a>(&'a str);
+pub struct User<'a>(&'a str, &'a [&'a str]);
 
 impl<'a> User<'a> {
-    pub fn new(modes: &'a str) -> Self {
-        Self(modes)
+    pub fn new(modes: &'a str, params: &'a [&'a str]) -> Self {
+        Self(modes, params)
     }
 
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = mode::Result<mode::UserChange>> + 'a {
-        mode::user_query(self.0)
+    pub fn iter(&self) -> impl Iterator<Item = mode::Result<mode::UserChange<'a>>> + 'a {
+        mode::user_query(self.0, self.1)
     }
 }
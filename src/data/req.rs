@@ -41,10 +41,33 @@ pub struct Kill<'a> {
     pub who: Nickname<'a>,
     pub reason: &'a str,
 }
+/// A server ban added by KLINE or DLINE.  The reason is empty when not given.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerBan<'a> {
+    pub mask: &'a str,
+    pub reason: &'a str,
+}
+/// The password is not kept, it is checked by `State::handle_message` before the request is
+/// handled, and must not end up in the logs.
 #[derive(Clone, Copy, Debug)]
 pub struct Oper<'a> {
     pub name: &'a str,
-    pub password: &'a str,
+}
+
+#[derive(Clone, Debug)]
+pub struct SaJoin<'a> {
+    pub who: Nickname<'a>,
+    pub to: JoinList<'a>,
+}
+#[derive(Clone, Copy, Debug)]
+pub struct SaNick<'a> {
+    pub who: Nickname<'a>,
+    pub nick: Nickname<'a>,
+}
+#[derive(Clone, Copy, Debug)]
+pub struct SaPart<'a> {
+    pub who: Nickname<'a>,
+    pub part: Part<'a>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -79,6 +102,8 @@ pub struct Kick<'a> {
 pub struct MessageAll<'a> {
     pub feedback: bool,
     pub command: Command,
+    /// The target of the message, of the form `$<server mask>`.
+    pub to: &'a str,
     pub content: Option<&'a str>,
 }
 #[derive(Clone, Copy, Debug)]
@@ -113,6 +138,7 @@ pub enum Request<'a> {
     Info,
     LUsers,
     Motd,
+    Stats(&'a str),
     Time,
     Version,
     WhoChannel(WhoChannel<'a>),
@@ -122,9 +148,21 @@ pub enum Request<'a> {
     WhoIs(Nickname<'a>),
 
     // IRCop restricted requests.
+    Dline(ServerBan<'a>),
+    GlobOps(&'a str),
     Kill(Kill<'a>),
+    Kline(ServerBan<'a>),
     Oper(Oper<'a>),
     Rehash,
+    SaJoin(SaJoin<'a>),
+    SaMode(ModeChannelSet<'a>),
+    SaNick(SaNick<'a>),
+    SaPart(SaPart<'a>),
+    SaTopic(TopicSet<'a>),
+    UnDline(&'a str),
+    UnKline(&'a str),
+    Upgrade,
+    WallOps(&'a str),
 
     // Requests about channel info.
     List(List<'a, ChannelName<'a>>),
@@ -180,6 +218,7 @@ impl<'a> Request<'a> {
             Command::Info => Self::Info,
             Command::LUsers => Self::LUsers,
             Command::Motd => Self::Motd,
+            Command::Stats => Self::Stats(msg.params[0]),
             Command::Time => Self::Time,
             Command::Version => Self::Version,
             Command::Who => {
@@ -201,17 +240,65 @@ impl<'a> Request<'a> {
                 Self::WhoIs(mask)
             }
 
+            Command::Dline => {
+                let mask = msg.params[0];
+                let reason = msg.params[1];
+                Self::Dline(ServerBan { mask, reason })
+            }
+            Command::GlobOps => Self::GlobOps(msg.params[0]),
             Command::Kill => {
                 let who = Nickname::try_from(msg.params[0])?;
                 let reason = msg.params[1];
                 Self::Kill(Kill { who, reason })
             }
+            Command::Kline => {
+                let mask = msg.params[0];
+                let reason = msg.params[1];
+                Self::Kline(ServerBan { mask, reason })
+            }
             Command::Oper => {
                 let name = msg.params[0];
-                let password = msg.params[1];
-                Self::Oper(Oper { name, password })
+                Self::Oper(Oper { name })
             }
             Command::Rehash => Self::Rehash,
+            Command::SaJoin => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let to = JoinList::new(msg.params[1], "");
+                Self::SaJoin(SaJoin { who, to })
+            }
+            Command::SaMode => {
+                let channel = ChannelName::try_from(msg.params[0])?;
+                let modes = modes::Channel::new(msg.params[1], &msg.params[2..msg.num_params]);
+                Self::SaMode(ModeChannelSet { channel, modes })
+            }
+            Command::SaNick => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let nick = Nickname::try_from(msg.params[1])
+                    .map_err(|_| Error::ErroneousNickname(msg.params[1]))?;
+                Self::SaNick(SaNick { who, nick })
+            }
+            Command::SaPart => {
+                let who = Nickname::try_from(msg.params[0])?;
+                let from = List::new(msg.params[1], ',');
+                let reason = if msg.params[2].is_empty() {
+                    None
+                } else {
+                    Some(msg.params[2])
+                };
+                Self::SaPart(SaPart {
+                    who,
+                    part: Part { from, reason },
+                })
+            }
+            Command::SaTopic => {
+                let channel = ChannelName::try_from(msg.params[0])?;
+                let topic = msg.params[1];
+                Self::SaTopic(TopicSet { channel, topic })
+            }
+            Command::UnDline => Self::UnDline(msg.params[0]),
+            Command::UnKline => Self::UnKline(msg.params[0]),
+            Command::Upgrade => Self::Upgrade,
+            Command::WallOps => Self::WallOps(msg.params[0]),
 
             Command::List => {
                 let channel_names = msg.params[0];
@@ -303,7 +390,7 @@ impl<'a> Request<'a> {
                     if n == 1 {
                         Self::ModeUserGet(user)
                     } else {
-                        let modes = modes::User::new(msg.params[1]);
+                        let modes = modes::User::new(msg.params[1], &msg.params[2..n]);
                         Self::ModeUserSet(ModeUserSet { user, modes })
                     }
                 }
@@ -357,10 +444,11 @@ impl<'a> Request<'a> {
                     Command::TagMsg => None,
                     _ => unreachable!(),
                 };
-                if msg.params[0] == "*" {
+                if msg.params[0].starts_with('$') {
                     Self::MessageAll(MessageAll {
                         feedback,
                         command,
+                        to: msg.params[0],
                         content,
                     })
                 } else if let Ok(to) = ChannelName::try_from(msg.params[0]) {
@@ -401,6 +489,7 @@ impl<'a> Request<'a> {
             Self::Info => 3,
             Self::LUsers => 3,
             Self::Motd => 3,
+            Self::Stats(_) => 4,
             Self::Time => 2,
             Self::Version => 2,
             Self::WhoChannel(_) => 5,
@@ -410,9 +499,21 @@ impl<'a> Request<'a> {
             Self::WhoIs(_) => 4,
 
             // IRCop restricted requests.
+            Self::Dline(_) => 16,
+            Self::GlobOps(_) => 16,
             Self::Kill(_) => 16,
+            Self::Kline(_) => 16,
             Self::Oper(_) => 16,
             Self::Rehash => 16,
+            Self::SaJoin(_) => 16,
+            Self::SaMode(_) => 16,
+            Self::SaNick(_) => 16,
+            Self::SaPart(_) => 16,
+            Self::SaTopic(_) => 16,
+            Self::UnDline(_) => 16,
+            Self::UnKline(_) => 16,
+            Self::Upgrade => 16,
+            Self::WallOps(_) => 24,
 
             // Requests about channel info.
             Self::List(_) => 4,
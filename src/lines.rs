@@ -23,6 +23,25 @@ where
 
 pub const REGISTRATION_TIMEOUT: &str = "Senpai is such a slowpoke... baka";
 
+pub const CLASS_FULL: &str = "Too many senpais from your class already, try again later!";
+
+pub const SENDQ_EXCEEDED: &str = "SendQ exceeded";
+
+pub const UPGRADE_RECONNECT: &str = "The server is upgrading, please reconnect senpai!";
+
+pub const UPGRADE_LOST: &str = "This senpai got lost during the upgrade...";
+
+pub const SHUTTING_DOWN: &str = "The server is going to sleep, see you later senpai!";
+
+pub const INTERNAL_ERROR: &str = "ellidri tripped over this message, sorry senpai...";
+
+#[macro_export]
+macro_rules! lines_ping_timeout {
+    ( $seconds:expr ) => {
+        format_args!("Ping timeout: {} seconds", $seconds)
+    };
+}
+
 //
 // IRC replies
 //
@@ -37,8 +56,22 @@ pub const UN_AWAY: &str = "Welcome back!";
 
 pub const BAD_CHAN_KEY: &str = "Whoops, guess you've entered the wrong channel key :s";
 
+pub const BAD_MASK: &str = "This mask doesn't match ellidri...";
+
+pub const BAN_ADDED: &str = "ellidri won't let them in anymore!";
+
+pub const BAN_EXISTS: &str = "This mask is already banned, senpai!";
+
+pub const BAN_REMOVED: &str = "ellidri will let them in again!";
+
 pub const BANNED_FROM_CHAN: &str = "They don't want you in here senpai...";
 
+pub const CANNOT_SEND_COLORS: &str = "Senpai, this channel doesn't like colors...";
+
+pub const CANNOT_SEND_CTCP: &str = "Senpai, this channel doesn't like CTCPs...";
+
+pub const CANNOT_SEND_NOTICE: &str = "Senpai, this channel doesn't like notices...";
+
 pub const CANNOT_SEND_TO_CHAN: &str = "They can't hear you from here senpai...";
 
 pub const CHAN_O_PRIVS_NEEDED: &str = "You need to ask a channel operator";
@@ -59,6 +92,8 @@ pub const END_OF_MOTD: &str = "End of MOTD";
 
 pub const END_OF_NAMES: &str = "End of names";
 
+pub const END_OF_STATS: &str = "End of STATS report";
+
 pub const END_OF_WHO: &str = "End of WHO list";
 
 pub const END_OF_WHOIS: &str = "End of WHOIS list";
@@ -72,6 +107,8 @@ pub const INVITE_ONLY_CHAN: &str = "They didn't invite you yet, keep trying~!";
 
 pub const KEY_SET: &str = "The channel key is already here, senpai!";
 
+pub const NEED_REGGED_NICK: &str = "Senpai, you need to log in to an account first!";
+
 pub const NEED_MORE_PARAMS: &str = "You are not telling me everything, are you?";
 
 pub const NICKNAME_IN_USE: &str = "Another senpai already took this nickname...";
@@ -80,10 +117,14 @@ pub const NO_MOTD: &str = "ellidri can't find the MOTD...";
 
 pub const NO_TOPIC: &str = "It seems this channel doesn't have any topic";
 
+pub const NO_OPER_HOST: &str = "ellidri doesn't know you, you can't be a BIG senpai from there!";
+
 pub const NO_PRIVILEDGES: &str = "Senpai, could you stop doing that? ellidri doesn't like it...";
 
 pub const NO_SUCH_NICK: &str = "I can't find this senpai...";
 
+pub const NO_SUCH_BAN: &str = "I can't find this ban, senpai...";
+
 pub const NO_SUCH_CHANNEL: &str = "I can't find this channel...";
 
 pub const NOT_ON_CHANNEL: &str = "Senpai... I can't do that if you're not on the channel!";
@@ -92,14 +133,22 @@ pub const NOT_REGISTERED: &str = "You must register first!";
 
 pub const PASSWORD_MISMATCH: &str = "Nope! Wrong password";
 
+pub const OPER_ONLY_CHAN: &str = "Only BIG senpais can go in there!";
+
 pub const PART_ALL: &str = "Baka!";
 
 pub const REHASHING: &str = "Oh~~!  Onwards to reload the configuration!";
 
+pub const UPGRADING: &str = "Hold on tight senpai, ellidri is upgrading!";
+
+pub const SECURE_ONLY_CHAN: &str = "Senpai, this channel is only for secure (TLS) connections!";
+
 pub const UNKNOWN_COMMAND: &str = "Hnn... What did you just say?";
 
 pub const UNKNOWN_MODE: &str = "This letter right here... what does it mean?";
 
+pub const SERVER_NOTICE_MASK: &str = "Server notice mask";
+
 pub const USER_NOT_IN_CHANNEL: &str = "This senpai isn't on the channel";
 
 pub const USERS_DONT_MATCH: &str = "Kyaaa! Peeking is bad senpai! Please don't do that again!";
@@ -110,6 +159,10 @@ pub const YOURE_OPER: &str = "You are now a BIG senpai!";
 
 pub const WHOIS_IDLE: &str = "Seconds since last activity, registration time";
 
+pub const WHOIS_HOST: &str = "is connecting from";
+
+pub const WHOIS_CLASS: &str = "is in connection class";
+
 //
 // Welcome messages
 //
@@ -130,6 +183,16 @@ macro_rules! lines_created {
     };
 }
 
+#[macro_export]
+macro_rules! lines_stats_uptime {
+    ( $days:expr, $hours:expr, $minutes:expr, $seconds:expr ) => {
+        format_args!(
+            "Server Up {} days {}:{:02}:{:02}",
+            $days, $hours, $minutes, $seconds
+        )
+    };
+}
+
 pub const LUSER_CHANNELS: &str = "channels created";
 
 #[macro_export]
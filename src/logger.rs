@@ -0,0 +1,122 @@
+//! Log setup.
+//!
+//! ellidri logs through the `log` crate and `env_logger`.  The `ELLIDRI_LOG` environment variable
+//! sets the filters, `ELLIDRI_LOG_STYLE` whether to use colors, and `ELLIDRI_LOG_FORMAT` the
+//! format of records:
+//!
+//! - `text` (the default):  `[LEVEL target] message`,
+//! - `json`:  one JSON object per line, with the `time`, `level`, `target` and `message` fields.
+//!
+//! JSON records emitted while the state handles a message from a client also carry the `conn`,
+//! `peer`, `nick` and `account` fields, which identify this client.  See `Context::enter`.
+
+use crate::{util, Client};
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, io};
+
+/// Whether records are formatted as JSON.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The client the records emitted on this thread are about.
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Installs the logger, according to the environment variables.
+pub fn init() {
+    let log_settings = env_logger::Env::new()
+        .filter_or("ELLIDRI_LOG", "ellidri=debug")
+        .write_style("ELLIDRI_LOG_STYLE");
+    let mut builder = env_logger::Builder::from_env(log_settings);
+
+    let format = env::var("ELLIDRI_LOG_FORMAT").unwrap_or_default();
+    if format == "json" {
+        JSON.store(true, Ordering::Relaxed);
+        builder.format(format_json);
+    } else {
+        builder.format(format_text);
+    }
+    builder.init();
+
+    if !format.is_empty() && format != "text" && format != "json" {
+        log::warn!("Unknown log format {:?}, using \"text\"", format);
+    }
+}
+
+fn format_text(buf: &mut env_logger::fmt::Formatter, r: &log::Record<'_>) -> io::Result<()> {
+    use std::io::Write;
+    writeln!(buf, "[{:<5} {}] {}", r.level(), r.target(), r.args())
+}
+
+fn format_json(buf: &mut env_logger::fmt::Formatter, r: &log::Record<'_>) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut line = String::with_capacity(128);
+    line.push_str("{\"time\":");
+    util::push_json_string(&mut line, &util::time_precise());
+    line.push_str(",\"level\":");
+    util::push_json_string(&mut line, r.level().as_str());
+    line.push_str(",\"target\":");
+    util::push_json_string(&mut line, r.target());
+    line.push_str(",\"message\":");
+    util::push_json_string(&mut line, &r.args().to_string());
+    CONTEXT.with(|context| {
+        if let Some(context) = &*context.borrow() {
+            line.push_str(",\"conn\":");
+            line.push_str(&context.id.to_string());
+            line.push_str(",\"peer\":");
+            match context.ip {
+                Some(ip) => util::push_json_string(&mut line, &ip.to_string()),
+                None => line.push_str("null"),
+            }
+            line.push_str(",\"nick\":");
+            util::push_json_string(&mut line, &context.nick);
+            line.push_str(",\"account\":");
+            match &context.account {
+                Some(account) => util::push_json_string(&mut line, account),
+                None => line.push_str("null"),
+            }
+        }
+    });
+    line.push('}');
+    writeln!(buf, "{}", line)
+}
+
+/// The client a log record is about.
+pub struct Context {
+    id: usize,
+    ip: Option<IpAddr>,
+    nick: String,
+    account: Option<String>,
+}
+
+impl Context {
+    /// Attaches the client `id` to the records emitted on this thread, until the returned guard
+    /// is dropped.  Does nothing unless records are formatted as JSON.
+    pub fn enter(id: usize, client: &Client) -> ContextGuard {
+        if !JSON.load(Ordering::Relaxed) {
+            return ContextGuard(None);
+        }
+        let context = Context {
+            id,
+            ip: client.ip(),
+            nick: client.nick().to_owned(),
+            account: client.account().map(str::to_owned),
+        };
+        ContextGuard(CONTEXT.with(|current| current.replace(Some(context))))
+    }
+}
+
+/// Restores the previous context when dropped.
+pub struct ContextGuard(Option<Context>);
+
+impl Drop for ContextGuard {
+    fn drop(&mut self) {
+        if JSON.load(Ordering::Relaxed) {
+            let previous = self.0.take();
+            CONTEXT.with(|current| current.replace(previous));
+        }
+    }
+}
@@ -16,6 +16,7 @@ use crate::config::Config;
 use crate::state::State;
 use std::{env, process};
 
+mod audit;
 mod channel;
 mod client;
 mod config;
@@ -23,48 +24,81 @@ mod control;
 mod data;
 #[macro_use]
 mod lines;
+mod logger;
 mod net;
+mod sandbox;
 mod state;
+mod systemd;
 mod tls;
+mod upgrade;
 mod util;
+mod watch;
 
 pub fn main() {
     if cfg!(debug_assertions) {
         env::set_var("RUST_BACKTRACE", "1");
     }
 
-    let log_settings = env_logger::Env::new()
-        .filter_or("ELLIDRI_LOG", "ellidri=debug")
-        .write_style("ELLIDRI_LOG_STYLE");
-    env_logger::Builder::from_env(log_settings)
-        .format(|buf, r| {
-            use std::io::Write;
-            writeln!(buf, "[{:<5} {}] {}", r.level(), r.target(), r.args())
-        })
-        .init();
+    logger::init();
 
-    let config_path = parse_args();
-    control::load_config_and_run(config_path);
+    let (config_path, action) = parse_args();
+    match action {
+        Action::Run => control::load_config_and_run(config_path),
+        Action::Check | Action::Dump => match Config::from_file(&config_path) {
+            Ok(cfg) if action == Action::Dump => print!("{}", cfg),
+            Ok(_) => println!("{}: OK", config_path),
+            Err(err @ config::Error::At(..)) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+            Err(err) => {
+                eprintln!("{}: {}", config_path, err);
+                process::exit(1);
+            }
+        },
+    }
+}
+
+/// What to do with the configuration file.
+#[derive(PartialEq)]
+enum Action {
+    /// Start the server.
+    Run,
+
+    /// Check the configuration file and exit.
+    Check,
+
+    /// Print the configuration, defaults included, and exit.
+    Dump,
 }
 
-fn parse_args() -> String {
+fn parse_args() -> (String, Action) {
     let mut args = env::args();
 
     let program = args.next().unwrap();
-
-    let config_path = args.next().unwrap_or_else(|| {
-        eprintln!("Usage: {} CONFIG_FILE", program);
+    let usage = || {
+        eprintln!("Usage: {} [--check-config | --dump-config] CONFIG_FILE", program);
         process::exit(1);
-    });
+    };
+
+    let mut config_path = args.next().unwrap_or_else(usage);
 
     if config_path == "-h" || config_path == "--help" {
         eprintln!("ellidri {}", env!("CARGO_PKG_VERSION"));
-        eprintln!("Usage: {} CONFIG_FILE", program);
-        process::exit(1);
+        usage();
     } else if config_path == "-v" || config_path == "--version" {
         eprintln!("ellidri {}", env!("CARGO_PKG_VERSION"));
         process::exit(1);
     }
 
-    config_path
+    let action = match config_path.as_str() {
+        "--check-config" => Action::Check,
+        "--dump-config" => Action::Dump,
+        _ => Action::Run,
+    };
+    if action != Action::Run {
+        config_path = args.next().unwrap_or_else(usage);
+    }
+
+    (config_path, action)
 }
@@ -1,33 +1,310 @@
-use crate::{control, lines, State, tls};
+use crate::{client, control, lines, sandbox, util, State, tls};
+use crate::config::{Address, SocketOptions};
 use ellidri_tokens::Message;
+use std::future::{self, Future};
 use std::net::SocketAddr;
-use std::str;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::Poll;
+use std::{fs, str};
 use tokio::sync::mpsc;
-use tokio::{io, net, sync, time};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tokio::{io, net, time};
+use tokio::io::{AsyncBufRead, AsyncWriteExt};
 
 #[cfg(feature = "tls")]
 const TLS_TIMEOUT_SECS: u64 = 30;
 const MAX_MESSAGE_LENGTH: u64 = 4096;
+const FLUSH_TIMEOUT_SECS: u64 = 5;
+const LISTEN_BACKLOG: u32 = 1024;
 
+/// Size of the buffer in which outgoing messages are gathered before being written to the
+/// connection.  This is the maximum size of a TLS record.
+const WRITE_BUFFER_SIZE: usize = 16384;
+
+/// A listening socket passed by the service manager.
+#[cfg(unix)]
+pub type InheritedFd = std::os::unix::io::OwnedFd;
+#[cfg(not(unix))]
+pub type InheritedFd = std::convert::Infallible;
+
+/// Number of connections that are still open, including those sending their last messages.
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the number of connections that are still open.
+///
+/// On shutdown, `control` waits for this to drop to zero so that clients receive their ERROR
+/// message before the runtime stops.
+pub fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::Acquire)
+}
+
+/// Counts a connection in `OPEN_CONNECTIONS` for as long as it lives.
+struct OpenConnection;
+
+impl OpenConnection {
+    fn new() -> Self {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::AcqRel);
+        Self
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Spawns the task of a connection.  The connection is counted in `OPEN_CONNECTIONS` from now on,
+/// so that it is waited for even when the task has not started yet.
+fn spawn_connection(task: impl Future<Output = ()> + Send + 'static) {
+    let open = OpenConnection::new();
+    tokio::spawn(async move {
+        task.await;
+        drop(open);
+    });
+}
+
+/// The socket of a plain-text connection.
+pub enum Socket {
+    Tcp(std::net::TcpStream),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixStream),
+}
+
+/// A plain-text connection taken out of its task, to be passed to the next process on upgrade.
+pub struct HandedOff {
+    /// The identifier of the client in the state, or `usize::MAX` when the connection has been
+    /// accepted during the upgrade.
+    pub id: usize,
+    pub peer: client::Peer,
+    pub socket: Socket,
+
+    /// Bytes received from the client that have not been handled yet.
+    pub unread: Vec<u8>,
+}
+
+/// A connection to a client.
+trait Connection: io::AsyncRead + io::AsyncWrite + Unpin + Send + 'static {
+    /// Returns the socket of a plain-text connection, so that it can be passed to the next process
+    /// on upgrade.  TLS connections cannot be passed and return `None`.
+    fn into_socket(self) -> Option<io::Result<Socket>>;
+}
+
+impl Connection for net::TcpStream {
+    fn into_socket(self) -> Option<io::Result<Socket>> {
+        Some(self.into_std().map(Socket::Tcp))
+    }
+}
+
+#[cfg(unix)]
+impl Connection for net::UnixStream {
+    fn into_socket(self) -> Option<io::Result<Socket>> {
+        Some(self.into_std().map(Socket::Unix))
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<S: Connection> Connection for tokio_rustls::server::TlsStream<S> {
+    fn into_socket(self) -> Option<io::Result<Socket>> {
+        None
+    }
+}
+
+/// The listening socket of a binding.
+enum Listener {
+    Tcp(net::TcpListener),
+    #[cfg(unix)]
+    Unix(net::UnixListener),
+}
+
+impl Listener {
+    fn bind(address: &Address, options: &SocketOptions) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(addr) => bind_tcp(*addr, options).map(Listener::Tcp),
+            #[cfg(unix)]
+            Address::Unix(path) => bind_unix(path, options).map(Listener::Unix),
+            #[cfg(not(unix))]
+            Address::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix domain sockets are not supported on this platform",
+            )),
+        }
+    }
+
+    /// Makes a listener out of a socket passed by the service manager.
+    #[cfg(unix)]
+    fn adopt(address: &Address, fd: InheritedFd) -> io::Result<Listener> {
+        match address {
+            Address::Tcp(_) => {
+                let ln = std::net::TcpListener::from(fd);
+                ln.local_addr()?;
+                ln.set_nonblocking(true)?;
+                net::TcpListener::from_std(ln).map(Listener::Tcp)
+            }
+            Address::Unix(_) => {
+                let ln = std::os::unix::net::UnixListener::from(fd);
+                ln.local_addr()?;
+                ln.set_nonblocking(true)?;
+                net::UnixListener::from_std(ln).map(Listener::Unix)
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn adopt(_: &Address, fd: InheritedFd) -> io::Result<Listener> {
+        match fd {}
+    }
+
+    /// Returns the listening socket, so that it can be passed to the next process on upgrade.
+    #[cfg(unix)]
+    fn into_fd(self) -> io::Result<InheritedFd> {
+        match self {
+            Listener::Tcp(ln) => ln.into_std().map(InheritedFd::from),
+            Listener::Unix(ln) => ln.into_std().map(InheritedFd::from),
+        }
+    }
+
+    /// Accepts a connection and spawns the task that handles it.
+    async fn accept(
+        &self,
+        address: &Address,
+        options: &SocketOptions,
+        acceptor: Option<&tls::Acceptor>,
+        shared: &State,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(ln) => {
+                let (conn, addr) = ln.accept().await?;
+                if let Err(err) = conn.set_nodelay(options.nodelay) {
+                    log::debug!("{}: Failed to set TCP_NODELAY: {}", addr, err);
+                }
+                handle_conn(conn, client::Peer::from(addr), acceptor, shared.clone());
+            }
+            #[cfg(unix)]
+            Listener::Unix(ln) => {
+                let (conn, _) = ln.accept().await?;
+                let peer = client::Peer::local(address, &options.host);
+                handle_conn(conn, peer, acceptor, shared.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Binds a listening socket on `address`, to be passed to `listen` later.  Must be called within a
+/// runtime.
+#[cfg(unix)]
+pub fn bind(address: &Address, options: &SocketOptions) -> io::Result<InheritedFd> {
+    Listener::bind(address, options)?.into_fd()
+}
+
+/// Binds a TCP listener on `addr`.
+///
+/// Socket buffer sizes are set on the listener, before `listen(2)`, so that accepted connections
+/// inherit them and TCP window scaling is negotiated accordingly.
+fn bind_tcp(addr: SocketAddr, options: &SocketOptions) -> io::Result<net::TcpListener> {
+    let socket = if addr.is_ipv4() {
+        net::TcpSocket::new_v4()?
+    } else {
+        net::TcpSocket::new_v6()?
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    if let Some(size) = options.send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = options.recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Binds a Unix domain socket listener at `path`, replacing the socket file left by a previous
+/// instance of ellidri.
+///
+/// The socket file is only removed when nothing accepts connections on it anymore.  Otherwise,
+/// this fails with `io::ErrorKind::AddrInUse`.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path, options: &SocketOptions) -> io::Result<net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt as _;
+
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        "another process listens on this socket",
+                    ));
+                }
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                    fs::remove_file(path)?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    let ln = net::UnixListener::bind(path)?;
+    set_permissions(path, options)?;
+    Ok(ln)
+}
+
+/// Applies the mode and the owner from `options` to the socket file at `path`.
+#[cfg(unix)]
+fn set_permissions(path: &std::path::Path, options: &SocketOptions) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    if let Some(mode) = options.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if options.owner.is_some() || options.group.is_some() {
+        std::os::unix::fs::chown(path, options.owner, options.group)?;
+    }
+    Ok(())
+}
 
 /// Returns a future that listens, accepts and handles incoming connections.
+///
+/// The binding uses the `inherited` socket when given, and binds its own otherwise.
 pub async fn listen(
-    addr: SocketAddr,
+    addr: Address,
+    inherited: Option<InheritedFd>,
+    mut options: SocketOptions,
     shared: State,
     mut acceptor: Option<tls::Acceptor>,
-    stop: mpsc::Sender<SocketAddr>,
+    stop: mpsc::Sender<Address>,
     mut commands: mpsc::Receiver<control::Command>,
 ) {
-    let ln = match net::TcpListener::bind(&addr).await {
+    let adopted = inherited.is_some();
+    let ln = match inherited {
+        Some(fd) => Listener::adopt(&addr, fd),
+        None => Listener::bind(&addr, &options),
+    };
+    let ln = match ln {
         Ok(ln) => ln,
         Err(err) => {
-            log::error!("Binding {} failed to come online: {}", addr, err);
+            let notice = if err.kind() == io::ErrorKind::PermissionDenied
+                && sandbox::privileges_dropped()
+            {
+                format!(
+                    "Binding {} failed to come online: {}, restart ellidri to bind it since it \
+                     has dropped its privileges",
+                    addr, err,
+                )
+            } else {
+                format!("Binding {} failed to come online: {}", addr, err)
+            };
+            log::error!("{}", notice);
+            shared.server_notice(ellidri_tokens::mode::snomask::REHASH, notice).await;
             let _ = stop.send(addr).await;
             return;
         }
     };
 
+    if adopted {
+        log::info!("Binding {} uses a socket opened before startup", addr);
+    }
     if acceptor.is_some() {
         log::info!("Binding {} online, accepting TLS connections", addr);
     } else {
@@ -36,12 +313,8 @@ pub async fn listen(
 
     loop {
         tokio::select! {
-            maybe_conn = ln.accept() => match maybe_conn {
-                Ok((conn, peer_addr)) => match acceptor.as_ref() {
-                    Some(a) => handle_tls(conn, peer_addr, shared.clone(), a.clone()),
-                    None => handle_tcp(conn, peer_addr, shared.clone()),
-                }
-                Err(err) => log::warn!("Binding {} failed to accept a connection: {}", addr, err),
+            res = ln.accept(&addr, &options, acceptor.as_ref(), &shared) => if let Err(err) = res {
+                log::warn!("Binding {} failed to accept a connection: {}", addr, err);
             },
             command = commands.recv() => match command {
                 Some(control::Command::UsePlain) => {
@@ -58,7 +331,45 @@ pub async fn listen(
                     }
                     acceptor = Some(a);
                 }
+                Some(control::Command::SetOptions(new_options)) => {
+                    if new_options.send_buffer != options.send_buffer
+                        || new_options.recv_buffer != options.recv_buffer
+                    {
+                        log::warn!("Binding {} must be restarted to change its buffer sizes", addr);
+                    }
+                    #[cfg(unix)]
+                    if let Address::Unix(path) = &addr {
+                        let owner = |o: &SocketOptions| (o.mode, o.owner, o.group);
+                        if owner(&new_options) != owner(&options) {
+                            if let Err(err) = set_permissions(path, &new_options) {
+                                log::warn!("Binding {} failed to set permissions: {}", addr, err);
+                            }
+                        }
+                    }
+                    options = SocketOptions {
+                        send_buffer: options.send_buffer,
+                        recv_buffer: options.recv_buffer,
+                        ..new_options
+                    };
+                }
+                #[cfg(unix)]
+                Some(control::Command::Upgrade(handoff)) => {
+                    match ln.into_fd() {
+                        Ok(fd) => {
+                            let _ = handoff.send(fd);
+                        }
+                        Err(err) => log::warn!("Binding {} failed to hand over: {}", addr, err),
+                    }
+                    log::info!("Binding {} handed over for the upgrade", addr);
+                    return;
+                }
                 None => {
+                    match &addr {
+                        Address::Unix(path) if !adopted => {
+                            let _ = fs::remove_file(path);
+                        }
+                        _ => {}
+                    }
                     log::info!("Binding {} now offline", addr);
                     return;
                 },
@@ -67,49 +378,71 @@ pub async fn listen(
     }
 }
 
-fn handle_tcp(conn: net::TcpStream, peer_addr: SocketAddr, shared: State) {
-    tokio::spawn(handle(conn, peer_addr, shared));
+/// Spawns the task that handles the connection `conn`, after a TLS handshake if `acceptor` is
+/// given.
+fn handle_conn<S>(conn: S, peer: client::Peer, acceptor: Option<&tls::Acceptor>, shared: State)
+where
+    S: Connection,
+{
+    match acceptor {
+        Some(acceptor) => handle_tls(conn, peer, shared, acceptor.clone()),
+        None => spawn_connection(handle(conn, peer, false, None, Vec::new(), shared)),
+    }
 }
 
 #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
-fn handle_tls(
-    conn: net::TcpStream,
-    peer_addr: SocketAddr,
-    shared: State,
-    acceptor: tls::Acceptor,
-) {
+fn handle_tls<S>(conn: S, peer: client::Peer, shared: State, acceptor: tls::Acceptor)
+where
+    S: Connection,
+{
     #[cfg(feature = "tls")]
-    tokio::spawn(async move {
+    spawn_connection(async move {
         let tls_handshake_timeout = time::Duration::from_secs(TLS_TIMEOUT_SECS);
         let tls_handshake = time::timeout(tls_handshake_timeout, acceptor.accept(conn));
         match tls_handshake.await {
-            Ok(Ok(tls_conn)) => handle(tls_conn, peer_addr, shared).await,
-            Ok(Err(err)) => log::warn!("TLS handshake with {} failed: {}", peer_addr, err),
-            Err(_) => log::warn!("TLS handshake with {} timed out", peer_addr),
+            Ok(Ok(tls_conn)) => {
+                let certfp = tls::fingerprint(&tls_conn);
+                handle(tls_conn, peer, true, certfp, Vec::new(), shared).await;
+            }
+            Ok(Err(err)) => {
+                log::warn!("TLS handshake with {} failed: {}", peer, err);
+                let notice = format!("TLS handshake with {} failed: {}", peer.host, err);
+                shared.server_notice(ellidri_tokens::mode::snomask::TLS, notice).await;
+            }
+            Err(_) => {
+                log::warn!("TLS handshake with {} timed out", peer);
+                let notice = format!("TLS handshake with {} timed out", peer.host);
+                shared.server_notice(ellidri_tokens::mode::snomask::TLS, notice).await;
+            }
         }
     });
 }
 
+/// Runs `$do` in a loop, and waits when the points it returns exceed the limits of the
+/// connection.  `$limits` is read at each iteration, since the class of the client may change.
+/// `$on_flood` is awaited when the connection starts to wait, but not again until it has stayed
+/// within its limits.
 macro_rules! rate_limit {
-    ( $rate:expr, $burst:expr, $do:expr ) => {{
-        let rate: u32 = $rate;
-        let burst: u32 = $burst;
+    ( $limits:expr, $do:expr, $on_flood:expr ) => {{
         let mut used_points: u32 = 0;
         let mut last_round = time::Instant::now();
+        let mut flooding = false;
 
         loop {
             used_points = match $do.await {
-                Ok(points) => used_points + points,
+                Ok(points) => used_points.saturating_add(points),
                 Err(err) => {
                     let res: io::Result<()> = Err(err);
                     break res;
                 }
             };
+            let rate: u32 = $limits.rate.load(Ordering::Relaxed).max(1);
+            let burst: u32 = $limits.burst.load(Ordering::Relaxed);
             if burst < used_points {
                 let elapsed = last_round.elapsed();
                 let millis = elapsed.as_millis();
-                let millis = if (std::u32::MAX as u128) < millis {
-                    std::u32::MAX
+                let millis = if (u32::MAX as u128) < millis {
+                    u32::MAX
                 } else {
                     millis as u32
                 };
@@ -118,47 +451,89 @@ macro_rules! rate_limit {
                 last_round += elapsed;
 
                 if burst < used_points {
+                    if !flooding {
+                        flooding = true;
+                        $on_flood.await;
+                    }
                     let wait_millis = (used_points - burst) * rate;
                     let wait = time::Duration::from_millis(wait_millis as u64);
                     time::sleep(wait).await;
                     used_points = burst;
                     last_round += wait;
+                } else {
+                    flooding = false;
                 }
+            } else {
+                flooding = false;
             }
         }
     }};
 }
 
 /// Returns a future that handles an IRC connection.
-async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer_addr: SocketAddr, shared: State) {
-    let (reader, mut writer) = io::split(conn);
+///
+/// `unread` holds bytes already received from the client, which are handled before the ones read
+/// from `conn`.
+async fn handle(
+    conn: impl Connection,
+    peer: client::Peer,
+    tls: bool,
+    certfp: Option<String>,
+    unread: Vec<u8>,
+    shared: State,
+) {
+    let (msg_queue, outgoing_msgs) = client::message_queue();
+    let peer_id = shared.peer_joined(&peer, tls, certfp, msg_queue).await;
+    tokio::spawn(login_timeout(peer_id, shared.clone()));
+    serve(conn, peer, peer_id, outgoing_msgs, unread, shared).await;
+}
+
+/// Returns a future that handles the connection of the client `peer_id`, once it has joined the
+/// state.
+async fn serve(
+    conn: impl Connection,
+    peer: client::Peer,
+    peer_id: usize,
+    mut outgoing_msgs: client::MessageQueueReceiver,
+    mut unread: Vec<u8>,
+    shared: State,
+) {
+    let (reader, writer) = io::split(conn);
     let mut reader = io::BufReader::new(reader);
+    let mut writer = io::BufWriter::with_capacity(WRITE_BUFFER_SIZE, writer);
 
-    let (msg_queue, mut outgoing_msgs) = sync::mpsc::unbounded_channel();
-    let peer_id = shared.peer_joined(peer_addr, msg_queue).await;
-    tokio::spawn(login_timeout(peer_id, shared.clone()));
+    let traffic = outgoing_msgs.traffic();
+    let limits = outgoing_msgs.limits();
 
     let incoming = async {
-        let mut buf = String::new();
-        rate_limit!(125, 32, async {
-            buf.clear();
-            let n = (&mut reader).take(MAX_MESSAGE_LENGTH).read_line(&mut buf).await?;
+        rate_limit!(limits, async {
+            let n = read_line(&mut reader, &mut unread).await?;
             if n == 0 {
                 return Err(io::Error::new(
                     io::ErrorKind::UnexpectedEof,
                     lines::CONNECTION_RESET,
                 ));
             }
-            log::trace!("{} >> {}", peer_addr, buf.trim());
-            Ok(handle_buffer(peer_id, &buf, &shared).await)
-        })
+            let buf = str::from_utf8(&unread[..n]).map_err(|_| io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            ))?;
+            log::trace!("{} >> {}", peer, buf.trim());
+            traffic.record_received(n);
+            match handle_buffer(peer_id, buf, &shared).await {
+                Some(points) => {
+                    unread.drain(..n);
+                    Ok(points)
+                }
+                // The client has been removed, keep the line in case it is handed over.
+                None => future::pending().await,
+            }
+        }, shared.peer_flooding(peer_id))
     };
 
     let outgoing = async {
-        use io::AsyncWriteExt as _;
-
         while let Some(msg) = outgoing_msgs.recv().await {
-            writer.write_all(msg.as_ref().as_bytes()).await?;
+            write_ready(&mut writer, msg, &mut outgoing_msgs).await?;
         }
         Ok(())
     };
@@ -167,20 +542,196 @@ async fn handle(conn: impl io::AsyncRead + io::AsyncWrite, peer_addr: SocketAddr
     tokio::select! {
         r = incoming => res = r.err(),
         r = outgoing => res = r.err(),
+        err = keepalive(peer_id, &traffic, &limits, &shared) => res = Some(err),
+        _ = limits.sendq_exceeded.notified() => {
+            shared.peer_exceeded_sendq(peer_id).await;
+            return;
+        }
+    }
+
+    if res.is_none() {
+        // The client has been removed from the state and all its messages have been written.  If
+        // it is because of an upgrade, give the connection to the next process.
+        if let Some(handoff) = shared.handoff_sender().await {
+            unread.extend_from_slice(reader.buffer());
+            let conn = reader.into_inner().unsplit(writer.into_inner());
+            match conn.into_socket() {
+                Some(Ok(socket)) => {
+                    let _ = handoff.send(HandedOff { id: peer_id, peer, socket, unread });
+                }
+                Some(Err(err)) => log::warn!("{}: Failed to hand the connection over: {}", peer, err),
+                None => {}
+            }
+            return;
+        }
+    }
+
+    // Otherwise, when `res` is `None`, the client has already been removed and its identifier may
+    // have been given to a new connection since.
+    if res.is_some() {
+        shared.peer_quit(peer_id, res).await;
+    }
+
+    // Try to send the last messages, like the ERROR message, before closing the connection.
+    let flush = async {
+        while let Some(msg) = outgoing_msgs.recv().await {
+            write_ready(&mut writer, msg, &mut outgoing_msgs).await?;
+        }
+        io::Result::Ok(())
+    };
+    let _ = time::timeout(time::Duration::from_secs(FLUSH_TIMEOUT_SECS), flush).await;
+}
+
+/// Spawns the task that handles a connection passed by the previous process on upgrade.
+///
+/// `restored` is the identifier and the message queue of the client, when it has been restored in
+/// the state.  Otherwise, the connection is handled as a new one.
+pub fn resume(
+    conn: HandedOff,
+    restored: Option<(usize, client::MessageQueueReceiver)>,
+    shared: State,
+) -> io::Result<()> {
+    let HandedOff { peer, socket, unread, .. } = conn;
+    match socket {
+        Socket::Tcp(conn) => {
+            conn.set_nonblocking(true)?;
+            resume_conn(net::TcpStream::from_std(conn)?, peer, restored, unread, shared);
+        }
+        #[cfg(unix)]
+        Socket::Unix(conn) => {
+            conn.set_nonblocking(true)?;
+            resume_conn(net::UnixStream::from_std(conn)?, peer, restored, unread, shared);
+        }
+    }
+    Ok(())
+}
+
+fn resume_conn(
+    conn: impl Connection,
+    peer: client::Peer,
+    restored: Option<(usize, client::MessageQueueReceiver)>,
+    unread: Vec<u8>,
+    shared: State,
+) {
+    match restored {
+        Some((peer_id, queue)) => {
+            tokio::spawn(login_timeout(peer_id, shared.clone()));
+            spawn_connection(serve(conn, peer, peer_id, queue, unread, shared));
+        }
+        None => spawn_connection(handle(conn, peer, false, None, unread, shared)),
+    }
+}
+
+/// Makes sure `buf` starts with a whole line, reading from `reader` if needed, and returns the
+/// length of this line.  Lines longer than `MAX_MESSAGE_LENGTH` are cut.
+///
+/// Returns 0 at the end of the stream.  Read bytes are moved to `buf` right away, so that nothing
+/// is lost when the returned future is dropped.
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin), buf: &mut Vec<u8>) -> io::Result<usize> {
+    let max = MAX_MESSAGE_LENGTH as usize;
+    loop {
+        if let Some(i) = buf.iter().take(max).position(|b| *b == b'\n') {
+            return Ok(i + 1);
+        }
+        if max <= buf.len() {
+            return Ok(max);
+        }
+        let n = future::poll_fn(|cx| {
+            let mut reader = Pin::new(&mut *reader);
+            let available = match reader.as_mut().poll_fill_buf(cx) {
+                Poll::Ready(Ok(available)) => available,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let n = available.len();
+            buf.extend_from_slice(available);
+            reader.consume(n);
+            Poll::Ready(Ok(n))
+        })
+        .await?;
+        if n == 0 {
+            return Ok(buf.len());
+        }
+    }
+}
+
+/// Writes `msg` and all the messages that are ready in the queue, then flushes them at once.
+///
+/// This way, a client that receives many messages at the same time (e.g. from a busy channel) costs
+/// one write per batch instead of one write per message.
+async fn write_ready<W>(
+    writer: &mut io::BufWriter<W>,
+    msg: client::MessageQueueItem,
+    queue: &mut client::MessageQueueReceiver,
+) -> io::Result<()>
+where
+    W: io::AsyncWrite + Unpin,
+{
+    let mut messages = 1;
+    let mut bytes = msg.as_ref().len();
+    writer.write_all(msg.as_ref().as_bytes()).await?;
+    while let Some(msg) = queue.recv_ready().await {
+        messages += 1;
+        bytes += msg.as_ref().len();
+        writer.write_all(msg.as_ref().as_bytes()).await?;
     }
+    writer.flush().await?;
+    queue.traffic().record_sent(messages, bytes);
+    Ok(())
+}
+
+/// Sends a PING to the client after `ping_freq` seconds of inactivity, and returns an error when
+/// the client stays silent for `ping_freq` more seconds.
+async fn keepalive(
+    peer_id: usize,
+    traffic: &client::Traffic,
+    limits: &client::Limits,
+    shared: &State,
+) -> io::Error {
+    let mut ping_sent_at: Option<u64> = None;
+
+    loop {
+        let ping_freq = limits.ping_freq.load(Ordering::Relaxed).max(1);
+        let last_received = traffic.last_received.load(Ordering::Relaxed);
+        let now = util::time();
 
-    shared.peer_quit(peer_id, res).await;
+        let wait = match ping_sent_at {
+            Some(sent_at) if last_received < sent_at => {
+                if sent_at + ping_freq <= now {
+                    let silence = now.saturating_sub(last_received);
+                    return io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        lines_ping_timeout!(silence).to_string(),
+                    );
+                }
+                sent_at + ping_freq - now
+            }
+            _ => {
+                ping_sent_at = None;
+                let idle = now.saturating_sub(last_received);
+                if ping_freq <= idle {
+                    shared.send_ping(peer_id).await;
+                    ping_sent_at = Some(now);
+                    ping_freq
+                } else {
+                    ping_freq - idle
+                }
+            }
+        };
+
+        time::sleep(time::Duration::from_secs(wait)).await;
+    }
 }
 
 /// Handle a line from the client.
 ///
 /// Returns `None` if the connection must be closed, `Some(points)` otherwise.  Points are used for
 /// rate limits.
-async fn handle_buffer(peer_id: usize, buf: &str, shared: &State) -> u32 {
+async fn handle_buffer(peer_id: usize, buf: &str, shared: &State) -> Option<u32> {
     if let Some(msg) = Message::parse(buf) {
         return shared.handle_message(peer_id, msg).await;
     }
-    1
+    Some(1)
 }
 
 async fn login_timeout(peer_id: usize, shared: State) {
@@ -188,3 +739,51 @@ async fn login_timeout(peer_id: usize, shared: State) {
     time::sleep(time::Duration::from_millis(timeout)).await;
     shared.remove_if_unregistered(peer_id).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::test::*;
+    use ellidri_tokens::Command;
+
+    #[tokio::test]
+    async fn test_keepalive_timeout() {
+        let state = state_with(simple_config()).await;
+        let (id, mut queue) = add_registered_client(&state, "alice").await;
+        let traffic = queue.traffic();
+        let limits = queue.limits();
+        limits.ping_freq.store(1, Ordering::Relaxed);
+        traffic.last_received.store(util::time() - 10, Ordering::Relaxed);
+
+        let err = keepalive(id, &traffic, &limits, &state).await;
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(err.to_string().starts_with("Ping timeout: "));
+
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert_msgs(&res, &[(None, Ok(Command::Ping), &["ellidri.test"])]);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_flood() {
+        let limits = client::Limits::default();
+        limits.rate.store(50, Ordering::Relaxed);
+        limits.burst.store(2, Ordering::Relaxed);
+
+        // The connection floods after the third message, and again once it has been quiet.
+        let mut points = vec![1, 1, 1, 1, 1, 0, 5, 1].into_iter();
+        let floods = std::cell::Cell::new(0);
+        let res = rate_limit!(
+            limits,
+            async {
+                match points.next() {
+                    Some(points) => Ok(points),
+                    None => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                }
+            },
+            async { floods.set(floods.get() + 1) }
+        );
+        assert_eq!(res.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(floods.get(), 2);
+    }
+}
@@ -0,0 +1,292 @@
+//! Dropping privileges and restricting the access to the filesystem.
+//!
+//! ellidri may need to start as root to bind privileged ports or to read TLS keys.  When `user`,
+//! `group`, `chroot` or `landlock` is set, the sockets of the bindings are bound and the TLS
+//! identities are loaded before the runtime starts (see `control::load_config_and_run`), and then:
+//!
+//! - a Landlock ruleset is built (on Linux only), that allows reading the configuration, the MOTD,
+//!   the TLS certificates and keys, writing the state file, the audit log and the files of
+//!   upgrades, and executing ellidri again on upgrade,
+//! - the root directory is changed to `chroot`,
+//! - the group ID and the user ID are changed to `group` and `user`,
+//! - the Landlock ruleset is enforced.
+//!
+//! This happens before the runtime starts its threads, because Landlock only restricts the thread
+//! that enforces it and the threads it creates afterwards.  Files that are only read are allowed
+//! one by one.  Landlock rules follow inodes, so a configuration file, MOTD, certificate or key
+//! that is replaced instead of rewritten in place cannot be read until the next restart.  This
+//! happens with some editors, and with certbot, which points its symbolic links to new files on
+//! renewal; on REHASH, bindings whose identity cannot be read keep the old one.  The state file
+//! and the audit log are allowed through their directories instead, because the state file is
+//! written to a temporary file that is renamed over the old one, and the audit log is rotated.
+//! Rules are built before the root directory changes, from the paths as seen from `chroot`, so
+//! that they can be opened whatever the permissions of the directories above `chroot`.  ellidri
+//! exits when a path of the configuration is missing.  When the kernel does not support Landlock,
+//! a warning is logged and ellidri runs without it.
+//!
+//! Privileges cannot be regained: bindings added on REHASH cannot listen on privileged ports, and
+//! files outside of the allowed paths cannot be read.  Changing these settings needs a
+//! restart.  The process started on upgrade inherits the root directory, the IDs and the Landlock
+//! restrictions of the previous one, so it does not change the root directory again.
+
+use crate::{util, Config};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, io, path, process};
+
+/// Whether the user ID has been changed, see `privileges_dropped`.
+static PRIVILEGES_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// The system libraries, which are needed to execute ellidri again on upgrade.
+#[cfg(target_os = "linux")]
+const SYSTEM_LIBRARIES: [&str; 5] = [
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+    "/etc/ld.so.cache",
+];
+
+/// Returns whether ellidri has switched to the configured user.  Binding privileged ports fails
+/// from then on.
+pub fn privileges_dropped() -> bool {
+    PRIVILEGES_DROPPED.load(Ordering::Relaxed)
+}
+
+/// What Landlock allows to do with a path.
+#[derive(Clone, Copy, Debug)]
+enum Access {
+    Read,
+
+    /// Read, create, write and remove files.
+    Write,
+
+    /// Read and execute files.
+    Execute,
+}
+
+/// A path allowed by Landlock.
+struct Rule {
+    path: path::PathBuf,
+    access: Access,
+
+    /// Whether the rule applies to the directory of `path`, and to the directory of the file it
+    /// links to, instead of `path` itself.
+    directory: bool,
+
+    /// Whether `path` may be missing.  Only the paths of the configuration must exist, system
+    /// files differ between distributions and may be missing from `chroot`, which only breaks
+    /// upgrades.
+    optional: bool,
+}
+
+/// The settings of the sandbox, see the module documentation.
+pub struct Sandbox {
+    user: Option<u32>,
+    group: Option<u32>,
+    chroot: String,
+    landlock: bool,
+    rules: Vec<Rule>,
+}
+
+impl Sandbox {
+    /// Gathers the settings and the paths of `cfg`.  Must be called before `motd_file` is replaced
+    /// by the contents of the MOTD.
+    pub fn new(cfg: &Config) -> Self {
+        let mut rules = Vec::new();
+        let mut add = |path: path::PathBuf, access, directory, optional| {
+            if !path.as_os_str().is_empty() {
+                rules.push(Rule {
+                    path,
+                    access,
+                    directory,
+                    optional,
+                });
+            }
+        };
+
+        for source in &cfg.sources {
+            add(source.clone(), Access::Read, false, false);
+        }
+        add(
+            cfg.state.motd_file.clone().into(),
+            Access::Read,
+            false,
+            false,
+        );
+        for tls in cfg
+            .bindings
+            .iter()
+            .filter_map(|binding| binding.tls.as_ref())
+        {
+            add(tls.certificate.clone(), Access::Read, false, false);
+            add(tls.key.clone(), Access::Read, false, false);
+        }
+        for database in &util::USER_DATABASES {
+            add(database.into(), Access::Read, false, true);
+        }
+        add(
+            cfg.state.state_file.clone().into(),
+            Access::Write,
+            true,
+            false,
+        );
+        add(
+            cfg.state.audit_log.clone().into(),
+            Access::Write,
+            true,
+            false,
+        );
+        // Upgrades write the saved state to `ellidri-upgrade.<pid>` in the temporary directory,
+        // for the new process to read and remove it.  See `upgrade::exec` and `upgrade::take`.
+        add(env::temp_dir(), Access::Write, false, true);
+        if let Ok(exe) = env::current_exe() {
+            add(exe, Access::Execute, true, true);
+        }
+        #[cfg(target_os = "linux")]
+        for library in &SYSTEM_LIBRARIES {
+            add(library.into(), Access::Execute, false, true);
+        }
+
+        Self {
+            user: cfg.user,
+            group: cfg.group,
+            chroot: cfg.chroot.clone(),
+            landlock: cfg.landlock,
+            rules,
+        }
+    }
+
+    /// Whether any of `user`, `group`, `chroot` or `landlock` is set.
+    pub fn is_enabled(&self) -> bool {
+        self.user.is_some() || self.group.is_some() || !self.chroot.is_empty() || self.landlock
+    }
+
+    /// Drops privileges and restricts the access to the filesystem.  Exits the program on
+    /// failure.
+    ///
+    /// `upgraded` is whether this process has been started by the previous one on upgrade.
+    #[cfg(unix)]
+    pub fn apply(self, upgraded: bool) {
+        let fail = |message: String| -> ! {
+            log::error!("{}", message);
+            process::exit(1);
+        };
+
+        // The process started on upgrade already runs in `chroot`.
+        let root = Some(path::Path::new(&self.chroot))
+            .filter(|root| !root.as_os_str().is_empty() && !upgraded);
+
+        #[cfg(target_os = "linux")]
+        let ruleset = if self.landlock {
+            self.landlock_ruleset(root).unwrap_or_else(|err| fail(err))
+        } else {
+            None
+        };
+        #[cfg(not(target_os = "linux"))]
+        if self.landlock {
+            log::warn!("'landlock' is only supported on Linux");
+        }
+
+        if let Some(root) = root {
+            if let Err(err) = ellidri_sys::chroot(root) {
+                fail(format!(
+                    "Failed to change the root directory to {:?}: {}",
+                    root, err
+                ));
+            }
+            log::info!("Changed the root directory to {:?}", root);
+        }
+
+        let (uid, gid) = ellidri_sys::ids();
+        if let Some(group) = self.group.filter(|group| *group != gid) {
+            if let Err(err) = ellidri_sys::set_group(group) {
+                fail(format!("Failed to switch to group {}: {}", group, err));
+            }
+            log::info!("Switched to group {}", group);
+        }
+        if let Some(user) = self.user {
+            if user != uid {
+                if let Err(err) = ellidri_sys::set_user(user) {
+                    fail(format!("Failed to switch to user {}: {}", user, err));
+                }
+                log::info!("Switched to user {}", user);
+            }
+            PRIVILEGES_DROPPED.store(user != 0, Ordering::Relaxed);
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Some(ruleset) = ruleset {
+            if let Err(err) = ruleset.restrict_self() {
+                fail(format!("Failed to enforce the Landlock ruleset: {}", err));
+            }
+            log::info!("Restricted the access to the filesystem with Landlock");
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(self, _upgraded: bool) {
+        log::warn!("'user', 'group', 'chroot' and 'landlock' are not supported on this platform");
+    }
+
+    /// Builds the Landlock ruleset from the rules, whose paths are resolved from `root` when the
+    /// root directory is about to change.  Returns `None` when the kernel does not support
+    /// Landlock, and fails when a path cannot be allowed.
+    #[cfg(target_os = "linux")]
+    fn landlock_ruleset(
+        &self,
+        root: Option<&path::Path>,
+    ) -> Result<Option<ellidri_sys::Landlock>, String> {
+        use ellidri_sys::landlock;
+
+        let ruleset = match ellidri_sys::Landlock::new() {
+            Ok(ruleset) => ruleset,
+            Err(err) => {
+                log::warn!(
+                    "Cannot use Landlock, the access to the filesystem is not restricted: {}",
+                    err
+                );
+                return Ok(None);
+            }
+        };
+        for rule in &self.rules {
+            let read = landlock::READ_FILE | landlock::READ_DIR;
+            let access = match rule.access {
+                Access::Read => read,
+                Access::Write => {
+                    read | landlock::WRITE_FILE | landlock::MAKE_REG | landlock::REMOVE_FILE
+                }
+                Access::Execute => read | landlock::EXECUTE,
+            };
+            // After chroot(2) the working directory is the new root, so relative paths are
+            // resolved from it as well.
+            let rule_path = match root {
+                Some(root) => root.join(rule.path.strip_prefix("/").unwrap_or(&rule.path)),
+                None => rule.path.clone(),
+            };
+            let mut paths = vec![rule_path.clone()];
+            if rule.directory {
+                paths.extend(std::fs::canonicalize(&rule_path));
+                for path in &mut paths {
+                    *path = match path.parent() {
+                        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+                        _ => path::PathBuf::from("."),
+                    };
+                }
+                paths.dedup();
+            }
+            for path in paths {
+                match ruleset.allow(&path, access) {
+                    Ok(()) => log::debug!("Landlock allows {:?} access to {:?}", rule.access, path),
+                    Err(err) if rule.optional && err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => {
+                        return Err(format!(
+                            "Landlock cannot allow access to {:?}: {}",
+                            path, err
+                        ));
+                    }
+                }
+            }
+        }
+        Ok(Some(ruleset))
+    }
+}
@@ -0,0 +1,407 @@
+//! Server bans.
+//!
+//! K-lines refuse the registration of users whose `user@host` matches their mask, and D-lines
+//! close the connections that come from an IP address that matches their mask.  They are read
+//! from the `kline` and `dline` directives and replaced on rehash.  Operators with the "kline"
+//! privilege can add more with KLINE and DLINE, which are kept across rehashes.  They are all
+//! listed by STATS k and d.
+
+use super::{CommandContext, HandlerResult as Result};
+use crate::client::Client;
+use crate::config::{Ban, Privilege};
+use crate::{data, lines, util};
+use ellidri_tokens::mode::snomask;
+use ellidri_tokens::{rpl, Command};
+
+/// A list of server bans.
+pub struct Bans {
+    configured: Vec<Ban>,
+    /// Bans added with KLINE or DLINE.
+    added: Vec<Ban>,
+}
+
+impl Bans {
+    pub fn new(configured: Vec<Ban>) -> Self {
+        Self {
+            configured,
+            added: Vec::new(),
+        }
+    }
+
+    /// Replaces the bans read from the configuration.
+    pub fn configure(&mut self, configured: Vec<Ban>) {
+        self.configured = configured;
+    }
+
+    /// The bans read from the configuration.
+    pub fn configured(&self) -> &[Ban] {
+        &self.configured
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.configured.iter().chain(&self.added)
+    }
+
+    /// Returns the first ban whose mask matches `s`.
+    pub fn find(&self, s: &str) -> Option<&Ban> {
+        self.iter().find(|ban| util::match_mask(&ban.mask, s))
+    }
+
+    /// Adds a ban, unless there is already one with the same mask.  Returns whether it has been
+    /// added.
+    pub fn add(&mut self, ban: Ban) -> bool {
+        if self.iter().any(|b| b.mask.eq_ignore_ascii_case(&ban.mask)) {
+            return false;
+        }
+        self.added.push(ban);
+        true
+    }
+
+    /// Removes the added ban of the given mask.  Bans read from the configuration are not
+    /// removed.
+    pub fn remove(&mut self, mask: &str) -> Option<Ban> {
+        let i = self
+            .added
+            .iter()
+            .position(|b| b.mask.eq_ignore_ascii_case(mask))?;
+        Some(self.added.remove(i))
+    }
+}
+
+/// The two kinds of server bans.
+#[derive(Clone, Copy)]
+enum Kind {
+    Kline,
+    Dline,
+}
+
+impl Kind {
+    fn command(self) -> &'static str {
+        match self {
+            Kind::Kline => "KLINE",
+            Kind::Dline => "DLINE",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::Kline => "K-line",
+            Kind::Dline => "D-line",
+        }
+    }
+
+    /// Whether `ban` applies to `client`.
+    fn matches(self, ban: &Ban, client: &Client) -> bool {
+        match self {
+            Kind::Kline => {
+                let user_host = format!("{}@{}", client.user(), client.host());
+                client.is_registered() && util::match_mask(&ban.mask, &user_host)
+            }
+            Kind::Dline => match client.ip() {
+                Some(ip) => util::match_mask(&ban.mask, &ip.to_string()),
+                None => false,
+            },
+        }
+    }
+}
+
+impl super::StateInner {
+    /// Returns the K-line matching the `user@host` of the given client, if any.
+    pub(super) fn find_kline(&self, id: usize) -> Option<Ban> {
+        let client = &self.clients[id];
+        let user_host = format!("{}@{}", client.user(), client.host());
+        self.klines.find(&user_host).cloned()
+    }
+
+    fn bans_mut(&mut self, kind: Kind) -> &mut Bans {
+        match kind {
+            Kind::Kline => &mut self.klines,
+            Kind::Dline => &mut self.dlines,
+        }
+    }
+
+    fn check_kline(&self, ctx: &mut CommandContext<'_>) -> Result {
+        if self.clients[ctx.id].has_privilege(Privilege::Kline) {
+            Ok(())
+        } else {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            Err(())
+        }
+    }
+
+    /// Adds a ban, and disconnects the clients it applies to.
+    fn add_ban(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        kind: Kind,
+        args: data::req::ServerBan<'_>,
+    ) -> Result {
+        self.check_kline(&mut ctx)?;
+        let reason = if args.reason.is_empty() {
+            "Banned"
+        } else {
+            args.reason
+        };
+        let ban = Ban {
+            mask: args.mask.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if !self.bans_mut(kind).add(ban.clone()) {
+            self.audit(ctx.id, kind.command(), args.mask, false, "already banned");
+            ctx.rb
+                .reply(Command::Notice)
+                .trailing_param(lines::BAN_EXISTS);
+            return Err(());
+        }
+
+        let issuer = &self.clients[ctx.id];
+        log::info!(
+            "{}: {} added {} for {} ({})",
+            ctx.id,
+            issuer.full_name(),
+            kind.name(),
+            ban.mask,
+            reason,
+        );
+        self.audit(ctx.id, kind.command(), args.mask, true, reason);
+        self.send_server_notice(
+            snomask::KILL,
+            format_args!(
+                "{} added {} for {} [{}]",
+                issuer.nick(),
+                kind.name(),
+                ban.mask,
+                reason,
+            ),
+        );
+        ctx.rb
+            .reply(Command::Notice)
+            .trailing_param(lines::BAN_ADDED);
+
+        let banned: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| kind.matches(&ban, client))
+            .map(|(id, _)| id)
+            .collect();
+        for id in banned {
+            self.remove_client(id, format_args!("Banned: {}", reason), "Banned");
+        }
+
+        Ok(())
+    }
+
+    /// Removes a ban added by `add_ban`.
+    fn remove_ban(&mut self, mut ctx: CommandContext<'_>, kind: Kind, mask: &str) -> Result {
+        self.check_kline(&mut ctx)?;
+        let command = match kind {
+            Kind::Kline => "UNKLINE",
+            Kind::Dline => "UNDLINE",
+        };
+        let ban = match self.bans_mut(kind).remove(mask) {
+            Some(ban) => ban,
+            None => {
+                self.audit(ctx.id, command, mask, false, "no such ban");
+                ctx.rb
+                    .reply(Command::Notice)
+                    .trailing_param(lines::NO_SUCH_BAN);
+                return Err(());
+            }
+        };
+
+        let issuer = &self.clients[ctx.id];
+        log::info!(
+            "{}: {} removed {} for {}",
+            ctx.id,
+            issuer.full_name(),
+            kind.name(),
+            ban.mask,
+        );
+        self.audit(ctx.id, command, &ban.mask, true, "");
+        self.send_server_notice(
+            snomask::KILL,
+            format_args!("{} removed {} for {}", issuer.nick(), kind.name(), ban.mask),
+        );
+        ctx.rb
+            .reply(Command::Notice)
+            .trailing_param(lines::BAN_REMOVED);
+
+        Ok(())
+    }
+
+    // DLINE, KLINE, UNDLINE, UNKLINE
+
+    pub fn cmd_dline(&mut self, ctx: CommandContext<'_>, args: data::req::ServerBan<'_>) -> Result {
+        self.add_ban(ctx, Kind::Dline, args)
+    }
+
+    pub fn cmd_kline(&mut self, ctx: CommandContext<'_>, args: data::req::ServerBan<'_>) -> Result {
+        self.add_ban(ctx, Kind::Kline, args)
+    }
+
+    pub fn cmd_undline(&mut self, ctx: CommandContext<'_>, mask: &str) -> Result {
+        self.remove_ban(ctx, Kind::Dline, mask)
+    }
+
+    pub fn cmd_unkline(&mut self, ctx: CommandContext<'_>, mask: &str) -> Result {
+        self.remove_ban(ctx, Kind::Kline, mask)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::*;
+    use super::super::State;
+    use crate::config::{self, Privilege};
+    use crate::lines;
+    use ellidri_tokens::{rpl, Command};
+
+    /// Registers a client with the given nickname and username.
+    async fn register(s: &State, nick: &str, user: &str) -> (ClientId, Queue) {
+        let (id, queue) = add_client(s).await;
+        handle_message(s, id, &format!("NICK {}", nick)).await;
+        handle_message(s, id, &format!("USER {} X X X", user)).await;
+        (id, queue)
+    }
+
+    /// Whether the client of the given queue has been disconnected with an ERROR message.
+    async fn is_disconnected(queue: &mut Queue) -> bool {
+        let mut res = String::new();
+        collect(&mut res, queue).await;
+        let disconnected = messages(&res).any(|msg| msg.command == Err("ERROR"));
+        disconnected
+    }
+
+    #[tokio::test]
+    async fn test_kline() {
+        let state = state_with(config::State {
+            klines: vec![config::Ban {
+                mask: String::from("*spam@*"),
+                reason: String::from("No spam"),
+            }],
+            ..oper_config(vec![Privilege::Kline])
+        })
+        .await;
+        let (admin, mut admin_queue) = add_registered_client(&state, "admin").await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (_, mut bob_queue) = register(&state, "bob", "bob").await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+        flush(&mut admin_queue).await;
+        flush(&mut bob_queue).await;
+
+        handle_message(&state, alice, "KLINE *bob@* :Go away").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_NOPRIVILEDGES),
+                &["alice", lines::NO_PRIVILEDGES],
+            )],
+        );
+
+        handle_message(&state, admin, "KLINE *bob@* :Go away").await;
+        handle_message(&state, admin, "KLINE *BOB@*").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &["admin", lines::BAN_ADDED],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &["admin", lines::BAN_EXISTS],
+                ),
+            ],
+        );
+        let mut res = String::new();
+        collect(&mut res, &mut bob_queue).await;
+        let error = messages(&res).find(|msg| msg.command == Err("ERROR"));
+        assert_eq!(error.unwrap().params[0], "Banned: Go away");
+        let (_, mut bob_queue) = register(&state, "bob", "bob").await;
+        let mut res = String::new();
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_YOUREBANNEDCREEP),
+                    &["bob", "Go away"],
+                ),
+                (None, Err("ERROR"), &["Banned: Go away"]),
+            ],
+        );
+
+        // Configured K-lines are removed from the configuration only.
+        handle_message(&state, admin, "UNKLINE *spam@*").await;
+        handle_message(&state, admin, "UNKLINE *bob@*").await;
+        handle_message(&state, admin, "UNKLINE *bob@*").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &["admin", lines::NO_SUCH_BAN],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &["admin", lines::BAN_REMOVED],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &["admin", lines::NO_SUCH_BAN],
+                ),
+            ],
+        );
+        let (_, mut bob_queue) = register(&state, "bob", "bob").await;
+        assert!(!is_disconnected(&mut bob_queue).await);
+        let (_, mut spam_queue) = register(&state, "spammer", "spam").await;
+        assert!(is_disconnected(&mut spam_queue).await);
+    }
+
+    #[tokio::test]
+    async fn test_dline() {
+        let state = state_with(oper_config(vec![Privilege::Kline])).await;
+        let (admin, mut admin_queue) = add_registered_client(&state, "admin").await;
+        let (_, mut bad_queue) = add_client_from(&state, ([192, 0, 2, 1], 1).into(), false).await;
+        let (_, mut good_queue) =
+            add_client_from(&state, ([198, 51, 100, 1], 1).into(), false).await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+        flush(&mut admin_queue).await;
+
+        handle_message(&state, admin, "DLINE 192.0.2.* :Bad network").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Ok(Command::Notice),
+                &["admin", lines::BAN_ADDED],
+            )],
+        );
+        assert!(is_disconnected(&mut bad_queue).await);
+        assert!(!is_disconnected(&mut good_queue).await);
+        let (_, mut bad_queue) = add_client_from(&state, ([192, 0, 2, 2], 1).into(), false).await;
+        assert!(is_disconnected(&mut bad_queue).await);
+
+        handle_message(&state, admin, "UNDLINE 192.0.2.*").await;
+        flush(&mut admin_queue).await;
+        let (_, mut bad_queue) = add_client_from(&state, ([192, 0, 2, 2], 1).into(), false).await;
+        assert!(!is_disconnected(&mut bad_queue).await);
+    }
+}
@@ -2,17 +2,27 @@
 
 #![allow(clippy::needless_pass_by_value)]
 
-use crate::{Channel, Client, config, data, lines, util};
-use crate::client::{MessageQueue, MessageQueueItem};
+use crate::{audit, Channel, Client, config, data, lines, logger, net, util};
+use crate::client::{ConnectionState, MessageQueue, MessageQueueItem, MessageQueueReceiver, Peer};
 use crate::data::Request;
-use ellidri_tokens::{mode, rpl, Buffer, Command, Message, ReplyBuffer};
+use ellidri_tokens::mode::{self, snomask};
+use ellidri_tokens::{rpl, Buffer, Command, Message, ReplyBuffer};
 use ellidri_unicase::{u, UniCase};
 use slab::Slab;
 use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{fmt, fs, net};
-use tokio::sync::{Mutex, Notify};
-
+use std::{fmt, panic, time};
+use tokio::sync::{mpsc, Mutex, Notify, RwLock};
+use tokio::task;
+
+mod bans;
+mod oper;
+mod persist;
+mod rehash;
+#[cfg(test)]
+pub(crate) mod test;
+mod upgrade;
 mod v1;
 mod v3;
 
@@ -37,11 +47,25 @@ pub struct CommandContext<'a> {
     client_tags: &'a str,
 }
 
+/// The password of an OPER message, checked by `State::verify_oper_password` against the hash of
+/// the OPER block of the same name.
+pub struct VerifiedPassword {
+    hash: String,
+    matches: bool,
+}
+
 /// State of an IRC network.
 ///
 /// This is used by ellidri to maintain a consistent state of the network.  Note that this is just
 /// an `Arc` to the real data, so it's cheap to clone and clones share the same data.
 ///
+/// The data is behind a read-write lock.  Requests that only read the state, like PRIVMSG or WHO,
+/// which make up most of the traffic, are handled at the same time as each other.  Requests that
+/// change the state, like JOIN or NICK, are handled one at a time, while no other request is.
+/// Messages to a channel are also sent with the lock of the channel held, so that all members
+/// receive the messages of a channel in the same order.  The messages of a connection are handled
+/// in the order they are received, since connection tasks wait for each of them to be handled.
+///
 /// At the time of writing, this only support the client-to-server API, so the network can only
 /// consist of one server.  Maybe in the long term it will support incoming messages from other
 /// servers.
@@ -50,33 +74,69 @@ pub struct CommandContext<'a> {
 ///
 /// [1]: https://tokio.rs
 #[derive(Clone)]
-pub struct State(Arc<Mutex<StateInner>>);
+pub struct State(Arc<RwLock<StateInner>>);
 
 impl State {
     /// Intialize the IRC state from the given configuration.
     ///
-    /// `rehash` will be notified/pinged whenever an operator sends a REHASH command.
-    pub async fn new(config: config::State, rehash: Arc<Notify>) -> Self {
-        let inner = StateInner::new(config, rehash).await;
-        Self(Arc::new(Mutex::new(inner)))
+    /// `config.motd_file` must be the contents of the MOTD file instead of its path.
+    ///
+    /// `rehash` will be notified/pinged whenever an operator sends a REHASH command, and `upgrade`
+    /// whenever an operator sends an UPGRADE command.
+    pub async fn new(
+        config: config::State,
+        rehash: Arc<Notify>,
+        upgrade: Arc<Notify>,
+        persist: Arc<Notify>,
+    ) -> Self {
+        let inner = StateInner::new(config, rehash, upgrade, persist).await;
+        Self(Arc::new(RwLock::new(inner)))
     }
 
     /// Reload state configuration.
     ///
     /// `cfg.motd_file` must be the contents of the MOTD file instead of its path.
     pub async fn rehash(&self, cfg: config::State) {
-        self.0.lock().await.rehash(cfg);
+        self.0.write().await.rehash(cfg);
+    }
+
+    /// Tells the operators that asked for a rehash that the configuration could not be read.
+    pub async fn rehash_failed(&self, err: &str) {
+        self.0.write().await.rehash_failed(err);
     }
 
     /// Adds a new connection to the state.
     ///
-    /// The given `addr`ess is used to build the client's host, and the given `queue` is used to
-    /// push messages back to the client.
+    /// The given `peer` gives the client's IP address and host, and the given `queue` is used to
+    /// push messages back to the client.  `tls` must be true when the connection uses TLS.
     ///
     /// Each connection is identified by an integer.  This function returns the identifier for this
     /// connection, which must be used to handle messages from this client.
-    pub async fn peer_joined(&self, addr: net::SocketAddr, queue: MessageQueue) -> usize {
-        self.0.lock().await.peer_joined(addr, queue)
+    pub async fn peer_joined(
+        &self,
+        peer: &Peer,
+        tls: bool,
+        certfp: Option<String>,
+        queue: MessageQueue,
+    ) -> usize {
+        self.0.write().await.peer_joined(peer, tls, certfp, queue)
+    }
+
+    /// Sends a PING to the given connection, to check whether it is still alive.
+    pub async fn send_ping(&self, id: usize) {
+        self.0.read().await.send_ping(id);
+    }
+
+    /// Removes the given connection from the state because it does not read the messages sent to
+    /// it fast enough.
+    pub async fn peer_exceeded_sendq(&self, id: usize) {
+        self.0.write().await.peer_exceeded_sendq(id);
+    }
+
+    /// Tells IRC operators that the given connection sends messages faster than its class
+    /// allows, and that they are delayed.
+    pub async fn peer_flooding(&self, id: usize) {
+        self.0.read().await.peer_flooding(id);
     }
 
     /// Removes the given connection from the state, with an optional error.
@@ -84,21 +144,163 @@ impl State {
     /// If the peer has quit unexpctedly, `err` should be set to `Some` and reflect the cause of
     /// the quit, so that other peers can be correctly informed.
     pub async fn peer_quit(&self, id: usize, err: Option<impl fmt::Display>) {
-        self.0.lock().await.peer_quit(id, err);
+        self.0.write().await.peer_quit(id, err);
     }
 
     /// Updates the state according to the given message from the given client.
-    pub async fn handle_message(&self, id: usize, msg: Message<'_>) -> u32 {
-        self.0.lock().await.handle_message(id, msg)
+    ///
+    /// Returns the number of points the message costs, or `None` when the client is not in the
+    /// state anymore, in which case the message is left unhandled.
+    ///
+    /// When handling the message panics, the client is removed from the state and the other
+    /// clients are left untouched.
+    pub async fn handle_message(&self, id: usize, msg: Message<'_>) -> Option<u32> {
+        let req = Request::new(&msg);
+        let res = if is_shared(&req) {
+            let state = self.0.read().await;
+            let _message_guard = match state.message_lock(&req) {
+                Some(lock) => Some(lock.lock_owned().await),
+                None => None,
+            };
+            panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                state.handle_shared_message(id, &msg, req)
+            }))
+        } else {
+            let oper_password = self.verify_oper_password(&msg).await;
+            let mut state = self.0.write().await;
+            panic::catch_unwind(panic::AssertUnwindSafe(|| {
+                state.handle_message(id, &msg, req, oper_password)
+            }))
+        };
+        match res {
+            Ok(points) => points,
+            Err(_) => {
+                log::error!("{}: Failed to handle {:?}, disconnecting", id, msg.command);
+                let mut state = self.0.write().await;
+                state.remove_client(id, lines::INTERNAL_ERROR, lines::INTERNAL_ERROR);
+                None
+            }
+        }
+    }
+
+    /// Checks the password of an OPER message against the OPER block of the same name.
+    ///
+    /// Password hashes are slow to verify on purpose, so this is done on a blocking thread without
+    /// holding the lock.  Returns `None` when `msg` is not an OPER message or when no OPER block
+    /// has this name.
+    async fn verify_oper_password(&self, msg: &Message<'_>) -> Option<VerifiedPassword> {
+        if msg.command != Ok(Command::Oper) || msg.num_params < 2 {
+            return None;
+        }
+        let hash = {
+            let state = self.0.read().await;
+            let oper = state.opers.iter().find(|oper| oper.name == msg.params[0])?;
+            oper.password.clone()
+        };
+        let password = msg.params[1].to_owned();
+        task::spawn_blocking(move || {
+            let matches = util::verify_password(&hash, &password);
+            VerifiedPassword { hash, matches }
+        })
+        .await
+        .ok()
+    }
+
+    /// Sends a server notice to the IRC operators subscribed to the given snomask.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    pub async fn server_notice(&self, snomask: char, text: impl fmt::Display) {
+        self.0.read().await.send_server_notice(snomask, text);
     }
 
     pub async fn remove_if_unregistered(&self, id: usize) {
-        self.0.lock().await.remove_if_unregistered(id);
+        self.0.write().await.remove_if_unregistered(id);
+    }
+
+    /// Saves the state, then removes every client from it to stop their connection tasks.
+    ///
+    /// TLS clients are disconnected.  Connection tasks of plain-text clients send their
+    /// connection to `handoff` once they have written the messages left in their queue.  See the
+    /// `upgrade` module.
+    pub async fn handoff(&self, handoff: mpsc::UnboundedSender<net::HandedOff>) -> String {
+        self.0.write().await.handoff(handoff)
+    }
+
+    /// Returns where connection tasks must send their connection when their client is removed,
+    /// when an upgrade is in progress.
+    pub async fn handoff_sender(&self) -> Option<mpsc::UnboundedSender<net::HandedOff>> {
+        self.0.read().await.handoff.clone()
+    }
+
+    /// Restores clients and channels saved by `State::handoff`, and ends the upgrade.
+    ///
+    /// Returns the new identifier and the message queue of each client, by its identifier in the
+    /// saved state.
+    pub async fn restore(&self, saved: &str) -> HashMap<usize, (usize, MessageQueueReceiver)> {
+        self.0.write().await.restore(saved)
+    }
+
+    /// Disconnects every client with the configured shutdown message.
+    pub async fn shutdown(&self) {
+        self.0.write().await.shutdown();
+    }
+
+    /// Returns the path of the state file and the permanent channels to write in it, or `None`
+    /// when no state file is configured.
+    pub async fn save_channels(&self) -> Option<(String, String)> {
+        self.0.read().await.save_channels()
+    }
+
+    /// Returns once the state can be locked for writing.  Used to check that it is not stuck.
+    pub async fn check_alive(&self) {
+        let _ = self.0.write().await;
     }
 
     /// Returns the timeout for registration, in milliseconds.
     pub async fn login_timeout(&self) -> u64 {
-        self.0.lock().await.login_timeout
+        self.0.read().await.login_timeout
+    }
+
+    /// Updates the list of listening addresses reported by STATS P.  The boolean is true when the
+    /// binding uses TLS.
+    pub async fn set_bindings(&self, bindings: Vec<(config::Address, bool)>) {
+        self.0.write().await.bindings = bindings;
+    }
+}
+
+/// Whether `req` is handled with shared access to the state, by
+/// `StateInner::handle_shared_message`.
+///
+/// These requests do not change the state, apart from the idle time of the client and the command
+/// counters which are atomic, and they do not change the connection state of the client either.
+/// Requests that could not be read are only answered with an error.
+fn is_shared(req: &Result<Request<'_>, data::Error<'_>>) -> bool {
+    match req {
+        Ok(req) => matches!(
+            req,
+            Request::Admin
+                | Request::Info
+                | Request::LUsers
+                | Request::Motd
+                | Request::Stats(_)
+                | Request::Time
+                | Request::Version
+                | Request::WhoChannel(_)
+                | Request::WhoMask(_)
+                | Request::WhoUser(_)
+                | Request::WhoAll(_)
+                | Request::WhoIs(_)
+                | Request::List(_)
+                | Request::ListAll
+                | Request::Names(_)
+                | Request::NamesAll
+                | Request::TopicGet(_)
+                | Request::Ping(_)
+                | Request::ModeUserGet(_)
+                | Request::MessageChannel(_)
+                | Request::MessageUser(_)
+                | Request::ModeChannelGet(_)
+        ),
+        Err(_) => true,
     }
 }
 
@@ -128,6 +330,15 @@ pub(crate) struct StateInner {
     /// register (in a "003 RPL_CREATED" reply).
     created_at: String,
 
+    /// When this instance has been created, for STATS u.
+    started_at: time::Instant,
+
+    /// Number of times each command has been issued, for STATS m.
+    command_counts: HashMap<&'static str, AtomicUsize>,
+
+    /// Addresses the server listens on and whether they use TLS, for STATS P.
+    bindings: Vec<(config::Address, bool)>,
+
     /// The message of the day.
     motd: Option<String>,
 
@@ -140,6 +351,16 @@ pub(crate) struct StateInner {
     /// A list of (name, password) that are valid OPER parameters.
     opers: Vec<config::Oper>,
 
+    /// Connection classes, in the order they are matched against clients.
+    classes: Vec<Arc<config::ConnectionClass>>,
+
+    /// The class of clients that match none of `classes`.
+    default_class: Arc<config::ConnectionClass>,
+
+    /// Server bans on `user@host` masks (K-lines) and on IP address masks (D-lines).
+    klines: bans::Bans,
+    dlines: bans::Bans,
+
     /// Limits in number of characters for user input.
     awaylen: usize,
     channellen: usize,
@@ -153,21 +374,48 @@ pub(crate) struct StateInner {
     /// Registration timeout, in milliseconds.
     login_timeout: u64,
 
+    /// Whether clients must answer a PING before being registered.
+    ping_cookie: bool,
+
+    /// The capabilities advertised to clients, separated by spaces.
+    capabilities: String,
+
+    /// The capabilities that clients cannot enable.
+    disabled_capabilities: Vec<String>,
+
+    /// The reason given to clients when the server shuts down.
+    shutdown_message: String,
+
+    /// Where the actions of IRC operators are recorded.
+    audit: audit::Log,
+
     /// Channel to send rehash notifications
     rehash: Arc<Notify>,
+
+    /// Clients that have sent a REHASH command, to be told the outcome.
+    rehash_requests: Vec<usize>,
+
+    /// Notified when an operator asks for an upgrade.
+    upgrade: Arc<Notify>,
+
+    /// Where permanent channels are written, or an empty string.
+    state_file: String,
+
+    /// Notified when a permanent channel changes and must be written to the state file.
+    persist: Arc<Notify>,
+
+    /// Where connection tasks send their connection during an upgrade, `None` otherwise.
+    handoff: Option<mpsc::UnboundedSender<net::HandedOff>>,
 }
 
 impl StateInner {
-    pub async fn new(config: config::State, rehash: Arc<Notify>) -> Self {
-        log::info!("Loading MOTD from {:?}", config.motd_file);
-        let motd = match fs::read_to_string(&config.motd_file) {
-            Ok(motd) => Some(motd),
-            Err(err) => {
-                log::warn!("Failed to read {:?}: {}", config.motd_file, err);
-                None
-            }
-        };
-        Self {
+    pub async fn new(
+        config: config::State,
+        rehash: Arc<Notify>,
+        upgrade: Arc<Notify>,
+        persist: Arc<Notify>,
+    ) -> Self {
+        let mut state = Self {
             domain: Arc::from(config.domain),
             org_name: config.org_name,
             org_location: config.org_location,
@@ -176,10 +424,20 @@ impl StateInner {
             nicks: HashMap::new(),
             channels: HashMap::new(),
             created_at: util::time_str(),
-            motd,
+            started_at: time::Instant::now(),
+            command_counts: Command::ALL
+                .iter()
+                .map(|command| (command.as_str(), AtomicUsize::new(0)))
+                .collect(),
+            bindings: Vec::new(),
+            motd: Some(config.motd_file).filter(|motd| !motd.is_empty()),
             password: config.password,
             default_chan_mode: config.default_chan_mode,
             opers: config.opers,
+            classes: config.classes.into_iter().map(Arc::new).collect(),
+            default_class: Arc::new(config.default_class),
+            klines: bans::Bans::new(config.klines),
+            dlines: bans::Bans::new(config.dlines),
             awaylen: config.awaylen,
             channellen: config.channellen,
             keylen: config.keylen,
@@ -189,40 +447,102 @@ impl StateInner {
             topiclen: config.topiclen,
             userlen: config.userlen,
             login_timeout: config.login_timeout,
+            ping_cookie: config.ping_cookie,
+            capabilities: v3::capabilities(&config.disabled_capabilities),
+            disabled_capabilities: config.disabled_capabilities,
+            shutdown_message: config.shutdown_message,
+            audit: audit::Log::new(config.audit_log, config.audit_log_size),
             rehash,
+            rehash_requests: Vec::new(),
+            upgrade,
+            state_file: config.state_file,
+            persist,
+            handoff: None,
+        };
+        state.load_channels();
+        state
+    }
+
+    pub fn peer_joined(
+        &mut self,
+        peer: &Peer,
+        tls: bool,
+        certfp: Option<String>,
+        queue: MessageQueue,
+    ) -> usize {
+        if self.handoff.is_some() {
+            // Dropping the queue makes the connection task hand the connection over, and the next
+            // process handles it as a new connection.
+            return usize::MAX;
+        }
+        log::debug!("{}: Connected", peer);
+        let host = &peer.host;
+        let dline = peer.ip.and_then(|ip| self.dlines.find(&ip.to_string()).cloned());
+        let class = self.default_class.clone();
+        let client = Client::new(self.domain.clone(), queue, peer, tls, certfp, class);
+        let id = self.clients.insert(client);
+        if !self.assign_class(id) {
+            let class = self.clients[id].class().clone();
+            log::debug!("{}: Class {:?} is full", peer, class.name);
+            self.send_server_notice(snomask::CONNECT, format_args!(
+                "Connection rejected: {} [class {} is full]",
+                host,
+                class.name,
+            ));
+            self.remove_client(id, lines::CLASS_FULL, "");
+        } else if let Some(ban) = dline {
+            log::debug!("{}: D-lined ({})", peer, ban.reason);
+            self.send_server_notice(snomask::CONNECT, format_args!(
+                "Connection rejected: {} [D-lined: {}]",
+                host,
+                ban.reason,
+            ));
+            self.remove_client(id, format_args!("Banned: {}", ban.reason), "");
         }
+        id
     }
 
-    pub fn rehash(&mut self, config: config::State) {
-        self.domain = Arc::from(config.domain);
-        self.org_name = config.org_name;
-        self.org_location = config.org_location;
-        self.org_mail = config.org_mail;
-        self.motd = if config.motd_file.is_empty() {
-            None
-        } else {
-            Some(config.motd_file)
+    pub fn send_ping(&self, id: usize) {
+        if let Some(client) = self.clients.get(id) {
+            let mut ping = Buffer::new();
+            ping.message("", Command::Ping).trailing_param(&self.domain);
+            client.send(ping);
+        }
+    }
+
+    pub fn peer_exceeded_sendq(&mut self, id: usize) {
+        let client = match self.clients.get(id) {
+            Some(client) => client,
+            None => return,
         };
-        self.password = config.password;
-        self.default_chan_mode = config.default_chan_mode;
-        self.opers = config.opers;
-        self.awaylen = config.awaylen;
-        self.channellen = config.channellen;
-        self.keylen = config.keylen;
-        self.kicklen = config.kicklen;
-        self.namelen = config.namelen;
-        self.topiclen = config.topiclen;
-        self.userlen = config.userlen;
-        self.login_timeout = config.login_timeout;
+        log::debug!("{}: SendQ exceeded", id);
+        self.send_server_notice(snomask::FLOOD, format_args!(
+            "Client exceeded its sendq: {} ({}@{}) [class {}]",
+            client.nick(),
+            client.user(),
+            client.host(),
+            client.class().name,
+        ));
+        self.remove_client(id, lines::SENDQ_EXCEEDED, lines::SENDQ_EXCEEDED);
     }
 
-    pub fn peer_joined(&mut self, addr: net::SocketAddr, queue: MessageQueue) -> usize {
-        log::debug!("{}: Connected", addr);
-        let client = Client::new(self.domain.clone(), queue, addr.ip().to_string());
-        self.clients.insert(client)
+    pub fn peer_flooding(&self, id: usize) {
+        let client = match self.clients.get(id) {
+            Some(client) => client,
+            None => return,
+        };
+        log::debug!("{}: Flooding", id);
+        self.send_server_notice(snomask::FLOOD, format_args!(
+            "Client flooding: {} ({}@{}) [class {}]",
+            client.nick(),
+            client.user(),
+            client.host(),
+            client.class().name,
+        ));
     }
 
     pub fn peer_quit(&mut self, id: usize, err: Option<impl fmt::Display>) {
+        let _context = self.clients.get(id).map(|client| logger::Context::enter(id, client));
         log::debug!("{}: Disconnected", id);
 
         if let Some(err) = err {
@@ -240,25 +560,38 @@ impl StateInner {
     /// - send a QUIT message to all cilents in these channels,
     /// - remove empty channels
     fn remove_client(&mut self, id: usize, msg_to_client: impl fmt::Display, msg_to_others: impl fmt::Display) {
-        if !self.clients.contains(id) {
-            return;
-        }
-
-        let client = self.clients.remove(id);
-        self.nicks.remove(u(client.nick()));
+        let client = match self.clients.get(id) {
+            Some(client) => client,
+            None => return,
+        };
 
         if client.is_registered() {
+            self.send_server_notice(snomask::QUIT, format_args!(
+                "Client exiting: {} ({}@{}) [{}]",
+                client.nick(),
+                client.user(),
+                client.host(),
+                msg_to_others,
+            ));
+
             let mut quit_notice = Buffer::new();
             quit_notice.message(client.full_name(), Command::Quit).fmt_trailing_param(msg_to_others);
 
             let quit_notice = MessageQueueItem::from(quit_notice);
             client.send(quit_notice.clone());
             self.send_notification(id, quit_notice, |_, _| true);
+        }
 
-            self.channels.retain(|_, channel| {
+        let client = self.clients.remove(id);
+        self.nicks.remove(u(client.nick()));
+
+        for channel_name in &client.channels {
+            if let Some(channel) = self.channels.get_mut(channel_name) {
                 channel.members.remove(&id);
-                !channel.members.is_empty()
-            });
+                if channel.members.is_empty() && !channel.permanent {
+                    self.channels.remove(channel_name);
+                }
+            }
         }
 
         let mut error = Buffer::new();
@@ -266,11 +599,48 @@ impl StateInner {
         client.send(error);
     }
 
-    pub fn handle_message(&mut self, id: usize, msg: Message<'_>) -> u32 {
-        let client = match self.clients.get(id) {
-            Some(client) => client,
-            None => return 999_999,
-        };
+    /// Sends an ERROR with the shutdown message to every client, and removes them all.
+    ///
+    /// Clients are not sent the QUIT of the others, since they are all leaving.  The ERROR is sent
+    /// regardless of the sendq limit, and the permanent channels are kept to be saved.
+    pub fn shutdown(&mut self) {
+        log::info!("Disconnecting {} clients", self.clients.len());
+        let mut error = Buffer::new();
+        error.message("", "ERROR").trailing_param(&self.shutdown_message);
+        let error = MessageQueueItem::from(error);
+        for client in self.clients.drain() {
+            client.send_last(error.clone());
+        }
+        self.nicks.clear();
+        self.rehash_requests.clear();
+        self.channels.retain(|_, channel| channel.permanent);
+        for channel in self.channels.values_mut() {
+            channel.members.clear();
+        }
+    }
+
+    /// Returns the lock of the channel `req` sends a message to, if any.
+    fn message_lock(&self, req: &Result<Request<'_>, data::Error<'_>>) -> Option<Arc<Mutex<()>>> {
+        match req {
+            Ok(Request::MessageChannel(args)) => {
+                let channel = self.channels.get(args.to.u())?;
+                Some(channel.message_lock.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Checks the request `req` of the message `msg` from the client `id`, and counts its command.
+    ///
+    /// Returns the request, the buffer of its replies and the number of points it costs, or the
+    /// number of points the message costs when it has been answered with an error.
+    fn prepare<'a>(
+        &self,
+        id: usize,
+        msg: &Message<'_>,
+        req: Result<Request<'a>, data::Error<'a>>,
+    ) -> Result<(Request<'a>, ReplyBuffer, u32), u32> {
+        let client = &self.clients[id];
 
         if MAX_TAG_DATA_LENGTH < msg.tags.len() {
             let mut rb = client.reply("");
@@ -278,7 +648,7 @@ impl StateInner {
                 .reply(rpl::ERR_INPUTTOOLONG)
                 .trailing_param(lines::INPUT_TOO_LONG);
             client.send(rb);
-            return 3;
+            return Err(3);
         }
 
         let label = msg.tags()
@@ -288,34 +658,37 @@ impl StateInner {
             .unwrap_or("");
 
         let mut rb = client.reply(label);
-        let is_operator = client.operator;
 
-        let req = match Request::new(&msg) {
+        if let Some(count) = msg.command.ok().and_then(|c| self.command_counts.get(c.as_str())) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let req = match req {
             Ok(req) => req,
             Err(data::Error::ErroneousNickname(name)) => {
                 rb.reply(rpl::ERR_ERRONEUSNICKNAME).param(name).trailing_param(lines::ERRONEOUS_NICKNAME);
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::InvalidCap) => {
                 rb.reply(Command::Cap).param("NAK").trailing_param(msg.params[1]);
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::InvalidCapCmd(cmd)) => {
                 rb.reply(rpl::ERR_INVALIDCAPCMD).param(cmd).trailing_param(lines::UNKNOWN_COMMAND);
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::NoSuchChannel(name)) => {
                 rb.reply(rpl::ERR_NOSUCHCHANNEL).param(name).trailing_param(lines::NO_SUCH_CHANNEL);
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::NoSuchNick(name)) => {
                 rb.reply(rpl::ERR_NOSUCHNICK).param(name).trailing_param(lines::NO_SUCH_NICK);
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::NeedMoreParams(command, n)) => {
                 match command {
@@ -333,7 +706,7 @@ impl StateInner {
                     }
                 }
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
             Err(data::Error::UnknownCommand(unknown)) => {
                 if client.is_registered() {
@@ -342,7 +715,7 @@ impl StateInner {
                     rb.reply(rpl::ERR_NOTREGISTERED).trailing_param(lines::NOT_REGISTERED);
                 }
                 client.send(rb);
-                return 6;
+                return Err(6);
             }
         };
 
@@ -353,10 +726,95 @@ impl StateInner {
                 rb.reply(rpl::ERR_NOTREGISTERED).trailing_param(lines::NOT_REGISTERED);
             }
             client.send(rb);
-            return 2;
+            return Err(2);
         }
 
-        let points = req.points();
+        let points = match msg.command {
+            Ok(command) => client.class().costs.get(command.as_str()).copied(),
+            Err(_) => None,
+        };
+        let points = points.unwrap_or_else(|| req.points());
+        let points = if client.is_exempt() { 0 } else { points };
+
+        Ok((req, rb, points))
+    }
+
+    /// Handles a request for which `is_shared` is true, with shared access to the state.
+    ///
+    /// Returns the number of points the message costs, or `None` when the client is not in the
+    /// state anymore.
+    pub fn handle_shared_message(
+        &self,
+        id: usize,
+        msg: &Message<'_>,
+        req: Result<Request<'_>, data::Error<'_>>,
+    ) -> Option<u32> {
+        let client = self.clients.get(id)?;
+        let _context = logger::Context::enter(id, client);
+
+        let (req, mut rb, points) = match self.prepare(id, msg, req) {
+            Ok(prepared) => prepared,
+            Err(points) => return Some(points),
+        };
+        let ctx = CommandContext {
+            id,
+            rb: &mut rb,
+            client_tags: msg.tags,
+        };
+
+        log::debug!("{}: {:?}", id, req);
+        let res = match req {
+            Request::Admin => self.cmd_admin(ctx),
+            Request::Info => self.cmd_info(ctx),
+            Request::LUsers => self.cmd_lusers(ctx),
+            Request::Motd => self.cmd_motd(ctx),
+            Request::Stats(args) => self.cmd_stats(ctx, args),
+            Request::Time => self.cmd_time(ctx),
+            Request::Version => self.cmd_version(ctx),
+            Request::WhoChannel(args) => self.cmd_who_channel(ctx, args),
+            Request::WhoMask(args) => self.cmd_who_mask(ctx, args),
+            Request::WhoUser(args) => self.cmd_who_user(ctx, args),
+            Request::WhoAll(args) => self.cmd_who_all(ctx, args),
+            Request::WhoIs(args) => self.cmd_whois(ctx, args),
+            Request::List(args) => self.cmd_list(ctx, args),
+            Request::ListAll => self.cmd_list_all(ctx),
+            Request::Names(args) => self.cmd_names(ctx, args),
+            Request::NamesAll => self.cmd_names_all(ctx),
+            Request::TopicGet(args) => self.cmd_topic_get(ctx, args),
+            Request::Ping(args) => self.cmd_ping(ctx, args),
+            Request::ModeUserGet(args) => self.cmd_mode_user_get(ctx, args),
+            Request::MessageChannel(args) => self.cmd_message_channel(ctx, args),
+            Request::MessageUser(args) => self.cmd_message_user(ctx, args),
+            Request::ModeChannelGet(args) => self.cmd_mode_channel_get(ctx, args),
+            req => unreachable!("{:?} needs exclusive access to the state", req),
+        };
+
+        rb.lr_end();
+        if !rb.is_empty() {
+            client.send(rb);
+        }
+
+        Some(if res.is_ok() { points } else { points.saturating_mul(2) })
+    }
+
+    /// Handles any request, with exclusive access to the state.
+    ///
+    /// Returns the number of points the message costs, or `None` when the client is not in the
+    /// state anymore.
+    pub fn handle_message(
+        &mut self,
+        id: usize,
+        msg: &Message<'_>,
+        req: Result<Request<'_>, data::Error<'_>>,
+        oper_password: Option<VerifiedPassword>,
+    ) -> Option<u32> {
+        let client = self.clients.get(id)?;
+        let _context = logger::Context::enter(id, client);
+
+        let (req, mut rb, points) = match self.prepare(id, msg, req) {
+            Ok(prepared) => prepared,
+            Err(points) => return Some(points),
+        };
         let ctx = CommandContext {
             id,
             rb: &mut rb,
@@ -370,6 +828,7 @@ impl StateInner {
             Request::Info => self.cmd_info(ctx),
             Request::LUsers => self.cmd_lusers(ctx),
             Request::Motd => self.cmd_motd(ctx),
+            Request::Stats(args) => self.cmd_stats(ctx, args),
             Request::Time => self.cmd_time(ctx),
             Request::Version => self.cmd_version(ctx),
             Request::WhoChannel(args) => self.cmd_who_channel(ctx, args),
@@ -379,9 +838,21 @@ impl StateInner {
             Request::WhoIs(args) => self.cmd_whois(ctx, args),
 
             // IRCop restricted requests.
+            Request::Dline(args) => self.cmd_dline(ctx, args),
+            Request::GlobOps(args) => self.cmd_globops(ctx, args),
             Request::Kill(args) => self.cmd_kill(ctx, args),
-            Request::Oper(args) => self.cmd_oper(ctx, args),
+            Request::Kline(args) => self.cmd_kline(ctx, args),
+            Request::Oper(args) => self.cmd_oper(ctx, args, oper_password),
             Request::Rehash => self.cmd_rehash(ctx),
+            Request::SaJoin(args) => self.cmd_sajoin(ctx, args),
+            Request::SaMode(args) => self.cmd_samode(ctx, args),
+            Request::SaNick(args) => self.cmd_sanick(ctx, args),
+            Request::SaPart(args) => self.cmd_sapart(ctx, args),
+            Request::SaTopic(args) => self.cmd_satopic(ctx, args),
+            Request::UnDline(args) => self.cmd_undline(ctx, args),
+            Request::UnKline(args) => self.cmd_unkline(ctx, args),
+            Request::Upgrade => self.cmd_upgrade(ctx),
+            Request::WallOps(args) => self.cmd_wallops(ctx, args),
 
             // Requests about channel info.
             Request::List(args) => self.cmd_list(ctx, args),
@@ -424,17 +895,69 @@ impl StateInner {
 
         if !self.clients.contains(id) {
             // Command handler removed the client from the network state.
-            return 999_999;
+            return Some(999_999);
         }
 
         let used_points = if res.is_ok() {
-            let client = self.clients.get_mut(id).unwrap();
-            let old_state = client.state();
-            let new_state = client.apply_request(&req);
+            let old_state = self.clients[id].state();
+            let registers = matches!(old_state.apply(&req), Ok(s) if s.is_registered());
+            if registers && !old_state.is_registered() {
+                if let Some(ban) = self.find_kline(id) {
+                    let client = &self.clients[id];
+                    log::debug!("{}: K-lined ({})", id, ban.reason);
+                    self.send_server_notice(snomask::CONNECT, format_args!(
+                        "Client rejected: {} ({}@{}) [K-lined: {}]",
+                        client.nick(),
+                        client.user(),
+                        client.host(),
+                        ban.reason,
+                    ));
+                    rb.reply(rpl::ERR_YOUREBANNEDCREEP).trailing_param(&ban.reason);
+                    client.send(rb);
+                    self.remove_client(id, format_args!("Banned: {}", ban.reason), "");
+                    return Some(999_999);
+                }
+
+                // The class of the client might depend on the account it has logged in to.
+                if !self.assign_class(id) {
+                    let client = &self.clients[id];
+                    log::debug!("{}: Class {:?} is full", id, client.class().name);
+                    self.send_server_notice(snomask::CONNECT, format_args!(
+                        "Client rejected: {} ({}@{}) [class {} is full]",
+                        client.nick(),
+                        client.user(),
+                        client.host(),
+                        client.class().name,
+                    ));
+                    client.send(rb);
+                    self.remove_client(id, lines::CLASS_FULL, "");
+                    return Some(999_999);
+                }
+            }
+
+            let mut new_state = self.clients[id].apply_request(&req);
+            // The cookie is only sent when the client would register, not when it answers it.
+            let registers = new_state.is_registered()
+                && !old_state.is_registered()
+                && old_state != ConnectionState::PongAwaited;
+            if registers && self.ping_cookie {
+                let cookie = util::new_ping_cookie();
+                rb.message("", Command::Ping).trailing_param(&cookie);
+                new_state = self.clients[id].await_pong(cookie);
+            }
 
             if new_state.is_registered() && !old_state.is_registered() {
                 log::debug!("{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
                 self.send_welcome(id, &mut rb);
+                let client = &self.clients[id];
+                self.send_server_notice(snomask::CONNECT, format_args!(
+                    "Client connecting: {} ({}@{}) [{}]{}",
+                    client.nick(),
+                    client.user(),
+                    client.host(),
+                    client.real(),
+                    if client.is_tls() { " (TLS)" } else { "" },
+                ));
             } else if !old_state.is_registered() {
                 log::debug!("{}: {:?} + {:?} == {:?}", id, old_state, msg.command, new_state);
             }
@@ -449,7 +972,30 @@ impl StateInner {
             self.clients[id].send(rb);
         }
 
-        if is_operator { 1 } else { used_points }
+        Some(used_points)
+    }
+
+    /// Returns the first class that matches the given client.
+    fn find_class(&self, client: &Client) -> Arc<config::ConnectionClass> {
+        self.classes
+            .iter()
+            .find(|class| class.matches(client.ip(), client.is_tls(), client.account()))
+            .unwrap_or(&self.default_class)
+            .clone()
+    }
+
+    /// Moves the given client to the first class that matches it.  Returns `false` when the class
+    /// is already full.
+    fn assign_class(&mut self, id: usize) -> bool {
+        let class = self.find_class(&self.clients[id]);
+        let members = self
+            .clients
+            .iter()
+            .filter(|(other, client)| *other != id && Arc::ptr_eq(client.class(), &class))
+            .count();
+        let is_full = class.max_clients != 0 && class.max_clients <= members;
+        self.clients[id].set_class(class);
+        !is_full
     }
 
     pub fn remove_if_unregistered(&mut self, id: usize) {
@@ -542,10 +1088,13 @@ impl StateInner {
     ) {
         let msg = buf.into();
 
-        let noticed = self
-            .channels
-            .values()
-            .filter(|channel| channel.members.contains_key(&issuer))
+        let issuer_channels = match self.clients.get(issuer) {
+            Some(client) => &client.channels,
+            None => return,
+        };
+        let noticed = issuer_channels
+            .iter()
+            .filter_map(|channel_name| self.channels.get(channel_name))
             .flat_map(|channel| channel.members.keys().cloned())
             .collect::<HashSet<_>>();
 
@@ -563,6 +1112,22 @@ impl StateInner {
         }
     }
 
+    /// Sends a server notice to the IRC operators subscribed to the given snomask.
+    fn send_server_notice(&self, snomask: char, text: impl fmt::Display) {
+        let subscribers = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.operator && client.snomask.contains(snomask));
+        for (_, client) in subscribers {
+            let mut notice = Buffer::new();
+            notice
+                .message(&self.domain, Command::Notice)
+                .param(client.nick())
+                .fmt_trailing_param(format_args!("*** Notice -- {}", text));
+            client.send(notice);
+        }
+    }
+
     fn send_i_support(&self, rb: &mut ReplyBuffer) {
         rb.reply(rpl::ISUPPORT)
             .param("CASEMAPPING=ascii")
@@ -717,3 +1282,429 @@ impl StateInner {
         self.send_motd(rb);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::test::*;
+    use super::State;
+    use crate::{config, lines};
+    use ellidri_tokens::{rpl, Command};
+    use std::sync::atomic::Ordering;
+
+    fn class(name: &str, ips: &str) -> config::ConnectionClass {
+        config::ConnectionClass {
+            name: String::from(name),
+            ips: vec![ips.parse().unwrap()],
+            ..config::ConnectionClass::default()
+        }
+    }
+
+    /// Whether the client of the given queue has been disconnected with an ERROR message.
+    async fn is_disconnected(queue: &mut Queue) -> bool {
+        let mut res = String::new();
+        collect(&mut res, queue).await;
+        let disconnected = messages(&res).any(|msg| msg.command == Err("ERROR"));
+        disconnected
+    }
+
+    #[tokio::test]
+    async fn test_class_max_clients() {
+        let mut config = simple_config();
+        config.default_class.max_clients = 2;
+        config.classes.push(config::ConnectionClass {
+            max_clients: 1,
+            ..class("local", "10.0.0.0/8")
+        });
+        let state = state_with(config).await;
+
+        let (_, mut queue1) = add_client_from(&state, ([192, 0, 2, 1], 1).into(), false).await;
+        let (_, mut queue2) = add_client_from(&state, ([192, 0, 2, 2], 1).into(), false).await;
+        let (_, mut queue3) = add_client_from(&state, ([192, 0, 2, 3], 1).into(), false).await;
+        assert!(!is_disconnected(&mut queue1).await);
+        assert!(!is_disconnected(&mut queue2).await);
+        assert!(is_disconnected(&mut queue3).await);
+
+        let (_, mut queue1) = add_client_from(&state, ([10, 0, 0, 1], 1).into(), false).await;
+        let (_, mut queue2) = add_client_from(&state, ([10, 0, 0, 2], 1).into(), false).await;
+        assert!(!is_disconnected(&mut queue1).await);
+        assert!(is_disconnected(&mut queue2).await);
+    }
+
+    #[tokio::test]
+    async fn test_class_limits() {
+        let mut config = simple_config();
+        config.classes.push(config::ConnectionClass {
+            sendq: 1 << 12,
+            ping_freq: 30,
+            ..class("local", "10.0.0.0/8")
+        });
+        let state = state_with(config).await;
+
+        let (_, queue) = add_client_from(&state, ([192, 0, 2, 1], 1).into(), false).await;
+        let limits = queue.limits();
+        assert_eq!(limits.sendq.load(Ordering::Relaxed), 1 << 20);
+        assert_eq!(limits.ping_freq.load(Ordering::Relaxed), 120);
+
+        let (id, mut queue) = add_client_from(&state, ([10, 0, 0, 1], 1).into(), false).await;
+        handle_message(&state, id, "NICK local").await;
+        handle_message(&state, id, "USER X X X X").await;
+        flush(&mut queue).await;
+        let limits = queue.limits();
+        assert_eq!(limits.sendq.load(Ordering::Relaxed), 1 << 12);
+        assert_eq!(limits.ping_freq.load(Ordering::Relaxed), 30);
+
+        // Messages that do not fit in the sendq are dropped.
+        let (sender, _) = add_registered_client(&state, "sender").await;
+        let line = format!("PRIVMSG local :{}", "a".repeat(400));
+        for _ in 0..20 {
+            handle_message(&state, sender, &line).await;
+        }
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert!(res.len() <= 1 << 12);
+        assert!((1..20).contains(&messages(&res).count()));
+        limits.sendq_exceeded.notified().await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let mut config = simple_config();
+        config.default_class.sendq = 1 << 12;
+        let reason = "Shutting down for maintenance, see you soon";
+        config.shutdown_message = String::from(reason);
+        let state = state_with(config).await;
+
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        handle_message(&state, alice, "JOIN #chan").await;
+        handle_message(&state, bob, "JOIN #chan").await;
+        flush(&mut alice_queue).await;
+        flush(&mut bob_queue).await;
+
+        // Fill the sendq of alice, leaving less room than the ERROR needs.
+        let line = format!("PRIVMSG alice :{}", "a".repeat(400));
+        for _ in 0..20 {
+            handle_message(&state, bob, &line).await;
+        }
+        for _ in 0..100 {
+            handle_message(&state, bob, "PRIVMSG alice :a").await;
+        }
+        flush(&mut bob_queue).await;
+
+        // Each client gets the ERROR, but not the QUIT of the others.
+        state.shutdown().await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        let last = messages(&res).last().unwrap();
+        assert_eq!(last.command, Err("ERROR"));
+        assert_eq!(&last.params[..last.num_params], [reason]);
+        let mut res = String::new();
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(&res, &[(None, Err("ERROR"), &[reason])]);
+        assert!(alice_queue.recv().await.is_none());
+        assert!(bob_queue.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_invalid_channels() {
+        let path = std::env::temp_dir().join(format!("ellidri-test-{}.state", std::process::id()));
+        let saved = "CHANNEL
+NOTICE #a +P
+:
+CHANNEL #good +Pn
+";
+        std::fs::write(&path, saved).unwrap();
+        let state_file = path.to_str().unwrap().to_owned();
+        let state = state_with(config::State {
+            state_file,
+            ..simple_config()
+        })
+        .await;
+        let _ = std::fs::remove_file(&path);
+
+        let (_, channels) = state.save_channels().await.unwrap();
+        assert_msgs(&channels, &[(None, Err("CHANNEL"), &["#good", "+Pn"])]);
+    }
+
+    /// Registers a client with the given nickname and returns the token of the registration PING.
+    async fn register_with_cookie(state: &State, id: ClientId, queue: &mut Queue) -> String {
+        handle_message(state, id, "NICK alice").await;
+        handle_message(state, id, "USER X X X X").await;
+        let mut res = String::new();
+        collect(&mut res, queue).await;
+        let ping = messages(&res).next().unwrap();
+        assert_eq!(ping.command, Ok(Command::Ping));
+        assert_eq!(messages(&res).count(), 1);
+        ping.params[0].to_owned()
+    }
+
+    #[tokio::test]
+    async fn test_ping_cookie() {
+        let state = state_with(config::State {
+            ping_cookie: true,
+            ..simple_config()
+        })
+        .await;
+        let (id, mut queue) = add_client(&state).await;
+        let cookie = register_with_cookie(&state, id, &mut queue).await;
+
+        handle_message(&state, id, "PONG :wrong").await;
+        handle_message(&state, id, "JOIN #a").await;
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_NOTREGISTERED),
+                &["alice", lines::NOT_REGISTERED],
+            )],
+        );
+
+        handle_message(&state, id, &format!("PONG :{}", cookie)).await;
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert!(messages(&res).any(|msg| msg.command == Err(rpl::WELCOME)));
+        assert!(!messages(&res).any(|msg| msg.command == Ok(Command::Ping)));
+
+        // Once registered, commands and keepalive PINGs do not ask for the cookie again.
+        handle_message(&state, id, "JOIN #a").await;
+        state.send_ping(id).await;
+        handle_message(&state, id, "PONG :ellidri.test").await;
+        handle_message(&state, id, "PART #a").await;
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        let commands: Vec<_> = messages(&res)
+            .map(|msg| msg.command)
+            .filter(|command| *command != Err(rpl::NAMREPLY) && *command != Err(rpl::ENDOFNAMES))
+            .collect();
+        assert_eq!(
+            commands,
+            [Ok(Command::Join), Ok(Command::Ping), Ok(Command::Part)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_server_bans() {
+        let ban = |mask: &str, reason: &str| config::Ban {
+            mask: mask.to_owned(),
+            reason: reason.to_owned(),
+        };
+        let state = state_with(config::State {
+            klines: vec![ban("*spam@*", "No spam")],
+            dlines: vec![ban("192.0.2.*", "Bad network")],
+            ..oper_config(vec![config::Privilege::Stats])
+        })
+        .await;
+
+        let (_, mut queue) = add_client_from(&state, ([192, 0, 2, 1], 1).into(), false).await;
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert_msgs(&res, &[(None, Err("ERROR"), &["Banned: Bad network"])]);
+        let (_, mut queue) = add_client_from(&state, ([198, 51, 100, 1], 1).into(), false).await;
+        assert!(!is_disconnected(&mut queue).await);
+
+        let (id, mut queue) = add_client(&state).await;
+        handle_message(&state, id, "NICK spammer").await;
+        handle_message(&state, id, "USER spam X X X").await;
+        let mut res = String::new();
+        collect(&mut res, &mut queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_YOUREBANNEDCREEP),
+                    &["spammer", "No spam"],
+                ),
+                (None, Err("ERROR"), &["Banned: No spam"]),
+            ],
+        );
+
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        handle_message(&state, alice, "STATS k").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert!(messages(&res).any(|msg| msg.command == Err(rpl::ERR_NOPRIVILEDGES)));
+
+        handle_message(&state, alice, "OPER admin hunter2").await;
+        flush(&mut alice_queue).await;
+        handle_message(&state, alice, "STATS k").await;
+        handle_message(&state, alice, "STATS d").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSKLINE),
+                    &["alice", "K", "*", "*", "*spam", "No spam"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["alice", "k", lines::END_OF_STATS],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSDLINE),
+                    &["alice", "D", "192.0.2.*", "Bad network"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["alice", "d", lines::END_OF_STATS],
+                ),
+            ],
+        );
+    }
+
+    /// Returns the channels of each client according to `Client::channels`, and according to the
+    /// member lists of the channels, sorted by client.
+    async fn channel_index(state: &State) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let state = state.0.read().await;
+        let mut res = Vec::new();
+        for (id, client) in &state.clients {
+            let mut indexed: Vec<_> = client.channels.iter()
+                .map(|name| name.get().to_owned())
+                .collect();
+            let mut members: Vec<_> = state.channels.iter()
+                .filter(|(_, channel)| channel.members.contains_key(&id))
+                .map(|(name, _)| name.get().to_owned())
+                .collect();
+            indexed.sort();
+            members.sort();
+            res.push((client.nick().to_owned(), indexed, members));
+        }
+        res.sort();
+        res
+    }
+
+    /// Checks that `Client::channels` matches the member lists, and that the clients are in the
+    /// given channels.
+    async fn assert_channels(state: &State, expected: &[(&str, &[&str])]) {
+        let index = channel_index(state).await;
+        assert_eq!(index.len(), expected.len());
+        for ((nick, indexed, members), (expected_nick, channels)) in index.iter().zip(expected) {
+            assert_eq!(nick, expected_nick);
+            assert_eq!(indexed, members, "channels of {}", nick);
+            assert_eq!(indexed, channels, "channels of {}", nick);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_index() {
+        let state = state_with(oper_config(vec![config::Privilege::Override])).await;
+        let (admin, _) = add_registered_client(&state, "admin").await;
+        let (alice, _) = add_registered_client(&state, "alice").await;
+        let (bob, _) = add_registered_client(&state, "bob").await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+
+        handle_message(&state, alice, "JOIN #a,#b,#c").await;
+        handle_message(&state, bob, "JOIN #b,#c,#d").await;
+        assert_channels(&state, &[
+            ("admin", &[]),
+            ("alice", &["#a", "#b", "#c"]),
+            ("bob", &["#b", "#c", "#d"]),
+        ]).await;
+
+        handle_message(&state, alice, "KICK #b bob").await;
+        handle_message(&state, alice, "PART #a").await;
+        assert_channels(&state, &[
+            ("admin", &[]),
+            ("alice", &["#b", "#c"]),
+            ("bob", &["#c", "#d"]),
+        ]).await;
+
+        handle_message(&state, admin, "SAJOIN bob #a").await;
+        handle_message(&state, admin, "SAPART alice #c").await;
+        assert_channels(&state, &[
+            ("admin", &[]),
+            ("alice", &["#b"]),
+            ("bob", &["#a", "#c", "#d"]),
+        ]).await;
+
+        handle_message(&state, bob, "JOIN 0").await;
+        handle_message(&state, alice, "JOIN #c").await;
+        assert_channels(&state, &[
+            ("admin", &[]),
+            ("alice", &["#b", "#c"]),
+            ("bob", &[]),
+        ]).await;
+
+        handle_message(&state, bob, "JOIN #b").await;
+        handle_message(&state, alice, "QUIT").await;
+        assert_channels(&state, &[
+            ("admin", &[]),
+            ("bob", &["#b"]),
+        ]).await;
+        let channels: Vec<_> = state.0.read().await.channels.keys()
+            .map(|name| name.get().to_owned())
+            .collect();
+        assert_eq!(channels, ["#b"]);
+    }
+
+    #[tokio::test]
+    async fn test_snomasks() {
+        let state = state_with(oper_config(vec![config::Privilege::Kill])).await;
+        let (admin, mut admin_queue) = add_registered_client(&state, "admin").await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+        flush(&mut admin_queue).await;
+
+        // Only operators can subscribe to server notices.
+        handle_message(&state, alice, "MODE alice +s").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_eq!(res, "");
+
+        handle_message(&state, admin, "MODE admin +s +cfq").await;
+        handle_message(&state, admin, "MODE admin +s -q+nx").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(&res, &[
+            (Some("admin!~X@127.0.0.1"), Ok(Command::Mode), &["admin", "+s"]),
+            (
+                Some("ellidri.test"),
+                Err(rpl::SNOMASKIS),
+                &["admin", "+cfq", lines::SERVER_NOTICE_MASK],
+            ),
+            (Some("admin!~X@127.0.0.1"), Ok(Command::Mode), &["admin", "+s"]),
+            (
+                Some("ellidri.test"),
+                Err(rpl::SNOMASKIS),
+                &["admin", "+cfn", lines::SERVER_NOTICE_MASK],
+            ),
+        ]);
+
+        // Notices of the c, f and n masks are sent, not the ones of k and q.
+        let (bob, _) = add_registered_client(&state, "bob").await;
+        state.peer_flooding(bob).await;
+        handle_message(&state, bob, "NICK carol").await;
+        handle_message(&state, admin, "KILL carol :Bye").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_eq!(notices(&res), [
+            "*** Notice -- Client connecting: bob (X@127.0.0.1) [X]",
+            "*** Notice -- Client flooding: bob (X@127.0.0.1) [class default]",
+            "*** Notice -- Nick change: bob -> carol (X@127.0.0.1)",
+        ]);
+
+        handle_message(&state, admin, "MODE admin -s").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(&res, &[
+            (Some("admin!~X@127.0.0.1"), Ok(Command::Mode), &["admin", "-s"]),
+            (
+                Some("ellidri.test"),
+                Err(rpl::SNOMASKIS),
+                &["admin", "+", lines::SERVER_NOTICE_MASK],
+            ),
+        ]);
+        add_registered_client(&state, "dave").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_eq!(res, "");
+    }
+}
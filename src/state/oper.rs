@@ -0,0 +1,288 @@
+//! Handlers for the IRC operator override commands (SAJOIN, SAPART, SANICK, SAMODE, SATOPIC).
+//!
+//! These commands reuse the regular handlers, either on behalf of the target user, or with
+//! channel checks disabled.  They require the "override" privilege.
+
+use super::{find_nick, CommandContext, HandlerResult as Result};
+use crate::config::Privilege;
+use crate::{data, lines};
+use ellidri_tokens::mode::snomask;
+use ellidri_tokens::{rpl, ReplyBuffer};
+use std::fmt;
+
+// Command handlers
+impl super::StateInner {
+    /// Returns `Ok(())` when the issuer is allowed to use override commands.  Otherwise returns
+    /// `Err(())` and send an error to the client.
+    fn check_override(&self, ctx: &mut CommandContext<'_>) -> Result {
+        if self.clients[ctx.id].has_privilege(Privilege::Override) {
+            Ok(())
+        } else {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            Err(())
+        }
+    }
+
+    /// Records an action of the client `id` in the audit log.
+    pub(super) fn audit(
+        &self,
+        id: usize,
+        action: &str,
+        target: &str,
+        success: bool,
+        details: &str,
+    ) {
+        self.audit.record(
+            self.clients[id].full_name(),
+            action,
+            target,
+            success,
+            details,
+        );
+    }
+
+    /// Logs the successful use of an override command and announces it to other operators.
+    fn announce_override(&self, id: usize, command: &str, args: fmt::Arguments<'_>) {
+        let issuer = &self.clients[id];
+        log::info!("{}: {} used {} {}", id, issuer.full_name(), command, args);
+        self.audit(id, command, &args.to_string(), true, "");
+        self.send_server_notice(
+            snomask::OVERRIDE,
+            format_args!("{} used {} {}", issuer.nick(), command, args),
+        );
+    }
+
+    /// Calls `handler` as if the client `target` had sent the command.  Replies are sent to
+    /// `target`.
+    fn on_behalf_of<F>(&mut self, ctx: &CommandContext<'_>, target: usize, handler: F) -> Result
+    where
+        F: FnOnce(&mut Self, CommandContext<'_>) -> Result,
+    {
+        let mut rb = self.clients[target].reply("");
+        let res = handler(
+            self,
+            CommandContext {
+                id: target,
+                rb: &mut rb,
+                client_tags: "",
+            },
+        );
+        if !rb.is_empty() {
+            self.clients[target].send(rb);
+        }
+
+        // `ReplyBuffer`s share the nickname of the client they reply to, restore the issuer's.
+        ReplyBuffer::set_nick(self.clients[ctx.id].nick());
+
+        res
+    }
+
+    // SAJOIN
+
+    pub fn cmd_sajoin(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::SaJoin<'_>,
+    ) -> Result {
+        self.check_override(&mut ctx)?;
+        let (target_id, target) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+
+        let target_nick = target.nick().to_owned();
+        let channels: Vec<_> = args
+            .to
+            .iter()
+            .map(|(name, _)| name.get().to_owned())
+            .collect();
+
+        self.on_behalf_of(&ctx, target_id, |state, target_ctx| {
+            state.join(target_ctx, args.to, true)
+        })?;
+        self.announce_override(
+            ctx.id,
+            "SAJOIN",
+            format_args!("{} {}", target_nick, channels.join(",")),
+        );
+        Ok(())
+    }
+
+    // SAMODE
+
+    pub fn cmd_samode(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::ModeChannelSet<'_>,
+    ) -> Result {
+        self.check_override(&mut ctx)?;
+        let id = ctx.id;
+        let applied = self.mode_channel_set(ctx, args, true)?;
+        if !applied.is_empty() {
+            self.announce_override(
+                id,
+                "SAMODE",
+                format_args!("{} {}", args.channel.get(), applied),
+            );
+        }
+        Ok(())
+    }
+
+    // SANICK
+
+    pub fn cmd_sanick(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::SaNick<'_>,
+    ) -> Result {
+        self.check_override(&mut ctx)?;
+        let (target_id, target) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+
+        if matches!(self.nicks.get(args.nick.u()), Some(id) if *id != target_id) {
+            log::debug!("{}:     Already in use", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_NICKNAMEINUSE)
+                .param(args.nick.get())
+                .trailing_param(lines::NICKNAME_IN_USE);
+            return Err(());
+        }
+
+        let target_nick = target.nick().to_owned();
+
+        self.on_behalf_of(&ctx, target_id, |state, target_ctx| {
+            state.cmd_nick(target_ctx, args.nick)
+        })?;
+        self.announce_override(
+            ctx.id,
+            "SANICK",
+            format_args!("{} {}", target_nick, args.nick.get()),
+        );
+        Ok(())
+    }
+
+    // SAPART
+
+    pub fn cmd_sapart(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::SaPart<'_>,
+    ) -> Result {
+        self.check_override(&mut ctx)?;
+        let (target_id, target) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+
+        let target_nick = target.nick().to_owned();
+        let channels: Vec<_> = args
+            .part
+            .from
+            .iter()
+            .map(|name| name.get().to_owned())
+            .collect();
+
+        self.on_behalf_of(&ctx, target_id, |state, target_ctx| {
+            state.cmd_part(target_ctx, args.part)
+        })?;
+        self.announce_override(
+            ctx.id,
+            "SAPART",
+            format_args!("{} {}", target_nick, channels.join(",")),
+        );
+        Ok(())
+    }
+
+    // SATOPIC
+
+    pub fn cmd_satopic(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::TopicSet<'_>,
+    ) -> Result {
+        self.check_override(&mut ctx)?;
+        let id = ctx.id;
+        self.topic_set(ctx, args, true)?;
+        self.announce_override(
+            id,
+            "SATOPIC",
+            format_args!("{} :{}", args.channel.get(), args.topic),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::*;
+    use crate::config::Privilege;
+    use ellidri_tokens::{rpl, Command};
+
+    #[tokio::test]
+    async fn test_override_announcements() {
+        let state = state_with(oper_config(vec![Privilege::Override])).await;
+        let (admin, mut admin_queue) = add_registered_client(&state, "admin").await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (_bob, _) = add_registered_client(&state, "bob").await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+        handle_message(&state, admin, "MODE admin +s +a").await;
+        handle_message(&state, alice, "JOIN #chan").await;
+        flush(&mut admin_queue).await;
+        flush(&mut alice_queue).await;
+
+        // Failed override commands are not announced.
+        handle_message(&state, admin, "SAMODE #nowhere +m").await;
+        handle_message(&state, admin, "SANICK alice bob").await;
+        handle_message(&state, admin, "SAPART alice #nowhere").await;
+        handle_message(&state, admin, "SATOPIC #nowhere :Hello").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_eq!(notices(&res), Vec::<String>::new());
+        flush(&mut alice_queue).await;
+
+        handle_message(&state, admin, "SAMODE #chan +mv alice").await;
+        handle_message(&state, admin, "SAMODE #chan +b").await;
+        handle_message(&state, admin, "SATOPIC #chan :Hello").await;
+        handle_message(&state, admin, "SAJOIN alice #other").await;
+        handle_message(&state, admin, "SAPART alice #other").await;
+        handle_message(&state, admin, "SANICK alice carol").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_eq!(
+            notices(&res),
+            vec![
+                "*** Notice -- admin used SAMODE #chan +mv alice",
+                "*** Notice -- admin used SATOPIC #chan :Hello",
+                "*** Notice -- admin used SAJOIN alice #other",
+                "*** Notice -- admin used SAPART alice #other",
+                "*** Notice -- admin used SANICK alice carol",
+            ]
+        );
+
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("admin!~X@127.0.0.1"),
+                    Ok(Command::Mode),
+                    &["#chan", "+mv", "alice"],
+                ),
+                (
+                    Some("admin!~X@127.0.0.1"),
+                    Ok(Command::Topic),
+                    &["#chan", "Hello"],
+                ),
+                (Some("alice!~X@127.0.0.1"), Ok(Command::Join), &["#other"]),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::NAMREPLY),
+                    &["alice", "@", "#other", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFNAMES),
+                    &["alice", "#other", ""],
+                ),
+                (Some("alice!~X@127.0.0.1"), Ok(Command::Part), &["#other"]),
+                (Some("alice!~X@127.0.0.1"), Ok(Command::Nick), &["carol"]),
+            ],
+        );
+    }
+}
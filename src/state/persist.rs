@@ -0,0 +1,75 @@
+//! Permanent channels.
+//!
+//! Channels with the +P mode are not removed when their last member leaves.  When `state_file` is
+//! set, `Control` writes them to this file each time `persist` is notified and on shutdown, and
+//! they are loaded on startup.  The file holds one `CHANNEL` line per channel, see `Channel::save`.
+
+use crate::Channel;
+use ellidri_tokens::{Buffer, Message};
+use ellidri_unicase::UniCase;
+use std::{fs, io};
+
+impl super::StateInner {
+    /// Loads the permanent channels from the state file.
+    pub(super) fn load_channels(&mut self) {
+        if self.state_file.is_empty() {
+            return;
+        }
+        let saved = match fs::read_to_string(&self.state_file) {
+            Ok(saved) => saved,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+            Err(err) => {
+                log::warn!("Failed to read {:?}: {}", self.state_file, err);
+                return;
+            }
+        };
+
+        for line in saved.lines() {
+            let msg = match Message::parse(line) {
+                Some(msg) if msg.command == Err("CHANNEL") => msg,
+                _ => {
+                    log::warn!(
+                        "Ignoring an invalid line of {:?}: {:?}",
+                        self.state_file,
+                        line
+                    );
+                    continue;
+                }
+            };
+            let mut channel = Channel::new("");
+            if channel.restore(&msg).is_err() {
+                log::warn!(
+                    "Ignoring an invalid line of {:?}: {:?}",
+                    self.state_file,
+                    line
+                );
+                continue;
+            }
+            channel.permanent = true;
+            self.channels
+                .insert(UniCase::new(msg.params[0].to_owned()), channel);
+        }
+        log::info!(
+            "Loaded {} permanent channels from {:?}",
+            self.channels.len(),
+            self.state_file
+        );
+    }
+
+    /// Returns the path of the state file and the permanent channels to write in it, or `None`
+    /// when no state file is configured.
+    pub fn save_channels(&self) -> Option<(String, String)> {
+        if self.state_file.is_empty() {
+            return None;
+        }
+        let mut buf = Buffer::new();
+        for (name, channel) in self
+            .channels
+            .iter()
+            .filter(|(_, channel)| channel.permanent)
+        {
+            channel.save(buf.tagged_message(""), name.get());
+        }
+        Some((self.state_file.clone(), buf.build()))
+    }
+}
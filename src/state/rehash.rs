@@ -0,0 +1,322 @@
+//! Configuration reloads.
+//!
+//! Every setting is replaced by the new one.  The operators that asked for the reload with REHASH
+//! are sent the list of the settings that changed.  Registered clients are sent RPL_ISUPPORT
+//! again when a limit it advertises has changed, and clients that support cap-notify are sent
+//! `CAP NEW` and `CAP DEL` when the set of capabilities has changed.
+
+use crate::{config, data};
+use ellidri_tokens::mode::snomask;
+use ellidri_tokens::{Buffer, Command};
+use std::convert::TryFrom;
+use std::fmt;
+use std::sync::Arc;
+
+impl super::StateInner {
+    /// Reloads the configuration.
+    ///
+    /// `config.motd_file` must be the contents of the MOTD file instead of its path.
+    pub fn rehash(&mut self, config: config::State) {
+        let changes = self.changes(&config);
+        let limits_changed = self.awaylen != config.awaylen
+            || self.channellen != config.channellen
+            || self.keylen != config.keylen
+            || self.kicklen != config.kicklen
+            || self.namelen != config.namelen
+            || self.nicklen != config.nicklen
+            || self.topiclen != config.topiclen;
+
+        self.domain = Arc::from(config.domain);
+        self.org_name = config.org_name;
+        self.org_location = config.org_location;
+        self.org_mail = config.org_mail;
+        self.motd = Some(config.motd_file).filter(|motd| !motd.is_empty());
+        self.password = config.password;
+        self.default_chan_mode = config.default_chan_mode;
+        self.opers = config.opers;
+        self.classes = config.classes.into_iter().map(Arc::new).collect();
+        self.default_class = Arc::new(config.default_class);
+        self.klines.configure(config.klines);
+        self.dlines.configure(config.dlines);
+        let ids: Vec<_> = self.clients.iter().map(|(id, _)| id).collect();
+        for id in ids {
+            let class = self.find_class(&self.clients[id]);
+            let client = &mut self.clients[id];
+            client.set_class(class);
+            client.domain = self.domain.clone();
+        }
+        self.awaylen = config.awaylen;
+        self.channellen = config.channellen;
+        self.keylen = config.keylen;
+        self.kicklen = config.kicklen;
+        self.namelen = config.namelen;
+        self.nicklen = config.nicklen;
+        self.topiclen = config.topiclen;
+        self.userlen = config.userlen;
+        self.login_timeout = config.login_timeout;
+        self.ping_cookie = config.ping_cookie;
+        let new_caps = super::v3::capabilities(&config.disabled_capabilities);
+        let old_caps = std::mem::replace(&mut self.capabilities, new_caps);
+        self.disabled_capabilities = config.disabled_capabilities;
+        self.shutdown_message = config.shutdown_message;
+        self.state_file = config.state_file;
+        self.audit
+            .configure(config.audit_log, config.audit_log_size);
+
+        if limits_changed {
+            for (_, client) in self.clients.iter().filter(|(_, c)| c.is_registered()) {
+                let mut rb = client.reply("");
+                self.send_i_support(&mut rb);
+                client.send(rb);
+            }
+        }
+        self.send_cap_changes(&old_caps);
+
+        for change in &changes {
+            log::info!("Configuration change: {}", change);
+        }
+        for id in std::mem::take(&mut self.rehash_requests) {
+            if changes.is_empty() {
+                self.send_notice(id, "Configuration reloaded, nothing has changed");
+            } else {
+                self.send_notice(id, "Configuration reloaded, changes:");
+            }
+            for change in &changes {
+                self.send_notice(id, change);
+            }
+        }
+        self.send_server_notice(snomask::REHASH, "Configuration reloaded");
+    }
+
+    /// Tells the operators that asked for a reload that the configuration could not be read.
+    pub fn rehash_failed(&mut self, err: &str) {
+        for id in std::mem::take(&mut self.rehash_requests) {
+            self.send_notice(
+                id,
+                format_args!("Failed to reload the configuration: {}", err),
+            );
+        }
+        self.send_server_notice(snomask::REHASH, "Failed to reload the configuration");
+    }
+
+    /// Returns a description of the settings that `config` changes.  Passwords are not shown.
+    fn changes(&self, config: &config::State) -> Vec<String> {
+        let mut res = Vec::new();
+        let mut value = |name: &str, old: &dyn fmt::Debug, new: &dyn fmt::Debug| {
+            let (old, new) = (format!("{:?}", old), format!("{:?}", new));
+            if old != new {
+                res.push(format!("{}: {} -> {}", name, old, new));
+            }
+        };
+
+        value("domain", &self.domain, &config.domain);
+        value("admin_info name", &self.org_name, &config.org_name);
+        value(
+            "admin_info location",
+            &self.org_location,
+            &config.org_location,
+        );
+        value("admin_info mail", &self.org_mail, &config.org_mail);
+        value(
+            "default_chan_mode",
+            &self.default_chan_mode,
+            &config.default_chan_mode,
+        );
+        value("awaylen", &self.awaylen, &config.awaylen);
+        value("channellen", &self.channellen, &config.channellen);
+        value("keylen", &self.keylen, &config.keylen);
+        value("kicklen", &self.kicklen, &config.kicklen);
+        value("namelen", &self.namelen, &config.namelen);
+        value("nicklen", &self.nicklen, &config.nicklen);
+        value("topiclen", &self.topiclen, &config.topiclen);
+        value("userlen", &self.userlen, &config.userlen);
+        value("login_timeout", &self.login_timeout, &config.login_timeout);
+        value("ping_cookie", &self.ping_cookie, &config.ping_cookie);
+        value(
+            "disabled_capabilities",
+            &self.disabled_capabilities,
+            &config.disabled_capabilities,
+        );
+        value(
+            "shutdown_message",
+            &self.shutdown_message,
+            &config.shutdown_message,
+        );
+        value("state_file", &self.state_file, &config.state_file);
+
+        if self.motd.as_deref().unwrap_or("") != config.motd_file {
+            res.push(String::from("motd: changed"));
+        }
+        if self.password != config.password {
+            res.push(String::from("password: changed"));
+        }
+        let opers: Vec<_> = self.opers.iter().collect();
+        list(
+            &mut res,
+            "oper",
+            &opers,
+            &config.opers.iter().collect::<Vec<_>>(),
+            |o| &o.name,
+        );
+        let classes: Vec<_> = self.classes.iter().map(|c| &**c).collect();
+        let new_classes: Vec<_> = config.classes.iter().collect();
+        list(&mut res, "connection_class", &classes, &new_classes, |c| {
+            &c.name
+        });
+        if *self.default_class != config.default_class {
+            res.push(String::from("connection_class \"default\": changed"));
+        }
+        let klines: Vec<_> = self.klines.configured().iter().collect();
+        let new_klines: Vec<_> = config.klines.iter().collect();
+        list(&mut res, "kline", &klines, &new_klines, |b| &b.mask);
+        let dlines: Vec<_> = self.dlines.configured().iter().collect();
+        let new_dlines: Vec<_> = config.dlines.iter().collect();
+        list(&mut res, "dline", &dlines, &new_dlines, |b| &b.mask);
+        res
+    }
+
+    /// Sends `CAP NEW` and `CAP DEL` to the clients that support cap-notify, and disables the
+    /// capabilities removed since `old`.
+    fn send_cap_changes(&mut self, old: &str) {
+        let new = self.capabilities.clone();
+        let added: Vec<_> = new
+            .split_whitespace()
+            .filter(|cap| !old.split_whitespace().any(|c| c == *cap))
+            .collect();
+        let removed: Vec<_> = old
+            .split_whitespace()
+            .filter(|cap| !new.split_whitespace().any(|c| c == *cap))
+            .collect();
+        if added.is_empty() && removed.is_empty() {
+            return;
+        }
+        let disabled: Vec<_> = removed.iter().map(|cap| format!("-{}", cap)).collect();
+        let disabled = data::cap::Diff::try_from(disabled.join(" ").as_str()).unwrap_or_default();
+
+        for (_, client) in &mut self.clients {
+            client.cap_enabled.update(disabled);
+            let cap_notify =
+                client.cap_enabled.cap_notify || client.cap_version == data::cap::Version::V302;
+            if !cap_notify {
+                continue;
+            }
+            let mut rb = client.reply("");
+            if !added.is_empty() {
+                rb.reply(Command::Cap)
+                    .param("NEW")
+                    .trailing_param(&added.join(" "));
+            }
+            if !removed.is_empty() {
+                rb.reply(Command::Cap)
+                    .param("DEL")
+                    .trailing_param(&removed.join(" "));
+            }
+            client.send(rb);
+        }
+    }
+
+    /// Sends a NOTICE from the server to the client `id`, if it is still connected.
+    fn send_notice(&self, id: usize, text: impl fmt::Display) {
+        if let Some(client) = self.clients.get(id) {
+            let mut notice = Buffer::new();
+            notice
+                .message(&self.domain, Command::Notice)
+                .param(client.nick())
+                .fmt_trailing_param(format_args!("*** {}", text));
+            client.send(notice);
+        }
+    }
+}
+
+/// Describes the differences between the lists of directives `old` and `new`, identified by
+/// `key`.
+fn list<T: PartialEq>(
+    res: &mut Vec<String>,
+    name: &str,
+    old: &[&T],
+    new: &[&T],
+    key: impl Fn(&T) -> &str,
+) {
+    for removed in old.iter().filter(|o| !new.iter().any(|n| key(o) == key(n))) {
+        res.push(format!("{} {:?}: removed", name, key(removed)));
+    }
+    for added in new.iter().filter(|n| !old.iter().any(|o| key(o) == key(n))) {
+        res.push(format!("{} {:?}: added", name, key(added)));
+    }
+    for changed in new
+        .iter()
+        .filter(|n| old.iter().any(|o| key(o) == key(n) && o != *n))
+    {
+        res.push(format!("{} {:?}: changed", name, key(changed)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::*;
+    use crate::config;
+    use ellidri_tokens::Command;
+
+    #[tokio::test]
+    async fn test_rehash_cap_notify() {
+        let state = state_with(simple_config()).await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (_bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        handle_message(&state, alice, "CAP REQ :cap-notify echo-message").await;
+        flush(&mut alice_queue).await;
+
+        let disabled = vec![String::from("echo-message"), String::from("setname")];
+        state
+            .rehash(config::State {
+                disabled_capabilities: disabled,
+                ..simple_config()
+            })
+            .await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Ok(Command::Cap),
+                &["alice", "DEL", "echo-message setname"],
+            )],
+        );
+
+        handle_message(&state, alice, "CAP REQ setname").await;
+        handle_message(&state, alice, "CAP LIST").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Cap),
+                    &["alice", "NAK", "setname"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Ok(Command::Cap),
+                    &["alice", "LIST", "cap-notify"],
+                ),
+            ],
+        );
+
+        state.rehash(simple_config()).await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Ok(Command::Cap),
+                &["alice", "NEW", "echo-message setname"],
+            )],
+        );
+
+        let mut res = String::new();
+        collect(&mut res, &mut bob_queue).await;
+        assert!(res.is_empty());
+    }
+}
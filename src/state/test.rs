@@ -1,38 +1,63 @@
 //! Testing utilities for `ellidri::state`
 
 use super::State;
-use crate::client::MessageQueueItem;
-use crate::{auth, config};
+use crate::client::{self, MessageQueueReceiver};
+use crate::config;
 use ellidri_tokens::{assert_msg, Command, Message};
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::sync::{mpsc, Notify};
+use tokio::sync::Notify;
 
 pub type ClientId = usize;
-pub type Queue = mpsc::UnboundedReceiver<MessageQueueItem>;
+pub type Queue = MessageQueueReceiver;
 
-pub fn simple_state() -> State {
-    let config = config::State::sample();
-    let auth_provider = auth::choose_provider(config::SaslBackend::None, None).unwrap();
-    let rehash = Arc::new(Notify::new());
-    State::new(config, auth_provider, rehash)
+/// A configuration without MOTD and with a fixed domain.
+pub fn simple_config() -> config::State {
+    config::State {
+        domain: String::from("ellidri.test"),
+        motd_file: String::new(),
+        ..config::State::default()
+    }
+}
+
+/// A configuration with an OPER block named "admin", of password "hunter2", that gives the
+/// given privileges.
+pub fn oper_config(privileges: Vec<config::Privilege>) -> config::State {
+    let oper = config::Oper {
+        name: String::from("admin"),
+        password: String::from("hunter2"),
+        class: config::OperClass {
+            name: String::from("test"),
+            privileges,
+        },
+        ..config::Oper::default()
+    };
+    config::State {
+        opers: vec![oper],
+        ..simple_config()
+    }
+}
+
+pub async fn state_with(config: config::State) -> State {
+    let notify = || Arc::new(Notify::new());
+    State::new(config, notify(), notify(), notify()).await
 }
 
 pub async fn add_client(s: &State) -> (ClientId, Queue) {
-    let port = s.0.lock().await.clients.len() as u16;
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    let (msg_queue, outgoing_msgs) = mpsc::unbounded_channel();
-    let res = s.peer_joined(addr, msg_queue).await;
-    (res, outgoing_msgs)
+    add_client_from(s, ([127, 0, 0, 1], 10_000).into(), false).await
 }
 
-pub async fn add_registered_client(s: &State, nickname: &str) -> (usize, Queue) {
-    let (id, queue) = add_client(s).await;
-    let nick = format!("NICK :{}", nickname);
-    let nick = Message::parse(&nick).unwrap();
-    let user = Message::parse("USER X X X X").unwrap();
-    let _ = s.handle_message(id, nick).await;
-    let _ = s.handle_message(id, user).await;
+pub async fn add_client_from(s: &State, addr: SocketAddr, tls: bool) -> (ClientId, Queue) {
+    let (queue, outgoing_msgs) = client::message_queue();
+    let id = s.peer_joined(&addr.into(), tls, None, queue).await;
+    (id, outgoing_msgs)
+}
+
+pub async fn add_registered_client(s: &State, nickname: &str) -> (ClientId, Queue) {
+    let (id, mut queue) = add_client(s).await;
+    handle_message(s, id, &format!("NICK :{}", nickname)).await;
+    handle_message(s, id, "USER X X X X").await;
+    flush(&mut queue).await;
     (id, queue)
 }
 
@@ -41,28 +66,22 @@ pub async fn handle_message(state: &State, id: ClientId, message: &str) {
     let _ = state.handle_message(id, message).await;
 }
 
-pub fn flush(queue: &mut Queue) {
-    loop {
-        match queue.try_recv() {
-            Ok(_msg) => {
-                //println!("flushed: {:?}", msg);
-            }
-            Err(mpsc::error::TryRecvError::Empty) => return,
-            Err(_) => unreachable!(),
-        }
-    }
+/// Yields to the runtime, so that the cooperative budget of the test task is refilled.
+/// Otherwise queues start to look empty after a hundred messages have been received.
+async fn refill_budget() {
+    let _ = tokio::task::yield_now().await;
+}
+
+pub async fn flush(queue: &mut Queue) {
+    refill_budget().await;
+    while queue.recv_ready().await.is_some() {}
 }
 
-pub fn collect(res: &mut String, queue: &mut Queue) {
-    loop {
-        match queue.try_recv() {
-            Ok(item) => {
-                let s: &str = item.as_ref();
-                res.push_str(s);
-            }
-            Err(mpsc::error::TryRecvError::Empty) => return,
-            Err(_) => unreachable!(),
-        }
+pub async fn collect(res: &mut String, queue: &mut Queue) {
+    refill_budget().await;
+    while let Some(item) = queue.recv_ready().await {
+        let s: &str = item.as_ref();
+        res.push_str(s);
     }
 }
 
@@ -71,6 +90,14 @@ pub fn messages(s: &str) -> impl Iterator<Item = Message<'_>> {
         .map(|line| Message::parse(line).expect("bad message"))
 }
 
+/// Returns the text of the notices in `s`.
+pub fn notices(s: &str) -> Vec<String> {
+    messages(s)
+        .filter(|msg| msg.command == Ok(Command::Notice))
+        .map(|msg| msg.params[1].to_owned())
+        .collect()
+}
+
 type ExpectedMessage<'a> = (Option<&'a str>, Result<Command, &'a str>, &'a [&'a str]);
 
 pub fn assert_msgs(s: &str, expected: &[ExpectedMessage<'_>]) {
@@ -0,0 +1,182 @@
+//! Saving and restoring the state across upgrades.
+//!
+//! The state is saved as IRC messages, one per line, whose tags hold the data:
+//!
+//! - `SERVER` holds the creation time and the uptime of the server,
+//! - `CLIENT <id>` holds a client, see `Client::save`,
+//! - `CHANNEL <name> <modes> [<limit>] [<key>]` holds a channel (see `Channel::save`) and its
+//!   members along with their modes.
+//!
+//! Other lines are ignored, so that the `upgrade` module can append its own.
+
+use crate::channel::MemberModes;
+use crate::client::{self, MessageQueueReceiver, Peer};
+use crate::{lines, net, Channel, Client};
+use ellidri_tokens::{Buffer, Message};
+use ellidri_unicase::UniCase;
+use std::collections::HashMap;
+use std::time;
+use tokio::sync::mpsc;
+
+impl super::StateInner {
+    pub fn handoff(&mut self, handoff: mpsc::UnboundedSender<net::HandedOff>) -> String {
+        let tls_clients: Vec<_> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.is_tls())
+            .map(|(id, _)| id)
+            .collect();
+        for id in tls_clients {
+            self.remove_client(id, lines::UPGRADE_RECONNECT, lines::UPGRADE_RECONNECT);
+        }
+
+        let saved = self.save();
+        log::info!("Handing {} clients over", self.clients.len());
+        self.handoff = Some(handoff);
+        self.clients.clear();
+        self.nicks.clear();
+        self.channels.clear();
+        saved
+    }
+
+    fn save(&self) -> String {
+        let mut buf = Buffer::new();
+
+        buf.tagged_message("")
+            .tag("created", Some(&self.created_at))
+            .tag("uptime", Some(self.started_at.elapsed().as_secs()))
+            .prefixed_command("", "SERVER");
+
+        for (id, client) in &self.clients {
+            client
+                .save(buf.tagged_message(""))
+                .prefixed_command("", "CLIENT")
+                .fmt_param(id);
+        }
+
+        for (name, channel) in &self.channels {
+            let members: Vec<String> = channel
+                .members
+                .iter()
+                .map(|(id, modes)| {
+                    let mut member = format!("{}:", id);
+                    modes.all_symbols(&mut member);
+                    member
+                })
+                .collect();
+            let msg = buf
+                .tagged_message("")
+                .tag("members", Some(members.join(",")));
+            channel.save(msg, name.get());
+        }
+
+        buf.build()
+    }
+
+    pub fn restore(&mut self, saved: &str) -> HashMap<usize, (usize, MessageQueueReceiver)> {
+        self.handoff = None;
+        let mut restored = HashMap::new();
+
+        for msg in saved.lines().filter_map(Message::parse) {
+            match msg.command {
+                Err("SERVER") => self.restore_server(&msg),
+                Err("CLIENT") => {
+                    if let Ok(saved_id) = msg.params[0].parse() {
+                        restored.insert(saved_id, self.restore_client(&msg));
+                    }
+                }
+                Err("CHANNEL") => self.restore_channel(&msg, &restored),
+                _ => {}
+            }
+        }
+
+        for (id, client) in &self.clients {
+            if client.nick() != "*" {
+                self.nicks
+                    .insert(UniCase::new(client.nick().to_owned()), id);
+            }
+        }
+
+        log::info!(
+            "Restored {} clients and {} channels",
+            self.clients.len(),
+            self.channels.len()
+        );
+        restored
+    }
+
+    fn restore_server(&mut self, msg: &Message<'_>) {
+        for tag in msg.tags() {
+            match tag.key {
+                "created" => self.created_at = tag.unescape_value(),
+                "uptime" => {
+                    let uptime = tag.value.and_then(|v| v.parse().ok()).unwrap_or(0);
+                    let uptime = time::Duration::from_secs(uptime);
+                    if let Some(started_at) = time::Instant::now().checked_sub(uptime) {
+                        self.started_at = started_at;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn restore_client(&mut self, msg: &Message<'_>) -> (usize, MessageQueueReceiver) {
+        let tag = |key| {
+            msg.tags()
+                .find(|tag| tag.key == key)
+                .map(|tag| tag.unescape_value())
+        };
+        let host = tag("host").unwrap_or_default();
+        let peer = Peer {
+            name: host.clone(),
+            ip: tag("ip").and_then(|ip| ip.parse().ok()),
+            host,
+        };
+
+        let (queue, outgoing_msgs) = client::message_queue();
+        let class = self.default_class.clone();
+        let mut client = Client::new(self.domain.clone(), queue, &peer, false, None, class);
+        client.restore(msg);
+        let class = self.find_class(&client);
+        client.set_class(class);
+
+        (self.clients.insert(client), outgoing_msgs)
+    }
+
+    fn restore_channel(
+        &mut self,
+        msg: &Message<'_>,
+        restored: &HashMap<usize, (usize, MessageQueueReceiver)>,
+    ) {
+        let name = msg.params[0];
+        let mut channel = Channel::new("");
+        if channel.restore(msg).is_err() {
+            log::warn!("Ignoring an invalid saved channel {:?}", name);
+            return;
+        }
+
+        let members = msg
+            .tags()
+            .find(|tag| tag.key == "members")
+            .map(|tag| tag.unescape_value());
+        for member in members.iter().flat_map(|members| members.split(',')) {
+            let (id, symbols) = member.split_at(member.find(':').unwrap_or(0));
+            if let Some((id, _)) = id.parse().ok().and_then(|id| restored.get(&id)) {
+                channel
+                    .members
+                    .insert(*id, MemberModes::from_symbols(symbols));
+            }
+        }
+
+        if channel.members.is_empty() && !channel.permanent {
+            return;
+        }
+        for id in channel.members.keys() {
+            self.clients[*id]
+                .channels
+                .insert(UniCase::new(name.to_owned()));
+        }
+        self.channels.insert(UniCase::new(name.to_owned()), channel);
+    }
+}
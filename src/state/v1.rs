@@ -5,13 +5,17 @@
 
 use super::{
     find_channel, find_channel_quiet, find_member, find_nick, CommandContext,
-    HandlerResult as Result,
+    HandlerResult as Result, VerifiedPassword,
 };
 use crate::channel::{MemberModes, Topic};
 use crate::client::MessageQueueItem;
+use crate::config::{Address, Privilege};
 use crate::{data, lines, util, Channel, Client};
-use ellidri_tokens::{mode, rpl, Buffer, Command, ReplyBuffer};
+use ellidri_tokens::mode::{self, snomask};
+use ellidri_tokens::{format, rpl, Buffer, Command, ReplyBuffer, TagBuffer};
 use ellidri_unicase::{u, UniCase};
+use std::borrow::Cow;
+use std::sync::atomic::Ordering;
 
 // Command handlers
 impl super::StateInner {
@@ -63,6 +67,25 @@ impl super::StateInner {
         Ok(())
     }
 
+    // GLOBOPS
+
+    pub fn cmd_globops(&self, ctx: CommandContext<'_>, content: &str) -> Result {
+        let issuer = &self.clients[ctx.id];
+        if !issuer.operator {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            return Err(());
+        }
+
+        self.send_server_notice(
+            snomask::GLOBOPS,
+            format_args!("Global -- from {}: {}", issuer.nick(), content),
+        );
+
+        Ok(())
+    }
+
     // INFO
 
     pub fn cmd_info(&self, ctx: CommandContext<'_>) -> Result {
@@ -197,6 +220,30 @@ impl super::StateInner {
                 .trailing_param(lines::BANNED_FROM_CHAN);
             return Err(());
         }
+        if channel.registered_only && client.account().is_none() {
+            log::debug!("{}:     not logged in", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_NEEDREGGEDNICK)
+                .param(channel_name)
+                .trailing_param(lines::NEED_REGGED_NICK);
+            return Err(());
+        }
+        if channel.tls_only && !client.is_tls() {
+            log::debug!("{}:     not using TLS", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_SECUREONLYCHAN)
+                .param(channel_name)
+                .trailing_param(lines::SECURE_ONLY_CHAN);
+            return Err(());
+        }
+        if channel.oper_only && !client.operator {
+            log::debug!("{}:     not operator", ctx.id);
+            ctx.rb
+                .reply(rpl::ERR_CANTJOINOPERSONLY)
+                .param(channel_name)
+                .trailing_param(lines::OPER_ONLY_CHAN);
+            return Err(());
+        }
         Ok(())
     }
 
@@ -243,12 +290,24 @@ impl super::StateInner {
         }
     }
 
-    pub fn cmd_join(&mut self, mut ctx: CommandContext<'_>, list: data::JoinList<'_>) -> Result {
+    pub fn cmd_join(&mut self, ctx: CommandContext<'_>, list: data::JoinList<'_>) -> Result {
+        self.join(ctx, list, false)
+    }
+
+    /// Makes the client join the given channels.  When `force` is true, channel keys, limits,
+    /// bans and modes are ignored.
+    pub(super) fn join(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        list: data::JoinList<'_>,
+        force: bool,
+    ) -> Result {
         let client = &self.clients[ctx.id];
 
-        let mut joined = false;
+        let mut joined = Vec::new();
         for (channel_name, key) in list.iter() {
             let can_join = match self.channels.get(channel_name.u()) {
+                Some(channel) if force => !channel.members.contains_key(&ctx.id),
                 Some(channel) => Self::check_join(
                     client,
                     channel,
@@ -272,15 +331,21 @@ impl super::StateInner {
                 self.send_join(ctx.id, &mut ctx.rb, channel_name.get(), client);
                 self.send_topic(&mut ctx.rb, channel_name, false);
                 self.send_names(ctx.id, &mut ctx.rb, channel_name);
-                joined = true;
+                joined.push(channel_name);
             }
         }
-        if joined {
+        if !joined.is_empty() {
             let client = &mut self.clients[ctx.id];
             client.update_idle_time();
             for (channel_name, _) in list.iter() {
                 client.invites.remove(channel_name.u());
             }
+            let channels = &self.channels;
+            let joined = joined
+                .into_iter()
+                .filter_map(|channel_name| channels.get_key_value(channel_name.u()))
+                .map(|(channel_name, _)| UniCase::new(channel_name.get().to_owned()));
+            client.channels.extend(joined);
         }
 
         Ok(())
@@ -340,8 +405,7 @@ impl super::StateInner {
         };
         let member_modes = find_member(ctx.id, ctx.rb, channel, args.from)?;
 
-        // TODO halfop + check if kicking an op.
-        if !member_modes.operator {
+        if !member_modes.is_at_least_halfop() {
             log::debug!("{}:     not operator", ctx.id);
             ctx.rb
                 .reply(rpl::ERR_CHANOPRIVSNEEDED)
@@ -356,10 +420,20 @@ impl super::StateInner {
             .map(|reason| &reason[..reason.len().min(kicklen)]);
 
         for kicked_nick in args.who.iter() {
-            let kicked_id = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, kicked_nick)
+            let kicked = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, kicked_nick)
                 .ok()
-                .and_then(|(id, _)| channel.members.remove(&id).map(|_| id));
-            if let Some(kicked_id) = kicked_id {
+                .and_then(|(id, _)| channel.members.get(&id).map(|modes| (id, *modes)));
+            if let Some((kicked_id, kicked_modes)) = kicked {
+                if kicked_id != ctx.id && !member_modes.can_act_on(kicked_modes) {
+                    log::debug!("{}:     {:?} outranks issuer", ctx.id, kicked_nick.get());
+                    ctx.rb
+                        .reply(rpl::ERR_CHANOPRIVSNEEDED)
+                        .param(args.from.get())
+                        .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
+                    continue;
+                }
+                channel.members.remove(&kicked_id);
+                self.clients[kicked_id].channels.remove(args.from.u());
                 Self::send_kick(
                     ctx.id,
                     &mut ctx.rb,
@@ -380,6 +454,10 @@ impl super::StateInner {
             }
         }
 
+        if channel.members.is_empty() && !channel.permanent {
+            self.channels.remove(args.from.u());
+        }
+
         Ok(())
     }
 
@@ -387,13 +465,23 @@ impl super::StateInner {
 
     pub fn cmd_kill(&mut self, ctx: CommandContext<'_>, args: data::req::Kill<'_>) -> Result {
         let client = &self.clients[ctx.id];
-        if !client.operator {
+        if !client.has_privilege(Privilege::Kill) {
             ctx.rb
                 .reply(rpl::ERR_NOPRIVILEDGES)
                 .trailing_param(lines::NO_PRIVILEDGES);
             return Err(());
         }
-        let (target_id, _) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+        let (target_id, target) = find_nick(ctx.id, ctx.rb, &self.clients, &self.nicks, args.who)?;
+        self.send_server_notice(
+            snomask::KILL,
+            format_args!(
+                "Received KILL message for {} from {} ({})",
+                target.nick(),
+                client.nick(),
+                args.reason,
+            ),
+        );
+        self.audit(ctx.id, "KILL", target.nick(), true, args.reason);
         self.remove_client(target_id, format_args!("Killed: {}", args.reason), "Killed");
         Ok(())
     }
@@ -405,7 +493,10 @@ impl super::StateInner {
         ctx.rb.lr_batch_begin();
 
         for (name, channel) in &self.channels {
-            if channel.secret && !client.operator && !channel.members.contains_key(&ctx.id) {
+            if channel.secret
+                && !client.has_privilege(Privilege::SeeHidden)
+                && !channel.members.contains_key(&ctx.id)
+            {
                 continue;
             }
             let msg = ctx.rb.reply(rpl::LIST).param(name.get());
@@ -429,7 +520,10 @@ impl super::StateInner {
 
         for name in targets.iter() {
             if let Some(channel) = self.channels.get(name.u()) {
-                if channel.secret && !client.operator && !channel.members.contains_key(&ctx.id) {
+                if channel.secret
+                    && !client.has_privilege(Privilege::SeeHidden)
+                    && !channel.members.contains_key(&ctx.id)
+                {
                     continue;
                 }
                 let msg = ctx.rb.reply(rpl::LIST).param(name.get());
@@ -460,7 +554,8 @@ impl super::StateInner {
         channel_name: data::ChannelName<'_>,
     ) -> Result {
         let channel = find_channel(ctx.id, ctx.rb, &self.channels, channel_name)?;
-        let full_info = channel.members.contains_key(&ctx.id) || self.clients[ctx.id].operator;
+        let full_info = channel.members.contains_key(&ctx.id)
+            || self.clients[ctx.id].has_privilege(Privilege::SeeHidden);
 
         let msg = ctx.rb.reply(rpl::CHANNELMODEIS).param(channel_name.get());
         channel.modes(msg, full_info);
@@ -470,9 +565,23 @@ impl super::StateInner {
 
     pub fn cmd_mode_channel_set(
         &mut self,
-        mut ctx: CommandContext<'_>,
+        ctx: CommandContext<'_>,
         args: data::req::ModeChannelSet<'_>,
     ) -> Result {
+        self.mode_channel_set(ctx, args, false).map(|_| ())
+    }
+
+    /// Changes the modes of a channel.  When `force` is true, the issuer doesn't need to be in
+    /// the channel and member ranks are ignored.
+    ///
+    /// Returns the modes that have been applied followed by their parameters, e.g. `+ov a b`, or
+    /// an empty string when no mode has changed.
+    pub(super) fn mode_channel_set(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::ModeChannelSet<'_>,
+        force: bool,
+    ) -> std::result::Result<String, ()> {
         let channel = match self.channels.get_mut(args.channel.u()) {
             Some(channel) => channel,
             None => {
@@ -486,15 +595,23 @@ impl super::StateInner {
         };
 
         let issuer = &self.clients[ctx.id];
-        let issuer_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
-
-        if !issuer.operator && !issuer_modes.can_change(args.modes) {
-            log::debug!("{}:     not operator", ctx.id);
-            ctx.rb
-                .reply(rpl::ERR_CHANOPRIVSNEEDED)
-                .param(args.channel.get())
-                .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
-            return Err(());
+        let can_override = force || issuer.has_privilege(Privilege::Override);
+
+        // Whether the issuer uses the override privilege to change the modes.
+        let mut overriding = false;
+        if !force {
+            let issuer_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
+            if !issuer_modes.can_change(args.modes, issuer.operator) {
+                if !can_override {
+                    log::debug!("{}:     not operator", ctx.id);
+                    ctx.rb
+                        .reply(rpl::ERR_CHANOPRIVSNEEDED)
+                        .param(args.channel.get())
+                        .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
+                    return Err(());
+                }
+                overriding = true;
+            }
         }
 
         let reply_list = |rb: &mut ReplyBuffer, item, end, line: &str, it: util::Masks<'_>| {
@@ -507,6 +624,7 @@ impl super::StateInner {
         ctx.rb.lr_batch_begin();
 
         let clients = &self.clients;
+        let nicks = &self.nicks;
         let mut applied_modes = String::new();
         let mut applied_modeparams = Vec::new();
         let mut last_applied_value = true;
@@ -539,7 +657,20 @@ impl super::StateInner {
                         channel.exception_mask.masks(),
                     );
                 }
+                Ok(change)
+                    if !can_override
+                        && !channel
+                            .outranks_target(ctx.id, &change, |n| nicks.get(u(n)).cloned()) =>
+                {
+                    log::debug!("{}:     target outranks issuer", ctx.id);
+                    ctx.rb
+                        .reply(rpl::ERR_CHANOPRIVSNEEDED)
+                        .param(args.channel.get())
+                        .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
+                }
                 Ok(change) => {
+                    overriding |= !force
+                        && !channel.outranks_target(ctx.id, &change, |n| nicks.get(u(n)).cloned());
                     match channel.apply_mode_change(change, self.keylen, |a| clients[a].nick()) {
                         Ok(true) => {
                             log::debug!("    - Applied {:?}", change);
@@ -602,9 +733,24 @@ impl super::StateInner {
                 .param(args.channel.get())
                 .param(&applied_modes);
             applied_modeparams.iter().fold(msg, |msg, mp| msg.param(mp));
+
+            if channel.permanent || applied_modes.contains('P') {
+                self.persist.notify_one();
+            }
         }
 
-        Ok(())
+        // SAMODE can make an empty channel temporary.
+        if channel.members.is_empty() && !channel.permanent {
+            self.channels.remove(args.channel.u());
+        }
+
+        applied_modeparams.insert(0, applied_modes);
+        let applied = applied_modeparams.join(" ");
+        if overriding && !applied.is_empty() {
+            self.audit(ctx.id, "MODE", args.channel.get(), true, &applied);
+        }
+
+        Ok(applied)
     }
 
     pub fn cmd_mode_user_set(
@@ -624,6 +770,7 @@ impl super::StateInner {
         }
 
         let mut applied_modes = String::with_capacity(args.modes.len() + 1);
+        let mut snomask_changed = false;
         for maybe_change in args.modes.iter() {
             match maybe_change {
                 Ok(change) => {
@@ -631,6 +778,7 @@ impl super::StateInner {
                         log::debug!("  - Applied {:?}", change);
                         applied_modes.push(if change.value() { '+' } else { '-' });
                         applied_modes.push(change.symbol());
+                        snomask_changed |= matches!(change, mode::UserChange::ServerNotices(..));
                     }
                 }
                 Err(mode::Error::Unknown(mode, _)) => {
@@ -647,6 +795,12 @@ impl super::StateInner {
                 .param(args.user.get())
                 .param(&applied_modes);
         }
+        if snomask_changed {
+            ctx.rb
+                .reply(rpl::SNOMASKIS)
+                .fmt_param(client.snomask)
+                .trailing_param(lines::SERVER_NOTICE_MASK);
+        }
 
         Ok(())
     }
@@ -743,9 +897,21 @@ impl super::StateInner {
             .message(issuer.full_name(), Command::Nick)
             .param(nick.get());
 
+        let old_nick = issuer.nick().to_owned();
         issuer.set_nick(nick.get());
         ReplyBuffer::set_nick(nick.get());
 
+        let issuer = &self.clients[ctx.id];
+        self.send_server_notice(
+            snomask::NICK,
+            format_args!(
+                "Nick change: {} -> {} ({}@{})",
+                old_nick,
+                issuer.nick(),
+                issuer.user(),
+                issuer.host(),
+            ),
+        );
         self.send_notification(ctx.id, nick_response, |_, _| true);
 
         Ok(())
@@ -753,21 +919,90 @@ impl super::StateInner {
 
     // OPER
 
-    pub fn cmd_oper(&mut self, ctx: CommandContext<'_>, args: data::req::Oper<'_>) -> Result {
-        if !self
-            .opers
-            .iter()
-            .any(|o| o.name == args.name && o.password == args.password)
-        {
+    /// `password` has been verified by `State::handle_message`, without holding the lock.
+    pub fn cmd_oper(
+        &mut self,
+        ctx: CommandContext<'_>,
+        args: data::req::Oper<'_>,
+        password: Option<VerifiedPassword>,
+    ) -> Result {
+        let client = &self.clients[ctx.id];
+
+        let oper = match self.opers.iter().find(|o| o.name == args.name) {
+            Some(oper) => oper,
+            None => {
+                log::debug!("{}:     Unknown oper name", ctx.id);
+                self.audit(ctx.id, "OPER", args.name, false, "unknown oper name");
+                ctx.rb
+                    .reply(rpl::ERR_NOOPERHOST)
+                    .trailing_param(lines::NO_OPER_HOST);
+                return Err(());
+            }
+        };
+
+        let user_host = format!("{}@{}", client.user(), client.host());
+        let host_matches = oper.hosts.is_empty()
+            || oper
+                .hosts
+                .iter()
+                .any(|mask| util::match_mask(mask, &user_host));
+        let fingerprint_matches = match &oper.fingerprint {
+            Some(fingerprint) => client.certfp() == Some(fingerprint.as_str()),
+            None => true,
+        };
+        if !host_matches || !fingerprint_matches {
+            log::debug!("{}:     Host or certificate mismatch", ctx.id);
+            self.audit(
+                ctx.id,
+                "OPER",
+                args.name,
+                false,
+                "host or certificate mismatch",
+            );
+            ctx.rb
+                .reply(rpl::ERR_NOOPERHOST)
+                .trailing_param(lines::NO_OPER_HOST);
+            return Err(());
+        }
+
+        // The OPER block might have been changed by a rehash since the password was verified.
+        let password_matches = match password {
+            Some(password) => password.matches && password.hash == oper.password,
+            None => false,
+        };
+        if !password_matches {
             log::debug!("{}:     Password mismatch", ctx.id);
+            self.audit(ctx.id, "OPER", args.name, false, "password mismatch");
             ctx.rb
                 .reply(rpl::ERR_PASSWDMISMATCH)
                 .trailing_param(lines::PASSWORD_MISMATCH);
             return Err(());
         }
 
+        log::info!(
+            "{}: {} is now an operator of class {:?}",
+            ctx.id,
+            client.full_name(),
+            oper.class.name,
+        );
+        self.send_server_notice(
+            snomask::OPER,
+            format_args!(
+                "{} ({}@{}) is now an operator of class {}",
+                client.nick(),
+                client.user(),
+                client.host(),
+                oper.class.name,
+            ),
+        );
+        let details = format!("class {}", oper.class.name);
+        self.audit(ctx.id, "OPER", args.name, true, &details);
+
+        let class = oper.class.clone();
         let client = &mut self.clients[ctx.id];
         client.operator = true;
+        client.oper_class = Some(class);
+        client.update_limits();
 
         ctx.rb.lr_batch_begin();
         ctx.rb
@@ -784,8 +1019,6 @@ impl super::StateInner {
     // PART
 
     pub fn cmd_part(&mut self, ctx: CommandContext<'_>, args: data::req::Part<'_>) -> Result {
-        let issuer = &self.clients[ctx.id];
-
         let mut res = Ok(());
 
         for channel_name in args.from.iter() {
@@ -813,8 +1046,10 @@ impl super::StateInner {
                 res = Err(());
                 continue;
             }
+            self.clients[ctx.id].channels.remove(channel_name.u());
+            let issuer = &self.clients[ctx.id];
 
-            if channel.members.is_empty() {
+            if channel.members.is_empty() && !channel.permanent {
                 self.channels.remove(channel_name.u());
             } else {
                 let mut part_notice = Buffer::with_capacity(512);
@@ -846,13 +1081,16 @@ impl super::StateInner {
     }
 
     pub fn cmd_part_all(&mut self, ctx: CommandContext<'_>) -> Result {
+        let channel_names = std::mem::take(&mut self.clients[ctx.id].channels);
         let clients = &self.clients;
         let issuer = &clients[ctx.id];
 
-        self.channels.retain(|channel_name, channel| {
-            if channel.members.remove(&ctx.id).is_none() {
-                return true;
-            }
+        for channel_name in channel_names {
+            let channel = match self.channels.get_mut(&channel_name) {
+                Some(channel) => channel,
+                None => continue,
+            };
+            channel.members.remove(&ctx.id);
 
             ctx.rb.lr_batch_begin();
             ctx.rb
@@ -860,8 +1098,9 @@ impl super::StateInner {
                 .param(channel_name.get())
                 .trailing_param(lines::PART_ALL);
 
-            let is_not_empty = !channel.members.is_empty();
-            if is_not_empty {
+            if channel.members.is_empty() && !channel.permanent {
+                self.channels.remove(&channel_name);
+            } else {
                 let mut part_notice = Buffer::with_capacity(512);
 
                 part_notice
@@ -875,9 +1114,7 @@ impl super::StateInner {
                     clients[*member].send(part_notice.clone());
                 }
             }
-
-            is_not_empty
-        });
+        }
 
         Ok(())
     }
@@ -894,7 +1131,7 @@ impl super::StateInner {
 
     // PING
 
-    pub fn cmd_ping(&mut self, ctx: CommandContext<'_>, payload: &str) -> Result {
+    pub fn cmd_ping(&self, ctx: CommandContext<'_>, payload: &str) -> Result {
         ctx.rb
             .prefixed_message(Command::Pong)
             .trailing_param(payload);
@@ -903,8 +1140,13 @@ impl super::StateInner {
 
     // PONG
 
-    pub fn cmd_pong(&mut self, _: CommandContext<'_>, _: &str) -> Result {
-        Ok(())
+    pub fn cmd_pong(&mut self, ctx: CommandContext<'_>, payload: &str) -> Result {
+        if self.clients[ctx.id].check_pong(payload) {
+            Ok(())
+        } else {
+            log::debug!("{}:     Wrong PING cookie", ctx.id);
+            Err(())
+        }
     }
 
     // QUIT
@@ -918,12 +1160,19 @@ impl super::StateInner {
 
     // REHASH
 
-    pub fn cmd_rehash(&self, ctx: CommandContext<'_>) -> Result {
-        if self.clients[ctx.id].operator {
+    pub fn cmd_rehash(&mut self, ctx: CommandContext<'_>) -> Result {
+        let client = &self.clients[ctx.id];
+        if client.has_privilege(Privilege::Rehash) {
+            self.send_server_notice(
+                snomask::REHASH,
+                format_args!("{} is rehashing the server configuration", client.nick(),),
+            );
+            self.audit(ctx.id, "REHASH", "", true, "");
             ctx.rb
                 .reply(rpl::REHASHING)
                 .param("--")
                 .trailing_param(lines::REHASHING);
+            self.rehash_requests.push(ctx.id);
             self.rehash.notify_one();
             Ok(())
         } else {
@@ -934,6 +1183,176 @@ impl super::StateInner {
         }
     }
 
+    // UPGRADE
+
+    pub fn cmd_upgrade(&self, ctx: CommandContext<'_>) -> Result {
+        let client = &self.clients[ctx.id];
+        if client.has_privilege(Privilege::Upgrade) {
+            self.send_server_notice(
+                snomask::REHASH,
+                format_args!("{} is upgrading the server", client.nick()),
+            );
+            self.audit(ctx.id, "UPGRADE", "", true, "");
+            ctx.rb
+                .reply(Command::Notice)
+                .trailing_param(lines::UPGRADING);
+            self.upgrade.notify_one();
+            Ok(())
+        } else {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            Err(())
+        }
+    }
+
+    // STATS
+
+    pub fn cmd_stats(&self, ctx: CommandContext<'_>, query: &str) -> Result {
+        let letter = match query.chars().next() {
+            Some(letter) => letter,
+            None => {
+                ctx.rb
+                    .reply(rpl::ENDOFSTATS)
+                    .param("*")
+                    .trailing_param(lines::END_OF_STATS);
+                return Ok(());
+            }
+        };
+
+        // The privilege needed for each letter, `None` for public letters.
+        let privilege = match letter {
+            'l' => Some(Privilege::SeeHidden),
+            'd' | 'k' | 'm' | 'o' | 'P' => Some(Privilege::Stats),
+            _ => None,
+        };
+        if matches!(privilege, Some(p) if !self.clients[ctx.id].has_privilege(p)) {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            ctx.rb
+                .reply(rpl::ENDOFSTATS)
+                .fmt_param(letter)
+                .trailing_param(lines::END_OF_STATS);
+            return Err(());
+        }
+
+        match letter {
+            'u' => {
+                let uptime = self.started_at.elapsed().as_secs();
+                ctx.rb
+                    .reply(rpl::STATSUPTIME)
+                    .fmt_trailing_param(lines_stats_uptime!(
+                        uptime / 86400,
+                        uptime / 3600 % 24,
+                        uptime / 60 % 60,
+                        uptime % 60
+                    ));
+            }
+            'm' => {
+                let mut counts: Vec<_> = self
+                    .command_counts
+                    .iter()
+                    .map(|(command, count)| (*command, count.load(Ordering::Relaxed)))
+                    .filter(|(_, count)| *count != 0)
+                    .collect();
+                counts.sort_unstable();
+                for (command, count) in counts {
+                    ctx.rb
+                        .reply(rpl::STATSCOMMANDS)
+                        .param(command)
+                        .fmt_param(count)
+                        .param("0")
+                        .param("0");
+                }
+            }
+            'l' => {
+                let now = util::time();
+                for (_, client) in &self.clients {
+                    let traffic = client.traffic();
+                    ctx.rb
+                        .reply(rpl::STATSLINKINFO)
+                        .fmt_param(format_args!(
+                            "{}[{}@{}]",
+                            client.nick(),
+                            client.user(),
+                            client.host(),
+                        ))
+                        .fmt_param(traffic.sendq.load(Ordering::Relaxed))
+                        .fmt_param(traffic.sent_messages.load(Ordering::Relaxed))
+                        .fmt_param(traffic.sent_bytes.load(Ordering::Relaxed) / 1024)
+                        .fmt_param(traffic.received_messages.load(Ordering::Relaxed))
+                        .fmt_param(traffic.received_bytes.load(Ordering::Relaxed) / 1024)
+                        .fmt_param(now.saturating_sub(client.signon_time()));
+                }
+            }
+            'o' => {
+                for oper in &self.opers {
+                    let any_host = [String::from("*@*")];
+                    let hosts = if oper.hosts.is_empty() {
+                        &any_host[..]
+                    } else {
+                        &oper.hosts
+                    };
+                    for host in hosts {
+                        ctx.rb
+                            .reply(rpl::STATSOLINE)
+                            .param("O")
+                            .param(host)
+                            .param("*")
+                            .param(&oper.name)
+                            .param("0")
+                            .param(&oper.class.name);
+                    }
+                }
+            }
+            'k' => {
+                for ban in self.klines.iter() {
+                    let (user, host) = match ban.mask.split_once('@') {
+                        Some((user, host)) => (user, host),
+                        None => ("*", &ban.mask[..]),
+                    };
+                    ctx.rb
+                        .reply(rpl::STATSKLINE)
+                        .param("K")
+                        .param(host)
+                        .param("*")
+                        .param(user)
+                        .trailing_param(&ban.reason);
+                }
+            }
+            'd' => {
+                for ban in self.dlines.iter() {
+                    ctx.rb
+                        .reply(rpl::STATSDLINE)
+                        .param("D")
+                        .param(&ban.mask)
+                        .trailing_param(&ban.reason);
+                }
+            }
+            'P' => {
+                for (address, tls) in &self.bindings {
+                    let msg = ctx.rb.reply(rpl::STATSPLINE).param("P");
+                    let msg = match address {
+                        Address::Tcp(address) => {
+                            msg.fmt_param(address.port()).fmt_param(address.ip())
+                        }
+                        Address::Unix(path) => msg.param("unix").fmt_param(path.display()),
+                    };
+                    msg.param(if *tls { "TLS" } else { "plain" });
+                }
+            }
+            _ => {}
+        }
+
+        ctx.rb
+            .reply(rpl::ENDOFSTATS)
+            .fmt_param(letter)
+            .trailing_param(lines::END_OF_STATS);
+
+        Ok(())
+    }
+
     // TIME
 
     pub fn cmd_time(&self, ctx: CommandContext<'_>) -> Result {
@@ -967,6 +1386,17 @@ impl super::StateInner {
         &mut self,
         ctx: CommandContext<'_>,
         args: data::req::TopicSet<'_>,
+    ) -> Result {
+        self.topic_set(ctx, args, false)
+    }
+
+    /// Changes the topic of a channel.  When `force` is true, the issuer doesn't need to be in
+    /// the channel nor to be a channel operator.
+    pub(super) fn topic_set(
+        &mut self,
+        ctx: CommandContext<'_>,
+        args: data::req::TopicSet<'_>,
+        force: bool,
     ) -> Result {
         let channel = match self.channels.get_mut(args.channel.u()) {
             Some(channel) => channel,
@@ -980,15 +1410,16 @@ impl super::StateInner {
             }
         };
 
-        let member_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
-
-        if !member_modes.operator && channel.topic_restricted {
-            log::debug!("{}:     not operator", ctx.id);
-            ctx.rb
-                .reply(rpl::ERR_CHANOPRIVSNEEDED)
-                .param(args.channel.get())
-                .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
-            return Err(());
+        if !force {
+            let member_modes = find_member(ctx.id, ctx.rb, channel, args.channel)?;
+            if !member_modes.is_at_least_op() && channel.topic_restricted {
+                log::debug!("{}:     not operator", ctx.id);
+                ctx.rb
+                    .reply(rpl::ERR_CHANOPRIVSNEEDED)
+                    .param(args.channel.get())
+                    .trailing_param(lines::CHAN_O_PRIVS_NEEDED);
+                return Err(());
+            }
         }
 
         let client = &self.clients[ctx.id];
@@ -1003,6 +1434,9 @@ impl super::StateInner {
                 time: util::time(),
             })
         };
+        if channel.permanent {
+            self.persist.notify_one();
+        }
 
         let mut topic_notice = Buffer::with_capacity(512);
         topic_notice
@@ -1055,6 +1489,28 @@ impl super::StateInner {
         Ok(())
     }
 
+    // WALLOPS
+
+    pub fn cmd_wallops(&self, mut ctx: CommandContext<'_>, content: &str) -> Result {
+        if !self.clients[ctx.id].has_privilege(Privilege::Broadcast) {
+            ctx.rb
+                .reply(rpl::ERR_NOPRIVILEDGES)
+                .trailing_param(lines::NO_PRIVILEDGES);
+            return Err(());
+        }
+
+        self.audit(ctx.id, "WALLOPS", "", true, content);
+        let mut msg = self.message_build(&mut ctx, Command::WallOps, None, Some(content));
+
+        for (target_id, target) in &self.clients {
+            if target_id != ctx.id && target.wallops {
+                target.send(msg.get(&target.cap_enabled));
+            }
+        }
+
+        Ok(())
+    }
+
     // WHO
 
     fn who_line(
@@ -1097,6 +1553,7 @@ impl super::StateInner {
         filter: data::req::WhoFilter,
     ) {
         let target = &self.clients[target_id];
+        let sees_hidden = issuer.has_privilege(Privilege::SeeHidden);
 
         if (filter.operator && !target.operator) || !target.is_registered() {
             // Either the filter doesn't match, or target is not registered.
@@ -1107,13 +1564,16 @@ impl super::StateInner {
         let mut channel_name = None;
         let mut member_modes = Default::default();
 
-        // TODO cache channels a client has joined.
-        for (name, channel) in &self.channels {
+        for name in &target.channels {
+            let channel = match self.channels.get(name) {
+                Some(channel) => channel,
+                None => continue,
+            };
             let this_member = match channel.members.get(&target_id) {
                 Some(member_modes) => *member_modes,
                 None => continue,
             };
-            if !issuer.operator
+            if !sees_hidden
                 && (target.invisible || channel.secret)
                 && !channel.members.contains_key(&issuer_id)
             {
@@ -1123,8 +1583,8 @@ impl super::StateInner {
                 // existence).  If issuer is in the channel, then it is fine for ellidri to
                 // show it with the line because issuer knows the existence of both the channel
                 // and target.
-                // IRCops bypass these restrictions, they can see secret channels and invisible
-                // users.
+                // IRCops with the see-hidden privilege bypass these restrictions, they can see
+                // secret channels and invisible users.
                 continue;
             }
 
@@ -1133,8 +1593,7 @@ impl super::StateInner {
 
             break;
         }
-        if !target.invisible || target_id == issuer_id || channel_name.is_some() || issuer.operator
-        {
+        if !target.invisible || target_id == issuer_id || channel_name.is_some() || sees_hidden {
             // The client can see the target.
             let channel_name = channel_name.map_or("*", UniCase::get);
             self.who_line(rb, issuer, target, channel_name, member_modes);
@@ -1143,7 +1602,7 @@ impl super::StateInner {
 
     pub fn cmd_who_all(&self, mut ctx: CommandContext<'_>, filter: data::req::WhoFilter) -> Result {
         let issuer = &self.clients[ctx.id];
-        if !issuer.operator {
+        if !issuer.has_privilege(Privilege::SeeHidden) {
             ctx.rb
                 .reply(rpl::ENDOFWHO)
                 .param("*")
@@ -1180,7 +1639,8 @@ impl super::StateInner {
             let issuer = &self.clients[ctx.id];
 
             let in_channel = channel.members.contains_key(&ctx.id);
-            if channel.secret && !in_channel && !issuer.operator {
+            let sees_hidden = issuer.has_privilege(Privilege::SeeHidden);
+            if channel.secret && !in_channel && !sees_hidden {
                 break;
             }
 
@@ -1188,7 +1648,7 @@ impl super::StateInner {
             for (member, modes) in &channel.members {
                 let target = &self.clients[*member];
                 if (args.filter.operator && !target.operator)
-                    || (!issuer.operator && target.invisible && !in_channel && *member != ctx.id)
+                    || (!sees_hidden && target.invisible && !in_channel && *member != ctx.id)
                 {
                     // Either the target isn't an operator while the client filtered for
                     // operators, or the client cannot see the member.
@@ -1212,7 +1672,7 @@ impl super::StateInner {
         args: data::req::WhoMask<'_>,
     ) -> Result {
         let issuer = &self.clients[ctx.id];
-        if !issuer.operator {
+        if !issuer.has_privilege(Privilege::SeeHidden) {
             ctx.rb
                 .reply(rpl::ENDOFWHO)
                 .param(args.mask.get())
@@ -1294,6 +1754,32 @@ impl super::StateInner {
             .param(target_client.nick())
             .param(&self.domain)
             .trailing_param(&self.org_name);
+        if self.clients[ctx.id].has_privilege(Privilege::SeeRealHosts) {
+            // Clients of Unix domain sockets have no IP address to show.
+            let ip = target_client
+                .ip()
+                .map(|ip| format!(" {}", ip))
+                .unwrap_or_default();
+            ctx.rb
+                .reply(rpl::WHOISHOST)
+                .param(target_client.nick())
+                .fmt_trailing_param(format_args!(
+                    "{} *@{}{}",
+                    lines::WHOIS_HOST,
+                    target_client.host(),
+                    ip,
+                ));
+        }
+        if self.clients[ctx.id].operator {
+            ctx.rb
+                .reply(rpl::WHOISSPECIAL)
+                .param(target_client.nick())
+                .fmt_trailing_param(format_args!(
+                    "{} {}",
+                    lines::WHOIS_CLASS,
+                    target_client.class().name,
+                ));
+        }
         ctx.rb
             .reply(rpl::WHOISIDLE)
             .param(target_client.nick())
@@ -1319,85 +1805,89 @@ impl super::StateInner {
     // PRIVMSG
     // NOTICE
     // TAGMSG
-    // TODO message_mask
 
-    fn message_build(
-        &self,
+    fn message_build<'a>(
+        &'a self,
         ctx: &mut CommandContext<'_>,
         command: Command,
-        target: &str,
-        content: Option<&str>,
-    ) -> MessageQueueItem {
+        target: Option<&'a str>,
+        content: Option<&'a str>,
+    ) -> MessageForms<'a> {
         let issuer = &self.clients[ctx.id];
-
-        let msgid = util::new_message_id();
-        let time = util::time_precise();
+        let forms = MessageForms {
+            client_tags: ctx.client_tags.to_owned(),
+            msgid: util::new_message_id(),
+            time: util::time_precise(),
+            account: issuer.account(),
+            prefix: issuer.full_name(),
+            command,
+            target,
+            content,
+            cache: Default::default(),
+        };
 
         if issuer.cap_enabled.echo_message {
-            if issuer.cap_enabled.has_message_tags() {
-                let mut msg = ctx
-                    .rb
-                    .tagged_message(ctx.client_tags)
-                    .tag("msgid", Some(&msgid))
-                    .tag("time", Some(&time));
-
-                if let Some(account) = issuer.account() {
-                    msg = msg.tag("account", Some(account));
-                }
+            let key = MessageForms::key(&issuer.cap_enabled);
+            let msg = ctx.rb.tagged_message(forms.client_tags(key));
+            forms.write(msg, key);
+        }
 
-                let msg = msg
-                    .prefixed_command(issuer.full_name(), command)
-                    .param(target);
+        forms
+    }
 
-                if let Some(content) = content {
-                    msg.trailing_param(content);
-                }
-            } else {
-                let msg = ctx.rb.message(issuer.full_name(), command).param(target);
-                if let Some(content) = content {
-                    msg.trailing_param(content);
-                }
+    /// Sends a message to all users of the server, when the server mask `args.to` matches the
+    /// domain of this server.
+    pub fn cmd_message_all(
+        &mut self,
+        mut ctx: CommandContext<'_>,
+        args: data::req::MessageAll<'_>,
+    ) -> Result {
+        if !self.clients[ctx.id].has_privilege(Privilege::Broadcast) {
+            if args.feedback {
+                ctx.rb
+                    .reply(rpl::ERR_NOPRIVILEDGES)
+                    .trailing_param(lines::NO_PRIVILEDGES);
             }
+            return Err(());
         }
 
-        let mut buf = Buffer::with_capacity(512);
-        let mut tag_len = 0;
-        {
-            let mut msg = buf
-                .tagged_message(ctx.client_tags)
-                .tag("msgid", Some(&msgid))
-                .tag("time", Some(&time));
-
-            if let Some(account) = issuer.account() {
-                msg = msg.tag("account", Some(account));
+        if !util::match_mask(&args.to[1..], &self.domain) {
+            log::debug!("{}:     mask doesn't match", ctx.id);
+            if args.feedback {
+                ctx.rb
+                    .reply(rpl::ERR_BADMASK)
+                    .param(args.to)
+                    .trailing_param(lines::BAD_MASK);
             }
+            return Err(());
+        }
 
-            let msg = msg
-                .save_tag_len(&mut tag_len)
-                .prefixed_command(issuer.full_name(), command)
-                .param(target);
-
-            if let Some(content) = content {
-                msg.trailing_param(content);
+        self.audit(
+            ctx.id,
+            args.command.as_str(),
+            args.to,
+            true,
+            args.content.unwrap_or(""),
+        );
+        let mut msg = self.message_build(&mut ctx, args.command, Some(args.to), args.content);
+
+        for (target_id, target) in &self.clients {
+            if target_id == ctx.id
+                || !target.is_registered()
+                || !target.cap_enabled.is_capable_of(args.command)
+            {
+                continue;
             }
+            target.send(msg.get(&target.cap_enabled));
         }
 
-        let mut msg = MessageQueueItem::from(buf);
-        msg.start = tag_len;
-        msg
-    }
+        self.clients[ctx.id].update_idle_time();
 
-    pub fn cmd_message_all(
-        &self,
-        _ctx: CommandContext<'_>,
-        _args: data::req::MessageAll<'_>,
-    ) -> Result {
-        // TODO cmd_message_all
-        todo!()
+        Ok(())
     }
 
     pub fn cmd_message_channel(
-        &mut self,
+        &self,
         mut ctx: CommandContext<'_>,
         args: data::req::MessageChannel<'_>,
     ) -> Result {
@@ -1427,8 +1917,46 @@ impl super::StateInner {
             }
             return Err(());
         }
+        if self.clients[ctx.id].account().is_none() && !channel.can_talk_anonymously(ctx.id) {
+            log::debug!("{}:     not logged in", ctx.id);
+            if args.feedback {
+                ctx.rb
+                    .reply(rpl::ERR_CANNOTSENDTOCHAN)
+                    .param(args.to.get())
+                    .trailing_param(lines::NEED_REGGED_NICK);
+            }
+            return Err(());
+        }
 
-        let msg = self.message_build(&mut ctx, args.command, args.to.get(), args.content);
+        let mut content = args.content.map(Cow::Borrowed);
+        if let Some(text) = args.content {
+            let is_ctcp = matches!(format::ctcp_command(text), Some(c) if c != "ACTION");
+            let blocked = if args.command == Command::Notice && channel.no_notice {
+                Some(lines::CANNOT_SEND_NOTICE)
+            } else if channel.no_ctcp && is_ctcp {
+                Some(lines::CANNOT_SEND_CTCP)
+            } else if channel.no_colors && format::has_formatting(text) {
+                Some(lines::CANNOT_SEND_COLORS)
+            } else {
+                None
+            };
+            if let Some(line) = blocked {
+                log::debug!("{}:     content filtered by channel", ctx.id);
+                if args.feedback {
+                    ctx.rb
+                        .reply(rpl::ERR_CANNOTSENDTOCHAN)
+                        .param(args.to.get())
+                        .trailing_param(line);
+                }
+                return Err(());
+            }
+            if channel.strip_colors {
+                content = Some(format::strip_formatting(text));
+            }
+        }
+
+        let target = Some(args.to.get());
+        let mut msg = self.message_build(&mut ctx, args.command, target, content.as_deref());
 
         for target_id in channel.members.keys() {
             if *target_id == ctx.id {
@@ -1441,16 +1969,16 @@ impl super::StateInner {
             if !target.cap_enabled.is_capable_of(args.command) {
                 continue;
             }
-            target.send(msg.clone());
+            target.send(msg.get(&target.cap_enabled));
         }
 
-        self.clients.get_mut(ctx.id).unwrap().update_idle_time();
+        self.clients[ctx.id].update_idle_time();
 
         Ok(())
     }
 
     pub fn cmd_message_user(
-        &mut self,
+        &self,
         mut ctx: CommandContext<'_>,
         args: data::req::MessageUser<'_>,
     ) -> Result {
@@ -1460,9 +1988,9 @@ impl super::StateInner {
             return Err(());
         }
 
-        let msg = self.message_build(&mut ctx, args.command, args.to.get(), args.content);
+        let mut msg = self.message_build(&mut ctx, args.command, Some(args.to.get()), args.content);
 
-        target.send(msg);
+        target.send(msg.get(&target.cap_enabled));
 
         if let Some(ref away_message) = target.away_message {
             ctx.rb
@@ -1471,8 +1999,686 @@ impl super::StateInner {
                 .trailing_param(away_message);
         }
 
-        self.clients.get_mut(ctx.id).unwrap().update_idle_time();
+        self.clients[ctx.id].update_idle_time();
 
         Ok(())
     }
 }
+
+/// The wire forms of a PRIVMSG, NOTICE, TAGMSG or WALLOPS.
+///
+/// Recipients get different tags depending on their capabilities.  Each form is built the first
+/// time a recipient needs it, and then shared with the other recipients that have the same
+/// capabilities.
+struct MessageForms<'a> {
+    client_tags: String,
+    msgid: String,
+    time: String,
+    account: Option<&'a str>,
+    prefix: &'a str,
+    command: Command,
+    target: Option<&'a str>,
+    content: Option<&'a str>,
+    cache: [Option<MessageQueueItem>; 8],
+}
+
+impl MessageForms<'_> {
+    const MESSAGE_TAGS: usize = 1;
+    const SERVER_TIME: usize = 2;
+    const ACCOUNT_TAG: usize = 4;
+
+    /// Returns the index of the form that fits the given capabilities.
+    fn key(caps: &data::Capabilities) -> usize {
+        let mut key = 0;
+        if caps.message_tags {
+            key |= Self::MESSAGE_TAGS;
+        }
+        if caps.server_time {
+            key |= Self::SERVER_TIME;
+        }
+        if caps.account_tag {
+            key |= Self::ACCOUNT_TAG;
+        }
+        key
+    }
+
+    /// Client-only tags are only relayed to recipients that have enabled message-tags.
+    fn client_tags(&self, key: usize) -> &str {
+        if key & Self::MESSAGE_TAGS != 0 {
+            &self.client_tags
+        } else {
+            ""
+        }
+    }
+
+    fn write(&self, mut msg: TagBuffer<'_>, key: usize) {
+        if key & Self::MESSAGE_TAGS != 0 {
+            msg = msg.tag("msgid", Some(&self.msgid));
+        }
+        if key & Self::SERVER_TIME != 0 {
+            msg = msg.tag("time", Some(&self.time));
+        }
+        if key & Self::ACCOUNT_TAG != 0 {
+            if let Some(account) = self.account {
+                msg = msg.tag("account", Some(account));
+            }
+        }
+
+        let mut msg = msg.prefixed_command(self.prefix, self.command);
+        if let Some(target) = self.target {
+            msg = msg.param(target);
+        }
+        if let Some(content) = self.content {
+            msg.trailing_param(content);
+        }
+    }
+
+    /// Returns the form of the message for a recipient with the given capabilities.
+    fn get(&mut self, caps: &data::Capabilities) -> MessageQueueItem {
+        let key = Self::key(caps);
+        if let Some(ref msg) = self.cache[key] {
+            return msg.clone();
+        }
+
+        let mut buf = Buffer::with_capacity(512);
+        self.write(buf.tagged_message(self.client_tags(key)), key);
+        let msg = MessageQueueItem::from(buf);
+        self.cache[key] = Some(msg.clone());
+        msg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test::*;
+    use super::super::State;
+    use crate::config::{self, Privilege};
+    use crate::lines;
+    use ellidri_tokens::{assert_msg, rpl, Command};
+    use ellidri_unicase::u;
+
+    #[tokio::test]
+    async fn test_oper() {
+        let mut config = oper_config(Vec::new());
+        config.opers[0].password = bcrypt::hash("hunter2", 4).unwrap();
+        config.opers.push(config::Oper {
+            name: String::from("remote"),
+            password: String::from("hunter2"),
+            hosts: vec![String::from("*@192.0.2.*")],
+            ..config::Oper::default()
+        });
+        let state = state_with(config).await;
+        let (alice, mut queue) = add_registered_client(&state, "alice").await;
+
+        // Unknown names and host mismatches get the same reply.
+        let mut res = String::new();
+        handle_message(&state, alice, "OPER nobody hunter2").await;
+        handle_message(&state, alice, "OPER remote hunter2").await;
+        handle_message(&state, alice, "OPER admin hunter3").await;
+        collect(&mut res, &mut queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_NOOPERHOST),
+                    &["alice", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_NOOPERHOST),
+                    &["alice", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_PASSWDMISMATCH),
+                    &["alice", ""],
+                ),
+            ],
+        );
+
+        let mut res = String::new();
+        handle_message(&state, alice, "OPER admin hunter2").await;
+        collect(&mut res, &mut queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (Some("ellidri.test"), Ok(Command::Mode), &["alice", "+o"]),
+                (Some("ellidri.test"), Err(rpl::YOUREOPER), &["alice", ""]),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let mut config = oper_config(vec![Privilege::Stats]);
+        config.opers.push(config::Oper {
+            name: String::from("helper"),
+            password: String::from("hunter2"),
+            class: config::OperClass {
+                name: String::from("helper"),
+                privileges: vec![Privilege::SeeHidden],
+            },
+            ..config::Oper::default()
+        });
+        let state = state_with(config).await;
+        let address = config::Address::Tcp(([127, 0, 0, 1], 6667).into());
+        state.set_bindings(vec![(address, false)]).await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        let (carol, mut carol_queue) = add_registered_client(&state, "carol").await;
+        handle_message(&state, bob, "OPER admin hunter2").await;
+        handle_message(&state, carol, "OPER helper hunter2").await;
+        flush(&mut bob_queue).await;
+        flush(&mut carol_queue).await;
+
+        // Only STATS u is public.
+        let mut res = String::new();
+        handle_message(&state, alice, "STATS u").await;
+        handle_message(&state, alice, "STATS m").await;
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (Some("ellidri.test"), Err(rpl::STATSUPTIME), &["alice", ""]),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["alice", "u", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_NOPRIVILEDGES),
+                    &["alice", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["alice", "m", ""],
+                ),
+            ],
+        );
+
+        let mut res = String::new();
+        handle_message(&state, bob, "STATS o").await;
+        handle_message(&state, bob, "STATS P").await;
+        handle_message(&state, bob, "STATS l").await;
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSOLINE),
+                    &["bob", "O", "*@*", "*", "admin", "0", "test"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSOLINE),
+                    &["bob", "O", "*@*", "*", "helper", "0", "helper"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["bob", "o", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSPLINE),
+                    &["bob", "P", "6667", "127.0.0.1", "plain"],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["bob", "P", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_NOPRIVILEDGES),
+                    &["bob", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["bob", "l", ""],
+                ),
+            ],
+        );
+
+        let mut res = String::new();
+        handle_message(&state, carol, "STATS l").await;
+        handle_message(&state, carol, "STATS m").await;
+        collect(&mut res, &mut carol_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSLINKINFO),
+                    &["carol", "alice[X@127.0.0.1]", "", "", "", "", "", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSLINKINFO),
+                    &["carol", "bob[X@127.0.0.1]", "", "", "", "", "", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::STATSLINKINFO),
+                    &["carol", "carol[X@127.0.0.1]", "", "", "", "", "", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["carol", "l", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_NOPRIVILEDGES),
+                    &["carol", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ENDOFSTATS),
+                    &["carol", "m", ""],
+                ),
+            ],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mode_oper_only_and_permanent() {
+        let state = state_with(oper_config(Vec::new())).await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        handle_message(&state, alice, "JOIN #chan").await;
+        handle_message(&state, bob, "JOIN #chan").await;
+        flush(&mut alice_queue).await;
+        flush(&mut bob_queue).await;
+
+        // Channel operators that are not IRC operators cannot set +O and +P.
+        let mut res = String::new();
+        handle_message(&state, alice, "MODE #chan +O").await;
+        handle_message(&state, alice, "MODE #chan +P").await;
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_CHANOPRIVSNEEDED),
+                    &["alice", "#chan", ""],
+                ),
+                (
+                    Some("ellidri.test"),
+                    Err(rpl::ERR_CHANOPRIVSNEEDED),
+                    &["alice", "#chan", ""],
+                ),
+            ],
+        );
+
+        // Neither can IRC operators that are not channel operators.
+        handle_message(&state, bob, "OPER admin hunter2").await;
+        flush(&mut bob_queue).await;
+        let mut res = String::new();
+        handle_message(&state, bob, "MODE #chan +P").await;
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_CHANOPRIVSNEEDED),
+                &["bob", "#chan", ""],
+            )],
+        );
+
+        // IRC operators that are channel operators can.
+        handle_message(&state, alice, "OPER admin hunter2").await;
+        flush(&mut alice_queue).await;
+        let mut res = String::new();
+        handle_message(&state, alice, "MODE #chan +OP").await;
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("alice!~X@127.0.0.1"),
+                Ok(Command::Mode),
+                &["#chan", "+OP"],
+            )],
+        );
+    }
+
+    /// Returns the symbols of all modes `id` has in `#chan`, or `None` if they are not a member.
+    async fn member_symbols(state: &State, id: usize) -> Option<String> {
+        let state = state.0.read().await;
+        let channel = state.channels.get(u("#chan"))?;
+        let mut symbols = String::new();
+        channel.members.get(&id)?.all_symbols(&mut symbols);
+        Some(symbols)
+    }
+
+    #[tokio::test]
+    async fn test_member_ranks() {
+        let state = state_with(simple_config()).await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        let (carol, mut carol_queue) = add_registered_client(&state, "carol").await;
+        handle_message(&state, alice, "JOIN #chan").await;
+        handle_message(&state, bob, "JOIN #chan").await;
+        handle_message(&state, carol, "JOIN #chan").await;
+        handle_message(&state, alice, "MODE #chan +o bob").await;
+        flush(&mut alice_queue).await;
+        flush(&mut bob_queue).await;
+        flush(&mut carol_queue).await;
+
+        // Operators cannot make anyone protected or founder.
+        let mut res = String::new();
+        handle_message(&state, bob, "MODE #chan +a carol").await;
+        handle_message(&state, bob, "MODE #chan +q bob").await;
+        collect(&mut res, &mut bob_queue).await;
+        let chanoprivsneeded = (
+            Some("ellidri.test"),
+            Err(rpl::ERR_CHANOPRIVSNEEDED),
+            &["bob", "#chan", ""][..],
+        );
+        assert_msgs(&res, &[chanoprivsneeded, chanoprivsneeded]);
+        assert_eq!(member_symbols(&state, bob).await.as_deref(), Some("@"));
+        assert_eq!(member_symbols(&state, carol).await.as_deref(), Some(""));
+
+        // Nor can they kick protected members.
+        handle_message(&state, alice, "MODE #chan +a carol").await;
+        flush(&mut bob_queue).await;
+        let mut res = String::new();
+        handle_message(&state, bob, "KICK #chan carol").await;
+        collect(&mut res, &mut bob_queue).await;
+        assert_msgs(&res, &[chanoprivsneeded]);
+        assert_eq!(member_symbols(&state, carol).await.as_deref(), Some("&"));
+
+        // Founders can share their rank, and step down.
+        handle_message(&state, alice, "MODE #chan +q carol").await;
+        assert_eq!(member_symbols(&state, carol).await.as_deref(), Some("~&"));
+        flush(&mut alice_queue).await;
+        let mut res = String::new();
+        handle_message(&state, alice, "MODE #chan -q alice").await;
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("alice!~X@127.0.0.1"),
+                Ok(Command::Mode),
+                &["#chan", "-q", "alice"],
+            )],
+        );
+        assert_eq!(member_symbols(&state, alice).await.as_deref(), Some("@"));
+
+        // Only the remaining founder can then act on protected members.
+        flush(&mut carol_queue).await;
+        let mut res = String::new();
+        handle_message(&state, alice, "KICK #chan carol").await;
+        handle_message(&state, carol, "KICK #chan bob").await;
+        collect(&mut res, &mut carol_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("carol!~X@127.0.0.1"),
+                Ok(Command::Kick),
+                &["#chan", "bob"],
+            )],
+        );
+        assert_eq!(member_symbols(&state, carol).await.as_deref(), Some("~&"));
+        assert_eq!(member_symbols(&state, bob).await, None);
+    }
+
+    /// Capabilities that change how PRIVMSGs are sent, combined by the bits of an index.
+    const FORM_CAPS: [&str; 4] = ["message-tags", "server-time", "account-tag", "echo-message"];
+
+    /// Returns the capabilities of `FORM_CAPS` picked by the bits of `i`.
+    fn form_caps(i: usize) -> Vec<&'static str> {
+        (0..FORM_CAPS.len())
+            .filter(|bit| i & 1 << bit != 0)
+            .map(|bit| FORM_CAPS[bit])
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_message_forms() {
+        let state = state_with(simple_config()).await;
+        let mut clients = Vec::new();
+        for i in 0..1 << FORM_CAPS.len() {
+            let caps = form_caps(i);
+            let (id, mut queue) = add_client(&state).await;
+            handle_message(&state, id, "CAP LS 302").await;
+            if !caps.is_empty() {
+                handle_message(&state, id, &format!("CAP REQ :{}", caps.join(" "))).await;
+            }
+            handle_message(&state, id, &format!("NICK user{}", i)).await;
+            handle_message(&state, id, "USER X X X X").await;
+            handle_message(&state, id, "CAP END").await;
+            handle_message(&state, id, "JOIN #chan").await;
+            flush(&mut queue).await;
+            clients.push((id, queue, caps));
+        }
+        for (_, queue, _) in &mut clients {
+            flush(queue).await;
+        }
+
+        for (id, _, _) in &clients {
+            handle_message(&state, *id, "@+example=1 PRIVMSG #chan :hello").await;
+        }
+
+        // The msgid of the message of each sender, shared by all recipients.
+        let mut msgids = vec![None; clients.len()];
+        for (i, (_, queue, caps)) in clients.iter_mut().enumerate() {
+            let mut res = String::new();
+            collect(&mut res, queue).await;
+            let mut expected_tags = Vec::new();
+            if caps.contains(&"message-tags") {
+                expected_tags.push("+example");
+                expected_tags.push("msgid");
+            }
+            if caps.contains(&"server-time") {
+                expected_tags.push("time");
+            }
+            let expected_senders: Vec<_> = (0..msgids.len())
+                .filter(|&sender| sender != i || caps.contains(&"echo-message"))
+                .collect();
+
+            let msgs: Vec<_> = messages(&res).collect();
+            assert_eq!(msgs.len(), expected_senders.len(), "user{}", i);
+            for (msg, sender) in msgs.iter().zip(expected_senders) {
+                let prefix = format!("user{}!~X@127.0.0.1", sender);
+                assert_msg(
+                    msg,
+                    Some(&prefix),
+                    Ok(Command::PrivMsg),
+                    &["#chan", "hello"],
+                );
+                let tags: Vec<_> = msg.tags().map(|tag| tag.key).collect();
+                assert_eq!(tags, expected_tags, "user{} from user{}", i, sender);
+                if let Some(msgid) = msg.tags().find(|tag| tag.key == "msgid") {
+                    let msgid = msgid.value.unwrap().to_owned();
+                    assert_eq!(msgids[sender].get_or_insert(msgid.clone()), &msgid);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_forms_cache() {
+        let mut forms = super::MessageForms {
+            client_tags: String::from("+example=1"),
+            msgid: String::from("id"),
+            time: String::from("now"),
+            account: Some("acc"),
+            prefix: "alice!~X@127.0.0.1",
+            command: Command::PrivMsg,
+            target: Some("#chan"),
+            content: Some("hello"),
+            cache: Default::default(),
+        };
+
+        // Forms are asked twice, the second time from the cache.
+        for i in (0..1 << FORM_CAPS.len()).chain(0..1 << FORM_CAPS.len()) {
+            let caps = form_caps(i);
+            let enabled = crate::data::Capabilities {
+                message_tags: caps.contains(&"message-tags"),
+                server_time: caps.contains(&"server-time"),
+                account_tag: caps.contains(&"account-tag"),
+                echo_message: caps.contains(&"echo-message"),
+                ..Default::default()
+            };
+
+            let mut tags = Vec::new();
+            if enabled.message_tags {
+                tags.push("+example=1");
+                tags.push("msgid=id");
+            }
+            if enabled.server_time {
+                tags.push("time=now");
+            }
+            if enabled.account_tag {
+                tags.push("account=acc");
+            }
+            let mut expected = String::new();
+            if !tags.is_empty() {
+                expected = format!("@{} ", tags.join(";"));
+            }
+            expected.push_str(":alice!~X@127.0.0.1 PRIVMSG #chan :hello\r\n");
+
+            let msg = forms.get(&enabled);
+            let msg: &str = msg.as_ref();
+            assert_eq!(msg, expected, "{:?}", caps);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts() {
+        let mut config = oper_config(vec![Privilege::Broadcast]);
+        config.opers.push(config::Oper {
+            name: String::from("helper"),
+            password: String::from("hunter2"),
+            class: config::OperClass {
+                name: String::from("helper"),
+                privileges: Vec::new(),
+            },
+            ..config::Oper::default()
+        });
+        let state = state_with(config).await;
+        let (admin, mut admin_queue) = add_registered_client(&state, "admin").await;
+        let (helper, mut helper_queue) = add_registered_client(&state, "helper").await;
+        let (alice, mut alice_queue) = add_registered_client(&state, "alice").await;
+        let (bob, mut bob_queue) = add_registered_client(&state, "bob").await;
+        let (_, mut unregistered_queue) = add_client(&state).await;
+        handle_message(&state, admin, "OPER admin hunter2").await;
+        handle_message(&state, admin, "MODE admin +s").await;
+        handle_message(&state, helper, "OPER helper hunter2").await;
+        handle_message(&state, helper, "MODE helper +s +g").await;
+        handle_message(&state, alice, "MODE alice +sw").await;
+        handle_message(&state, bob, "MODE bob +s").await;
+        for queue in &mut [
+            &mut admin_queue,
+            &mut helper_queue,
+            &mut alice_queue,
+            &mut bob_queue,
+            &mut unregistered_queue,
+        ] {
+            flush(queue).await;
+        }
+
+        // Server masks need the broadcast privilege, and must match the server name.
+        handle_message(&state, helper, "PRIVMSG $*.test :Hello").await;
+        handle_message(&state, helper, "NOTICE $*.test :Hello").await;
+        let mut res = String::new();
+        collect(&mut res, &mut helper_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_NOPRIVILEDGES),
+                &["helper", lines::NO_PRIVILEDGES],
+            )],
+        );
+        handle_message(&state, admin, "PRIVMSG $*.other :Hello").await;
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_BADMASK),
+                &["admin", "$*.other", lines::BAD_MASK],
+            )],
+        );
+
+        handle_message(&state, admin, "PRIVMSG $ellidri.* :Hello").await;
+        for queue in &mut [&mut helper_queue, &mut alice_queue, &mut bob_queue] {
+            let mut res = String::new();
+            collect(&mut res, queue).await;
+            assert_msgs(
+                &res,
+                &[(
+                    Some("admin!~X@127.0.0.1"),
+                    Ok(Command::PrivMsg),
+                    &["$ellidri.*", "Hello"],
+                )],
+            );
+        }
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        collect(&mut res, &mut unregistered_queue).await;
+        assert_eq!(res, "");
+
+        // WALLOPS reach the users with the +w mode.
+        handle_message(&state, helper, "WALLOPS :Hello").await;
+        handle_message(&state, admin, "WALLOPS :Hello").await;
+        let mut res = String::new();
+        collect(&mut res, &mut helper_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_NOPRIVILEDGES),
+                &["helper", lines::NO_PRIVILEDGES],
+            )],
+        );
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(Some("admin!~X@127.0.0.1"), Ok(Command::WallOps), &["Hello"])],
+        );
+        let mut res = String::new();
+        collect(&mut res, &mut admin_queue).await;
+        collect(&mut res, &mut bob_queue).await;
+        assert_eq!(res, "");
+
+        // GLOBOPS reach the operators subscribed to the g snomask, and need to be an operator.
+        handle_message(&state, alice, "GLOBOPS :Hello").await;
+        handle_message(&state, helper, "GLOBOPS :Hello").await;
+        let mut res = String::new();
+        collect(&mut res, &mut alice_queue).await;
+        assert_msgs(
+            &res,
+            &[(
+                Some("ellidri.test"),
+                Err(rpl::ERR_NOPRIVILEDGES),
+                &["alice", lines::NO_PRIVILEDGES],
+            )],
+        );
+        for (nick, queue) in &mut [("admin", &mut admin_queue), ("helper", &mut helper_queue)] {
+            let mut res = String::new();
+            collect(&mut res, queue).await;
+            assert_msgs(
+                &res,
+                &[(
+                    Some("ellidri.test"),
+                    Ok(Command::Notice),
+                    &[nick, "*** Notice -- Global -- from helper: Hello"],
+                )],
+            );
+        }
+        let mut res = String::new();
+        collect(&mut res, &mut bob_queue).await;
+        assert_eq!(res, "");
+    }
+}
@@ -29,14 +29,30 @@ impl super::StateInner {
         let mut msg = ctx.rb.reply(Command::Cap).param("LS");
 
         let trailing = msg.raw_trailing_param();
-        trailing.push_str(data::cap::ls_common());
+        trailing.push_str(self.capabilities());
 
         Ok(())
     }
 
+    /// Returns the capabilities advertised to clients, separated by spaces.
+    pub(super) fn capabilities(&self) -> &str {
+        &self.capabilities
+    }
+
     pub fn cmd_cap_req(&mut self, ctx: CommandContext<'_>, req: data::cap::Diff) -> Result {
-        let client = &mut self.clients[ctx.id];
+        let mut requested = String::new();
+        req.write(&mut requested);
+        let disabled = data::cap::query(&requested)
+            .any(|(cap, enable)| enable && self.disabled_capabilities.iter().any(|d| d == cap));
+        if disabled {
+            ctx.rb
+                .reply(Command::Cap)
+                .param("NAK")
+                .trailing_param(&requested);
+            return Err(());
+        }
 
+        let client = &mut self.clients[ctx.id];
         client.cap_enabled.update(req);
 
         let mut msg = ctx.rb.reply(Command::Cap).param("ACK");
@@ -50,6 +66,16 @@ impl super::StateInner {
     }
 }
 
+/// Returns the capabilities advertised to clients when `disabled` cannot be enabled, separated by
+/// spaces.
+pub(super) fn capabilities(disabled: &[String]) -> String {
+    let caps: Vec<_> = data::cap::ls_common()
+        .split(' ')
+        .filter(|cap| !disabled.iter().any(|d| d == cap))
+        .collect();
+    caps.join(" ")
+}
+
 /// Handlers for commands related to the setname specification.
 impl super::StateInner {
     pub fn cmd_setname(&mut self, ctx: CommandContext<'_>, realname: &str) -> Result {
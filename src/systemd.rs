@@ -0,0 +1,90 @@
+//! Integration with systemd: socket activation, readiness notifications and the watchdog.
+//!
+//! See `sd_listen_fds(3)` and `sd_notify(3)`.  Notifications are sent to the socket given by the
+//! service manager in `NOTIFY_SOCKET`, and ignored when ellidri is not run by systemd.
+
+use crate::{net, State};
+use std::env;
+use std::time::Duration;
+use tokio::time;
+
+/// The service has finished starting up or reloading its configuration.
+pub const READY: &str = "READY=1";
+
+/// The service is reloading its configuration.
+pub const RELOADING: &str = "RELOADING=1";
+
+/// The service is shutting down.
+pub const STOPPING: &str = "STOPPING=1";
+
+const WATCHDOG: &str = "WATCHDOG=1";
+
+/// Returns the sockets passed by the service manager, along with their name.
+#[cfg(unix)]
+pub fn listen_fds() -> Vec<(String, net::InheritedFd)> {
+    let fds = ellidri_sys::listen_fds();
+    for (name, _) in &fds {
+        log::debug!("Received socket {:?} from the service manager", name);
+    }
+    fds
+}
+
+#[cfg(not(unix))]
+pub fn listen_fds() -> Vec<(String, net::InheritedFd)> {
+    Vec::new()
+}
+
+/// Sends `state` to the service manager.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let res = UnixDatagram::unbound().and_then(|socket| match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt as _;
+            use std::os::unix::net::SocketAddr;
+
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(err) = res {
+        log::warn!(
+            "Failed to notify the service manager at {:?}: {}",
+            path,
+            err
+        );
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify(_: &str) {}
+
+/// Returns the interval at which the watchdog expects heartbeats, if it is enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec / 2)).filter(|interval| !interval.is_zero())
+}
+
+/// Sends heartbeats to the watchdog every `interval`.
+///
+/// The state is locked before each heartbeat, so that the service manager restarts ellidri when
+/// the state is stuck.
+pub async fn watchdog(shared: State, interval: Duration) {
+    loop {
+        time::sleep(interval).await;
+        shared.check_alive().await;
+        notify(WATCHDOG);
+    }
+}
@@ -1,5 +1,5 @@
 #[cfg(feature = "tls")]
-pub use tls_enabled::{Acceptor, IdentityStore};
+pub use tls_enabled::{fingerprint, Acceptor, IdentityStore};
 
 #[cfg(not(feature = "tls"))]
 pub use tls_disabled::{Acceptor, IdentityStore};
@@ -11,10 +11,54 @@ mod tls_enabled {
     use std::path::{Path, PathBuf};
     use std::sync::Arc;
     use std::{fs, io};
-    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::{self, Certificate, DistinguishedNames, Session};
+    use tokio_rustls::server::TlsStream;
+    use tokio_rustls::{webpki, TlsAcceptor};
 
     pub type Acceptor = Arc<TlsAcceptor>;
 
+    /// Client certificate verifier that accepts any certificate, or none at all.
+    ///
+    /// Certificates are only used to identify clients by their fingerprint, so they are not
+    /// checked against any authority.
+    struct AnyClientCert;
+
+    impl rustls::ClientCertVerifier for AnyClientCert {
+        fn client_auth_mandatory(&self, _sni: Option<&webpki::DNSName>) -> Option<bool> {
+            Some(false)
+        }
+
+        fn client_auth_root_subjects(
+            &self,
+            _sni: Option<&webpki::DNSName>,
+        ) -> Option<DistinguishedNames> {
+            Some(DistinguishedNames::new())
+        }
+
+        fn verify_client_cert(
+            &self,
+            _presented_certs: &[Certificate],
+            _sni: Option<&webpki::DNSName>,
+        ) -> Result<rustls::ClientCertVerified, rustls::TLSError> {
+            Ok(rustls::ClientCertVerified::assertion())
+        }
+    }
+
+    /// Returns the SHA-256 fingerprint of the certificate the client has presented, in lowercase
+    /// hexadecimal.
+    pub fn fingerprint<IO>(conn: &TlsStream<IO>) -> Option<String> {
+        use sha2::Digest;
+        use std::fmt::Write as _;
+
+        let certs = conn.get_ref().1.get_peer_certificates()?;
+        let digest = sha2::Sha256::digest(&certs.first()?.0);
+        let mut res = String::with_capacity(2 * digest.len());
+        for byte in digest {
+            let _ = write!(res, "{:02x}", byte);
+        }
+        Some(res)
+    }
+
     /// [Acceptor] cache, to avoid reading the same files several times.
     #[derive(Default)]
     pub struct IdentityStore {
@@ -48,9 +92,9 @@ mod tls_enabled {
         keyfile: &Path,
     ) -> Result<TlsAcceptor, Box<dyn Error + 'static>> {
         use tokio_rustls::rustls::internal::pemfile;
-        use tokio_rustls::rustls::{NoClientAuth, ServerConfig};
+        use tokio_rustls::rustls::ServerConfig;
 
-        let mut config = ServerConfig::new(NoClientAuth::new());
+        let mut config = ServerConfig::new(Arc::new(AnyClientCert));
 
         log::info!("Loading TLS certificate from {:?}", certfile.display());
         let cert = fs::read(certfile).map_err(|err| {
@@ -0,0 +1,245 @@
+//! Upgrades without disconnecting clients.
+//!
+//! Upon receiving SIGUSR2 or an UPGRADE command, `Control` asks every binding to stop and send
+//! its listening socket back.  The state is then saved and emptied (see `State::handoff`), which
+//! makes connection tasks write the messages left in their queue and send their connection here.
+//! TLS connections cannot be passed to another process and are closed.
+//!
+//! Everything is written to the handoff file, along with the number of the file descriptors, and
+//! ellidri replaces itself with the executable at the path it has been started from.  The path of the handoff
+//! file is passed to the new process in the `ELLIDRI_UPGRADE` environment variable.  On failure,
+//! the current process resumes as if the handoff file came from a previous process.
+//!
+//! The handoff file is made of the saved state, followed by these lines:
+//!
+//! - `LISTENER <fd> :<address>` for each listening socket,
+//! - `CONNECTION <fd> <id> <tcp|unix>` for each connection, where `id` is the identifier of the
+//!   client in the saved state.  Tags hold the name, IP address and host of the peer, and the
+//!   bytes received but not yet handled, in base64.
+
+use crate::{client, lines, net, State};
+use std::collections::HashMap;
+#[cfg(unix)]
+use {
+    ellidri_tokens::{Buffer, Message},
+    std::os::unix::{fs::OpenOptionsExt, io::AsRawFd, process::CommandExt},
+    std::{env, fs, io, path, process, time},
+    tokio::sync::mpsc,
+};
+
+/// The environment variable that holds the path of the handoff file.
+#[cfg(unix)]
+const UPGRADE_VAR: &str = "ELLIDRI_UPGRADE";
+
+/// Maximum number of seconds to wait for connection tasks to hand their connection over.
+#[cfg(unix)]
+const HANDOFF_TIMEOUT_SECS: u64 = 10;
+
+/// What is passed from a process to the next on upgrade.
+pub struct Handoff {
+    /// The saved state, see `State::restore`.
+    state: String,
+
+    /// The listening sockets, along with the address of their binding.
+    pub listeners: Vec<(String, net::InheritedFd)>,
+
+    /// The connections of the clients.
+    connections: Vec<net::HandedOff>,
+}
+
+/// Stops the connections and gathers them along with the state and the given listening sockets.
+#[cfg(unix)]
+pub async fn handoff(shared: &State, listeners: Vec<(String, net::InheritedFd)>) -> Handoff {
+    let (sender, mut handed_off) = mpsc::unbounded_channel();
+    let state = shared.handoff(sender).await;
+
+    let mut connections = Vec::new();
+    let gather = async {
+        while net::open_connections() != 0 {
+            tokio::select! {
+                Some(conn) = handed_off.recv() => connections.push(conn),
+                _ = tokio::time::sleep(time::Duration::from_millis(50)) => {}
+            }
+        }
+    };
+    let timeout = time::Duration::from_secs(HANDOFF_TIMEOUT_SECS);
+    if tokio::time::timeout(timeout, gather).await.is_err() {
+        log::warn!(
+            "{} connections were not handed over in time",
+            net::open_connections()
+        );
+    }
+
+    // Connections that have been sent while waiting for the last tasks to end.
+    handed_off.close();
+    while let Some(conn) = handed_off.recv().await {
+        connections.push(conn);
+    }
+
+    Handoff {
+        state,
+        listeners,
+        connections,
+    }
+}
+
+/// Writes the handoff file and replaces the current process with the executable at `exe`, with
+/// the same arguments.  Only returns on failure.
+#[cfg(unix)]
+pub fn exec(handoff: &Handoff, exe: &path::Path) -> io::Error {
+    let path = env::temp_dir().join(format!("ellidri-upgrade.{}", process::id()));
+    if let Err(err) = write(&path, handoff) {
+        let _ = fs::remove_file(&path);
+        return err;
+    }
+
+    log::info!("Handing over to {:?}", exe);
+    let err = process::Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(UPGRADE_VAR, &path)
+        .exec();
+    let _ = fs::remove_file(&path);
+    err
+}
+
+/// Writes the handoff file and makes the file descriptors it references survive `execve(2)`.
+#[cfg(unix)]
+fn write(path: &path::Path, handoff: &Handoff) -> io::Result<()> {
+    let mut buf = Buffer::new();
+
+    for (address, fd) in &handoff.listeners {
+        ellidri_sys::set_inheritable(fd)?;
+        buf.tagged_message("")
+            .prefixed_command("", "LISTENER")
+            .fmt_param(fd.as_raw_fd())
+            .trailing_param(address);
+    }
+
+    for conn in &handoff.connections {
+        let (fd, kind) = match &conn.socket {
+            net::Socket::Tcp(socket) => (socket.as_raw_fd(), "tcp"),
+            net::Socket::Unix(socket) => (socket.as_raw_fd(), "unix"),
+        };
+        ellidri_sys::set_inheritable(&fd)?;
+        buf.tagged_message("")
+            .tag("name", Some(&conn.peer.name))
+            .tag("ip", conn.peer.ip)
+            .tag("host", Some(&conn.peer.host))
+            .tag("unread", Some(base64::encode(&conn.unread)))
+            .prefixed_command("", "CONNECTION")
+            .fmt_param(fd)
+            .fmt_param(conn.id)
+            .param(kind);
+    }
+
+    let _ = fs::remove_file(path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    io::Write::write_all(&mut file, handoff.state.as_bytes())?;
+    io::Write::write_all(&mut file, buf.build().as_bytes())
+}
+
+/// Takes what the previous process has passed, if ellidri has been started by an upgrade.
+///
+/// Like `systemd::listen_fds`, it must be called before other threads are spawned.
+#[cfg(unix)]
+pub fn take() -> Option<Handoff> {
+    let path = env::var_os(UPGRADE_VAR)?;
+    env::remove_var(UPGRADE_VAR);
+    let saved = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    let state = match saved {
+        Ok(state) => state,
+        Err(err) => {
+            log::error!("Failed to read the handoff file {:?}: {}", path, err);
+            return None;
+        }
+    };
+
+    let mut listeners = Vec::new();
+    let mut connections = Vec::new();
+    for msg in state.lines().filter_map(Message::parse) {
+        let fd = msg.params[0]
+            .parse()
+            .ok()
+            .and_then(ellidri_sys::inherited_fd);
+        let fd = match (msg.command, fd) {
+            (Err("LISTENER"), Some(fd)) | (Err("CONNECTION"), Some(fd)) => fd,
+            (Err("LISTENER"), None) | (Err("CONNECTION"), None) => {
+                log::warn!(
+                    "Ignoring a socket that has not been passed: {:?}",
+                    msg.params[0]
+                );
+                continue;
+            }
+            _ => continue,
+        };
+        if msg.command == Err("LISTENER") {
+            listeners.push((msg.params[1].to_owned(), fd));
+            continue;
+        }
+
+        let tag = |key| {
+            msg.tags()
+                .find(|tag| tag.key == key)
+                .map(|tag| tag.unescape_value())
+        };
+        let socket = match msg.params[2] {
+            "unix" => net::Socket::Unix(fd.into()),
+            _ => net::Socket::Tcp(fd.into()),
+        };
+        connections.push(net::HandedOff {
+            id: msg.params[1].parse().unwrap_or(usize::MAX),
+            peer: client::Peer {
+                name: tag("name").unwrap_or_default(),
+                ip: tag("ip").and_then(|ip| ip.parse().ok()),
+                host: tag("host").unwrap_or_default(),
+            },
+            socket,
+            unread: tag("unread")
+                .and_then(|u| base64::decode(u).ok())
+                .unwrap_or_default(),
+        });
+    }
+
+    log::info!(
+        "Taking over {} connections from the previous process",
+        connections.len()
+    );
+    Some(Handoff {
+        state,
+        listeners,
+        connections,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn take() -> Option<Handoff> {
+    None
+}
+
+/// Restores the state and resumes the connections passed by `take` or `handoff`.
+///
+/// Restored clients whose connection has been lost are removed.
+pub async fn resume(handoff: Handoff, shared: &State) {
+    let mut restored: HashMap<_, _> = shared.restore(&handoff.state).await;
+
+    for conn in handoff.connections {
+        let client = restored.remove(&conn.id);
+        let peer_id = client.as_ref().map(|(peer_id, _)| *peer_id);
+        let peer = conn.peer.name.clone();
+        if let Err(err) = net::resume(conn, client, shared.clone()) {
+            log::warn!("{}: Failed to resume the connection: {}", peer, err);
+            if let Some(peer_id) = peer_id {
+                shared.peer_quit(peer_id, Some(lines::UPGRADE_LOST)).await;
+            }
+        }
+    }
+
+    for (_, (peer_id, _)) in restored {
+        shared.peer_quit(peer_id, Some(lines::UPGRADE_LOST)).await;
+    }
+}
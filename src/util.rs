@@ -1,7 +1,10 @@
 use rand_chacha::rand_core::{RngCore, SeedableRng};
 use rand_chacha::ChaChaRng;
 use std::cell::RefCell;
-use std::time;
+use std::{fmt, fs, io, time};
+
+/// The files read by the name service switch to map user and group names to their IDs.
+pub const USER_DATABASES: [&str; 3] = ["/etc/nsswitch.conf", "/etc/passwd", "/etc/group"];
 
 thread_local! {
     static RNG: RefCell<ChaChaRng> = RefCell::new(ChaChaRng::seed_from_u64(time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs()));
@@ -120,6 +123,84 @@ fn match_chunk<'a>(chunk: &str, mut s: &'a str) -> (&'a str, bool) {
     (s, true)
 }
 
+/// Checks `password` against the OPER password `hash`.
+///
+/// `hash` is either a bcrypt hash (`$2b$...`), an argon2 hash in the PHC string format
+/// (`$argon2id$...`), or the plain-text password.  Plain-text passwords are compared in constant
+/// time, so that the time it takes does not tell how many characters are right.
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        use argon2::password_hash::{PasswordHash, PasswordVerifier};
+        match PasswordHash::new(hash) {
+            Ok(hash) => argon2::Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        constant_time_eq(hash.as_bytes(), password.as_bytes())
+    }
+}
+
+/// Compares `a` and `b` in a time that only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    std::hint::black_box(diff) == 0
+}
+
+/// Returns the ID and the primary group ID of the user `name`, given either by name or by ID.
+///
+/// Users are looked up through the name service switch, which reads `USER_DATABASES` and may ask
+/// other sources like LDAP.
+#[cfg(unix)]
+pub fn lookup_user(name: &str) -> io::Result<Option<(u32, u32)>> {
+    match name.parse() {
+        Ok(uid) => ellidri_sys::user_by_id(uid),
+        Err(_) => ellidri_sys::user_by_name(name),
+    }
+}
+
+/// Returns the ID of the group `name`, either looked up like users in `lookup_user`, or from
+/// `name` itself when it is a number.
+#[cfg(unix)]
+pub fn lookup_group(name: &str) -> io::Result<Option<u32>> {
+    if let Ok(gid) = name.parse() {
+        return Ok(Some(gid));
+    }
+    ellidri_sys::group_by_name(name)
+}
+
+#[cfg(not(unix))]
+pub fn lookup_user(_: &str) -> io::Result<Option<(u32, u32)>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "users are only supported on Unix",
+    ))
+}
+
+#[cfg(not(unix))]
+pub fn lookup_group(_: &str) -> io::Result<Option<u32>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "groups are only supported on Unix",
+    ))
+}
+
+/// Replaces the contents of the file at `path`, so that readers see either the old or the new
+/// contents, even if ellidri crashes while writing.
+pub fn write_atomically(path: &str, contents: &str) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = fs::File::create(&tmp_path)?;
+    io::Write::write_all(&mut file, contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
 pub fn new_message_id() -> String {
     let mut bytes = [0x0; 24];
     RNG.with(|rng| {
@@ -132,6 +213,12 @@ pub fn new_message_id() -> String {
     std::str::from_utf8(&encoded).unwrap().to_owned()
 }
 
+/// Returns a random token for registration PINGs.
+pub fn new_ping_cookie() -> String {
+    let cookie = RNG.with(|rng| rng.borrow_mut().next_u32());
+    format!("{:08X}", cookie)
+}
+
 /// Current time formatted for message tags.
 pub fn time_precise() -> String {
     let now = time::SystemTime::now();
@@ -154,6 +241,25 @@ pub fn time() -> u64 {
     }
 }
 
+/// Appends `s` to `out` as a JSON string, quotes included.
+pub fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = fmt::Write::write_fmt(out, format_args!("\\u{:04x}", c as u32));
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -184,4 +290,45 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_verify_password() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        assert!(verify_password("hunter2", "hunter2"));
+        assert!(!verify_password("hunter2", "hunter3"));
+        assert!(!verify_password("hunter2", "hunter"));
+        assert!(!verify_password("hunter2", ""));
+
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        assert!(verify_password(&bcrypt, "hunter2"));
+        assert!(!verify_password(&bcrypt, "hunter3"));
+
+        let salt = SaltString::new("c29tZXNhbHQ").unwrap();
+        let argon2 = argon2::Argon2::default()
+            .hash_password(b"hunter2", &salt)
+            .unwrap()
+            .to_string();
+        assert!(verify_password(&argon2, "hunter2"));
+        assert!(!verify_password(&argon2, "hunter3"));
+        assert!(!verify_password("$argon2id$garbage", "$argon2id$garbage"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), Some((0, 0)));
+        assert_eq!(lookup_user("0").unwrap(), Some((0, 0)));
+        assert_eq!(lookup_user("no-such-user-ellidri").unwrap(), None);
+        assert_eq!(lookup_group("root").unwrap(), Some(0));
+        assert_eq!(lookup_group("12345").unwrap(), Some(12345));
+        assert_eq!(lookup_group("no-such-group-ellidri").unwrap(), None);
+    }
+
+    #[test]
+    fn test_push_json_string() {
+        let mut out = String::new();
+        push_json_string(&mut out, "a \"quoted\" \\ line\n\x01☺");
+        assert_eq!(out, r#""a \"quoted\" \\ line\n\u0001☺""#);
+    }
 } // mod tests
@@ -0,0 +1,141 @@
+//! Automatic reloads of the configuration.
+//!
+//! When `watch_config` is set, the directories of the configuration file and of its include
+//! patterns are watched with inotify (on Linux only).  Once a matching file has been written,
+//! moved or removed, and nothing else has changed for `DEBOUNCE_MILLIS`, `rehash` is notified.
+//! The configuration is then reloaded the same way as on REHASH or SIGUSR1: if it cannot be read,
+//! the error is logged and the running configuration is kept.
+
+use std::path;
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::task;
+#[cfg(target_os = "linux")]
+use {
+    crate::util, ellidri_sys::Inotify, std::collections::HashMap, std::io,
+    tokio::io::unix::AsyncFd, tokio::time,
+};
+
+/// Number of milliseconds without changes to wait for before reloading, so that editors and
+/// deployment tools can write several files at once.
+#[cfg(target_os = "linux")]
+const DEBOUNCE_MILLIS: u64 = 500;
+
+/// Watches the configuration files, see the module documentation.
+pub struct Watcher {
+    rehash: Arc<Notify>,
+
+    /// What is being watched, see `Config::sources`.
+    sources: Vec<path::PathBuf>,
+
+    task: Option<task::JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn new(rehash: Arc<Notify>) -> Self {
+        Self {
+            rehash,
+            sources: Vec::new(),
+            task: None,
+        }
+    }
+
+    /// Starts, restarts or stops watching the given configuration files and include patterns.
+    pub fn configure(&mut self, watch_config: bool, sources: Vec<path::PathBuf>) {
+        if !watch_config {
+            self.stop();
+            return;
+        }
+        if self.task.is_some() && self.sources == sources {
+            return;
+        }
+        self.stop();
+        self.sources = sources;
+        self.start();
+    }
+
+    #[cfg(target_os = "linux")]
+    fn start(&mut self) {
+        let sources = self.sources.clone();
+        let rehash = self.rehash.clone();
+        self.task = Some(tokio::spawn(async move {
+            if let Err(err) = watch(&sources, &rehash).await {
+                log::error!("Stopped watching the configuration: {}", err);
+            }
+        }));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn start(&mut self) {
+        log::warn!("'watch_config' is only supported on Linux");
+    }
+
+    fn stop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.sources.clear();
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Notifies `rehash` each time the files that match `sources` change.
+#[cfg(target_os = "linux")]
+async fn watch(sources: &[path::PathBuf], rehash: &Notify) -> io::Result<()> {
+    let inotify = Inotify::new()?;
+    let mut masks: HashMap<i32, Vec<String>> = HashMap::new();
+    for source in sources {
+        let dir = source
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| path::Path::new("."));
+        let mask = match source.file_name().and_then(|name| name.to_str()) {
+            Some(mask) => mask.to_owned(),
+            None => continue,
+        };
+        match inotify.watch_dir(dir) {
+            Ok(watch) => masks.entry(watch).or_default().push(mask),
+            Err(err) => log::warn!("Failed to watch {:?}: {}", dir, err),
+        }
+    }
+    let inotify = AsyncFd::new(inotify)?;
+    log::debug!("Watching {:?}", sources);
+
+    loop {
+        changed(&inotify, &masks).await?;
+        let debounce = time::Duration::from_millis(DEBOUNCE_MILLIS);
+        while let Ok(res) = time::timeout(debounce, changed(&inotify, &masks)).await {
+            res?;
+        }
+        log::info!("The configuration has changed");
+        rehash.notify_one();
+    }
+}
+
+/// Returns once a file that matches one of the masks of its directory has changed.
+#[cfg(target_os = "linux")]
+async fn changed(inotify: &AsyncFd<Inotify>, masks: &HashMap<i32, Vec<String>>) -> io::Result<()> {
+    loop {
+        let mut guard = inotify.readable().await?;
+        let events = match guard.try_io(|inotify| inotify.get_ref().read_events()) {
+            Ok(events) => events?,
+            Err(_would_block) => continue,
+        };
+        let matches = events.iter().any(|event| {
+            let name = match event.name.to_str() {
+                Some(name) => name,
+                None => return false,
+            };
+            let masks = masks.get(&event.watch).map_or(&[][..], Vec::as_slice);
+            masks.iter().any(|mask| util::match_mask(mask, name))
+        });
+        if matches {
+            return Ok(());
+        }
+    }
+}